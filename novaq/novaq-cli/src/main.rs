@@ -1,15 +1,18 @@
 use std::fs::File;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use ndarray::Array2;
-use novaq_core::{QuantizationConfig, Quantizer};
+use novaq_core::{QuantizationConfig, QuantizedModel, Quantizer};
 use novaq_io::{
-    assemble_manifest, ArtifactWriter, ArtifactWriterConfig, GgufLoader, HuggingFaceConfig,
-    HuggingFaceLoader, ModelFormat, ModelLocator, ProgressTracker, SafeTensorsLoader,
+    assemble_manifest, check_reproduction, is_safetensors_index, ArchiveLoader, ArtifactReader,
+    ArtifactWriter, ArtifactWriterConfig, GgufExportMode, GgufExporter, GgufLoader, GgufMetadata,
+    HuggingFaceConfig, HuggingFaceLoader, ModelFormat, ModelLocator, OnnxLoader, ProgressTracker,
+    PyTorchLoader, SafeTensorsExportMode, SafeTensorsExporter, SafeTensorsLoader, MANIFEST_FILE,
 };
-use novaq_manifest::Manifest;
+use novaq_manifest::{CentroidPrecision, Manifest};
 use rand::{Rng, SeedableRng};
 use tracing::info;
 
@@ -46,15 +49,93 @@ enum Commands {
         #[arg(long, default_value_t = 16)]
         level1_centroids: usize,
 
-        #[arg(long, default_value_t = 8)]
-        level2_centroids: usize,
+        /// Codebook sizes of the residual stages after stage 1, comma separated.
+        #[arg(
+            long,
+            alias = "level2-centroids",
+            value_delimiter = ',',
+            default_value = "8"
+        )]
+        residual_centroids: Vec<usize>,
 
         #[arg(long, default_value_t = 16)]
         max_subspace_dim: usize,
 
         #[arg(long)]
         disable_progress: bool,
+
+        /// Store codebooks at half precision in the packed layer chunks.
+        #[arg(long)]
+        f16_centroids: bool,
+
+        /// Keep downloaded Hugging Face files here and resume interrupted
+        /// downloads on the next run.
+        #[arg(long)]
+        cache_dir: Option<PathBuf>,
+
+        /// Load Hugging Face models from --cache-dir only.
+        #[arg(long, requires = "cache_dir")]
+        offline: bool,
     },
+    /// Compress an artifact's source again with the settings recorded in its
+    /// manifest and check that every chunk comes out identical.
+    Reproduce {
+        #[arg(long, default_value = "artifacts")]
+        artifact: PathBuf,
+
+        /// Directory for the reproduced artifact; must differ from --artifact.
+        #[arg(long)]
+        output: PathBuf,
+
+        #[arg(long)]
+        hf_token: Option<String>,
+
+        #[arg(long)]
+        cache_dir: Option<PathBuf>,
+
+        #[arg(long, requires = "cache_dir")]
+        offline: bool,
+    },
+    /// Write a compressed artifact back out as a GGUF file.
+    ExportGguf {
+        #[arg(long, default_value = "artifacts")]
+        artifact: PathBuf,
+
+        #[arg(long)]
+        output: PathBuf,
+
+        #[arg(long, value_enum, default_value_t = GgufModeArg::F16)]
+        mode: GgufModeArg,
+    },
+    /// Write a compressed artifact back out as a SafeTensors file.
+    ExportSafetensors {
+        #[arg(long, default_value = "artifacts")]
+        artifact: PathBuf,
+
+        #[arg(long)]
+        output: PathBuf,
+
+        #[arg(long, value_enum, default_value_t = SafeTensorsModeArg::F16)]
+        mode: SafeTensorsModeArg,
+    },
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum GgufModeArg {
+    /// Quantized layers dequantized to standard F16 tensors.
+    F16,
+    /// NOVAQ codebooks and packed indices, readable by NOVAQ's loaders.
+    Codebook,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum SafeTensorsModeArg {
+    /// Dequantized F16 tensors for Hugging Face tooling.
+    F16,
+    /// Dequantized BF16 tensors for Hugging Face tooling.
+    Bf16,
+    /// NOVAQ codebooks, packed assignments and normalization statistics.
+    Codebook,
 }
 
 fn main() -> Result<()> {
@@ -74,9 +155,12 @@ fn main() -> Result<()> {
             hf_token,
             target_bits,
             level1_centroids,
-            level2_centroids,
+            residual_centroids,
             max_subspace_dim,
             disable_progress,
+            f16_centroids,
+            cache_dir,
+            offline,
         } => {
             run_compress_model(
                 &input,
@@ -84,15 +168,87 @@ fn main() -> Result<()> {
                 hf_token,
                 target_bits,
                 level1_centroids,
-                level2_centroids,
+                residual_centroids,
                 max_subspace_dim,
                 !disable_progress,
+                f16_centroids,
+                cache_dir,
+                offline,
             )?;
         }
+        Commands::Reproduce {
+            artifact,
+            output,
+            hf_token,
+            cache_dir,
+            offline,
+        } => {
+            run_reproduce(&artifact, &output, hf_token, cache_dir, offline)?;
+        }
+        Commands::ExportGguf {
+            artifact,
+            output,
+            mode,
+        } => {
+            run_export_gguf(&artifact, &output, mode)?;
+        }
+        Commands::ExportSafetensors {
+            artifact,
+            output,
+            mode,
+        } => {
+            run_export_safetensors(&artifact, &output, mode)?;
+        }
     }
     Ok(())
 }
 
+fn run_export_gguf(artifact: &Path, output: &Path, mode: GgufModeArg) -> Result<()> {
+    let reader = ArtifactReader::open(artifact)?;
+    let model = reader.load_model()?;
+    let mode = match mode {
+        GgufModeArg::F16 => GgufExportMode::F16,
+        GgufModeArg::Codebook => GgufExportMode::Codebook,
+    };
+    let mut exporter = GgufExporter::new(mode)
+        .with_centroid_precision(reader.manifest().layer_encoding.centroid_precision);
+    if let Some(metadata) = GgufMetadata::from_artifact(&reader)? {
+        exporter = exporter.with_metadata(metadata);
+    }
+
+    let mut file = std::io::BufWriter::new(File::create(output)?);
+    exporter.write(&model, &mut file)?;
+    file.flush()?;
+    println!(
+        "exported {} layers to {}",
+        model.layers.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+fn run_export_safetensors(artifact: &Path, output: &Path, mode: SafeTensorsModeArg) -> Result<()> {
+    let reader = ArtifactReader::open(artifact)?;
+    let model = reader.load_model()?;
+    let mode = match mode {
+        SafeTensorsModeArg::F16 => SafeTensorsExportMode::F16,
+        SafeTensorsModeArg::Bf16 => SafeTensorsExportMode::BF16,
+        SafeTensorsModeArg::Codebook => SafeTensorsExportMode::Codebook,
+    };
+
+    let mut file = std::io::BufWriter::new(File::create(output)?);
+    SafeTensorsExporter::new(mode)
+        .with_metadata(reader.manifest().metadata.clone())
+        .write(&model, &mut file)?;
+    file.flush()?;
+    println!(
+        "exported {} layers to {}",
+        model.layers.len(),
+        output.display()
+    );
+    Ok(())
+}
+
 fn run_compress_matrix(rows: usize, cols: usize, seed: u64) -> Result<()> {
     if rows == 0 || cols == 0 {
         return Err(anyhow!("rows and cols must be greater than zero"));
@@ -118,47 +274,136 @@ fn run_compress_matrix(rows: usize, cols: usize, seed: u64) -> Result<()> {
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn run_compress_model(
     input: &str,
     output: &Path,
     hf_token: Option<String>,
     target_bits: f32,
     level1_centroids: usize,
-    level2_centroids: usize,
+    residual_centroids: Vec<usize>,
     max_subspace_dim: usize,
     enable_progress: bool,
+    f16_centroids: bool,
+    cache_dir: Option<PathBuf>,
+    offline: bool,
 ) -> Result<()> {
-    let locator = ModelLocator::new(input);
-    locator
-        .validate()
-        .map_err(|err| anyhow!(err.into_owned()))?;
-    std::fs::create_dir_all(output)?;
-
-    let format = ModelFormat::detect(locator.as_str());
-    let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+    let mut writer_config = ArtifactWriterConfig {
         chunk_bytes: 1 << 20,
         output_dir: output.to_path_buf(),
-    });
-    writer.set_metadata("source", locator.as_str());
-    writer.set_metadata("format", format_string(format));
+        ..ArtifactWriterConfig::default()
+    };
+    if f16_centroids {
+        writer_config.layer_encoding.centroid_precision = CentroidPrecision::F16;
+    }
 
     let config = QuantizationConfig {
         target_bits,
         level1_centroids,
-        level2_centroids,
+        residual_centroids,
         max_subspace_dim,
         ..QuantizationConfig::default()
     };
 
+    let hf_cfg = HuggingFaceConfig {
+        token: hf_token,
+        cache_dir,
+        offline,
+        ..HuggingFaceConfig::default()
+    };
+
+    let (manifest, model) = compress(input, writer_config, &config, hf_cfg, enable_progress)?;
+    write_manifest(output, &manifest)?;
+    print_summary(&model);
+
+    Ok(())
+}
+
+fn run_reproduce(
+    artifact: &Path,
+    output: &Path,
+    hf_token: Option<String>,
+    cache_dir: Option<PathBuf>,
+    offline: bool,
+) -> Result<()> {
+    let original = ArtifactReader::open(artifact)?.manifest().clone();
+    if output.exists() && std::fs::canonicalize(output)? == std::fs::canonicalize(artifact)? {
+        return Err(anyhow!(
+            "--output must not be the artifact being reproduced"
+        ));
+    }
+
+    let config: QuantizationConfig =
+        serde_json::from_value(original.quantization.config.clone())
+            .map_err(|err| anyhow!("manifest holds an unreadable quantization config: {err}"))?;
+    // Fetch the exact commit the original run saw, not wherever its branch
+    // points now.
+    let input = match original
+        .source
+        .as_ref()
+        .and_then(|source| Some((&source.repo_id, source.commit.as_ref()?)))
+    {
+        Some((repo_id, commit)) => format!("hf://{repo_id}@{commit}"),
+        None => original.source_locator.clone(),
+    };
+    let writer_config = ArtifactWriterConfig {
+        chunk_bytes: original.chunk_bytes.unwrap_or(1 << 20),
+        output_dir: output.to_path_buf(),
+        layer_encoding: original.layer_encoding,
+    };
+    let hf_cfg = HuggingFaceConfig {
+        token: hf_token,
+        cache_dir,
+        offline,
+        ..HuggingFaceConfig::default()
+    };
+
+    info!(input, "reproducing artifact");
+    let (manifest, _) = compress(&input, writer_config, &config, hf_cfg, false)?;
+    write_manifest(output, &manifest)?;
+    check_reproduction(&original, &manifest)?;
+    println!(
+        "reproduced {} chunks of {} bit for bit",
+        manifest.chunks.len(),
+        artifact.display()
+    );
+    Ok(())
+}
+
+/// Compresses `input` into `writer_config.output_dir` and returns the
+/// manifest describing the result, without writing it.
+fn compress(
+    input: &str,
+    writer_config: ArtifactWriterConfig,
+    config: &QuantizationConfig,
+    hf_cfg: HuggingFaceConfig,
+    enable_progress: bool,
+) -> Result<(Manifest, QuantizedModel)> {
+    let locator = ModelLocator::new(input);
+    locator
+        .validate()
+        .map_err(|err| anyhow!(err.into_owned()))?;
+    std::fs::create_dir_all(&writer_config.output_dir)?;
+
+    let format = ModelFormat::detect(locator.as_str());
+    let mut writer = ArtifactWriter::new(writer_config);
+    writer.set_metadata("source", locator.as_str());
+    writer.set_metadata("format", format_string(format));
+
     let runtime = tokio::runtime::Runtime::new()?;
     let model = match format {
         ModelFormat::SafeTensors => {
             let loader = SafeTensorsLoader::new(config.clone())?;
-            runtime.block_on(async {
-                let file = tokio::fs::File::open(locator.as_str()).await?;
-                let mut reader = tokio::io::BufReader::new(file);
-                loader.load_from_reader(&mut reader, &mut writer).await
-            })?
+            if is_safetensors_index(locator.as_str()) {
+                runtime
+                    .block_on(loader.load_from_index(Path::new(locator.as_str()), &mut writer))?
+            } else {
+                runtime.block_on(async {
+                    let file = tokio::fs::File::open(locator.as_str()).await?;
+                    let mut reader = tokio::io::BufReader::new(file);
+                    loader.load_from_reader(&mut reader, &mut writer).await
+                })?
+            }
         }
         ModelFormat::Gguf => {
             let loader = GgufLoader::new(config.clone())?;
@@ -168,13 +413,24 @@ fn run_compress_model(
                 loader.load_from_reader(&mut reader, &mut writer).await
             })?
         }
+        ModelFormat::Onnx => {
+            let loader = OnnxLoader::new(config.clone())?;
+            runtime.block_on(loader.load_from_path(Path::new(locator.as_str()), &mut writer))?
+        }
+        ModelFormat::Archive => {
+            let loader = ArchiveLoader::new(config.clone())?;
+            runtime.block_on(loader.load_from_path(Path::new(locator.as_str()), &mut writer))?
+        }
+        ModelFormat::PyTorchStateDict => PyTorchLoader::new(config.clone())?
+            .load_from_path(Path::new(locator.as_str()), &mut writer)?,
         ModelFormat::HuggingFaceSnapshot => {
-            let token = hf_token
-                .or_else(|| std::env::var("HUGGINGFACE_TOKEN").ok())
-                .or_else(|| std::env::var("HF_TOKEN").ok());
-
-            let mut hf_cfg = HuggingFaceConfig::default();
-            hf_cfg.token = token;
+            let hf_cfg = HuggingFaceConfig {
+                token: hf_cfg
+                    .token
+                    .or_else(|| std::env::var("HUGGINGFACE_TOKEN").ok())
+                    .or_else(|| std::env::var("HF_TOKEN").ok()),
+                ..hf_cfg
+            };
 
             let mut loader = HuggingFaceLoader::new(hf_cfg, config.clone())?;
 
@@ -187,36 +443,33 @@ fn run_compress_model(
         }
         _ => {
             return Err(anyhow!(
-                "unsupported model format: {:?}. Supported inputs are safetensors, gguf, and huggingface snapshots",
+                "unsupported model format: {:?}. Supported inputs are safetensors, gguf, onnx, pytorch checkpoints, archives, and huggingface snapshots",
                 format
             ));
         }
     };
 
-    let artifact_manifest = writer.into_manifest();
+    let artifact_manifest = writer.into_manifest()?;
     let manifest = assemble_manifest(
         &locator,
         format!("novaq-cli/{}", env!("CARGO_PKG_VERSION")),
-        &config,
+        config,
         &model,
         &artifact_manifest,
     )?;
 
-    write_manifest(output, &manifest)?;
-    print_summary(&model);
-
-    Ok(())
+    Ok((manifest, model))
 }
 
 fn write_manifest(output: &Path, manifest: &Manifest) -> Result<()> {
-    let path = output.join("manifest.json");
+    let path = output.join(MANIFEST_FILE);
     let file = File::create(&path)?;
     serde_json::to_writer_pretty(file, manifest)?;
     println!("wrote manifest to {}", path.display());
     Ok(())
 }
 
-fn print_summary(model: &novaq_core::QuantizedModel) {
+fn print_summary(model: &QuantizedModel) {
     let summary = &model.summary;
     println!(
         "compressed {} layers → avg bits/weight {:.3}, mse {:.6}, cosine {:.6}",
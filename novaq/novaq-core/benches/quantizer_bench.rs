@@ -1,6 +1,8 @@
-use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
-use ndarray::Array2;
-use novaq_core::{QuantizationConfig, Quantizer};
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use ndarray::{Array2, Axis};
+use novaq_core::{CentroidTable, QuantizationConfig, Quantizer};
 use rand::{rngs::StdRng, Rng, SeedableRng};
 
 fn synthetic_layer(rows: usize, cols: usize, seed: u64) -> Array2<f32> {
@@ -13,7 +15,7 @@ fn bench_quantizer(c: &mut Criterion) {
     let config = QuantizationConfig {
         max_subspace_dim: 16,
         level1_centroids: 16,
-        level2_centroids: 8,
+        residual_centroids: vec![8],
         ..QuantizationConfig::default()
     };
     let quantizer = Quantizer::new(config).expect("valid config");
@@ -36,5 +38,62 @@ fn bench_quantizer(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(quantizer, bench_quantizer);
+/// The per-centroid scalar scan the assignment step used before `CentroidTable`.
+fn scalar_nearest(point: &[f32], centroids: &Array2<f32>) -> (usize, f32) {
+    let mut best_idx = 0usize;
+    let mut best_distance = f32::MAX;
+    for (idx, centroid) in centroids.axis_iter(Axis(0)).enumerate() {
+        let mut distance = 0.0f32;
+        for (a, b) in point.iter().zip(centroid.iter()) {
+            let diff = a - b;
+            distance += diff * diff;
+        }
+        if distance < best_distance {
+            best_distance = distance;
+            best_idx = idx;
+        }
+    }
+    (best_idx, best_distance)
+}
+
+fn bench_assignment(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nearest_centroid");
+    let rows = 4096usize;
+    for &(k, dim) in &[(256usize, 8usize), (256, 16), (256, 32), (256, 128)] {
+        let points = synthetic_layer(rows, dim, 7);
+        let centroids = synthetic_layer(k, dim, 8);
+        let points: Vec<Vec<f32>> = points.outer_iter().map(|row| row.to_vec()).collect();
+        let label = format!("k{}_d{}", k, dim);
+        group.throughput(Throughput::Elements(rows as u64));
+
+        group.bench_function(BenchmarkId::new("scalar", &label), |b| {
+            b.iter(|| {
+                for point in &points {
+                    black_box(scalar_nearest(point, &centroids));
+                }
+            });
+        });
+
+        let table = CentroidTable::new(&centroids);
+        group.bench_function(BenchmarkId::new("table", &label), |b| {
+            b.iter(|| {
+                for point in &points {
+                    black_box(table.nearest(point));
+                }
+            });
+        });
+
+        let partial = CentroidTable::new(&centroids).with_partial_distance(true);
+        group.bench_function(BenchmarkId::new("partial_distance", &label), |b| {
+            b.iter(|| {
+                for point in &points {
+                    black_box(partial.nearest(point));
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(quantizer, bench_quantizer, bench_assignment);
 criterion_main!(quantizer);
@@ -0,0 +1,459 @@
+//! Mixed-precision bit allocation across the layers of a model.
+//!
+//! Every layer is profiled with its statistical analysis plus a trial
+//! quantization at the global codebook sizes. A greedy rate-distortion search
+//! then spends the global `target_bits` budget on the layers where an extra
+//! bit buys the largest (sensitivity-weighted) error reduction.
+
+use ndarray::{Array2, Axis};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument, warn};
+
+use crate::config::QuantizationConfig;
+use crate::error::Result;
+use crate::model::{LayerAllocation, LayerAnalysis};
+use crate::quantization::bits_for_indices;
+use crate::subspace::plan_subspaces;
+use crate::Quantizer;
+
+/// Rows sampled for the trial quantization used to estimate layer error.
+const TRIAL_MAX_ROWS: usize = 256;
+
+/// Largest codebook the allocator will hand out (indices must fit in `u16`).
+const MAX_CENTROIDS: usize = 1 << 12;
+
+const EPS: f32 = 1e-9;
+
+/// Coarse functional role inferred from a tensor name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LayerRole {
+    Embedding,
+    LmHead,
+    AttentionOutput,
+    AttentionProjection,
+    Mlp,
+    Other,
+}
+
+impl LayerRole {
+    pub fn from_name(name: &str) -> Self {
+        let lowered = name.to_ascii_lowercase();
+        let contains_any = |needles: &[&str]| needles.iter().any(|n| lowered.contains(n));
+
+        if contains_any(&["lm_head", "output.weight", "classifier"]) {
+            Self::LmHead
+        } else if contains_any(&["embed", "wte", "wpe", "token_embd"]) {
+            Self::Embedding
+        } else if contains_any(&[
+            "o_proj",
+            "out_proj",
+            "attn.c_proj",
+            "attn_output",
+            "dense.weight",
+        ]) {
+            Self::AttentionOutput
+        } else if contains_any(&[
+            "q_proj", "k_proj", "v_proj", "qkv", "c_attn", "attn_q", "attn_k", "attn_v",
+        ]) {
+            Self::AttentionProjection
+        } else if contains_any(&[
+            "mlp",
+            "ffn",
+            "up_proj",
+            "down_proj",
+            "gate_proj",
+            "fc1",
+            "fc2",
+            "c_fc",
+        ]) {
+            Self::Mlp
+        } else {
+            Self::Other
+        }
+    }
+
+    /// Prior importance multiplier applied on top of the measured error.
+    pub fn weight(self) -> f32 {
+        match self {
+            Self::LmHead => 3.0,
+            Self::AttentionOutput => 2.0,
+            Self::Embedding => 1.5,
+            Self::AttentionProjection => 1.0,
+            Self::Other => 1.0,
+            Self::Mlp => 0.5,
+        }
+    }
+}
+
+/// Per-layer inputs to the bit allocator.
+#[derive(Debug, Clone)]
+pub struct LayerProfile {
+    pub name: String,
+    pub rows: usize,
+    pub cols: usize,
+    pub role: LayerRole,
+    /// Subspace widths chosen by the planner for this layer.
+    pub subspace_widths: Vec<usize>,
+    /// Relative MSE (MSE / variance) of a trial quantization at the global config.
+    pub trial_error: f32,
+    /// Combined importance score; higher means more bits are worth spending here.
+    pub sensitivity: f32,
+    /// Estimated cost of the layer's sparse outliers, which no codebook choice changes.
+    pub outlier_bits: u64,
+}
+
+impl LayerProfile {
+    pub fn parameter_count(&self) -> usize {
+        self.rows * self.cols
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    level1: usize,
+    /// Number of configured residual stages kept.
+    depth: usize,
+    bits: u64,
+    distortion: f64,
+}
+
+pub struct BitAllocator<'a> {
+    config: &'a QuantizationConfig,
+}
+
+impl<'a> BitAllocator<'a> {
+    pub fn new(config: &'a QuantizationConfig) -> Self {
+        Self { config }
+    }
+
+    /// Analyses a layer and runs a trial quantization on a row sample.
+    #[instrument(skip(self, weights), fields(layer = name))]
+    pub fn profile_layer(
+        &self,
+        name: &str,
+        index: usize,
+        weights: &Array2<f32>,
+    ) -> Result<LayerProfile> {
+        let (analysis, plan) = plan_subspaces(self.config, weights)?;
+        let sample = sample_rows(weights, TRIAL_MAX_ROWS);
+        let trial_config = QuantizationConfig {
+            use_parallel: false,
+            mixed_precision: false,
+            ..self.config.clone()
+        };
+        let trial = Quantizer::new(trial_config)?.quantize_layer(name, index, &sample)?;
+        let variance = sample_variance(&sample).max(EPS);
+        let trial_error = trial.metrics.mse / variance;
+        // The trial only saw the sampled rows; outliers scale with the rest.
+        let outlier_bits = trial.normalization.outlier_bits() * analysis.rows as u64
+            / sample.nrows().max(1) as u64;
+
+        let role = LayerRole::from_name(name);
+        let sensitivity = sensitivity_score(role, &analysis, trial_error);
+        debug!(?role, trial_error, sensitivity, "profiled layer");
+
+        Ok(LayerProfile {
+            name: name.to_string(),
+            rows: analysis.rows,
+            cols: analysis.cols,
+            role,
+            subspace_widths: plan.iter().map(|spec| spec.columns.len()).collect(),
+            trial_error,
+            sensitivity,
+            outlier_bits,
+        })
+    }
+
+    /// Chooses per-layer codebook sizes so the estimated total stays within
+    /// `target_bits` per weight while minimizing sensitivity-weighted error.
+    pub fn allocate(&self, profiles: &[LayerProfile]) -> Vec<LayerAllocation> {
+        let total_params: u64 = profiles.iter().map(|p| p.parameter_count() as u64).sum();
+        let budget = (self.config.target_bits as f64 * total_params as f64) as u64;
+
+        let residual_options = self.residual_options();
+        let ladders: Vec<Vec<Candidate>> = profiles
+            .iter()
+            .map(|profile| self.candidate_ladder(profile, &residual_options))
+            .collect();
+        let mut chosen = vec![0usize; profiles.len()];
+        let mut spent: u64 = ladders.iter().map(|ladder| ladder[0].bits).sum();
+        if spent > budget {
+            warn!(
+                spent,
+                budget, "cheapest allocation already exceeds target_bits budget"
+            );
+        }
+
+        loop {
+            let mut best: Option<(usize, f64)> = None;
+            for (layer, ladder) in ladders.iter().enumerate() {
+                let Some(next) = ladder.get(chosen[layer] + 1) else {
+                    continue;
+                };
+                let current = ladder[chosen[layer]];
+                let extra = next.bits.saturating_sub(current.bits).max(1);
+                if spent + extra > budget {
+                    continue;
+                }
+                let gain = (current.distortion - next.distortion) / extra as f64;
+                if best.is_none_or(|(_, best_gain)| gain > best_gain) {
+                    best = Some((layer, gain));
+                }
+            }
+            let Some((layer, _)) = best else {
+                break;
+            };
+            let ladder = &ladders[layer];
+            spent += ladder[chosen[layer] + 1].bits - ladder[chosen[layer]].bits;
+            chosen[layer] += 1;
+        }
+
+        profiles
+            .iter()
+            .zip(ladders.iter().zip(chosen))
+            .map(|(profile, (ladder, choice))| {
+                let candidate = ladder[choice];
+                debug!(
+                    layer = profile.name,
+                    level1 = candidate.level1,
+                    residual_stages = candidate.depth,
+                    bits_per_weight =
+                        candidate.bits as f32 / profile.parameter_count().max(1) as f32,
+                    "allocated layer bits"
+                );
+                let residual_centroids = residual_options[..candidate.depth].to_vec();
+                LayerAllocation {
+                    level1_centroids: candidate.level1,
+                    force_residual: !residual_centroids.is_empty(),
+                    residual_centroids,
+                    sensitivity: Some(profile.sensitivity),
+                }
+            })
+            .collect()
+    }
+
+    /// Configured residual codebook sizes, raised to the minimum cluster size.
+    fn residual_options(&self) -> Vec<usize> {
+        let floor = self.config.min_cluster_size.max(2);
+        self.config
+            .residual_centroids
+            .iter()
+            .map(|&k| k.max(floor))
+            .collect()
+    }
+
+    /// Returns the Pareto-optimal codebook options for a layer, cheapest first.
+    ///
+    /// Candidates combine a stage-1 size with a prefix of the residual stages.
+    fn candidate_ladder(&self, profile: &LayerProfile, residual: &[usize]) -> Vec<Candidate> {
+        let floor = self.config.min_cluster_size.max(2);
+        let base1 = self.config.level1_centroids;
+        let mut level1_options: Vec<usize> = [base1 / 4, base1 / 2, base1, base1 * 2, base1 * 4]
+            .into_iter()
+            .map(|k| k.clamp(floor, MAX_CENTROIDS).min(profile.rows.max(floor)))
+            .collect();
+        level1_options.sort_unstable();
+        level1_options.dedup();
+
+        let base_index_bits =
+            self.index_bits_per_weight(profile, base1, &self.config.residual_centroids);
+        let mut candidates: Vec<Candidate> = level1_options
+            .iter()
+            .flat_map(|&level1| (0..=residual.len()).map(move |depth| (level1, depth)))
+            .map(|(level1, depth)| {
+                let bits = self.estimate_bits(profile, level1, &residual[..depth]);
+                let index_bits = self.index_bits_per_weight(profile, level1, &residual[..depth]);
+                // Rate-distortion rule of thumb: each extra bit per weight quarters the error.
+                let distortion = profile.sensitivity as f64
+                    * profile.parameter_count() as f64
+                    * 2f64.powf(-2.0 * (index_bits - base_index_bits));
+                Candidate {
+                    level1,
+                    depth,
+                    bits,
+                    distortion,
+                }
+            })
+            .collect();
+        candidates.sort_by(|a, b| {
+            a.bits
+                .cmp(&b.bits)
+                .then(b.distortion.total_cmp(&a.distortion))
+        });
+
+        let mut ladder: Vec<Candidate> = Vec::with_capacity(candidates.len());
+        for candidate in candidates {
+            if ladder
+                .last()
+                .is_none_or(|last| candidate.distortion < last.distortion)
+            {
+                ladder.push(candidate);
+            }
+        }
+        ladder
+    }
+
+    fn estimate_bits(&self, profile: &LayerProfile, level1: usize, residual: &[usize]) -> u64 {
+        let rows = profile.rows as u64;
+        let stage_bits = |k: usize, width: u64| -> u64 {
+            let k = k.min(profile.rows.max(1)) as u64;
+            k * width * 32 + rows * bits_for_indices(k)
+        };
+        let codebook_bits: u64 = profile
+            .subspace_widths
+            .iter()
+            .map(|&width| {
+                let width = width as u64;
+                stage_bits(level1, width)
+                    + residual.iter().map(|&k| stage_bits(k, width)).sum::<u64>()
+            })
+            .sum();
+        codebook_bits + profile.outlier_bits
+    }
+
+    fn index_bits_per_weight(
+        &self,
+        profile: &LayerProfile,
+        level1: usize,
+        residual: &[usize],
+    ) -> f64 {
+        let cols = profile.cols.max(1) as f64;
+        let per_row: f64 = profile
+            .subspace_widths
+            .iter()
+            .map(|_| {
+                let stage_bits = |k: usize| (k.min(profile.rows.max(1)).max(1) as f64).log2();
+                stage_bits(level1) + residual.iter().map(|&k| stage_bits(k)).sum::<f64>()
+            })
+            .sum();
+        per_row / cols
+    }
+}
+
+fn sensitivity_score(role: LayerRole, analysis: &LayerAnalysis, trial_error: f32) -> f32 {
+    let tails = (analysis.kurtosis / 3.0).max(1.0).ln();
+    let spread = analysis.anisotropy.max(1.0).ln();
+    let peak = (analysis.max_abs / analysis.std.max(EPS)).max(1.0).ln();
+    role.weight() * trial_error.max(EPS) * (1.0 + 0.5 * tails + 0.1 * spread + 0.1 * peak)
+}
+
+fn sample_rows(weights: &Array2<f32>, max_rows: usize) -> Array2<f32> {
+    let rows = weights.nrows();
+    if rows <= max_rows {
+        return weights.clone();
+    }
+    let stride = rows.div_ceil(max_rows);
+    let indices: Vec<usize> = (0..rows).step_by(stride).collect();
+    weights.select(Axis(0), &indices)
+}
+
+fn sample_variance(weights: &Array2<f32>) -> f32 {
+    let len = weights.len().max(1) as f64;
+    let mean = weights.iter().map(|&v| v as f64).sum::<f64>() / len;
+    let variance = weights
+        .iter()
+        .map(|&v| {
+            let diff = v as f64 - mean;
+            diff * diff
+        })
+        .sum::<f64>()
+        / len;
+    variance as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn random_matrix(rows: usize, cols: usize, seed: u64) -> Array2<f32> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        Array2::from_shape_fn((rows, cols), |_| rng.gen_range(-1.0..1.0))
+    }
+
+    #[test]
+    fn roles_are_inferred_from_names() {
+        assert_eq!(LayerRole::from_name("lm_head.weight"), LayerRole::LmHead);
+        assert_eq!(
+            LayerRole::from_name("model.layers.3.self_attn.o_proj.weight"),
+            LayerRole::AttentionOutput
+        );
+        assert_eq!(
+            LayerRole::from_name("model.layers.3.mlp.up_proj.weight"),
+            LayerRole::Mlp
+        );
+        assert_eq!(
+            LayerRole::from_name("model.embed_tokens.weight"),
+            LayerRole::Embedding
+        );
+    }
+
+    #[test]
+    fn sensitive_layers_receive_more_centroids_within_budget() {
+        let config = QuantizationConfig {
+            // Outliers take close to half a bit per weight of this budget.
+            target_bits: 1.5,
+            max_subspace_dim: 8,
+            level1_centroids: 8,
+            residual_centroids: vec![4],
+            ..QuantizationConfig::default()
+        };
+        let allocator = BitAllocator::new(&config);
+        let weights = random_matrix(512, 32, 5);
+        let profiles = vec![
+            allocator
+                .profile_layer("model.layers.0.mlp.up_proj.weight", 0, &weights)
+                .unwrap(),
+            allocator
+                .profile_layer("lm_head.weight", 1, &weights)
+                .unwrap(),
+        ];
+        let allocations = allocator.allocate(&profiles);
+        assert_eq!(allocations.len(), 2);
+
+        let mlp = &allocations[0];
+        let head = &allocations[1];
+        let capacity = |a: &LayerAllocation| {
+            a.level1_centroids * a.residual_centroids.iter().product::<usize>()
+        };
+        assert!(capacity(head) > capacity(mlp));
+
+        let spent: u64 = profiles
+            .iter()
+            .zip(&allocations)
+            .map(|(p, a)| allocator.estimate_bits(p, a.level1_centroids, &a.residual_centroids))
+            .sum();
+        let params: usize = profiles.iter().map(|p| p.parameter_count()).sum();
+        assert!(spent as f32 / params as f32 <= config.target_bits);
+    }
+
+    #[test]
+    fn estimates_count_outlier_storage() {
+        let config = QuantizationConfig {
+            max_subspace_dim: 8,
+            level1_centroids: 8,
+            residual_centroids: vec![4],
+            outlier_percentile: 0.02,
+            ..QuantizationConfig::default()
+        };
+        let allocator = BitAllocator::new(&config);
+        // Few enough rows that the trial quantizes the whole layer.
+        let weights = random_matrix(128, 32, 9);
+        let profile = allocator.profile_layer("mlp.weight", 0, &weights).unwrap();
+        let layer = Quantizer::new(config.clone())
+            .unwrap()
+            .quantize_layer("mlp.weight", 0, &weights)
+            .unwrap();
+        assert!(profile.outlier_bits > 0);
+        assert_eq!(profile.outlier_bits, layer.normalization.outlier_bits());
+
+        let without = LayerProfile {
+            outlier_bits: 0,
+            ..profile.clone()
+        };
+        assert_eq!(
+            allocator.estimate_bits(&profile, 8, &[4]) - allocator.estimate_bits(&without, 8, &[4]),
+            profile.outlier_bits
+        );
+    }
+}
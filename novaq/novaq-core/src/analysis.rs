@@ -150,6 +150,153 @@ pub fn analyze_layer(weights: &Array2<f32>) -> Result<LayerAnalysis> {
     })
 }
 
+/// Incremental counterpart of [`analyze_layer`] for layers supplied in row blocks.
+///
+/// Higher moments use the single-pass update of Terriberry, so the result
+/// matches the in-memory analysis up to floating-point rounding.
+pub(crate) struct AnalysisAccumulator {
+    cols: usize,
+    rows: usize,
+    count: f64,
+    mean: f64,
+    m2: f64,
+    m3: f64,
+    m4: f64,
+    sumsq: f64,
+    zero_count: usize,
+    max_abs: f32,
+    column_means: Vec<f64>,
+    column_m2: Vec<f64>,
+    row_variances: Vec<f32>,
+}
+
+impl AnalysisAccumulator {
+    pub fn new(cols: usize) -> Self {
+        Self {
+            cols,
+            rows: 0,
+            count: 0.0,
+            mean: 0.0,
+            m2: 0.0,
+            m3: 0.0,
+            m4: 0.0,
+            sumsq: 0.0,
+            zero_count: 0,
+            max_abs: 0.0,
+            column_means: vec![0.0; cols],
+            column_m2: vec![0.0; cols],
+            row_variances: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, block: &Array2<f32>) -> Result<()> {
+        if block.ncols() != self.cols {
+            return Err(NovaQError::DimensionMismatch {
+                expected: self.cols,
+                found: block.ncols(),
+            });
+        }
+        validate_finite(block, "streamed layer block")?;
+
+        for row in block.axis_iter(Axis(0)) {
+            self.rows += 1;
+            let row_count = self.rows as f64;
+            let mut row_mean = 0.0f64;
+            let mut row_m2 = 0.0f64;
+            for (col, &value) in row.iter().enumerate() {
+                let value64 = value as f64;
+
+                let n1 = self.count;
+                self.count += 1.0;
+                let n = self.count;
+                let delta = value64 - self.mean;
+                let delta_n = delta / n;
+                let delta_n2 = delta_n * delta_n;
+                let term1 = delta * delta_n * n1;
+                self.mean += delta_n;
+                self.m4 += term1 * delta_n2 * (n * n - 3.0 * n + 3.0) + 6.0 * delta_n2 * self.m2
+                    - 4.0 * delta_n * self.m3;
+                self.m3 += term1 * delta_n * (n - 2.0) - 3.0 * delta_n * self.m2;
+                self.m2 += term1;
+
+                self.sumsq += value64 * value64;
+                if value.abs() <= 1e-8 {
+                    self.zero_count += 1;
+                }
+                self.max_abs = self.max_abs.max(value.abs());
+
+                let col_delta = value64 - self.column_means[col];
+                self.column_means[col] += col_delta / row_count;
+                self.column_m2[col] += col_delta * (value64 - self.column_means[col]);
+
+                let row_delta = value64 - row_mean;
+                row_mean += row_delta / (col + 1) as f64;
+                row_m2 += row_delta * (value64 - row_mean);
+            }
+            let variance_row = if self.cols > 1 {
+                row_m2 / self.cols as f64
+            } else {
+                0.0
+            };
+            self.row_variances.push(variance_row.max(0.0) as f32);
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<LayerAnalysis> {
+        if self.rows == 0 || self.cols == 0 {
+            return Err(NovaQError::EmptyTensor);
+        }
+        let len = self.count;
+        let variance = if len > 1.0 {
+            (self.m2 / len).max(0.0)
+        } else {
+            0.0
+        };
+        let std = variance.sqrt();
+        let skewness = (self.m3 / len) / std.powi(3).max(EPS);
+        let kurtosis = (self.m4 / len) / std.powi(4).max(EPS);
+
+        let column_variances: Vec<f32> = self
+            .column_m2
+            .iter()
+            .map(|&m2| {
+                if self.rows > 1 {
+                    (m2 / self.rows as f64).max(0.0) as f32
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+        let max_col_var = column_variances.iter().copied().fold(0.0f32, f32::max);
+        let mut min_col_var = column_variances.iter().copied().fold(f32::MAX, f32::min);
+        if min_col_var <= 0.0 {
+            min_col_var = EPS as f32;
+        }
+        let anisotropy = if max_col_var <= EPS as f32 {
+            1.0
+        } else {
+            max_col_var / (min_col_var + EPS as f32)
+        };
+
+        Ok(LayerAnalysis {
+            rows: self.rows,
+            cols: self.cols,
+            mean: self.mean as f32,
+            variance: variance as f32,
+            std: std as f32,
+            kurtosis: kurtosis as f32,
+            skewness: skewness as f32,
+            sparsity: (self.zero_count as f64 / len) as f32,
+            max_abs: self.max_abs,
+            l2_norm: self.sumsq.sqrt() as f32,
+            anisotropy,
+            column_variances,
+            row_variances: self.row_variances,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -167,4 +314,41 @@ mod tests {
         assert_eq!(analysis.row_variances.len(), 3);
         assert!(analysis.anisotropy >= 1.0);
     }
+
+    #[test]
+    fn accumulator_matches_in_memory_analysis() {
+        let weights = Array2::from_shape_fn((37, 5), |(i, j)| {
+            ((i * 5 + j) as f32 * 0.77).sin() * (1.0 + j as f32) + 0.3
+        });
+        let expected = analyze_layer(&weights).unwrap();
+
+        let mut accumulator = AnalysisAccumulator::new(5);
+        for start in (0..37).step_by(8) {
+            let end = (start + 8).min(37);
+            let block = weights.slice(ndarray::s![start..end, ..]).to_owned();
+            accumulator.push(&block).unwrap();
+        }
+        let streamed = accumulator.finish().unwrap();
+
+        let close = |a: f32, b: f32| (a - b).abs() <= 1e-4 * (1.0 + a.abs());
+        assert_eq!(streamed.rows, expected.rows);
+        assert!(close(streamed.mean, expected.mean));
+        assert!(close(streamed.variance, expected.variance));
+        assert!(close(streamed.skewness, expected.skewness));
+        assert!(close(streamed.kurtosis, expected.kurtosis));
+        assert!(close(streamed.anisotropy, expected.anisotropy));
+        for (a, b) in streamed
+            .column_variances
+            .iter()
+            .chain(&streamed.row_variances)
+            .zip(
+                expected
+                    .column_variances
+                    .iter()
+                    .chain(&expected.row_variances),
+            )
+        {
+            assert!(close(*a, *b));
+        }
+    }
 }
@@ -0,0 +1,712 @@
+//! Clustering backends used to train codebook stages.
+//!
+//! Every backend seeds with k-means++ and minimizes the squared distance between
+//! rows and their centroid, optionally with each column's term weighted by its
+//! calibration importance; they differ in how the centroids are then fitted.
+
+use ndarray::{Array2, Axis};
+use rand::rngs::StdRng;
+use rand::Rng;
+
+use crate::config::{ClusteringMethod, QuantizationConfig};
+use crate::distance::{squared_distance, weighted_squared_distance, CentroidTable};
+use crate::error::{NovaQError, Result};
+
+/// Centroids and per-row assignments produced by a [`Clusterer`].
+#[derive(Debug, Clone)]
+pub struct Clustering {
+    pub centroids: Array2<f32>,
+    pub assignments: Vec<usize>,
+    /// Iterations performed; restarting backends report the kept run.
+    pub iterations: usize,
+    /// Column-weighted sum of squared distances to the assigned centroids.
+    pub inertia: f32,
+}
+
+impl Clustering {
+    /// Fraction of centroids that have at least one row assigned to them.
+    pub fn utilization(&self) -> f32 {
+        codebook_utilization(&self.assignments, self.centroids.nrows())
+    }
+}
+
+/// A clustering algorithm that can train a codebook stage.
+pub trait Clusterer: Send + Sync {
+    /// Partitions the rows of `data` into `k` clusters, with `column_weights`
+    /// scaling each column's term of the squared distance.
+    fn cluster(
+        &self,
+        data: &Array2<f32>,
+        column_weights: Option<&[f32]>,
+        k: usize,
+        rng: &mut StdRng,
+    ) -> Result<Clustering>;
+
+    /// Reassigns every row of `data` to one of the trained `centroids` under
+    /// this backend's assignment rule and returns the new inertia.
+    ///
+    /// Returns `None`, leaving `assignments` untouched, for backends whose
+    /// guarantees a full pass over the rows would break.
+    fn reassign(
+        &self,
+        data: &Array2<f32>,
+        column_weights: Option<&[f32]>,
+        centroids: &Array2<f32>,
+        assignments: &mut [usize],
+    ) -> Option<f32>;
+}
+
+/// Builds the backend selected by `config.clustering`.
+pub fn clusterer_for(config: &QuantizationConfig) -> Box<dyn Clusterer> {
+    let lloyd = Lloyd {
+        max_iterations: config.max_iterations,
+        tolerance: config.tolerance,
+        partial_distance: config.partial_distance_search,
+    };
+    match config.clustering {
+        ClusteringMethod::Lloyd => Box::new(lloyd),
+        ClusteringMethod::MiniBatch { batch_size } => Box::new(MiniBatch {
+            batch_size,
+            max_iterations: config.max_iterations,
+            tolerance: config.tolerance,
+            partial_distance: config.partial_distance_search,
+        }),
+        ClusteringMethod::Restarts { restarts } => Box::new(Restarts { restarts, lloyd }),
+        ClusteringMethod::Balanced { capacity_slack } => Box::new(Balanced {
+            capacity_slack,
+            max_iterations: config.max_iterations,
+            tolerance: config.tolerance,
+            partial_distance: config.partial_distance_search,
+        }),
+    }
+}
+
+/// Classic batch k-means: full reassignment then centroid means until
+/// the centroids stop moving.
+#[derive(Debug, Clone, Copy)]
+pub struct Lloyd {
+    pub max_iterations: usize,
+    pub tolerance: f32,
+    /// Use partial-distance search for large codebooks, see [`CentroidTable::with_partial_distance`].
+    pub partial_distance: bool,
+}
+
+impl Clusterer for Lloyd {
+    fn cluster(
+        &self,
+        data: &Array2<f32>,
+        column_weights: Option<&[f32]>,
+        k: usize,
+        rng: &mut StdRng,
+    ) -> Result<Clustering> {
+        check_inputs(data, k)?;
+        let mut centroids = initialize_centroids(data, column_weights, k, rng);
+        let mut assignments = vec![0usize; data.nrows()];
+
+        for iteration in 0..self.max_iterations {
+            let inertia = assign_points(
+                data,
+                column_weights,
+                &centroids,
+                &mut assignments,
+                self.partial_distance,
+            );
+            let new_centroids = recompute_centroids(data, &assignments, k);
+            let shift = centroid_shift(&centroids, &new_centroids);
+            centroids = new_centroids;
+
+            if shift < self.tolerance {
+                return Ok(Clustering {
+                    centroids,
+                    assignments,
+                    iterations: iteration + 1,
+                    inertia,
+                });
+            }
+        }
+
+        let inertia = assign_points(
+            data,
+            column_weights,
+            &centroids,
+            &mut assignments,
+            self.partial_distance,
+        );
+        Ok(Clustering {
+            centroids,
+            assignments,
+            iterations: self.max_iterations,
+            inertia,
+        })
+    }
+
+    fn reassign(
+        &self,
+        data: &Array2<f32>,
+        column_weights: Option<&[f32]>,
+        centroids: &Array2<f32>,
+        assignments: &mut [usize],
+    ) -> Option<f32> {
+        Some(assign_points(
+            data,
+            column_weights,
+            centroids,
+            assignments,
+            self.partial_distance,
+        ))
+    }
+}
+
+/// Mini-batch k-means (Sculley, 2010): each iteration moves the centroids
+/// toward a random batch of rows with per-centroid decaying step sizes, so the
+/// cost per iteration does not grow with the number of rows.
+#[derive(Debug, Clone, Copy)]
+pub struct MiniBatch {
+    pub batch_size: usize,
+    pub max_iterations: usize,
+    pub tolerance: f32,
+    /// Use partial-distance search for large codebooks, see [`CentroidTable::with_partial_distance`].
+    pub partial_distance: bool,
+}
+
+impl Clusterer for MiniBatch {
+    fn cluster(
+        &self,
+        data: &Array2<f32>,
+        column_weights: Option<&[f32]>,
+        k: usize,
+        rng: &mut StdRng,
+    ) -> Result<Clustering> {
+        check_inputs(data, k)?;
+        let rows = data.nrows();
+        if rows <= self.batch_size {
+            // A batch would cover every row anyway.
+            let lloyd = Lloyd {
+                max_iterations: self.max_iterations,
+                tolerance: self.tolerance,
+                partial_distance: self.partial_distance,
+            };
+            return lloyd.cluster(data, column_weights, k, rng);
+        }
+
+        let standard = data.as_standard_layout();
+        let flat = standard.as_slice().expect("standard layout is contiguous");
+        let dim = data.ncols();
+        let row_at = |row: usize| &flat[row * dim..(row + 1) * dim];
+
+        let mut centroids = initialize_centroids(data, column_weights, k, rng);
+        let mut mass = vec![0.0f32; k];
+        let mut batch = vec![0usize; self.batch_size];
+        let mut nearest = vec![0usize; self.batch_size];
+        let mut iterations = self.max_iterations;
+
+        for iteration in 0..self.max_iterations {
+            let previous = centroids.clone();
+            for row in batch.iter_mut() {
+                *row = rng.gen_range(0..rows);
+            }
+            // Assign the whole batch before moving anything, as in the reference algorithm.
+            let table = CentroidTable::new(&centroids)
+                .with_dimension_weights(column_weights)
+                .with_partial_distance(self.partial_distance);
+            for (&row, closest) in batch.iter().zip(nearest.iter_mut()) {
+                *closest = table.nearest(row_at(row)).0;
+            }
+            for (&row, &closest) in batch.iter().zip(&nearest) {
+                mass[closest] += 1.0;
+                let eta = 1.0 / mass[closest];
+                centroids
+                    .row_mut(closest)
+                    .zip_mut_with(&data.row(row), |centroid, &value| {
+                        *centroid += eta * (value - *centroid);
+                    });
+            }
+
+            if centroid_shift(&previous, &centroids) < self.tolerance {
+                iterations = iteration + 1;
+                break;
+            }
+        }
+
+        let mut assignments = vec![0usize; rows];
+        let inertia = assign_points(
+            data,
+            column_weights,
+            &centroids,
+            &mut assignments,
+            self.partial_distance,
+        );
+        Ok(Clustering {
+            centroids,
+            assignments,
+            iterations,
+            inertia,
+        })
+    }
+
+    /// Mini-batch is chosen to keep per-iteration cost independent of the row
+    /// count, so it never takes part in full-pass refinement.
+    fn reassign(
+        &self,
+        _data: &Array2<f32>,
+        _column_weights: Option<&[f32]>,
+        _centroids: &Array2<f32>,
+        _assignments: &mut [usize],
+    ) -> Option<f32> {
+        None
+    }
+}
+
+/// Runs Lloyd from several independent k-means++ seedings and keeps the run
+/// with the lowest inertia.
+#[derive(Debug, Clone, Copy)]
+pub struct Restarts {
+    pub restarts: usize,
+    pub lloyd: Lloyd,
+}
+
+impl Clusterer for Restarts {
+    fn cluster(
+        &self,
+        data: &Array2<f32>,
+        column_weights: Option<&[f32]>,
+        k: usize,
+        rng: &mut StdRng,
+    ) -> Result<Clustering> {
+        let mut best = self.lloyd.cluster(data, column_weights, k, rng)?;
+        for _ in 1..self.restarts {
+            let candidate = self.lloyd.cluster(data, column_weights, k, rng)?;
+            if candidate.inertia < best.inertia {
+                best = candidate;
+            }
+        }
+        Ok(best)
+    }
+
+    fn reassign(
+        &self,
+        data: &Array2<f32>,
+        column_weights: Option<&[f32]>,
+        centroids: &Array2<f32>,
+        assignments: &mut [usize],
+    ) -> Option<f32> {
+        self.lloyd
+            .reassign(data, column_weights, centroids, assignments)
+    }
+}
+
+/// Lloyd iterations with capacity-constrained assignment: no cluster may hold
+/// more than `(1 + capacity_slack) * rows / k` rows, and every centroid is
+/// given at least one row when there are enough rows to go around.
+#[derive(Debug, Clone, Copy)]
+pub struct Balanced {
+    pub capacity_slack: f32,
+    pub max_iterations: usize,
+    pub tolerance: f32,
+    /// Use partial-distance search for large codebooks, see [`CentroidTable::with_partial_distance`].
+    pub partial_distance: bool,
+}
+
+impl Balanced {
+    fn capacity(&self, rows: usize, k: usize) -> usize {
+        let even = rows as f32 / k as f32;
+        ((even * (1.0 + self.capacity_slack)).ceil() as usize).max(rows.div_ceil(k))
+    }
+}
+
+impl Clusterer for Balanced {
+    fn cluster(
+        &self,
+        data: &Array2<f32>,
+        column_weights: Option<&[f32]>,
+        k: usize,
+        rng: &mut StdRng,
+    ) -> Result<Clustering> {
+        check_inputs(data, k)?;
+        let capacity = self.capacity(data.nrows(), k);
+        let mut centroids = initialize_centroids(data, column_weights, k, rng);
+        let mut assignments = vec![0usize; data.nrows()];
+        let mut iterations = self.max_iterations;
+
+        for iteration in 0..self.max_iterations {
+            balanced_assign(data, column_weights, &centroids, capacity, &mut assignments);
+            let new_centroids = recompute_centroids(data, &assignments, k);
+            let shift = centroid_shift(&centroids, &new_centroids);
+            centroids = new_centroids;
+            if shift < self.tolerance {
+                iterations = iteration + 1;
+                break;
+            }
+        }
+
+        balanced_assign(data, column_weights, &centroids, capacity, &mut assignments);
+        let inertia = weighted_inertia(data, column_weights, &centroids, &assignments);
+        Ok(Clustering {
+            centroids,
+            assignments,
+            iterations,
+            inertia,
+        })
+    }
+
+    fn reassign(
+        &self,
+        data: &Array2<f32>,
+        column_weights: Option<&[f32]>,
+        centroids: &Array2<f32>,
+        assignments: &mut [usize],
+    ) -> Option<f32> {
+        let capacity = self.capacity(data.nrows(), centroids.nrows());
+        balanced_assign(data, column_weights, centroids, capacity, assignments);
+        Some(weighted_inertia(
+            data,
+            column_weights,
+            centroids,
+            assignments,
+        ))
+    }
+}
+
+/// Greedy capacity-constrained assignment. Rows that lose the most by missing
+/// their nearest centroid choose first; afterwards each empty cluster takes the
+/// closest row from a cluster that can spare one.
+fn balanced_assign(
+    data: &Array2<f32>,
+    column_weights: Option<&[f32]>,
+    centroids: &Array2<f32>,
+    capacity: usize,
+    assignments: &mut [usize],
+) {
+    let rows = data.nrows();
+    let k = centroids.nrows();
+    let table = CentroidTable::new(centroids).with_dimension_weights(column_weights);
+    let standard = data.as_standard_layout();
+    let flat = standard.as_slice().expect("standard layout is contiguous");
+    let mut distances = vec![0.0f32; rows * k];
+    for (point, out) in flat
+        .chunks_exact(data.ncols())
+        .zip(distances.chunks_exact_mut(k))
+    {
+        table.distances(point, out);
+    }
+    let row_distances = |row: usize| &distances[row * k..(row + 1) * k];
+
+    let regret = |row: usize| {
+        let mut best = f32::MAX;
+        let mut second = f32::MAX;
+        for &distance in row_distances(row) {
+            if distance < best {
+                second = best;
+                best = distance;
+            } else if distance < second {
+                second = distance;
+            }
+        }
+        if second == f32::MAX {
+            0.0
+        } else {
+            second - best
+        }
+    };
+    let mut order: Vec<(usize, f32)> = (0..rows).map(|row| (row, regret(row))).collect();
+    order.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let mut load = vec![0usize; k];
+    for (row, _) in order {
+        let mut best_idx = 0usize;
+        let mut best_distance = f32::MAX;
+        for (idx, &distance) in row_distances(row).iter().enumerate() {
+            if load[idx] < capacity && distance < best_distance {
+                best_distance = distance;
+                best_idx = idx;
+            }
+        }
+        load[best_idx] += 1;
+        assignments[row] = best_idx;
+    }
+
+    if rows < k {
+        return;
+    }
+    for empty in 0..k {
+        if load[empty] > 0 {
+            continue;
+        }
+        let donor = (0..rows)
+            .filter(|&row| load[assignments[row]] > 1)
+            .min_by(|&a, &b| row_distances(a)[empty].total_cmp(&row_distances(b)[empty]));
+        if let Some(row) = donor {
+            load[assignments[row]] -= 1;
+            load[empty] += 1;
+            assignments[row] = empty;
+        }
+    }
+}
+
+fn check_inputs(data: &Array2<f32>, k: usize) -> Result<()> {
+    if data.nrows() == 0 || data.ncols() == 0 {
+        return Err(NovaQError::EmptyTensor);
+    }
+    if k == 0 {
+        return Err(NovaQError::InvalidConfig(
+            "cannot cluster into zero centroids".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Fraction of the `k` centroids referenced by at least one assignment.
+pub(crate) fn codebook_utilization(assignments: &[usize], k: usize) -> f32 {
+    if k == 0 {
+        return 0.0;
+    }
+    let mut used = vec![false; k];
+    for &idx in assignments {
+        used[idx] = true;
+    }
+    used.iter().filter(|&&u| u).count() as f32 / k as f32
+}
+
+pub(crate) fn centroid_shift(old: &Array2<f32>, new: &Array2<f32>) -> f32 {
+    let mut shift = 0.0f32;
+    for (prev, next) in old.iter().zip(new.iter()) {
+        let diff = prev - next;
+        shift += diff * diff;
+    }
+    (shift / old.len().max(1) as f32).sqrt()
+}
+
+/// Assigns each row to the centroid nearest under the column-weighted distance
+/// and returns the resulting inertia.
+pub(crate) fn assign_points(
+    data: &Array2<f32>,
+    column_weights: Option<&[f32]>,
+    centroids: &Array2<f32>,
+    assignments: &mut [usize],
+    partial_distance: bool,
+) -> f32 {
+    let table = CentroidTable::new(centroids)
+        .with_dimension_weights(column_weights)
+        .with_partial_distance(partial_distance);
+    let standard = data.as_standard_layout();
+    let flat = standard.as_slice().expect("standard layout is contiguous");
+    let mut inertia = 0.0f32;
+    for (row_idx, point) in flat.chunks_exact(data.ncols()).enumerate() {
+        let (closest, distance) = table.nearest(point);
+        assignments[row_idx] = closest;
+        inertia += distance;
+    }
+    inertia
+}
+
+/// Column-weighted squared distance from every row to its assigned centroid.
+fn weighted_inertia(
+    data: &Array2<f32>,
+    column_weights: Option<&[f32]>,
+    centroids: &Array2<f32>,
+    assignments: &[usize],
+) -> f32 {
+    let standard = data.as_standard_layout();
+    let flat = standard.as_slice().expect("standard layout is contiguous");
+    let centroids = centroids.as_standard_layout();
+    flat.chunks_exact(data.ncols())
+        .zip(assignments)
+        .map(|(point, &idx)| {
+            let centroid = centroids.row(idx);
+            let centroid = centroid.as_slice().expect("standard layout is contiguous");
+            distance(point, centroid, column_weights)
+        })
+        .sum()
+}
+
+/// Mean of the rows assigned to each centroid. Column weights scale every row
+/// of a column alike, so they leave the minimizing centroid unchanged.
+pub(crate) fn recompute_centroids(
+    data: &Array2<f32>,
+    assignments: &[usize],
+    k: usize,
+) -> Array2<f32> {
+    let dim = data.ncols();
+    let mut counts = vec![0usize; k];
+    let mut new_centroids = Array2::<f32>::zeros((k, dim));
+
+    for (row_idx, point) in data.axis_iter(Axis(0)).enumerate() {
+        let centroid_idx = assignments[row_idx];
+        counts[centroid_idx] += 1;
+        for (dest, value) in new_centroids
+            .row_mut(centroid_idx)
+            .iter_mut()
+            .zip(point.iter())
+        {
+            *dest += *value;
+        }
+    }
+
+    for (idx, count) in counts.iter().enumerate() {
+        if *count == 0 {
+            if let Some((_, fallback)) =
+                data.axis_iter(Axis(0))
+                    .enumerate()
+                    .max_by(|(_, a), (_, b)| {
+                        let norm_a = a.iter().map(|v| v * v).sum::<f32>();
+                        let norm_b = b.iter().map(|v| v * v).sum::<f32>();
+                        norm_a
+                            .partial_cmp(&norm_b)
+                            .unwrap_or(std::cmp::Ordering::Equal)
+                    })
+            {
+                new_centroids.row_mut(idx).assign(&fallback);
+            }
+            continue;
+        }
+        for value in new_centroids.row_mut(idx).iter_mut() {
+            *value /= *count as f32;
+        }
+    }
+
+    new_centroids
+}
+
+/// k-means++ seeding under the column-weighted distance.
+pub(crate) fn initialize_centroids(
+    data: &Array2<f32>,
+    column_weights: Option<&[f32]>,
+    k: usize,
+    rng: &mut StdRng,
+) -> Array2<f32> {
+    let rows = data.nrows();
+    let dim = data.ncols();
+    let mut centroids = Array2::<f32>::zeros((k, dim));
+    let first_idx = rng.gen_range(0..rows);
+    centroids.row_mut(0).assign(&data.row(first_idx));
+
+    let standard = data.as_standard_layout();
+    let flat = standard.as_slice().expect("standard layout is contiguous");
+    let points: Vec<&[f32]> = flat.chunks_exact(dim).collect();
+
+    // Squared distance from each row to its nearest centroid chosen so far.
+    let mut distances = vec![f32::MAX; rows];
+    for centroid_idx in 1..k {
+        let newest = centroids.row(centroid_idx - 1).to_vec();
+        for (row_idx, point) in points.iter().enumerate() {
+            distances[row_idx] = distances[row_idx].min(distance(point, &newest, column_weights));
+        }
+        let total_distance: f32 = distances.iter().sum();
+        let mut sample = rng.gen::<f32>() * total_distance.max(1e-9);
+        // Rows at zero distance (the centroids themselves) are never drawn, and
+        // rounding that leaves `sample` positive settles on the last candidate.
+        let mut chosen = None;
+        for (idx, dist) in distances.iter().enumerate() {
+            if *dist <= 0.0 {
+                continue;
+            }
+            chosen = Some(idx);
+            sample -= *dist;
+            if sample <= 0.0 {
+                break;
+            }
+        }
+        let chosen = chosen.unwrap_or(0);
+        centroids.row_mut(centroid_idx).assign(&data.row(chosen));
+    }
+
+    centroids
+}
+
+fn distance(a: &[f32], b: &[f32], column_weights: Option<&[f32]>) -> f32 {
+    match column_weights {
+        Some(weights) => weighted_squared_distance(a, b, weights),
+        None => squared_distance(a, b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    /// Three well separated blobs of unequal size in 2-D.
+    fn blobs() -> Array2<f32> {
+        let centers = [(-4.0f32, 0.0f32, 120usize), (4.0, 0.0, 60), (0.0, 5.0, 20)];
+        let mut rng = StdRng::seed_from_u64(3);
+        let rows: usize = centers.iter().map(|c| c.2).sum();
+        let mut data = Array2::<f32>::zeros((rows, 2));
+        let mut row = 0;
+        for &(x, y, count) in &centers {
+            for _ in 0..count {
+                data[[row, 0]] = x + rng.gen_range(-0.5..0.5);
+                data[[row, 1]] = y + rng.gen_range(-0.5..0.5);
+                row += 1;
+            }
+        }
+        data
+    }
+
+    fn lloyd() -> Lloyd {
+        Lloyd {
+            max_iterations: 100,
+            tolerance: 1e-4,
+            partial_distance: false,
+        }
+    }
+
+    #[test]
+    fn restarts_never_lose_to_a_single_run() {
+        let data = blobs();
+        let single = lloyd()
+            .cluster(&data, None, 8, &mut StdRng::seed_from_u64(11))
+            .unwrap();
+        let restarts = Restarts {
+            restarts: 5,
+            lloyd: lloyd(),
+        }
+        .cluster(&data, None, 8, &mut StdRng::seed_from_u64(11))
+        .unwrap();
+        assert!(restarts.inertia <= single.inertia);
+    }
+
+    #[test]
+    fn mini_batch_approaches_lloyd_inertia() {
+        let data = blobs();
+        let full = lloyd()
+            .cluster(&data, None, 3, &mut StdRng::seed_from_u64(5))
+            .unwrap();
+        let mini = MiniBatch {
+            batch_size: 32,
+            max_iterations: 100,
+            tolerance: 1e-4,
+            partial_distance: false,
+        }
+        .cluster(&data, None, 3, &mut StdRng::seed_from_u64(5))
+        .unwrap();
+        assert_eq!(mini.assignments.len(), data.nrows());
+        assert!(
+            mini.inertia <= full.inertia * 1.5,
+            "{} vs {}",
+            mini.inertia,
+            full.inertia
+        );
+    }
+
+    #[test]
+    fn balanced_uses_every_centroid_within_capacity() {
+        let data = blobs();
+        let balanced = Balanced {
+            capacity_slack: 0.1,
+            max_iterations: 50,
+            tolerance: 1e-4,
+            partial_distance: false,
+        };
+        let k = 8;
+        let result = balanced
+            .cluster(&data, None, k, &mut StdRng::seed_from_u64(9))
+            .unwrap();
+        let capacity = balanced.capacity(data.nrows(), k);
+        let mut load = vec![0usize; k];
+        for &idx in &result.assignments {
+            load[idx] += 1;
+        }
+        assert!(load.iter().all(|&l| l > 0 && l <= capacity), "{load:?}");
+        assert_eq!(result.utilization(), 1.0);
+    }
+}
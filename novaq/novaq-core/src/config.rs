@@ -1,20 +1,111 @@
 use blake3::Hasher;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::error::{NovaQError, Result};
 
+/// How columns are reordered before being split into subspaces.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnPermutation {
+    /// Keep the original column order.
+    #[default]
+    None,
+    /// Sort columns by descending variance so columns of similar scale share a subspace.
+    Variance,
+    /// Greedily fill each subspace with the columns most correlated with its seed.
+    Correlation,
+}
+
+/// Precision outlier values are kept at in the normalization record.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutlierPrecision {
+    /// Keep outliers bit-exact.
+    #[default]
+    F32,
+    /// Round outliers to half precision, halving their storage. Values outside
+    /// the f16 range stay at full precision.
+    F16,
+}
+
+impl OutlierPrecision {
+    /// Rounds `value` to this precision.
+    pub fn round(self, value: f32) -> f32 {
+        match self {
+            OutlierPrecision::F32 => value,
+            OutlierPrecision::F16 => {
+                let rounded = half::f16::from_f32(value).to_f32();
+                // Values beyond the f16 range are kept rather than saturated to infinity.
+                if rounded.is_finite() {
+                    rounded
+                } else {
+                    value
+                }
+            }
+        }
+    }
+}
+
+/// Algorithm used to cluster rows when training each codebook stage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum ClusteringMethod {
+    /// Batch Lloyd iterations from a single k-means++ seeding.
+    #[default]
+    Lloyd,
+    /// Mini-batch k-means drawing `batch_size` rows per iteration.
+    MiniBatch { batch_size: usize },
+    /// Lloyd from `restarts` k-means++ seedings, keeping the lowest inertia.
+    Restarts { restarts: usize },
+    /// Lloyd with cluster sizes capped at `(1 + capacity_slack)` times an even
+    /// split, so every centroid is used.
+    Balanced { capacity_slack: f32 },
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QuantizationConfig {
     pub target_bits: f32,
     pub max_subspace_dim: usize,
     pub min_subspace_dim: usize,
+    /// Column reordering applied before planning subspaces.
+    #[serde(default)]
+    pub column_permutation: ColumnPermutation,
     pub level1_centroids: usize,
-    pub level2_centroids: usize,
+    /// Codebook sizes for the additive residual stages that follow stage 1, in order.
+    ///
+    /// Configs written before stages were generalized carry a scalar
+    /// `level2_centroids` instead, which is read as a single residual stage.
+    #[serde(
+        default = "default_residual_centroids",
+        alias = "level2_centroids",
+        deserialize_with = "deserialize_residual_centroids"
+    )]
+    pub residual_centroids: Vec<usize>,
+    /// Minimum relative drop in residual energy a residual stage must deliver to be kept.
+    #[serde(default = "default_min_stage_gain")]
+    pub min_stage_gain: f32,
     pub outlier_percentile: f32,
+    #[serde(default)]
+    pub outlier_precision: OutlierPrecision,
+    #[serde(default)]
+    pub clustering: ClusteringMethod,
+    /// Prune nearest-centroid scans with partial distances for codebooks of 64+
+    /// entries over more than 16 dimensions.
+    #[serde(default)]
+    pub partial_distance_search: bool,
     pub max_iterations: usize,
     pub tolerance: f32,
     pub seed: u64,
     pub use_parallel: bool,
+    /// Upper bound on the input bytes held in flight by parallel `quantize_model` batches.
+    #[serde(default = "default_parallel_memory_budget")]
+    pub parallel_memory_budget: usize,
+    /// Spread `target_bits` unevenly across layers according to their sensitivity.
+    #[serde(default)]
+    pub mixed_precision: bool,
+    /// Rows kept in the reservoir sample that streaming quantization trains codebooks on.
+    #[serde(default = "default_reservoir_rows")]
+    pub reservoir_rows: usize,
     pub min_cluster_size: usize,
     pub residual_variance_floor: f32,
     pub max_refinement_steps: usize,
@@ -29,13 +120,21 @@ impl Default for QuantizationConfig {
             target_bits: 1.5,
             max_subspace_dim: 16,
             min_subspace_dim: 4,
+            column_permutation: ColumnPermutation::None,
             level1_centroids: 16,
-            level2_centroids: 8,
+            residual_centroids: default_residual_centroids(),
+            min_stage_gain: default_min_stage_gain(),
             outlier_percentile: 0.01,
+            outlier_precision: OutlierPrecision::F32,
+            clustering: ClusteringMethod::Lloyd,
+            partial_distance_search: false,
             max_iterations: 100,
             tolerance: 1e-4,
             seed: 42,
             use_parallel: true,
+            parallel_memory_budget: default_parallel_memory_budget(),
+            mixed_precision: false,
+            reservoir_rows: default_reservoir_rows(),
             min_cluster_size: 4,
             residual_variance_floor: 1e-6,
             max_refinement_steps: 25,
@@ -46,6 +145,41 @@ impl Default for QuantizationConfig {
     }
 }
 
+fn default_parallel_memory_budget() -> usize {
+    1 << 30
+}
+
+fn default_reservoir_rows() -> usize {
+    1 << 14
+}
+
+fn default_residual_centroids() -> Vec<usize> {
+    vec![8]
+}
+
+fn default_min_stage_gain() -> f32 {
+    0.01
+}
+
+/// Accepts a list of residual codebook sizes or the legacy scalar stage-2 size,
+/// where values below 2 meant residual quantization was off.
+pub(crate) fn deserialize_residual_centroids<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<usize>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ResidualCentroids {
+        Stages(Vec<usize>),
+        Level2(usize),
+    }
+
+    Ok(match ResidualCentroids::deserialize(deserializer)? {
+        ResidualCentroids::Stages(stages) => stages,
+        ResidualCentroids::Level2(k) if k >= 2 => vec![k],
+        ResidualCentroids::Level2(_) => Vec::new(),
+    })
+}
+
 impl QuantizationConfig {
     pub fn validate(&self) -> Result<()> {
         if !(0.5..=8.0).contains(&self.target_bits) {
@@ -73,6 +207,25 @@ impl QuantizationConfig {
             ));
         }
 
+        if self.residual_centroids.iter().any(|&k| k < 2) {
+            return Err(NovaQError::InvalidConfig(
+                "residual_centroids entries must be at least 2".to_string(),
+            ));
+        }
+
+        if self.residual_centroids.len() >= u8::MAX as usize {
+            return Err(NovaQError::InvalidConfig(format!(
+                "at most {} residual stages are supported",
+                u8::MAX - 1
+            )));
+        }
+
+        if !(0.0..1.0).contains(&self.min_stage_gain) {
+            return Err(NovaQError::InvalidConfig(
+                "min_stage_gain must be in [0, 1)".to_string(),
+            ));
+        }
+
         if self.outlier_percentile <= 0.0 || self.outlier_percentile >= 1.0 {
             return Err(NovaQError::InvalidConfig(
                 "outlier_percentile must be in (0, 1)".to_string(),
@@ -85,12 +238,50 @@ impl QuantizationConfig {
             ));
         }
 
+        match self.clustering {
+            ClusteringMethod::Lloyd => {}
+            ClusteringMethod::MiniBatch { batch_size } => {
+                if batch_size == 0 {
+                    return Err(NovaQError::InvalidConfig(
+                        "mini-batch clustering needs a positive batch_size".to_string(),
+                    ));
+                }
+            }
+            ClusteringMethod::Restarts { restarts } => {
+                if restarts == 0 {
+                    return Err(NovaQError::InvalidConfig(
+                        "clustering restarts must be positive".to_string(),
+                    ));
+                }
+            }
+            ClusteringMethod::Balanced { capacity_slack } => {
+                if !capacity_slack.is_finite() || capacity_slack < 0.0 {
+                    return Err(NovaQError::InvalidConfig(
+                        "balanced clustering capacity_slack must be finite and non-negative"
+                            .to_string(),
+                    ));
+                }
+            }
+        }
+
         if self.tolerance <= 0.0 {
             return Err(NovaQError::InvalidConfig(
                 "tolerance must be positive".to_string(),
             ));
         }
 
+        if self.parallel_memory_budget == 0 {
+            return Err(NovaQError::InvalidConfig(
+                "parallel_memory_budget must be positive".to_string(),
+            ));
+        }
+
+        if self.reservoir_rows == 0 {
+            return Err(NovaQError::InvalidConfig(
+                "reservoir_rows must be positive".to_string(),
+            ));
+        }
+
         if self.min_cluster_size == 0 {
             return Err(NovaQError::InvalidConfig(
                 "min_cluster_size must be positive".to_string(),
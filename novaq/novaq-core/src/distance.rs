@@ -0,0 +1,273 @@
+//! Nearest-centroid search shared by the clustering backends and row encoding.
+//!
+//! Squared distances are ranked as `||c||^2 - 2 x.c` with the centroid norms
+//! cached, so each comparison costs a single dot product. With the `simd`
+//! feature the kernels run eight lanes at a time through `wide`.
+
+use ndarray::{ArrayBase, Data, Ix2};
+
+/// Codebooks at least this large use partial-distance search when it is enabled.
+pub const PARTIAL_DISTANCE_MIN_CENTROIDS: usize = 64;
+
+/// Dimensions accumulated between early-exit checks in partial-distance search.
+const PARTIAL_DISTANCE_BLOCK: usize = 16;
+
+/// Row-major copy of a codebook with precomputed squared norms.
+#[derive(Debug, Clone)]
+pub struct CentroidTable {
+    values: Vec<f32>,
+    norms: Vec<f32>,
+    weights: Option<Vec<f32>>,
+    dim: usize,
+    partial_distance: bool,
+}
+
+impl CentroidTable {
+    pub fn new<S>(centroids: &ArrayBase<S, Ix2>) -> Self
+    where
+        S: Data<Elem = f32>,
+    {
+        let dim = centroids.ncols();
+        let values: Vec<f32> = centroids.iter().copied().collect();
+        let norms = (0..centroids.nrows())
+            .map(|idx| {
+                let centroid = &values[idx * dim..(idx + 1) * dim];
+                kernel::dot(centroid, centroid)
+            })
+            .collect();
+        Self {
+            values,
+            norms,
+            weights: None,
+            dim,
+            partial_distance: false,
+        }
+    }
+
+    /// Scales each dimension's squared difference by `weights`, e.g. the
+    /// calibration importance of the columns a subspace covers.
+    pub fn with_dimension_weights(mut self, weights: Option<&[f32]>) -> Self {
+        let Some(weights) = weights else {
+            return self;
+        };
+        debug_assert_eq!(weights.len(), self.dim);
+        for (idx, norm) in self.norms.iter_mut().enumerate() {
+            let centroid = &self.values[idx * self.dim..(idx + 1) * self.dim];
+            *norm = centroid.iter().zip(weights).map(|(c, w)| w * c * c).sum();
+        }
+        self.weights = Some(weights.to_vec());
+        self
+    }
+
+    /// Scans codebooks of at least [`PARTIAL_DISTANCE_MIN_CENTROIDS`] entries by
+    /// accumulating each squared distance block by block and abandoning a
+    /// centroid as soon as it exceeds the best one found so far. Centroids no
+    /// wider than one block keep the cached-norm scan, since there is nothing
+    /// to abandon early.
+    pub fn with_partial_distance(mut self, enabled: bool) -> Self {
+        self.partial_distance = enabled;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.norms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.norms.is_empty()
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    /// Index of the centroid closest to `point` and its squared distance; ties
+    /// go to the lowest index.
+    pub fn nearest(&self, point: &[f32]) -> (usize, f32) {
+        debug_assert_eq!(point.len(), self.dim);
+        if self.partial_distance
+            && self.len() >= PARTIAL_DISTANCE_MIN_CENTROIDS
+            && self.dim > PARTIAL_DISTANCE_BLOCK
+        {
+            return self.nearest_partial(point);
+        }
+        let weighted = self.weighted_point(point);
+        let weighted = weighted.as_deref().unwrap_or(point);
+        let mut best_idx = 0usize;
+        let mut best_score = f32::MAX;
+        for (idx, norm) in self.norms.iter().enumerate() {
+            let score = norm - 2.0 * kernel::dot(weighted, self.centroid(idx));
+            if score < best_score {
+                best_score = score;
+                best_idx = idx;
+            }
+        }
+        // The expanded form can dip below zero through cancellation.
+        let distance = (kernel::dot(weighted, point) + best_score).max(0.0);
+        (best_idx, distance)
+    }
+
+    /// Writes the squared distance from `point` to every centroid into `out`.
+    pub fn distances(&self, point: &[f32], out: &mut [f32]) {
+        debug_assert_eq!(out.len(), self.len());
+        let weighted = self.weighted_point(point);
+        let weighted = weighted.as_deref().unwrap_or(point);
+        let point_norm = kernel::dot(weighted, point);
+        for (idx, (dest, norm)) in out.iter_mut().zip(&self.norms).enumerate() {
+            *dest = (point_norm + norm - 2.0 * kernel::dot(weighted, self.centroid(idx))).max(0.0);
+        }
+    }
+
+    /// `point` scaled by the dimension weights, so `weighted . c` is the weighted dot product.
+    fn weighted_point(&self, point: &[f32]) -> Option<Vec<f32>> {
+        self.weights
+            .as_ref()
+            .map(|weights| point.iter().zip(weights).map(|(x, w)| x * w).collect())
+    }
+
+    fn nearest_partial(&self, point: &[f32]) -> (usize, f32) {
+        let mut best_idx = 0usize;
+        let mut best_distance = f32::MAX;
+        for idx in 0..self.len() {
+            let mut distance = 0.0f32;
+            let mut pruned = false;
+            for (block, (a, b)) in point
+                .chunks(PARTIAL_DISTANCE_BLOCK)
+                .zip(self.centroid(idx).chunks(PARTIAL_DISTANCE_BLOCK))
+                .enumerate()
+            {
+                distance += match &self.weights {
+                    Some(weights) => {
+                        let start = block * PARTIAL_DISTANCE_BLOCK;
+                        weighted_squared_distance(a, b, &weights[start..start + a.len()])
+                    }
+                    None => kernel::squared_distance(a, b),
+                };
+                if distance >= best_distance {
+                    pruned = true;
+                    break;
+                }
+            }
+            if !pruned {
+                best_distance = distance;
+                best_idx = idx;
+            }
+        }
+        (best_idx, best_distance)
+    }
+
+    fn centroid(&self, idx: usize) -> &[f32] {
+        &self.values[idx * self.dim..(idx + 1) * self.dim]
+    }
+}
+
+/// Squared Euclidean distance between two equally long slices.
+pub(crate) fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    kernel::squared_distance(a, b)
+}
+
+/// Squared distance with each dimension's term scaled by its weight.
+pub(crate) fn weighted_squared_distance(a: &[f32], b: &[f32], weights: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .zip(weights)
+        .map(|((x, y), w)| w * (x - y) * (x - y))
+        .sum()
+}
+
+#[cfg(feature = "simd")]
+mod kernel {
+    use wide::f32x8;
+
+    const LANES: usize = 8;
+
+    fn load(chunk: &[f32]) -> f32x8 {
+        let mut lanes = [0.0f32; LANES];
+        lanes.copy_from_slice(chunk);
+        f32x8::new(lanes)
+    }
+
+    pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let a_chunks = a.chunks_exact(LANES);
+        let b_chunks = b.chunks_exact(LANES);
+        let tail: f32 = a_chunks
+            .remainder()
+            .iter()
+            .zip(b_chunks.remainder())
+            .map(|(x, y)| x * y)
+            .sum();
+        let mut acc = f32x8::ZERO;
+        for (x, y) in a_chunks.zip(b_chunks) {
+            acc += load(x) * load(y);
+        }
+        acc.reduce_add() + tail
+    }
+
+    pub fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+        let a_chunks = a.chunks_exact(LANES);
+        let b_chunks = b.chunks_exact(LANES);
+        let tail: f32 = a_chunks
+            .remainder()
+            .iter()
+            .zip(b_chunks.remainder())
+            .map(|(x, y)| (x - y) * (x - y))
+            .sum();
+        let mut acc = f32x8::ZERO;
+        for (x, y) in a_chunks.zip(b_chunks) {
+            let diff = load(x) - load(y);
+            acc += diff * diff;
+        }
+        acc.reduce_add() + tail
+    }
+}
+
+#[cfg(not(feature = "simd"))]
+mod kernel {
+    pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(x, y)| x * y).sum()
+    }
+
+    pub fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array2;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn brute_force(point: &[f32], centroids: &Array2<f32>) -> (usize, f32) {
+        let mut best = (0usize, f32::MAX);
+        for (idx, centroid) in centroids.outer_iter().enumerate() {
+            let distance: f32 = point
+                .iter()
+                .zip(centroid.iter())
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum();
+            if distance < best.1 {
+                best = (idx, distance);
+            }
+        }
+        best
+    }
+
+    #[test]
+    fn searches_agree_with_brute_force() {
+        let mut rng = StdRng::seed_from_u64(17);
+        // 19 columns exercises both the vector body and the scalar tail.
+        let centroids = Array2::from_shape_fn((96, 19), |_| rng.gen_range(-2.0f32..2.0));
+        let exhaustive = CentroidTable::new(&centroids);
+        let partial = CentroidTable::new(&centroids).with_partial_distance(true);
+        for _ in 0..200 {
+            let point: Vec<f32> = (0..19).map(|_| rng.gen_range(-2.0f32..2.0)).collect();
+            let (expected_idx, expected_distance) = brute_force(&point, &centroids);
+            for table in [&exhaustive, &partial] {
+                let (idx, distance) = table.nearest(&point);
+                assert_eq!(idx, expected_idx);
+                assert!((distance - expected_distance).abs() < 1e-3 * expected_distance.max(1.0));
+            }
+        }
+    }
+}
@@ -8,6 +8,7 @@ pub fn compute_layer_metrics(
     original: &Array2<f32>,
     reconstructed: &Array2<f32>,
     compressed_bits: u64,
+    column_weights: Option<&[f32]>,
 ) -> LayerMetrics {
     assert_eq!(original.shape(), reconstructed.shape());
 
@@ -29,9 +30,11 @@ pub fn compute_layer_metrics(
     let cosine = (dot / ((norm_orig.sqrt() + EPS) * (norm_rec.sqrt() + EPS))) as f32;
 
     let kl = kl_divergence(original, reconstructed) as f32;
+    let calibrated_mse =
+        column_weights.map(|weights| weighted_mse(original, reconstructed, weights));
 
     let original_bits = (original.len() as u64) * 32;
-    let bits_per_weight = if original.len() == 0 {
+    let bits_per_weight = if original.is_empty() {
         0.0
     } else {
         compressed_bits as f32 / original.len() as f32
@@ -39,6 +42,7 @@ pub fn compute_layer_metrics(
 
     LayerMetrics {
         mse,
+        calibrated_mse,
         cosine_similarity: cosine,
         kl_divergence: kl,
         original_bits,
@@ -47,6 +51,131 @@ pub fn compute_layer_metrics(
     }
 }
 
+/// Streaming counterpart of [`compute_layer_metrics`] for layers reconstructed
+/// one row block at a time.
+///
+/// The softmax KL divergence is kept exact with running log-sum-exp terms:
+/// `KL = sum_i p_i (o_i - r_i) - LSE(o) + LSE(r)`.
+pub(crate) struct MetricsAccumulator {
+    len: usize,
+    mse_acc: f64,
+    dot: f64,
+    norm_orig: f64,
+    norm_rec: f64,
+    weighted_acc: f64,
+    weighted_mass: f64,
+    calibrated: bool,
+    max_orig: f64,
+    exp_orig: f64,
+    cross: f64,
+    max_rec: f64,
+    exp_rec: f64,
+}
+
+impl MetricsAccumulator {
+    pub fn new() -> Self {
+        Self {
+            len: 0,
+            mse_acc: 0.0,
+            dot: 0.0,
+            norm_orig: 0.0,
+            norm_rec: 0.0,
+            weighted_acc: 0.0,
+            weighted_mass: 0.0,
+            calibrated: false,
+            max_orig: f64::NEG_INFINITY,
+            exp_orig: 0.0,
+            cross: 0.0,
+            max_rec: f64::NEG_INFINITY,
+            exp_rec: 0.0,
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        original: &Array2<f32>,
+        reconstructed: &Array2<f32>,
+        column_weights: Option<&[f32]>,
+    ) {
+        assert_eq!(original.shape(), reconstructed.shape());
+        self.calibrated |= column_weights.is_some();
+        for (orig_row, rec_row) in original.rows().into_iter().zip(reconstructed.rows()) {
+            for (col, (o, r)) in orig_row.iter().zip(rec_row.iter()).enumerate() {
+                let weight = column_weights.map_or(1.0, |w| w[col] as f64);
+                let (o, r) = (*o as f64, *r as f64);
+                let diff = o - r;
+                self.mse_acc += diff * diff;
+                self.weighted_acc += weight * diff * diff;
+                self.dot += o * r;
+                self.norm_orig += o * o;
+                self.norm_rec += r * r;
+
+                if o > self.max_orig {
+                    let scale = (self.max_orig - o).exp();
+                    self.exp_orig *= scale;
+                    self.cross *= scale;
+                    self.max_orig = o;
+                }
+                let p = (o - self.max_orig).exp();
+                self.exp_orig += p;
+                self.cross += p * diff;
+
+                if r > self.max_rec {
+                    self.exp_rec *= (self.max_rec - r).exp();
+                    self.max_rec = r;
+                }
+                self.exp_rec += (r - self.max_rec).exp();
+                self.weighted_mass += weight;
+            }
+        }
+        self.len += original.len();
+    }
+
+    pub fn finish(self, compressed_bits: u64) -> LayerMetrics {
+        let len = self.len.max(1) as f64;
+        let cosine = self.dot / ((self.norm_orig.sqrt() + EPS) * (self.norm_rec.sqrt() + EPS));
+        let lse_orig = self.max_orig + self.exp_orig.max(EPS).ln();
+        let lse_rec = self.max_rec + self.exp_rec.max(EPS).ln();
+        let kl = self.cross / self.exp_orig.max(EPS) - lse_orig + lse_rec;
+        let original_bits = self.len as u64 * 32;
+        let bits_per_weight = if self.len == 0 {
+            0.0
+        } else {
+            compressed_bits as f32 / self.len as f32
+        };
+
+        LayerMetrics {
+            mse: (self.mse_acc / len) as f32,
+            calibrated_mse: self
+                .calibrated
+                .then(|| (self.weighted_acc / self.weighted_mass.max(EPS)) as f32),
+            cosine_similarity: cosine as f32,
+            kl_divergence: kl as f32,
+            original_bits,
+            compressed_bits,
+            bits_per_weight,
+        }
+    }
+}
+
+/// Column-weighted MSE, where each column's weight is its calibration importance.
+fn weighted_mse(
+    original: &Array2<f32>,
+    reconstructed: &Array2<f32>,
+    column_weights: &[f32],
+) -> f32 {
+    let mut acc = 0.0f64;
+    let mut mass = 0.0f64;
+    for (orig_row, rec_row) in original.rows().into_iter().zip(reconstructed.rows()) {
+        for ((o, r), &weight) in orig_row.iter().zip(rec_row.iter()).zip(column_weights) {
+            let diff = (*o as f64) - (*r as f64);
+            acc += weight as f64 * diff * diff;
+            mass += weight as f64;
+        }
+    }
+    (acc / mass.max(EPS)) as f32
+}
+
 fn kl_divergence(original: &Array2<f32>, reconstructed: &Array2<f32>) -> f64 {
     let p = to_distribution(original);
     let q = to_distribution(reconstructed);
@@ -88,8 +217,45 @@ mod tests {
     fn metrics_are_reasonable() {
         let orig = array![[1.0f32, 2.0, 3.0]];
         let rec = array![[1.0f32, 1.9, 2.9]];
-        let metrics = compute_layer_metrics(&orig, &rec, 12);
+        let metrics = compute_layer_metrics(&orig, &rec, 12, None);
         assert!(metrics.mse < 0.02);
         assert!(metrics.cosine_similarity > 0.99);
+        assert!(metrics.calibrated_mse.is_none());
+    }
+
+    #[test]
+    fn calibrated_mse_weights_columns() {
+        let orig = array![[1.0f32, 1.0], [1.0, 1.0]];
+        let rec = array![[1.0f32, 0.0], [1.0, 0.0]];
+        let metrics = compute_layer_metrics(&orig, &rec, 4, Some(&[3.0, 1.0]));
+        assert!((metrics.mse - 0.5).abs() < 1e-6);
+        assert!((metrics.calibrated_mse.unwrap() - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn accumulated_metrics_match_full_computation() {
+        let orig = array![[1.0f32, 2.0, 3.0], [0.5, -1.0, 4.0], [2.0, 2.0, -2.0]];
+        let rec = array![[1.1f32, 1.8, 3.0], [0.4, -0.7, 3.5], [2.0, 2.2, -1.9]];
+        let weights = [2.0f32, 0.5, 0.5];
+        let expected = compute_layer_metrics(&orig, &rec, 30, Some(&weights));
+
+        let mut accumulator = MetricsAccumulator::new();
+        accumulator.push(
+            &orig.slice(ndarray::s![..2, ..]).to_owned(),
+            &rec.slice(ndarray::s![..2, ..]).to_owned(),
+            Some(&weights),
+        );
+        accumulator.push(
+            &orig.slice(ndarray::s![2.., ..]).to_owned(),
+            &rec.slice(ndarray::s![2.., ..]).to_owned(),
+            Some(&weights),
+        );
+        let streamed = accumulator.finish(30);
+
+        assert!((streamed.mse - expected.mse).abs() < 1e-6);
+        assert!((streamed.cosine_similarity - expected.cosine_similarity).abs() < 1e-6);
+        assert!((streamed.kl_divergence - expected.kl_divergence).abs() < 1e-5);
+        assert!((streamed.calibrated_mse.unwrap() - expected.calibrated_mse.unwrap()).abs() < 1e-6);
+        assert_eq!(streamed.original_bits, expected.original_bits);
     }
 }
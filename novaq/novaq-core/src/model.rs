@@ -5,6 +5,13 @@ use std::ops::Range;
 use ndarray::Array2;
 use serde::{Deserialize, Serialize};
 
+use crate::config::{deserialize_residual_centroids, QuantizationConfig};
+use crate::error::NovaQError;
+use crate::normalization::denormalize_with_record;
+use crate::outliers::SparseOutliers;
+use crate::quantization::reconstruct_subspaces;
+use crate::subspace::restore_column_order;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OutlierEntry {
     pub row: usize,
@@ -16,15 +23,27 @@ pub struct OutlierEntry {
 pub struct NormalizationRecord {
     pub column_means: Vec<f32>,
     pub column_stds: Vec<f32>,
+    /// Masked values in `(row, col)` order, stored as [`SparseOutliers`].
+    #[serde(
+        serialize_with = "crate::outliers::serialize_outliers",
+        deserialize_with = "crate::outliers::deserialize_outliers"
+    )]
     pub outliers: Vec<OutlierEntry>,
 }
 
+impl NormalizationRecord {
+    /// Bits the outliers occupy in their serialized sparse form.
+    pub fn outlier_bits(&self) -> u64 {
+        SparseOutliers::encode(&self.outliers).encoded_bits()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 /// Statistical snapshot captured before quantization.
 pub struct LayerAnalysis {
-    /// Number of weight rows (input channels).
+    /// Number of weight rows (output channels).
     pub rows: usize,
-    /// Number of weight columns (output channels).
+    /// Number of weight columns (input channels).
     pub cols: usize,
     /// Mean of the tensor values.
     pub mean: f32,
@@ -53,16 +72,58 @@ pub struct CodebookStage {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "StoredSubspace")]
 pub struct QuantizedSubspace {
     pub columns: Range<usize>,
-    pub stage1: CodebookStage,
-    pub stage2: Option<CodebookStage>,
+    /// Additive codebook stages; stage 1 comes first and each later stage encodes
+    /// the residual left by the ones before it.
+    pub stages: Vec<CodebookStage>,
     pub residual_energy: f32,
 }
 
+/// Serialized form of [`QuantizedSubspace`], which also accepts the
+/// `stage1`/`stage2` pair written before stages were generalized.
+#[derive(Deserialize)]
+struct StoredSubspace {
+    columns: Range<usize>,
+    #[serde(default)]
+    stages: Vec<CodebookStage>,
+    #[serde(default)]
+    stage1: Option<CodebookStage>,
+    #[serde(default)]
+    stage2: Option<CodebookStage>,
+    residual_energy: f32,
+}
+
+impl TryFrom<StoredSubspace> for QuantizedSubspace {
+    type Error = String;
+
+    fn try_from(stored: StoredSubspace) -> Result<Self, Self::Error> {
+        let stages = if stored.stages.is_empty() {
+            stored.stage1.into_iter().chain(stored.stage2).collect()
+        } else {
+            stored.stages
+        };
+        if stages.is_empty() {
+            return Err(format!(
+                "subspace {:?} has no codebook stages",
+                stored.columns
+            ));
+        }
+        Ok(Self {
+            columns: stored.columns,
+            stages,
+            residual_energy: stored.residual_energy,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LayerMetrics {
     pub mse: f32,
+    /// MSE weighted by per-input-channel calibration importance, when calibration data was supplied.
+    #[serde(default)]
+    pub calibrated_mse: Option<f32>,
     pub cosine_similarity: f32,
     pub kl_divergence: f32,
     pub original_bits: u64,
@@ -79,23 +140,90 @@ impl LayerMetrics {
     }
 }
 
+/// Training statistics for one codebook stage of a subspace.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageTelemetry {
+    pub stage_id: u8,
+    /// Lloyd iterations performed, including refinement passes.
+    pub iterations: usize,
+    /// Final inertia reported by training.
+    pub inertia: f32,
+    /// Residual energy left once this stage was added, before refinement.
+    pub residual_energy: f32,
+    /// Fraction of the stage's centroids that at least one row was assigned to.
+    #[serde(default)]
+    pub utilization: f32,
+}
+
 /// Telemetry gathered for each quantized subspace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "StoredSubspaceTelemetry")]
 pub struct SubspaceTelemetry {
     /// Column range covered by this subspace.
     pub columns: Range<usize>,
-    /// Lloyd iterations performed for stage 1.
-    pub stage1_iterations: usize,
-    /// Lloyd iterations performed for stage 2 (if enabled).
-    pub stage2_iterations: Option<usize>,
-    /// Final inertia reported by stage 1 training.
-    pub stage1_inertia: f32,
-    /// Final inertia reported by stage 2 training (if enabled).
-    pub stage2_inertia: Option<f32>,
+    /// Per-stage statistics for every stage that was kept.
+    pub stages: Vec<StageTelemetry>,
+    /// Residual stages trained but dropped for missing `min_stage_gain`.
+    pub rejected_stages: usize,
     /// Average residual energy remaining after reconstruction.
     pub residual_energy: f32,
-    /// Whether stage 2 residual quantization was applied.
-    pub enabled_stage2: bool,
+    /// Codebook fine-tuning loss: the starting loss, then one entry per accepted step.
+    #[serde(default)]
+    pub refinement_loss: Vec<f32>,
+}
+
+/// Serialized form of [`SubspaceTelemetry`], which also accepts the per-stage
+/// fields of the two-stage layout.
+#[derive(Deserialize)]
+struct StoredSubspaceTelemetry {
+    columns: Range<usize>,
+    #[serde(default)]
+    stages: Vec<StageTelemetry>,
+    #[serde(default)]
+    rejected_stages: usize,
+    residual_energy: f32,
+    #[serde(default)]
+    refinement_loss: Vec<f32>,
+    #[serde(default)]
+    stage1_iterations: Option<usize>,
+    #[serde(default)]
+    stage1_inertia: Option<f32>,
+    #[serde(default)]
+    stage2_iterations: Option<usize>,
+    #[serde(default)]
+    stage2_inertia: Option<f32>,
+}
+
+impl From<StoredSubspaceTelemetry> for SubspaceTelemetry {
+    fn from(stored: StoredSubspaceTelemetry) -> Self {
+        let mut stages = stored.stages;
+        if stages.is_empty() {
+            // The two-stage layout only recorded the final residual energy.
+            let legacy = [
+                (1, stored.stage1_iterations, stored.stage1_inertia),
+                (2, stored.stage2_iterations, stored.stage2_inertia),
+            ];
+            stages = legacy
+                .into_iter()
+                .filter_map(|(stage_id, iterations, inertia)| {
+                    Some(StageTelemetry {
+                        stage_id,
+                        iterations: iterations?,
+                        inertia: inertia.unwrap_or_default(),
+                        residual_energy: stored.residual_energy,
+                        utilization: 0.0,
+                    })
+                })
+                .collect();
+        }
+        Self {
+            columns: stored.columns,
+            stages,
+            rejected_stages: stored.rejected_stages,
+            residual_energy: stored.residual_energy,
+            refinement_loss: stored.refinement_loss,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -105,6 +233,44 @@ pub struct LayerTelemetry {
     pub subspaces: Vec<SubspaceTelemetry>,
 }
 
+/// Codebook sizes a layer was quantized with.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LayerAllocation {
+    pub level1_centroids: usize,
+    /// Residual stage codebook sizes; an empty list disables residual quantization.
+    #[serde(
+        alias = "level2_centroids",
+        deserialize_with = "deserialize_residual_centroids"
+    )]
+    pub residual_centroids: Vec<usize>,
+    /// Offer residual stages to every subspace instead of deferring to the planner heuristics.
+    #[serde(alias = "force_stage2")]
+    pub force_residual: bool,
+    /// Sensitivity score assigned by the mixed-precision allocator, if it was used.
+    pub sensitivity: Option<f32>,
+}
+
+impl LayerAllocation {
+    /// Allocation that reproduces the global configuration unchanged.
+    pub fn from_config(config: &QuantizationConfig) -> Self {
+        Self {
+            level1_centroids: config.level1_centroids,
+            residual_centroids: config.residual_centroids.clone(),
+            force_residual: false,
+            sensitivity: None,
+        }
+    }
+
+    /// Returns a copy of `config` with this allocation's codebook sizes applied.
+    pub fn apply(&self, config: &QuantizationConfig) -> QuantizationConfig {
+        QuantizationConfig {
+            level1_centroids: self.level1_centroids,
+            residual_centroids: self.residual_centroids.clone(),
+            ..config.clone()
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QuantizedLayer {
     pub name: String,
@@ -117,6 +283,13 @@ pub struct QuantizedLayer {
     pub metrics: LayerMetrics,
     pub quantization_time_us: u64,
     pub telemetry: LayerTelemetry,
+    #[serde(default)]
+    pub allocation: LayerAllocation,
+    /// Column order the layer was quantized in: permuted column `j` is original
+    /// column `column_permutation[j]`. Subspace ranges and normalization data
+    /// are expressed in permuted order.
+    #[serde(default)]
+    pub column_permutation: Option<Vec<usize>>,
 }
 
 impl QuantizedLayer {
@@ -131,6 +304,95 @@ impl QuantizedLayer {
     pub fn original_bits(&self) -> u64 {
         self.metrics.original_bits
     }
+
+    /// Rebuilds the original-scale weights from the stored codebooks, column
+    /// statistics and outliers.
+    pub fn dequantize(&self) -> Array2<f32> {
+        reconstruct_weights(
+            (self.rows, self.cols),
+            &self.normalization,
+            &self.subspaces,
+            self.column_permutation.as_deref(),
+        )
+    }
+
+    /// Checks that the codebooks, statistics and column order fit the layer's
+    /// shape, so that [`Self::dequantize`] cannot index out of bounds. Layers
+    /// read from disk should pass this before they are used.
+    pub fn validate(&self) -> crate::error::Result<()> {
+        let invalid = |reason: String| NovaQError::InvalidInput {
+            reason: format!("layer {}: {reason}", self.name),
+        };
+        let (rows, cols) = (self.rows, self.cols);
+        for subspace in &self.subspaces {
+            let columns = &subspace.columns;
+            if columns.start > columns.end || columns.end > cols {
+                return Err(invalid(format!(
+                    "subspace columns {columns:?} do not fit {cols} columns"
+                )));
+            }
+            let width = columns.len();
+            for stage in &subspace.stages {
+                let id = stage.stage_id;
+                if stage.centroids.ncols() != width {
+                    return Err(invalid(format!(
+                        "stage {id} of subspace {columns:?} has {}-wide centroids",
+                        stage.centroids.ncols()
+                    )));
+                }
+                if stage.assignments.len() != rows {
+                    return Err(invalid(format!(
+                        "stage {id} of subspace {columns:?} assigns {} of {rows} rows",
+                        stage.assignments.len()
+                    )));
+                }
+                let k = stage.centroids.nrows();
+                if let Some(&code) = stage.assignments.iter().find(|&&a| a as usize >= k) {
+                    return Err(invalid(format!(
+                        "stage {id} of subspace {columns:?} assigns centroid {code} of {k}"
+                    )));
+                }
+            }
+        }
+        let normalization = &self.normalization;
+        for (stat, len) in [
+            ("means", normalization.column_means.len()),
+            ("standard deviations", normalization.column_stds.len()),
+        ] {
+            if len != cols {
+                return Err(invalid(format!("{len} column {stat} for {cols} columns")));
+            }
+        }
+        if let Some(permutation) = &self.column_permutation {
+            let mut seen = vec![false; cols];
+            let is_permutation = permutation.len() == cols
+                && permutation
+                    .iter()
+                    .all(|&col| col < cols && !std::mem::replace(&mut seen[col], true));
+            if !is_permutation {
+                return Err(invalid(format!(
+                    "column permutation is not a permutation of {cols} columns"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rebuilds original-scale weights from the parts of a layer, for callers that
+/// store codebooks and statistics without a full [`QuantizedLayer`].
+pub fn reconstruct_weights(
+    (rows, cols): (usize, usize),
+    normalization: &NormalizationRecord,
+    subspaces: &[QuantizedSubspace],
+    column_permutation: Option<&[usize]>,
+) -> Array2<f32> {
+    let normalized = reconstruct_subspaces(rows, cols, subspaces);
+    let weights = denormalize_with_record(&normalized, normalization);
+    match column_permutation {
+        Some(permutation) => restore_column_order(&weights, permutation),
+        None => weights,
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -226,4 +488,88 @@ impl QuantizedModel {
 
         Self { layers, summary }
     }
+
+    /// Lazily dequantizes each layer in order, yielding `(name, weights)` pairs.
+    pub fn dequantize_iter(&self) -> impl Iterator<Item = (&str, Array2<f32>)> + '_ {
+        self.layers
+            .iter()
+            .map(|layer| (layer.name.as_str(), layer.dequantize()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_stage_artifacts_still_load() {
+        let layer: QuantizedLayer =
+            serde_json::from_str(include_str!("../testdata/legacy_two_stage_layer.json")).unwrap();
+        assert_eq!(layer.subspaces.len(), 2);
+        for (subspace, telemetry) in layer.subspaces.iter().zip(&layer.telemetry.subspaces) {
+            let ids: Vec<u8> = subspace.stages.iter().map(|stage| stage.stage_id).collect();
+            assert_eq!(ids, vec![1, 2]);
+            let telemetry_ids: Vec<u8> = telemetry.stages.iter().map(|s| s.stage_id).collect();
+            assert_eq!(telemetry_ids, ids);
+        }
+        assert_eq!(layer.allocation, LayerAllocation::default());
+
+        // The fixture was written from this matrix by the two-stage quantizer.
+        let weights = Array2::from_shape_fn((8, 4), |(i, j)| {
+            ((i * 4 + j) as f32 * 0.731).sin() * (1.0 + j as f32)
+        });
+        let restored = layer.dequantize();
+        let mse = restored
+            .iter()
+            .zip(weights.iter())
+            .map(|(r, w)| (r - w) * (r - w))
+            .sum::<f32>()
+            / weights.len() as f32;
+        assert!((mse - layer.metrics.mse).abs() < 1e-5, "{mse}");
+
+        let reloaded: QuantizedLayer =
+            serde_json::from_str(&serde_json::to_string(&layer).unwrap()).unwrap();
+        assert_eq!(reloaded.subspaces[1].stages.len(), 2);
+    }
+
+    #[test]
+    fn validate_rejects_layers_that_do_not_fit_their_shape() {
+        let quantizer = crate::Quantizer::new(QuantizationConfig::default()).unwrap();
+        let weights = Array2::from_shape_fn((16, 8), |(i, j)| ((i * 8 + j) as f32 * 0.37).sin());
+        let layer = quantizer.quantize_layer("layer", 0, &weights).unwrap();
+        layer.validate().unwrap();
+
+        let tampered: [fn(&mut QuantizedLayer); 6] = [
+            |layer| layer.subspaces[0].columns.end = layer.cols + 1,
+            |layer| layer.subspaces[0].stages[0].centroids = Array2::zeros((4, 1)),
+            |layer| {
+                layer.subspaces[0].stages[0].assignments.pop();
+            },
+            |layer| layer.subspaces[0].stages[0].assignments[0] = u16::MAX,
+            |layer| {
+                layer.normalization.column_stds.push(1.0);
+            },
+            |layer| layer.column_permutation = Some(vec![0; layer.cols]),
+        ];
+        for (case, tamper) in tampered.iter().enumerate() {
+            let mut broken = layer.clone();
+            tamper(&mut broken);
+            assert!(broken.validate().is_err(), "case {case}");
+        }
+    }
+
+    #[test]
+    fn level2_centroids_map_to_residual_stages() {
+        let config: QuantizationConfig =
+            serde_json::from_str(include_str!("../testdata/legacy_config.json")).unwrap();
+        assert_eq!(config.residual_centroids, vec![4]);
+        config.validate().unwrap();
+
+        let allocation: LayerAllocation = serde_json::from_str(
+            r#"{"level1_centroids": 16, "level2_centroids": 0, "force_stage2": true, "sensitivity": null}"#,
+        )
+        .unwrap();
+        assert!(allocation.residual_centroids.is_empty());
+        assert!(allocation.force_residual);
+    }
 }
@@ -32,6 +32,24 @@ impl Normalizer {
         self.normalize_with_context(weights, None)
     }
 
+    /// Number of values to mask as outliers, with the percentile widened for
+    /// heavy-tailed layers and narrowed for very sparse ones.
+    pub(crate) fn outlier_count(&self, len: usize, analysis: Option<&LayerAnalysis>) -> usize {
+        let mut percentile = self.percentile;
+        if let Some(analysis) = analysis {
+            if analysis.kurtosis > 3.0 {
+                let scaling = (analysis.kurtosis / 3.0).min(5.0);
+                percentile = (percentile * scaling).min(0.1);
+            }
+            if analysis.sparsity > 0.9 {
+                percentile = (percentile * 0.5).max(1e-4);
+            }
+        }
+        let outlier_count = ((len as f32) * percentile).ceil() as usize;
+        trace!(percentile, outlier_count, "computed outlier threshold");
+        outlier_count
+    }
+
     #[instrument(skip(self, weights, analysis))]
     pub fn normalize_with_context(
         &self,
@@ -49,18 +67,7 @@ impl Normalizer {
         let cols = weights.ncols();
 
         let mut magnitudes: Vec<f32> = weights.iter().map(|v| v.abs()).collect();
-        let mut percentile = self.percentile;
-        if let Some(analysis) = analysis {
-            if analysis.kurtosis > 3.0 {
-                let scaling = (analysis.kurtosis / 3.0).min(5.0);
-                percentile = (percentile * scaling).min(0.1);
-            }
-            if analysis.sparsity > 0.9 {
-                percentile = (percentile * 0.5).max(1e-4);
-            }
-        }
-        let outlier_count = ((magnitudes.len() as f32) * percentile).ceil() as usize;
-        trace!(percentile, outlier_count, "computed outlier threshold");
+        let outlier_count = self.outlier_count(magnitudes.len(), analysis);
 
         // FIXED: Use deterministic sorting instead of select_nth_unstable_by
         // This ensures reproducibility and correct handling of all values
@@ -157,27 +164,225 @@ impl Normalizer {
         normalized: &Array2<f32>,
         record: &NormalizationRecord,
     ) -> Array2<f32> {
-        let mut reconstructed = normalized.clone();
-        let rows = normalized.nrows();
-        let cols = normalized.ncols();
+        denormalize_with_record(normalized, record)
+    }
+}
 
-        for col in 0..cols {
-            let mean = record.column_means[col];
-            let std = record.column_stds[col].max(EPSILON);
-            for row in 0..rows {
-                let val = normalized[[row, col]];
-                reconstructed[[row, col]] = val * std + mean;
+/// Undoes column standardization and restores the masked outliers verbatim.
+pub(crate) fn denormalize_with_record(
+    normalized: &Array2<f32>,
+    record: &NormalizationRecord,
+) -> Array2<f32> {
+    let mut reconstructed = destandardize(normalized, record);
+    let rows = normalized.nrows();
+    let cols = normalized.ncols();
+
+    for outlier in &record.outliers {
+        if outlier.row < rows && outlier.col < cols {
+            reconstructed[[outlier.row, outlier.col]] = outlier.value;
+        }
+    }
+
+    reconstructed
+}
+
+/// Undoes column standardization only, leaving outlier positions untouched.
+pub(crate) fn destandardize(normalized: &Array2<f32>, record: &NormalizationRecord) -> Array2<f32> {
+    let mut reconstructed = normalized.clone();
+    for (col, mut column) in reconstructed.axis_iter_mut(Axis(1)).enumerate() {
+        let mean = record.column_means[col];
+        let std = record.column_stds[col].max(EPSILON);
+        column.mapv_inplace(|val| val * std + mean);
+    }
+    reconstructed
+}
+
+/// Bits dropped from `|value|` when bucketing magnitudes (11 mantissa bits kept).
+const MAGNITUDE_BUCKET_SHIFT: u32 = 12;
+
+/// Largest boundary bucket whose values are buffered to resolve the exact
+/// threshold; beyond this the whole bucket is treated as outliers.
+const MAX_PENDING_OUTLIERS: u64 = 1 << 20;
+
+/// Histogram of value magnitudes used to pick the outlier threshold without
+/// holding every value in memory.
+pub(crate) struct MagnitudeHistogram {
+    counts: Vec<u64>,
+    total: usize,
+}
+
+/// Where the outlier threshold falls: every magnitude at or above `upper` is an
+/// outlier, and the top `needed` magnitudes in `[lower, upper)` are as well.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct OutlierCut {
+    lower: f32,
+    upper: f32,
+    needed: usize,
+    resolve: bool,
+}
+
+impl MagnitudeHistogram {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; ((f32::INFINITY.to_bits() >> MAGNITUDE_BUCKET_SHIFT) + 1) as usize],
+            total: 0,
+        }
+    }
+
+    pub fn push(&mut self, block: &Array2<f32>) {
+        for value in block.iter() {
+            self.counts[(value.abs().to_bits() >> MAGNITUDE_BUCKET_SHIFT) as usize] += 1;
+        }
+        self.total += block.len();
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Locates the bucket holding the `outlier_count`-th largest magnitude.
+    pub fn cut(&self, outlier_count: usize) -> OutlierCut {
+        let none = OutlierCut {
+            lower: f32::INFINITY,
+            upper: f32::INFINITY,
+            needed: 0,
+            resolve: false,
+        };
+        if outlier_count == 0 || outlier_count >= self.total {
+            return none;
+        }
+        let mut above = 0u64;
+        for (bucket, &count) in self.counts.iter().enumerate().rev() {
+            if above + count >= outlier_count as u64 {
+                return OutlierCut {
+                    lower: f32::from_bits((bucket as u32) << MAGNITUDE_BUCKET_SHIFT),
+                    upper: f32::from_bits((bucket as u32 + 1) << MAGNITUDE_BUCKET_SHIFT),
+                    needed: (outlier_count as u64 - above) as usize,
+                    resolve: count <= MAX_PENDING_OUTLIERS,
+                };
             }
+            above += count;
+        }
+        none
+    }
+}
+
+/// Streams column means and standard deviations over the non-outlier values and
+/// collects the outliers themselves.
+pub(crate) struct ColumnStatsAccumulator {
+    cut: OutlierCut,
+    rows: usize,
+    counts: Vec<usize>,
+    means: Vec<f64>,
+    m2: Vec<f64>,
+    outliers: Vec<OutlierEntry>,
+    pending: Vec<OutlierEntry>,
+}
+
+impl ColumnStatsAccumulator {
+    pub fn new(cols: usize, cut: OutlierCut) -> Self {
+        Self {
+            cut,
+            rows: 0,
+            counts: vec![0; cols],
+            means: vec![0.0; cols],
+            m2: vec![0.0; cols],
+            outliers: Vec::new(),
+            pending: Vec::new(),
         }
+    }
 
-        for outlier in &record.outliers {
-            if outlier.row < rows && outlier.col < cols {
-                reconstructed[[outlier.row, outlier.col]] = outlier.value;
+    pub fn push(&mut self, block: &Array2<f32>) {
+        for row in block.axis_iter(Axis(0)) {
+            for (col, &value) in row.iter().enumerate() {
+                let magnitude = value.abs();
+                if magnitude >= self.cut.lower {
+                    let entry = OutlierEntry {
+                        row: self.rows,
+                        col,
+                        value,
+                    };
+                    if self.cut.resolve && magnitude < self.cut.upper {
+                        self.pending.push(entry);
+                    } else {
+                        self.outliers.push(entry);
+                    }
+                    continue;
+                }
+                self.observe(col, value);
             }
+            self.rows += 1;
         }
+    }
 
-        reconstructed
+    pub fn rows(&self) -> usize {
+        self.rows
     }
+
+    fn observe(&mut self, col: usize, value: f32) {
+        self.counts[col] += 1;
+        let value64 = value as f64;
+        let delta = value64 - self.means[col];
+        self.means[col] += delta / self.counts[col] as f64;
+        self.m2[col] += delta * (value64 - self.means[col]);
+    }
+
+    /// Resolves the exact threshold and returns it with the finished record.
+    pub fn finish(mut self) -> (NormalizationRecord, f32) {
+        let mut threshold = self.cut.lower;
+        if self.cut.resolve && self.cut.needed > 0 {
+            let mut magnitudes: Vec<f32> = self.pending.iter().map(|o| o.value.abs()).collect();
+            magnitudes.sort_by(|a, b| b.total_cmp(a));
+            threshold = magnitudes[self.cut.needed.min(magnitudes.len()) - 1];
+            for entry in std::mem::take(&mut self.pending) {
+                if entry.value.abs() >= threshold {
+                    self.outliers.push(entry);
+                } else {
+                    self.observe(entry.col, entry.value);
+                }
+            }
+            self.outliers.sort_by_key(|o| (o.row, o.col));
+        }
+
+        let column_stds = self
+            .m2
+            .iter()
+            .zip(&self.counts)
+            .map(|(&m2, &count)| (m2 / count.max(1) as f64).sqrt().max(EPSILON as f64) as f32)
+            .collect();
+        debug!(
+            total_outliers = self.outliers.len(),
+            threshold, "streamed normalization complete"
+        );
+        let record = NormalizationRecord {
+            column_means: self.means.iter().map(|&mean| mean as f32).collect(),
+            column_stds,
+            outliers: self.outliers,
+        };
+        (record, threshold)
+    }
+}
+
+/// Standardizes a block of rows with precomputed statistics, zeroing every value
+/// whose magnitude reaches the outlier threshold.
+pub(crate) fn normalize_rows(
+    block: &Array2<f32>,
+    record: &NormalizationRecord,
+    threshold: f32,
+) -> Array2<f32> {
+    let mut normalized = block.clone();
+    for (col, mut column) in normalized.axis_iter_mut(Axis(1)).enumerate() {
+        let mean = record.column_means[col] as f64;
+        let std = record.column_stds[col] as f64;
+        column.mapv_inplace(|val| {
+            if val.abs() >= threshold {
+                0.0
+            } else {
+                ((val as f64 - mean) / std) as f32
+            }
+        });
+    }
+    normalized
 }
 
 #[cfg(test)]
@@ -195,4 +400,37 @@ mod tests {
             assert!((orig - rec).abs() < 1e-4);
         }
     }
+
+    #[test]
+    fn streamed_statistics_match_in_memory_normalization() {
+        let weights = Array2::from_shape_fn((40, 6), |(i, j)| {
+            ((i * 6 + j) as f32 * 1.37).sin() * (1.0 + (i % 7) as f32)
+        });
+        let normalizer = Normalizer::new(0.05).unwrap();
+        let (normalized, record) = normalizer.normalize(&weights).unwrap();
+
+        let mut histogram = MagnitudeHistogram::new();
+        histogram.push(&weights);
+        let cut = histogram.cut(normalizer.outlier_count(histogram.total(), None));
+        let mut accumulator = ColumnStatsAccumulator::new(6, cut);
+        for block in weights.axis_chunks_iter(Axis(0), 9) {
+            accumulator.push(&block.to_owned());
+        }
+        let (streamed, threshold) = accumulator.finish();
+
+        let positions = |record: &NormalizationRecord| -> Vec<(usize, usize)> {
+            record.outliers.iter().map(|o| (o.row, o.col)).collect()
+        };
+        assert_eq!(positions(&streamed), positions(&record));
+        for (a, b) in streamed.column_means.iter().zip(&record.column_means) {
+            assert!((a - b).abs() < 1e-5);
+        }
+        for (a, b) in streamed.column_stds.iter().zip(&record.column_stds) {
+            assert!((a - b).abs() < 1e-5);
+        }
+        let renormalized = normalize_rows(&weights, &streamed, threshold);
+        for (a, b) in renormalized.iter().zip(normalized.iter()) {
+            assert!((a - b).abs() < 1e-4);
+        }
+    }
 }
@@ -0,0 +1,502 @@
+//! Compact sparse encoding of the outliers masked out during normalization.
+//!
+//! Positions are written as LEB128 varints in whichever of two layouts is
+//! smaller. `Coordinate` stores each outlier as a row delta followed by its
+//! column, delta-coded against the previous outlier on the same row.
+//! `CompressedRows` walks every row up to the last outlier and stores its
+//! outlier count followed by either delta-coded columns or a column bitmap.
+//!
+//! Values are stored as f16 when every one of them converts losslessly and as
+//! f32 otherwise, so decoding reproduces the entries bit for bit.
+
+use std::borrow::Cow;
+
+use half::f16;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::config::OutlierPrecision;
+use crate::error::{NovaQError, Result};
+use crate::model::OutlierEntry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutlierLayout {
+    Coordinate,
+    CompressedRows,
+}
+
+/// Serialized form of [`NormalizationRecord::outliers`](crate::NormalizationRecord).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SparseOutliers {
+    pub layout: OutlierLayout,
+    pub precision: OutlierPrecision,
+    pub count: usize,
+    /// Width of the column bitmaps: one past the largest outlier column.
+    pub cols: usize,
+    #[serde(with = "packed_bytes")]
+    pub positions: Vec<u8>,
+    /// Little-endian values at `precision`, in position order.
+    #[serde(with = "packed_bytes")]
+    pub values: Vec<u8>,
+}
+
+impl SparseOutliers {
+    /// Encodes `outliers` in `(row, col)` order. Unsorted input is sorted first,
+    /// and a position listed more than once keeps its last value, which is the
+    /// one restoring the record would leave in place.
+    pub fn encode(outliers: &[OutlierEntry]) -> Self {
+        let outliers = canonical_order(outliers);
+        let outliers = outliers.as_ref();
+        let cols = outliers.iter().map(|o| o.col + 1).max().unwrap_or(0);
+        let coordinate = encode_coordinate(outliers);
+        let compressed_rows = encode_compressed_rows(outliers, cols);
+        let (layout, positions) = if compressed_rows.len() < coordinate.len() {
+            (OutlierLayout::CompressedRows, compressed_rows)
+        } else {
+            (OutlierLayout::Coordinate, coordinate)
+        };
+
+        let half_exact = outliers
+            .iter()
+            .all(|o| f16::from_f32(o.value).to_f32().to_bits() == o.value.to_bits());
+        let (precision, values) = if half_exact {
+            let values = outliers
+                .iter()
+                .flat_map(|o| f16::from_f32(o.value).to_le_bytes())
+                .collect();
+            (OutlierPrecision::F16, values)
+        } else {
+            let values = outliers
+                .iter()
+                .flat_map(|o| o.value.to_le_bytes())
+                .collect();
+            (OutlierPrecision::F32, values)
+        };
+
+        Self {
+            layout,
+            precision,
+            count: outliers.len(),
+            cols,
+            positions,
+            values,
+        }
+    }
+
+    pub fn decode(&self) -> Result<Vec<OutlierEntry>> {
+        let width = match self.precision {
+            OutlierPrecision::F16 => 2,
+            OutlierPrecision::F32 => 4,
+        };
+        // Checked before decoding positions so `count` is bounded by real bytes.
+        if self.count.checked_mul(width) != Some(self.values.len()) {
+            return Err(malformed(format!(
+                "{} outliers do not fit {} value bytes",
+                self.count,
+                self.values.len()
+            )));
+        }
+        let positions = match self.layout {
+            OutlierLayout::Coordinate => decode_coordinate(&self.positions, self.count)?,
+            OutlierLayout::CompressedRows => {
+                decode_compressed_rows(&self.positions, self.count, self.cols)?
+            }
+        };
+        let values = self.values.chunks_exact(width).map(|bytes| match width {
+            2 => f16::from_le_bytes([bytes[0], bytes[1]]).to_f32(),
+            _ => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        });
+        Ok(positions
+            .into_iter()
+            .zip(values)
+            .map(|((row, col), value)| OutlierEntry { row, col, value })
+            .collect())
+    }
+
+    /// Storage cost in bits: a one-byte layout/precision tag, the varint count
+    /// and bitmap width, and the two payloads. An empty set costs nothing.
+    pub fn encoded_bits(&self) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let header = 1 + varint_len(self.count as u64) + varint_len(self.cols as u64);
+        8 * (header + self.positions.len() + self.values.len()) as u64
+    }
+}
+
+/// `outliers` sorted by `(row, col)` with one entry per position.
+fn canonical_order(outliers: &[OutlierEntry]) -> Cow<'_, [OutlierEntry]> {
+    if outliers
+        .windows(2)
+        .all(|pair| (pair[0].row, pair[0].col) < (pair[1].row, pair[1].col))
+    {
+        return Cow::Borrowed(outliers);
+    }
+    let mut sorted = outliers.to_vec();
+    // Stable, so repeated positions stay in input order.
+    sorted.sort_by_key(|o| (o.row, o.col));
+    let mut unique: Vec<OutlierEntry> = Vec::with_capacity(sorted.len());
+    for outlier in sorted {
+        match unique.last_mut() {
+            Some(last) if (last.row, last.col) == (outlier.row, outlier.col) => *last = outlier,
+            _ => unique.push(outlier),
+        }
+    }
+    Cow::Owned(unique)
+}
+
+fn encode_coordinate(outliers: &[OutlierEntry]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut previous: Option<(usize, usize)> = None;
+    for outlier in outliers {
+        match previous {
+            Some((row, col)) if row == outlier.row => {
+                write_varint(&mut out, 0);
+                write_varint(&mut out, (outlier.col - col - 1) as u64);
+            }
+            _ => {
+                let row = previous.map_or(0, |(row, _)| row);
+                write_varint(&mut out, (outlier.row - row) as u64);
+                write_varint(&mut out, outlier.col as u64);
+            }
+        }
+        previous = Some((outlier.row, outlier.col));
+    }
+    out
+}
+
+fn decode_coordinate(bytes: &[u8], count: usize) -> Result<Vec<(usize, usize)>> {
+    let mut reader = VarintReader::new(bytes);
+    // Every position takes at least two varint bytes.
+    let mut positions = Vec::with_capacity(count.min(bytes.len() / 2));
+    let mut previous: Option<(usize, usize)> = None;
+    for _ in 0..count {
+        let row_delta = reader.next()?;
+        let col_code = reader.next()?;
+        let position = match previous {
+            Some((row, col)) if row_delta == 0 => (row, checked(col, col_code, 1)?),
+            _ => (
+                checked(previous.map_or(0, |(row, _)| row), row_delta, 0)?,
+                col_code,
+            ),
+        };
+        positions.push(position);
+        previous = Some(position);
+    }
+    reader.finish()?;
+    Ok(positions)
+}
+
+fn encode_compressed_rows(outliers: &[OutlierEntry], cols: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    let Some(last) = outliers.last() else {
+        return out;
+    };
+    let bitmap_len = cols.div_ceil(8);
+    let mut scratch = Vec::new();
+    let mut start = 0usize;
+    for row in 0..=last.row {
+        let end = start
+            + outliers[start..]
+                .iter()
+                .take_while(|outlier| outlier.row == row)
+                .count();
+        let entries = &outliers[start..end];
+        start = end;
+
+        scratch.clear();
+        let mut previous = None;
+        for outlier in entries {
+            let code = match previous {
+                Some(col) => outlier.col - col - 1,
+                None => outlier.col,
+            };
+            write_varint(&mut scratch, code as u64);
+            previous = Some(outlier.col);
+        }
+        let use_bitmap = !entries.is_empty() && bitmap_len < scratch.len();
+        write_varint(&mut out, ((entries.len() as u64) << 1) | use_bitmap as u64);
+        if use_bitmap {
+            let mut bitmap = vec![0u8; bitmap_len];
+            for outlier in entries {
+                bitmap[outlier.col / 8] |= 1 << (outlier.col % 8);
+            }
+            out.extend_from_slice(&bitmap);
+        } else {
+            out.extend_from_slice(&scratch);
+        }
+    }
+    out
+}
+
+fn decode_compressed_rows(bytes: &[u8], count: usize, cols: usize) -> Result<Vec<(usize, usize)>> {
+    let mut reader = VarintReader::new(bytes);
+    // A bitmap byte holds at most eight positions.
+    let mut positions = Vec::with_capacity(count.min(bytes.len().saturating_mul(8)));
+    let bitmap_len = cols.div_ceil(8);
+    let mut row = 0usize;
+    while positions.len() < count {
+        let header = reader.next()?;
+        let row_count = header >> 1;
+        if row_count > count - positions.len() {
+            return Err(malformed(format!("row {row} claims {row_count} outliers")));
+        }
+        if header & 1 == 1 {
+            let bitmap = reader.take(bitmap_len)?;
+            let before = positions.len();
+            for col in 0..cols {
+                if bitmap[col / 8] & (1 << (col % 8)) != 0 {
+                    positions.push((row, col));
+                }
+            }
+            if positions.len() - before != row_count {
+                return Err(malformed(format!(
+                    "row {row} bitmap does not match its count"
+                )));
+            }
+        } else {
+            let mut previous = None;
+            for _ in 0..row_count {
+                let code = reader.next()?;
+                let col = match previous {
+                    Some(col) => checked(col, code, 1)?,
+                    None => code,
+                };
+                positions.push((row, col));
+                previous = Some(col);
+            }
+        }
+        row += 1;
+    }
+    reader.finish()?;
+    Ok(positions)
+}
+
+fn checked(base: usize, delta: usize, gap: usize) -> Result<usize> {
+    base.checked_add(delta)
+        .and_then(|value| value.checked_add(gap))
+        .ok_or_else(|| malformed("position overflows usize".to_string()))
+}
+
+fn malformed(reason: String) -> NovaQError {
+    NovaQError::InvalidInput {
+        reason: format!("malformed sparse outliers: {reason}"),
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn varint_len(value: u64) -> usize {
+    (64 - value.leading_zeros() as usize).div_ceil(7).max(1)
+}
+
+struct VarintReader<'a> {
+    bytes: &'a [u8],
+    cursor: usize,
+}
+
+impl<'a> VarintReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, cursor: 0 }
+    }
+
+    fn next(&mut self) -> Result<usize> {
+        let mut value = 0u64;
+        let mut shift = 0u32;
+        loop {
+            let byte = *self
+                .bytes
+                .get(self.cursor)
+                .ok_or_else(|| malformed("position stream is truncated".to_string()))?;
+            self.cursor += 1;
+            if shift >= 64 {
+                return Err(malformed("varint is too long".to_string()));
+            }
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return usize::try_from(value)
+                    .map_err(|_| malformed("position overflows usize".to_string()));
+            }
+            shift += 7;
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.cursor + len;
+        let slice = self
+            .bytes
+            .get(self.cursor..end)
+            .ok_or_else(|| malformed("position stream is truncated".to_string()))?;
+        self.cursor = end;
+        Ok(slice)
+    }
+
+    fn finish(&self) -> Result<()> {
+        if self.cursor != self.bytes.len() {
+            return Err(malformed(format!(
+                "{} trailing position bytes",
+                self.bytes.len() - self.cursor
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Rounds the record's outliers to `precision` so the stored values and the
+/// ones used for metrics agree.
+pub(crate) fn round_outliers(outliers: &mut [OutlierEntry], precision: OutlierPrecision) {
+    for outlier in outliers {
+        outlier.value = precision.round(outlier.value);
+    }
+}
+
+pub(crate) fn serialize_outliers<S: Serializer>(
+    outliers: &[OutlierEntry],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    SparseOutliers::encode(outliers).serialize(serializer)
+}
+
+/// Accepts the sparse form as well as the plain entry list older artifacts used.
+pub(crate) fn deserialize_outliers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<OutlierEntry>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Sparse(SparseOutliers),
+        Entries(Vec<OutlierEntry>),
+    }
+    match Stored::deserialize(deserializer)? {
+        Stored::Sparse(sparse) => sparse.decode().map_err(D::Error::custom),
+        Stored::Entries(entries) => Ok(entries),
+    }
+}
+
+/// Base64 text for human-readable formats, raw bytes otherwise.
+mod packed_bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let text = String::deserialize(deserializer)?;
+            STANDARD.decode(text).map_err(D::Error::custom)
+        } else {
+            Vec::<u8>::deserialize(deserializer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::NormalizationRecord;
+
+    fn entry(row: usize, col: usize, value: f32) -> OutlierEntry {
+        OutlierEntry { row, col, value }
+    }
+
+    fn assert_round_trip(outliers: &[OutlierEntry]) -> SparseOutliers {
+        let sparse = SparseOutliers::encode(outliers);
+        let decoded = sparse.decode().unwrap();
+        assert_eq!(decoded.len(), outliers.len());
+        for (a, b) in decoded.iter().zip(outliers) {
+            assert_eq!((a.row, a.col), (b.row, b.col));
+            assert_eq!(a.value.to_bits(), b.value.to_bits());
+        }
+        sparse
+    }
+
+    #[test]
+    fn scattered_outliers_use_coordinates_and_round_trip() {
+        let outliers = vec![
+            entry(0, 3, 7.25),
+            entry(0, 900, -12.5),
+            entry(41, 2, 1.0e-3),
+            entry(1000, 4095, 9.0e4),
+        ];
+        let sparse = assert_round_trip(&outliers);
+        assert_eq!(sparse.layout, OutlierLayout::Coordinate);
+        // 1.0e-3 and 9.0e4 do not survive f16.
+        assert_eq!(sparse.precision, OutlierPrecision::F32);
+    }
+
+    #[test]
+    fn dense_rows_switch_to_bitmaps() {
+        let outliers: Vec<OutlierEntry> = (0..4)
+            .flat_map(|row| (0..64).step_by(2).map(move |col| entry(row, col, -4.0)))
+            .collect();
+        let sparse = assert_round_trip(&outliers);
+        assert_eq!(sparse.layout, OutlierLayout::CompressedRows);
+        assert_eq!(sparse.precision, OutlierPrecision::F16);
+        // Four 8-byte bitmaps plus one header byte per row.
+        assert_eq!(sparse.positions.len(), 4 * 9);
+        assert!(sparse.encoded_bits() < (outliers.len() * 32) as u64);
+    }
+
+    #[test]
+    fn records_serialize_compactly_and_read_legacy_entries() {
+        let record = NormalizationRecord {
+            column_means: vec![0.0; 4],
+            column_stds: vec![1.0; 4],
+            outliers: vec![entry(2, 1, 3.5), entry(2, 3, -0.1), entry(5, 0, 8.0)],
+        };
+        let json = serde_json::to_string(&record).unwrap();
+        let restored: NormalizationRecord = serde_json::from_str(&json).unwrap();
+        assert_round_trip(&restored.outliers);
+        assert_eq!(restored.outliers.len(), 3);
+        assert_eq!(restored.outliers[1].value.to_bits(), (-0.1f32).to_bits());
+
+        let legacy = r#"{"column_means":[0.0],"column_stds":[1.0],
+            "outliers":[{"row":0,"col":0,"value":2.0}]}"#;
+        let restored: NormalizationRecord = serde_json::from_str(legacy).unwrap();
+        assert_eq!(restored.outliers.len(), 1);
+        assert_eq!(restored.outliers[0].value, 2.0);
+    }
+
+    #[test]
+    fn unsorted_and_repeated_outliers_are_canonicalized() {
+        let outliers = vec![
+            entry(4, 2, 1.0),
+            entry(0, 7, 2.0),
+            entry(4, 2, 3.0),
+            entry(0, 1, 4.0),
+        ];
+        let decoded = SparseOutliers::encode(&outliers).decode().unwrap();
+        let positions: Vec<(usize, usize, f32)> =
+            decoded.iter().map(|o| (o.row, o.col, o.value)).collect();
+        assert_eq!(positions, vec![(0, 1, 4.0), (0, 7, 2.0), (4, 2, 3.0)]);
+    }
+
+    #[test]
+    fn counts_beyond_the_payload_are_rejected() {
+        let mut sparse = SparseOutliers::encode(&[entry(3, 9, 1.5)]);
+        sparse.count = usize::MAX / 2;
+        assert!(sparse.decode().is_err());
+    }
+
+    #[test]
+    fn truncated_positions_are_rejected() {
+        let mut sparse = SparseOutliers::encode(&[entry(3, 9, 1.5), entry(7, 1, 2.5)]);
+        sparse.positions.pop();
+        assert!(sparse.decode().is_err());
+    }
+}
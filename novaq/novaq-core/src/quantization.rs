@@ -1,13 +1,15 @@
-use ndarray::{s, Array2, ArrayBase, ArrayView1, Axis, Ix2, Zip};
+use ndarray::{s, Array2, ArrayView2, ArrayViewMut2, Axis, Zip};
 use rand::rngs::StdRng;
-use rand::Rng;
 use tracing::warn;
 
+use crate::clustering::{clusterer_for, codebook_utilization, recompute_centroids, Clusterer};
 use crate::config::QuantizationConfig;
+use crate::distance::CentroidTable;
 use crate::error::{NovaQError, Result};
-use crate::model::{CodebookStage, QuantizedSubspace, SubspaceTelemetry};
+use crate::model::{CodebookStage, QuantizedSubspace, StageTelemetry, SubspaceTelemetry};
+use crate::refinement::{CodebookRefiner, StageCodebook};
 use crate::subspace::SubspaceSpec;
-use crate::validation::validate_centroid_distinctness;
+use crate::validation::{validate_centroid_distinctness, validate_finite};
 
 /// Minimum L2 distance required between centroids to avoid degenerate clustering.
 /// Set to sqrt(eps) to allow for numerical precision while catching true duplicates.
@@ -15,6 +17,7 @@ const MIN_CENTROID_DISTANCE: f32 = 1e-3;
 
 pub struct ProductQuantizer<'a> {
     config: &'a QuantizationConfig,
+    clusterer: Box<dyn Clusterer>,
 }
 
 pub struct DistillationHints<'a> {
@@ -22,6 +25,86 @@ pub struct DistillationHints<'a> {
     pub temperature: f32,
 }
 
+/// Per-input-channel importance gathered from a calibration set.
+///
+/// Weights are laid out `[out, in]`, so each column is one input channel; its
+/// importance scales that column's term in the subspace distance, the k-means
+/// objective and the calibrated metrics, in the spirit of GPTQ/AWQ
+/// diagonal-Hessian weighting.
+#[derive(Debug, Clone)]
+pub struct CalibrationStats {
+    importance: Vec<f32>,
+}
+
+impl CalibrationStats {
+    /// Builds stats from a diagonal Hessian estimate (or any non-negative importance).
+    pub fn from_diagonal_hessian(diagonal: Vec<f32>) -> Result<Self> {
+        if diagonal.is_empty() {
+            return Err(NovaQError::EmptyTensor);
+        }
+        if let Some(bad) = diagonal.iter().find(|v| !v.is_finite() || **v < 0.0) {
+            return Err(NovaQError::InvalidInput {
+                reason: format!("calibration importance must be finite and >= 0, found {bad}"),
+            });
+        }
+        let mean = diagonal.iter().map(|&v| v as f64).sum::<f64>() / diagonal.len() as f64;
+        if mean <= 0.0 {
+            return Err(NovaQError::InvalidInput {
+                reason: "calibration importance is zero for every channel".to_string(),
+            });
+        }
+        let importance = diagonal
+            .into_iter()
+            .map(|v| (v as f64 / mean) as f32)
+            .collect();
+        Ok(Self { importance })
+    }
+
+    /// Builds stats from calibration activations shaped `(samples, input_channels)`
+    /// using the mean squared activation of each channel.
+    pub fn from_activations(activations: &Array2<f32>) -> Result<Self> {
+        if activations.is_empty() {
+            return Err(NovaQError::EmptyTensor);
+        }
+        let samples = activations.nrows() as f32;
+        let diagonal = activations
+            .axis_iter(Axis(1))
+            .map(|channel| channel.iter().map(|v| v * v).sum::<f32>() / samples)
+            .collect();
+        Self::from_diagonal_hessian(diagonal)
+    }
+
+    /// Importance per input channel, normalized to a mean of one.
+    pub fn importance(&self) -> &[f32] {
+        &self.importance
+    }
+
+    pub fn channels(&self) -> usize {
+        self.importance.len()
+    }
+
+    /// Fails unless there is one importance value per weight column.
+    pub(crate) fn check_columns(&self, cols: usize) -> Result<()> {
+        if self.channels() != cols {
+            return Err(NovaQError::DimensionMismatch {
+                expected: cols,
+                found: self.channels(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Reorders the channels to follow a column permutation.
+    pub(crate) fn permuted(&self, permutation: &[usize]) -> Self {
+        Self {
+            importance: permutation
+                .iter()
+                .map(|&col| self.importance[col])
+                .collect(),
+        }
+    }
+}
+
 pub struct QuantizationResult {
     pub subspaces: Vec<QuantizedSubspace>,
     pub telemetry: Vec<SubspaceTelemetry>,
@@ -30,7 +113,10 @@ pub struct QuantizationResult {
 impl<'a> ProductQuantizer<'a> {
     pub fn new(config: &'a QuantizationConfig) -> Result<Self> {
         config.validate()?;
-        Ok(Self { config })
+        Ok(Self {
+            config,
+            clusterer: clusterer_for(config),
+        })
     }
 
     pub fn quantize(
@@ -39,6 +125,7 @@ impl<'a> ProductQuantizer<'a> {
         plan: &[SubspaceSpec],
         rng: &mut StdRng,
         hints: Option<&DistillationHints>,
+        calibration: Option<&CalibrationStats>,
     ) -> Result<QuantizationResult> {
         let rows = normalized.nrows();
         let cols = normalized.ncols();
@@ -83,12 +170,18 @@ impl<'a> ProductQuantizer<'a> {
             }
         }
 
+        if let Some(calibration) = calibration {
+            calibration.check_columns(cols)?;
+        }
+
         let mut subspaces = Vec::with_capacity(plan.len());
         let mut telemetry = Vec::with_capacity(plan.len());
 
         for (index, spec) in plan.iter().enumerate() {
             let view = normalized.slice(s![.., spec.columns.clone()]);
             let data = view.to_owned();
+            let column_weights =
+                calibration.map(|calibration| &calibration.importance()[spec.columns.clone()]);
 
             let training_data = if let Some(hints) = hints {
                 let teacher_view = hints.teacher_logits.slice(s![.., spec.columns.clone()]);
@@ -104,81 +197,119 @@ impl<'a> ProductQuantizer<'a> {
                 data.clone()
             };
 
-            let (mut stage1, _) = run_kmeans(
+            let (stage1, stage1_contrib) = run_kmeans(
                 self.config,
+                self.clusterer.as_ref(),
                 &training_data,
+                column_weights,
                 self.config.level1_centroids,
                 1,
                 rng,
             )?;
             
-            // CRITICAL: Validate that stage1 centroids are distinct
-            validate_centroid_distinctness(&stage1.centroids, MIN_CENTROID_DISTANCE)?;
-            
-            let mut stage1_contrib =
-                reconstruct_from_centroids(&stage1.centroids, &stage1.assignments);
-
-            let mut stage2_state: Option<StageState> = None;
-            let mut stage2_contrib: Option<Array2<f32>> = None;
+            // CRITICAL: Validate that stage1 centroids are distinct, unless the
+            // data itself has too few distinct rows to tell them apart.
+            let k = stage1.centroids.nrows();
+            if has_distinct_rows(&training_data, k, MIN_CENTROID_DISTANCE) {
+                validate_centroid_distinctness(&stage1.centroids, MIN_CENTROID_DISTANCE)?;
+            }
 
-            if spec.enable_stage2 && self.config.level2_centroids >= 2 && data.ncols() > 0 {
-                let residual_for_stage2 = &training_data - &stage1_contrib;
-                if average_squared_norm(&residual_for_stage2) > self.config.residual_variance_floor
-                {
-                    let (state, _) = run_kmeans(
+            let mut energy = residual_energy(&data, column_weights, &stage1_contrib);
+            let mut stage_energies = vec![energy];
+            let mut reconstruction = stage1_contrib.clone();
+            let mut stages = vec![stage1];
+            let mut contribs = vec![stage1_contrib];
+            let mut rejected_stages = 0usize;
+
+            if spec.enable_residual {
+                for (offset, &centroids) in self.config.residual_centroids.iter().enumerate() {
+                    let stage_id = (offset + 2) as u8;
+                    let residual = &training_data - &reconstruction;
+                    if average_squared_norm(&residual) <= self.config.residual_variance_floor {
+                        break;
+                    }
+                    let (state, contrib) = run_kmeans(
                         self.config,
-                        &residual_for_stage2,
-                        self.config.level2_centroids,
-                        2,
+                        self.clusterer.as_ref(),
+                        &residual,
+                        column_weights,
+                        centroids,
+                        stage_id,
                         rng,
                     )?;
-                    
-                    // CRITICAL: Validate that stage2 centroids are distinct
-                    if let Err(e) = validate_centroid_distinctness(&state.centroids, MIN_CENTROID_DISTANCE) {
+
+                    // CRITICAL: Validate that residual centroids are distinct
+                    if let Err(e) =
+                        validate_centroid_distinctness(&state.centroids, MIN_CENTROID_DISTANCE)
+                    {
                         warn!(
                             subspace = index,
+                            stage = stage_id,
                             error = %e,
-                            "Stage2 centroids not distinct, skipping stage2 for this subspace"
+                            "Residual centroids not distinct, stopping residual stages for this subspace"
+                        );
+                        // Don't fail the entire quantization, just keep the stages trained so far
+                        break;
+                    }
+
+                    let candidate = &reconstruction + &contrib;
+                    let candidate_energy = residual_energy(&data, column_weights, &candidate);
+                    if candidate_energy > energy * (1.0 - self.config.min_stage_gain) {
+                        tracing::debug!(
+                            subspace = index,
+                            stage = stage_id,
+                            energy,
+                            candidate_energy,
+                            "residual stage below min_stage_gain, dropping"
                         );
-                        // Don't fail the entire quantization, just skip stage2 for this subspace
-                    } else {
-                        stage2_contrib = Some(reconstruct_from_centroids(
-                            &state.centroids,
-                            &state.assignments,
-                        ));
-                        stage2_state = Some(state);
+                        rejected_stages += 1;
+                        break;
                     }
+
+                    energy = candidate_energy;
+                    stage_energies.push(energy);
+                    reconstruction = candidate;
+                    stages.push(state);
+                    contribs.push(contrib);
                 }
             }
 
-            let residual_energy = refine_subspace(
+            let (residual_energy, refinement_loss) = self.refine_subspace(
                 &data,
                 &training_data,
-                &mut stage1,
-                &mut stage1_contrib,
-                stage2_state.as_mut(),
-                &mut stage2_contrib,
+                column_weights,
+                &mut stages,
+                &mut contribs,
                 spec,
-                self.config,
-            );
+            )?;
 
             let telemetry_entry = SubspaceTelemetry {
                 columns: spec.columns.clone(),
-                stage1_iterations: stage1.iterations,
-                stage2_iterations: stage2_state.as_ref().map(|s| s.iterations),
-                stage1_inertia: stage1.inertia,
-                stage2_inertia: stage2_state.as_ref().map(|s| s.inertia),
+                stages: stages
+                    .iter()
+                    .zip(&stage_energies)
+                    .map(|(stage, &energy)| StageTelemetry {
+                        stage_id: stage.id,
+                        iterations: stage.iterations,
+                        inertia: stage.inertia,
+                        residual_energy: energy,
+                        utilization: codebook_utilization(
+                            &stage.assignments,
+                            stage.centroids.nrows(),
+                        ),
+                    })
+                    .collect(),
+                rejected_stages,
                 residual_energy,
-                enabled_stage2: stage2_state.is_some(),
+                refinement_loss,
             };
 
             let quantized_subspace = QuantizedSubspace {
                 columns: spec.columns.clone(),
-                stage1: stage1.into_codebook_stage()?,
-                stage2: match stage2_state {
-                    Some(state) => Some(state.into_codebook_stage()?),
-                    None => None,
-                },
+                stages: stages
+                    .into_iter()
+                    .map(StageState::into_codebook_stage)
+                    .collect::<Result<_>>()?,
                 residual_energy,
             };
 
@@ -186,7 +317,8 @@ impl<'a> ProductQuantizer<'a> {
                 subspace = index,
                 cols = spec.columns.end - spec.columns.start,
                 residual_energy,
-                enabled_stage2 = telemetry_entry.enabled_stage2,
+                stages = quantized_subspace.stages.len(),
+                rejected_stages,
                 "quantized subspace"
             );
 
@@ -206,23 +338,7 @@ impl<'a> ProductQuantizer<'a> {
         cols: usize,
         subspaces: &[QuantizedSubspace],
     ) -> Array2<f32> {
-        let mut reconstructed = Array2::<f32>::zeros((rows, cols));
-        for subspace in subspaces {
-            let start = subspace.columns.start;
-            let end = subspace.columns.end;
-            for row in 0..rows {
-                let mut target = reconstructed.slice_mut(s![row, start..end]);
-                let idx = subspace.stage1.assignments[row] as usize;
-                let centroid = subspace.stage1.centroids.row(idx);
-                add_assign(&mut target, &centroid);
-                if let Some(stage2) = &subspace.stage2 {
-                    let idx = stage2.assignments[row] as usize;
-                    let centroid = stage2.centroids.row(idx);
-                    add_assign(&mut target, &centroid);
-                }
-            }
-        }
-        reconstructed
+        reconstruct_subspaces(rows, cols, subspaces)
     }
 
     pub fn estimate_compressed_bits(&self, rows: usize, subspaces: &[QuantizedSubspace]) -> u64 {
@@ -230,28 +346,91 @@ impl<'a> ProductQuantizer<'a> {
             .iter()
             .map(|subspace| {
                 let width = (subspace.columns.end - subspace.columns.start) as u64;
-                let k1 = subspace.stage1.centroids.nrows() as u64;
-                let centroid_bits = k1 * width * 32;
-                let index_bits = rows as u64 * bits_for_indices(k1);
-                let level1_bits = centroid_bits + index_bits;
-
-                let level2_bits = subspace
-                    .stage2
-                    .as_ref()
+                subspace
+                    .stages
+                    .iter()
                     .map(|stage| {
-                        let k2 = stage.centroids.nrows() as u64;
-                        let centroid_bits = k2 * width * 32;
-                        let index_bits = rows as u64 * bits_for_indices(k2);
+                        let k = stage.centroids.nrows() as u64;
+                        let centroid_bits = k * width * 32;
+                        let index_bits = rows as u64 * bits_for_indices(k);
                         centroid_bits + index_bits
                     })
-                    .unwrap_or(0);
-
-                level1_bits + level2_bits
+                    .sum::<u64>()
             })
             .sum()
     }
 }
 
+/// Rebuilds the normalized matrix by summing the codebook entries selected for each row.
+pub(crate) fn reconstruct_subspaces(
+    rows: usize,
+    cols: usize,
+    subspaces: &[QuantizedSubspace],
+) -> Array2<f32> {
+    let mut reconstructed = Array2::<f32>::zeros((rows, cols));
+    for subspace in subspaces {
+        let start = subspace.columns.start;
+        let end = subspace.columns.end;
+        for row in 0..rows {
+            let mut target = reconstructed.slice_mut(s![row, start..end]);
+            for stage in &subspace.stages {
+                let idx = stage.assignments[row] as usize;
+                let centroid = stage.centroids.row(idx);
+                add_assign(&mut target, &centroid);
+            }
+        }
+    }
+    reconstructed
+}
+
+/// Encodes rows against an already trained subspace: each stage picks the
+/// centroid closest to what the earlier stages left over.
+///
+/// Writes the reconstruction into `reconstruction` and returns the per-stage
+/// assignments together with the column-weighted squared error.
+pub(crate) fn encode_rows(
+    subspace: &QuantizedSubspace,
+    data: ArrayView2<'_, f32>,
+    mut reconstruction: ArrayViewMut2<'_, f32>,
+    column_weights: Option<&[f32]>,
+) -> (Vec<Vec<u16>>, f64) {
+    let mut codes: Vec<Vec<u16>> = subspace
+        .stages
+        .iter()
+        .map(|_| Vec::with_capacity(data.nrows()))
+        .collect();
+    let tables: Vec<CentroidTable> = subspace
+        .stages
+        .iter()
+        .map(|stage| CentroidTable::new(&stage.centroids).with_dimension_weights(column_weights))
+        .collect();
+    let mut error = 0.0f64;
+    for (point, mut target) in data
+        .axis_iter(Axis(0))
+        .zip(reconstruction.axis_iter_mut(Axis(0)))
+    {
+        let mut residual = point.to_owned();
+        for ((stage, table), stage_codes) in
+            subspace.stages.iter().zip(&tables).zip(codes.iter_mut())
+        {
+            let (idx, _) = table.nearest(residual.as_slice().expect("owned rows are contiguous"));
+            let centroid = stage.centroids.row(idx);
+            residual -= &centroid;
+            add_assign(&mut target, &centroid);
+            stage_codes.push(idx as u16);
+        }
+        error += residual
+            .iter()
+            .enumerate()
+            .map(|(col, v)| {
+                let weight = column_weights.map_or(1.0, |w| w[col]) as f64;
+                weight * (*v as f64) * (*v as f64)
+            })
+            .sum::<f64>();
+    }
+    (codes, error)
+}
+
 #[derive(Clone)]
 struct StageState {
     id: u8,
@@ -276,7 +455,9 @@ impl StageState {
 
 fn run_kmeans(
     config: &QuantizationConfig,
+    clusterer: &dyn Clusterer,
     data: &Array2<f32>,
+    column_weights: Option<&[f32]>,
     requested_centroids: usize,
     stage_id: u8,
     rng: &mut StdRng,
@@ -295,111 +476,138 @@ fn run_kmeans(
         )));
     }
 
-    let mut centroids = initialize_centroids(data, k, rng);
-    let mut assignments = vec![0usize; rows];
-
-    for iteration in 0..config.max_iterations {
-        let inertia = assign_points(data, &centroids, &mut assignments);
-        let new_centroids = recompute_centroids(data, &assignments, k);
-        let shift = centroid_shift(&centroids, &new_centroids);
-        centroids = new_centroids;
-
-        if shift < config.tolerance {
-            let reconstruction = reconstruct_from_centroids(&centroids, &assignments);
-            return Ok((
-                StageState {
-                    id: stage_id,
-                    centroids,
-                    assignments,
-                    iterations: iteration + 1,
-                    inertia,
-                },
-                reconstruction,
-            ));
-        }
-    }
-
-    let inertia = assign_points(data, &centroids, &mut assignments);
-    let reconstruction = reconstruct_from_centroids(&centroids, &assignments);
+    let clustering = clusterer.cluster(data, column_weights, k, rng)?;
+    let reconstruction = reconstruct_from_centroids(&clustering.centroids, &clustering.assignments);
     Ok((
         StageState {
             id: stage_id,
-            centroids,
-            assignments,
-            iterations: config.max_iterations,
-            inertia,
+            centroids: clustering.centroids,
+            assignments: clustering.assignments,
+            iterations: clustering.iterations,
+            inertia: clustering.inertia,
         },
         reconstruction,
     ))
 }
 
-fn refine_subspace(
-    original: &Array2<f32>,
-    training: &Array2<f32>,
-    stage1: &mut StageState,
-    stage1_contrib: &mut Array2<f32>,
-    stage2: Option<&mut StageState>,
-    stage2_contrib: &mut Option<Array2<f32>>,
-    spec: &SubspaceSpec,
-    config: &QuantizationConfig,
-) -> f32 {
-    let mut best_energy = residual_energy(original, stage1_contrib, stage2_contrib.as_ref());
-    if spec.refinement_steps == 0 {
-        return best_energy;
-    }
+impl ProductQuantizer<'_> {
+    /// Alternates reassignment and damped centroid updates across all stages; stage
+    /// `i` is refit against the training data minus the stages before it, using
+    /// the selected clusterer's own assignment rule. The centroids are then fine-tuned jointly
+    /// with assignments frozen.
+    ///
+    /// Returns the final residual energy and the fine-tuning loss history, or an
+    /// error if fine-tuning left a centroid non-finite.
+    fn refine_subspace(
+        &self,
+        original: &Array2<f32>,
+        training: &Array2<f32>,
+        column_weights: Option<&[f32]>,
+        stages: &mut [StageState],
+        contribs: &mut [Array2<f32>],
+        spec: &SubspaceSpec,
+    ) -> Result<(f32, Vec<f32>)> {
+        let config = self.config;
+        let mut best_energy =
+            residual_energy(original, column_weights, &sum_contributions(contribs));
+        if spec.refinement_steps == 0 {
+            return Ok((best_energy, Vec::new()));
+        }
 
-    let mut stage2_state_opt = stage2;
+        for _ in 0..spec.refinement_steps {
+            let mut changed = false;
+            let mut target = training.clone();
+            for (stage, contrib) in stages.iter_mut().zip(contribs.iter_mut()) {
+                changed |= reassign_and_update(
+                    self.clusterer.as_ref(),
+                    &target,
+                    column_weights,
+                    stage,
+                    config,
+                );
+                *contrib = reconstruct_from_centroids(&stage.centroids, &stage.assignments);
+                target -= &*contrib;
+            }
 
-    for _ in 0..spec.refinement_steps {
-        let mut changed = false;
-        changed |= reassign_and_update(training, stage1, config.refinement_learning_rate);
-        *stage1_contrib = reconstruct_from_centroids(&stage1.centroids, &stage1.assignments);
+            let energy = residual_energy(original, column_weights, &sum_contributions(contribs));
+            best_energy = energy;
 
-        if let Some(stage2_state) = stage2_state_opt.as_deref_mut() {
-            let residual_training = training - &*stage1_contrib;
-            changed |= reassign_and_update(
-                &residual_training,
-                stage2_state,
-                config.refinement_learning_rate,
-            );
-            let contrib =
-                reconstruct_from_centroids(&stage2_state.centroids, &stage2_state.assignments);
-            *stage2_contrib = Some(contrib);
+            if energy <= config.residual_variance_floor || !changed {
+                break;
+            }
+        }
+        if best_energy <= config.residual_variance_floor {
+            return Ok((best_energy, Vec::new()));
         }
 
-        let energy = residual_energy(original, stage1_contrib, stage2_contrib.as_ref());
-        best_energy = energy;
+        let mut codebooks: Vec<StageCodebook<'_>> = stages
+            .iter_mut()
+            .map(|stage| StageCodebook {
+                centroids: &mut stage.centroids,
+                assignments: &stage.assignments,
+            })
+            .collect();
+        let loss_history = CodebookRefiner::new(
+            column_weights,
+            config.refinement_learning_rate,
+            spec.refinement_steps,
+        )
+        .refine(training, &mut codebooks);
+        for (stage, contrib) in stages.iter().zip(contribs.iter_mut()) {
+            validate_finite(
+                &stage.centroids,
+                &format!("fine-tuned stage {} centroids", stage.id),
+            )?;
+            *contrib = reconstruct_from_centroids(&stage.centroids, &stage.assignments);
+        }
+        best_energy = residual_energy(original, column_weights, &sum_contributions(contribs));
 
-        if energy <= config.residual_variance_floor || !changed {
+        Ok((best_energy, loss_history))
+    }
+}
+
+/// Whether `data` holds at least `k` rows lying `min_distance` apart, so that
+/// clustering it can yield `k` distinct centroids. Narrow subspaces, or rows
+/// whose outliers were zeroed, often hold fewer.
+fn has_distinct_rows(data: &Array2<f32>, k: usize, min_distance: f32) -> bool {
+    let threshold = min_distance * min_distance;
+    let mut distinct: Vec<ndarray::ArrayView1<'_, f32>> = Vec::with_capacity(k);
+    for row in data.axis_iter(Axis(0)) {
+        if distinct.len() == k {
             break;
         }
+        let coincides = distinct.iter().any(|other| {
+            let distance: f32 = row.iter().zip(other).map(|(a, b)| (a - b) * (a - b)).sum();
+            distance < threshold
+        });
+        if !coincides {
+            distinct.push(row);
+        }
     }
+    distinct.len() == k
+}
 
-    best_energy
+fn sum_contributions(contribs: &[Array2<f32>]) -> Array2<f32> {
+    let mut total = contribs[0].clone();
+    for contrib in &contribs[1..] {
+        total += contrib;
+    }
+    total
 }
 
 fn residual_energy(
     original: &Array2<f32>,
-    stage1: &Array2<f32>,
-    stage2: Option<&Array2<f32>>,
+    column_weights: Option<&[f32]>,
+    reconstruction: &Array2<f32>,
 ) -> f32 {
     let mut total = 0.0f32;
     let mut count = 0usize;
-    match stage2 {
-        Some(s2) => {
-            for ((orig, contrib1), contrib2) in original.iter().zip(stage1.iter()).zip(s2.iter()) {
-                let diff = *orig - (*contrib1 + *contrib2);
-                total += diff * diff;
-                count += 1;
-            }
-        }
-        None => {
-            for (orig, contrib1) in original.iter().zip(stage1.iter()) {
-                let diff = *orig - *contrib1;
-                total += diff * diff;
-                count += 1;
-            }
-        }
+    let cols = original.ncols().max(1);
+    let weight_at = |idx: usize| column_weights.map_or(1.0, |w| w[idx % cols]);
+    for (idx, (orig, approx)) in original.iter().zip(reconstruction.iter()).enumerate() {
+        let diff = *orig - *approx;
+        total += weight_at(idx) * diff * diff;
+        count += 1;
     }
     total / count.max(1) as f32
 }
@@ -429,7 +637,8 @@ fn add_assign(target: &mut ndarray::ArrayViewMut1<'_, f32>, source: &ndarray::Ar
     }
 }
 
-fn bits_for_indices(k: u64) -> u64 {
+/// Width of one assignment index into a codebook of `k` centroids.
+pub fn bits_for_indices(k: u64) -> u64 {
     let k = k.max(1) as f64;
     k.log2().ceil() as u64
 }
@@ -439,72 +648,27 @@ fn average_squared_norm(matrix: &Array2<f32>) -> f32 {
     total / matrix.len().max(1) as f32
 }
 
-fn centroid_shift(old: &Array2<f32>, new: &Array2<f32>) -> f32 {
-    let mut shift = 0.0f32;
-    for (prev, next) in old.iter().zip(new.iter()) {
-        let diff = prev - next;
-        shift += diff * diff;
-    }
-    (shift / old.len().max(1) as f32).sqrt()
-}
-
-fn assign_points(data: &Array2<f32>, centroids: &Array2<f32>, assignments: &mut [usize]) -> f32 {
-    let mut inertia = 0.0f32;
-    for (row_idx, point) in data.axis_iter(Axis(0)).enumerate() {
-        let (closest, distance) = closest_centroid(&point, centroids);
-        assignments[row_idx] = closest;
-        inertia += distance;
-    }
-    inertia
-}
-
-fn recompute_centroids(data: &Array2<f32>, assignments: &[usize], k: usize) -> Array2<f32> {
-    let dim = data.ncols();
-    let mut counts = vec![0usize; k];
-    let mut new_centroids = Array2::<f32>::zeros((k, dim));
-
-    for (row_idx, point) in data.axis_iter(Axis(0)).enumerate() {
-        let centroid_idx = assignments[row_idx];
-        counts[centroid_idx] += 1;
-        for (dest, value) in new_centroids
-            .row_mut(centroid_idx)
-            .iter_mut()
-            .zip(point.iter())
-        {
-            *dest += *value;
-        }
-    }
-
-    for (idx, count) in counts.iter().enumerate() {
-        if *count == 0 {
-            if let Some((_, fallback)) =
-                data.axis_iter(Axis(0))
-                    .enumerate()
-                    .max_by(|(_, a), (_, b)| {
-                        let norm_a = a.iter().map(|v| v * v).sum::<f32>();
-                        let norm_b = b.iter().map(|v| v * v).sum::<f32>();
-                        norm_a
-                            .partial_cmp(&norm_b)
-                            .unwrap_or(std::cmp::Ordering::Equal)
-                    })
-            {
-                new_centroids.row_mut(idx).assign(&fallback);
-            }
-            continue;
-        }
-        for value in new_centroids.row_mut(idx).iter_mut() {
-            *value /= *count as f32;
-        }
-    }
-
-    new_centroids
-}
-
-fn reassign_and_update(data: &Array2<f32>, state: &mut StageState, learning_rate: f32) -> bool {
+/// One damped refit of `state` against `data`. Backends that opt out of
+/// reassignment leave the stage as trained and report no change.
+fn reassign_and_update(
+    clusterer: &dyn Clusterer,
+    data: &Array2<f32>,
+    column_weights: Option<&[f32]>,
+    state: &mut StageState,
+    config: &QuantizationConfig,
+) -> bool {
     let previous_assignments = state.assignments.clone();
-    state.inertia = assign_points(data, &state.centroids, &mut state.assignments);
+    let Some(inertia) = clusterer.reassign(
+        data,
+        column_weights,
+        &state.centroids,
+        &mut state.assignments,
+    ) else {
+        return false;
+    };
+    state.inertia = inertia;
     let new_centroids = recompute_centroids(data, &state.assignments, state.centroids.nrows());
-    let blend = learning_rate.clamp(0.0, 1.0);
+    let blend = config.refinement_learning_rate.clamp(0.0, 1.0);
     let changed_assignments = previous_assignments != state.assignments;
     let mut changed_centroids = false;
     for (dest, updated) in state.centroids.iter_mut().zip(new_centroids.iter()) {
@@ -519,26 +683,6 @@ fn reassign_and_update(data: &Array2<f32>, state: &mut StageState, learning_rate
     changed
 }
 
-fn closest_centroid<S>(point: &ArrayView1<'_, f32>, centroids: &ArrayBase<S, Ix2>) -> (usize, f32)
-where
-    S: ndarray::Data<Elem = f32>,
-{
-    let mut best_idx = 0usize;
-    let mut best_distance = f32::MAX;
-    for (idx, centroid) in centroids.axis_iter(Axis(0)).enumerate() {
-        let mut distance = 0.0f32;
-        for (a, b) in point.iter().zip(centroid.iter()) {
-            let diff = a - b;
-            distance += diff * diff;
-        }
-        if distance < best_distance {
-            best_distance = distance;
-            best_idx = idx;
-        }
-    }
-    (best_idx, best_distance)
-}
-
 fn reconstruct_from_centroids(centroids: &Array2<f32>, assignments: &[usize]) -> Array2<f32> {
     let rows = assignments.len();
     let dim = centroids.ncols();
@@ -551,35 +695,6 @@ fn reconstruct_from_centroids(centroids: &Array2<f32>, assignments: &[usize]) ->
     reconstruction
 }
 
-fn initialize_centroids(data: &Array2<f32>, k: usize, rng: &mut StdRng) -> Array2<f32> {
-    let rows = data.nrows();
-    let dim = data.ncols();
-    let mut centroids = Array2::<f32>::zeros((k, dim));
-    let first_idx = rng.gen_range(0..rows);
-    centroids.row_mut(0).assign(&data.row(first_idx));
-
-    let mut distances = vec![0.0f32; rows];
-    for centroid_idx in 1..k {
-        for (row_idx, point) in data.axis_iter(Axis(0)).enumerate() {
-            let (_, dist) = closest_centroid(&point, &centroids.slice(s![0..centroid_idx, ..]));
-            distances[row_idx] = dist;
-        }
-        let total_distance: f32 = distances.iter().sum();
-        let mut sample = rng.gen::<f32>() * total_distance.max(1e-9);
-        let mut chosen = 0usize;
-        for (idx, dist) in distances.iter().enumerate() {
-            sample -= *dist;
-            if sample <= 0.0 {
-                chosen = idx;
-                break;
-            }
-        }
-        centroids.row_mut(centroid_idx).assign(&data.row(chosen));
-    }
-
-    centroids
-}
-
 fn build_stage(
     stage_id: u8,
     centroids: Array2<f32>,
@@ -587,9 +702,9 @@ fn build_stage(
     iterations: usize,
     inertia: f32,
 ) -> Result<CodebookStage> {
-    if assignments.len() >= (1 << 16) {
+    if centroids.nrows() > (1 << 16) {
         return Err(NovaQError::InvalidConfig(
-            "number of assignments exceeds u16 capacity".to_string(),
+            "number of centroids exceeds u16 index capacity".to_string(),
         ));
     }
     let assignments: Vec<u16> = assignments.iter().map(|&idx| idx as u16).collect();
@@ -605,6 +720,7 @@ fn build_stage(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::ClusteringMethod;
     use ndarray::array;
     use rand::SeedableRng;
 
@@ -619,16 +735,229 @@ mod tests {
         });
         let plan = vec![SubspaceSpec {
             columns: 0..4,
-            enable_stage2: true,
+            enable_residual: true,
             refinement_steps: 4,
         }];
-        let result = pq.quantize(&data, &plan, &mut rng, None).unwrap();
+        let result = pq.quantize(&data, &plan, &mut rng, None, None).unwrap();
         assert_eq!(result.subspaces.len(), 1);
         let first = &result.subspaces[0];
-        assert_eq!(first.stage1.centroids.ncols(), 4);
+        assert_eq!(first.stages[0].centroids.ncols(), 4);
         assert_eq!(result.telemetry[0].columns, (0..4));
     }
 
+    #[test]
+    fn subspaces_with_few_distinct_rows_quantize() {
+        let config = QuantizationConfig {
+            level1_centroids: 8,
+            ..QuantizationConfig::default()
+        };
+        let pq = ProductQuantizer::new(&config).unwrap();
+        // Three distinct rows cannot fill eight distinct centroids.
+        let data = Array2::from_shape_fn((32, 4), |(i, j)| ((i % 3) * 4 + j) as f32);
+        let plan = vec![SubspaceSpec {
+            columns: 0..4,
+            enable_residual: false,
+            refinement_steps: 0,
+        }];
+        let mut rng = StdRng::seed_from_u64(7);
+        let result = pq.quantize(&data, &plan, &mut rng, None, None).unwrap();
+        assert_eq!(result.subspaces[0].stages[0].centroids.nrows(), 8);
+        assert!(has_distinct_rows(&data, 3, MIN_CENTROID_DISTANCE));
+        assert!(!has_distinct_rows(&data, 4, MIN_CENTROID_DISTANCE));
+    }
+
+    #[test]
+    fn residual_stages_are_gated_by_min_stage_gain() {
+        let data = Array2::from_shape_fn((64, 4), |(i, j)| {
+            ((i as f32) * 0.37 + (j as f32) * 1.3).sin() * (1.0 + (i % 5) as f32)
+        });
+        let plan = vec![SubspaceSpec {
+            columns: 0..4,
+            enable_residual: true,
+            refinement_steps: 0,
+        }];
+        let quantize = |min_stage_gain: f32| {
+            let config = QuantizationConfig {
+                level1_centroids: 4,
+                residual_centroids: vec![4, 4, 4],
+                min_stage_gain,
+                ..QuantizationConfig::default()
+            };
+            let pq = ProductQuantizer::new(&config).unwrap();
+            let mut rng = StdRng::seed_from_u64(99);
+            let result = pq.quantize(&data, &plan, &mut rng, None, None).unwrap();
+            let bits = pq.estimate_compressed_bits(data.nrows(), &result.subspaces);
+            (result, bits)
+        };
+
+        let (deep, deep_bits) = quantize(0.0);
+        let stages = &deep.subspaces[0].stages;
+        assert!(stages.len() > 2);
+        let ids: Vec<u8> = stages.iter().map(|stage| stage.stage_id).collect();
+        assert_eq!(ids, (1..=stages.len() as u8).collect::<Vec<_>>());
+        let energies: Vec<f32> = deep.telemetry[0]
+            .stages
+            .iter()
+            .map(|stage| stage.residual_energy)
+            .collect();
+        assert!(energies.windows(2).all(|pair| pair[1] < pair[0]));
+
+        let summed = stages
+            .iter()
+            .map(|stage| {
+                let indices: Vec<usize> = stage.assignments.iter().map(|&i| i as usize).collect();
+                reconstruct_from_centroids(&stage.centroids, &indices)
+            })
+            .collect::<Vec<_>>();
+        let expected = sum_contributions(&summed);
+        let rebuilt = reconstruct_subspaces(data.nrows(), data.ncols(), &deep.subspaces);
+        assert_eq!(rebuilt, expected);
+
+        let (shallow, shallow_bits) = quantize(0.99);
+        assert_eq!(shallow.subspaces[0].stages.len(), 1);
+        assert_eq!(shallow.telemetry[0].rejected_stages, 1);
+        assert!(shallow_bits < deep_bits);
+    }
+
+    #[test]
+    fn codebook_finetuning_records_decreasing_loss() {
+        let config = QuantizationConfig {
+            level1_centroids: 4,
+            residual_centroids: vec![4],
+            refinement_learning_rate: 0.25,
+            ..QuantizationConfig::default()
+        };
+        let pq = ProductQuantizer::new(&config).unwrap();
+        let mut rng = StdRng::seed_from_u64(5);
+        let data = Array2::from_shape_fn((48, 4), |(i, j)| ((i * 7 + j * 3) as f32 * 0.41).cos());
+        let plan = vec![SubspaceSpec {
+            columns: 0..4,
+            enable_residual: true,
+            refinement_steps: 8,
+        }];
+        let result = pq.quantize(&data, &plan, &mut rng, None, None).unwrap();
+        let loss = &result.telemetry[0].refinement_loss;
+        assert!(loss.len() > 1);
+        assert!(loss.windows(2).all(|pair| pair[1] < pair[0]));
+        assert!(result.telemetry[0].residual_energy <= loss[0]);
+    }
+
+    #[test]
+    fn calibration_reweights_columns_of_non_square_layers() {
+        // 64 output rows by 4 input columns: the first two columns carry small
+        // structure, the last two large values that dominate the plain distance.
+        let sign = |positive: bool| if positive { 0.5 } else { -0.5 };
+        let data = Array2::from_shape_fn((64, 4), |(i, j)| match j {
+            0 => sign(i % 2 == 0),
+            1 => sign((i / 2) % 2 == 0),
+            _ => ((i * 7 + j * 5) % 8) as f32 - 3.5,
+        });
+        let activations = Array2::from_shape_fn((16, 4), |(s, j)| {
+            let scale = if j < 2 { 10.0 } else { 0.1 };
+            scale * if (s + j) % 2 == 0 { 1.0 } else { -1.0 }
+        });
+        let stats = CalibrationStats::from_activations(&activations).unwrap();
+        assert_eq!(stats.channels(), data.ncols());
+
+        let config = QuantizationConfig {
+            level1_centroids: 4,
+            ..QuantizationConfig::default()
+        };
+        let pq = ProductQuantizer::new(&config).unwrap();
+        let plan = vec![SubspaceSpec {
+            columns: 0..4,
+            enable_residual: false,
+            refinement_steps: 0,
+        }];
+        let quantize = |calibration: Option<&CalibrationStats>| {
+            let mut rng = StdRng::seed_from_u64(21);
+            pq.quantize(&data, &plan, &mut rng, None, calibration)
+                .unwrap()
+        };
+        let weighted_error = |result: &QuantizationResult| {
+            let rebuilt = reconstruct_subspaces(64, 4, &result.subspaces);
+            residual_energy(&data, Some(stats.importance()), &rebuilt)
+        };
+
+        let plain = quantize(None);
+        let calibrated = quantize(Some(&stats));
+        assert_ne!(
+            plain.subspaces[0].stages[0].assignments,
+            calibrated.subspaces[0].stages[0].assignments
+        );
+        assert!(weighted_error(&calibrated) < weighted_error(&plain));
+        assert!(
+            (calibrated.subspaces[0].residual_energy - weighted_error(&calibrated)).abs() < 1e-4
+        );
+    }
+
+    #[test]
+    fn calibration_stats_reject_mismatched_columns() {
+        let config = QuantizationConfig::default();
+        let pq = ProductQuantizer::new(&config).unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+        let data = Array2::from_shape_fn((8, 4), |(i, j)| (i * 4 + j) as f32);
+        let plan = vec![SubspaceSpec {
+            columns: 0..4,
+            enable_residual: false,
+            refinement_steps: 1,
+        }];
+        let stats = CalibrationStats::from_diagonal_hessian(vec![1.0; 8]).unwrap();
+        assert!(pq
+            .quantize(&data, &plan, &mut rng, None, Some(&stats))
+            .is_err());
+        assert!(CalibrationStats::from_diagonal_hessian(vec![0.0, 0.0]).is_err());
+    }
+
+    #[test]
+    fn balanced_clustering_keeps_capacity_through_refinement() {
+        // Three quarters of the rows sit in one tight cluster, so nearest-centroid
+        // reassignment alone would pile them onto a single code.
+        let data = Array2::from_shape_fn((64, 4), |(i, j)| {
+            let center = if i < 48 {
+                0.0
+            } else {
+                6.0 + (i % 4) as f32 * 3.0
+            };
+            center + ((i * 5 + j * 3) as f32 * 0.77).sin() * 0.1
+        });
+        let capacity_slack = 0.25f32;
+        let config = QuantizationConfig {
+            level1_centroids: 4,
+            residual_centroids: vec![4],
+            min_stage_gain: 0.0,
+            clustering: ClusteringMethod::Balanced { capacity_slack },
+            refinement_learning_rate: 0.5,
+            ..QuantizationConfig::default()
+        };
+        let pq = ProductQuantizer::new(&config).unwrap();
+        let mut rng = StdRng::seed_from_u64(17);
+        let plan = vec![SubspaceSpec {
+            columns: 0..4,
+            enable_residual: true,
+            refinement_steps: 8,
+        }];
+        let result = pq.quantize(&data, &plan, &mut rng, None, None).unwrap();
+
+        for stage in &result.subspaces[0].stages {
+            let k = stage.centroids.nrows();
+            let even = data.nrows() as f32 / k as f32;
+            let capacity =
+                ((even * (1.0 + capacity_slack)).ceil() as usize).max(data.nrows().div_ceil(k));
+            let mut load = vec![0usize; k];
+            for &code in &stage.assignments {
+                load[code as usize] += 1;
+            }
+            assert!(
+                load.iter().all(|&rows| rows <= capacity),
+                "stage {} load {:?} exceeds capacity {}",
+                stage.stage_id,
+                load,
+                capacity
+            );
+        }
+    }
+
     #[test]
     fn distillation_blend_shifts_values() {
         let data = array![[0.0f32, 0.0]];
@@ -0,0 +1,177 @@
+//! Gradient fine-tuning of codebook centroids after k-means.
+//!
+//! Assignments are held fixed and every stage's centroids move together along
+//! the gradient of the (optionally calibration-weighted) reconstruction loss
+//! `mean_r sum_j w_j * (x_r - sum_s C_s[a_s(r)])_j^2`, where `w_j` is the
+//! importance of column `j`.
+
+use ndarray::{Array2, Axis};
+
+/// Steps whose step size has been halved this many times without improving stop the pass.
+const MAX_BACKTRACKS: u32 = 4;
+
+/// Relative loss improvement below which fine-tuning is considered converged.
+const MIN_RELATIVE_IMPROVEMENT: f32 = 1e-6;
+
+/// One additive stage: its trainable centroids and the frozen row assignments.
+pub(crate) struct StageCodebook<'a> {
+    pub centroids: &'a mut Array2<f32>,
+    pub assignments: &'a [usize],
+}
+
+pub(crate) struct CodebookRefiner<'a> {
+    column_weights: Option<&'a [f32]>,
+    learning_rate: f32,
+    steps: usize,
+}
+
+impl<'a> CodebookRefiner<'a> {
+    pub fn new(column_weights: Option<&'a [f32]>, learning_rate: f32, steps: usize) -> Self {
+        Self {
+            column_weights,
+            learning_rate,
+            steps,
+        }
+    }
+
+    /// Runs up to `steps` joint gradient steps against `target`.
+    ///
+    /// Returns the loss before fine-tuning followed by the loss after each
+    /// accepted step, so the history is strictly decreasing.
+    pub fn refine(&self, target: &Array2<f32>, stages: &mut [StageCodebook<'_>]) -> Vec<f32> {
+        let mut residual = self.residual(target, stages);
+        let mut loss = self.loss(&residual);
+        let mut history = vec![loss];
+        if stages.is_empty() || self.learning_rate <= 0.0 {
+            return history;
+        }
+
+        let mut step = self.learning_rate;
+        let mut backtracks = 0u32;
+        for _ in 0..self.steps {
+            // Per-centroid gradients are scaled by the inverse of the weight mass
+            // assigned to them, so `step = 1` would jump a lone stage to its optimum.
+            // A column's weight multiplies both, so the direction is the plain mean.
+            let directions: Vec<Array2<f32>> = stages
+                .iter()
+                .map(|stage| self.descent_direction(&residual, stage))
+                .collect();
+            apply(stages, &directions, step);
+
+            let candidate = self.residual(target, stages);
+            let candidate_loss = self.loss(&candidate);
+            if candidate_loss < loss {
+                let improvement = (loss - candidate_loss) / loss.max(f32::MIN_POSITIVE);
+                residual = candidate;
+                loss = candidate_loss;
+                history.push(loss);
+                if improvement < MIN_RELATIVE_IMPROVEMENT {
+                    break;
+                }
+            } else {
+                apply(stages, &directions, -step);
+                backtracks += 1;
+                if backtracks > MAX_BACKTRACKS {
+                    break;
+                }
+                step *= 0.5;
+            }
+        }
+        history
+    }
+
+    fn residual(&self, target: &Array2<f32>, stages: &[StageCodebook<'_>]) -> Array2<f32> {
+        let mut residual = target.clone();
+        for stage in stages {
+            for (mut row, &idx) in residual.axis_iter_mut(Axis(0)).zip(stage.assignments) {
+                row -= &stage.centroids.row(idx);
+            }
+        }
+        residual
+    }
+
+    fn loss(&self, residual: &Array2<f32>) -> f32 {
+        let total: f32 = residual
+            .axis_iter(Axis(0))
+            .map(|values| match self.column_weights {
+                Some(weights) => values.iter().zip(weights).map(|(v, w)| w * v * v).sum(),
+                None => values.iter().map(|v| v * v).sum::<f32>(),
+            })
+            .sum();
+        total / residual.len().max(1) as f32
+    }
+
+    fn descent_direction(&self, residual: &Array2<f32>, stage: &StageCodebook<'_>) -> Array2<f32> {
+        let mut direction = Array2::<f32>::zeros(stage.centroids.raw_dim());
+        let mut mass = vec![0.0f32; stage.centroids.nrows()];
+        for (values, &idx) in residual.axis_iter(Axis(0)).zip(stage.assignments) {
+            mass[idx] += 1.0;
+            direction.row_mut(idx).scaled_add(1.0, &values);
+        }
+        for (mut row, &mass) in direction.axis_iter_mut(Axis(0)).zip(&mass) {
+            if mass > 0.0 {
+                row /= mass;
+            }
+        }
+        direction
+    }
+}
+
+fn apply(stages: &mut [StageCodebook<'_>], directions: &[Array2<f32>], step: f32) {
+    for (stage, direction) in stages.iter_mut().zip(directions) {
+        stage.centroids.scaled_add(step, direction);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn joint_steps_reduce_loss_monotonically() {
+        let target = array![[1.0f32, 2.0], [1.2, 1.8], [-1.0, 0.5], [-0.8, 0.7]];
+        let mut stage1 = array![[0.0f32, 0.0], [0.0, 0.0]];
+        let mut stage2 = array![[0.1f32, 0.1], [-0.1, -0.1]];
+        let assignments1 = [0usize, 0, 1, 1];
+        let assignments2 = [0usize, 1, 0, 1];
+        let mut stages = vec![
+            StageCodebook {
+                centroids: &mut stage1,
+                assignments: &assignments1,
+            },
+            StageCodebook {
+                centroids: &mut stage2,
+                assignments: &assignments2,
+            },
+        ];
+        let history = CodebookRefiner::new(None, 0.5, 20).refine(&target, &mut stages);
+        assert!(history.len() > 1);
+        assert!(history.windows(2).all(|pair| pair[1] < pair[0]));
+        assert!(*history.last().unwrap() < history[0] * 0.1);
+    }
+
+    #[test]
+    fn calibration_weights_scale_column_losses() {
+        let target = array![[0.0f32, 0.0], [10.0, 2.0]];
+        let assignments = [0usize, 0];
+        let refine = |weights: Option<&[f32]>| {
+            let mut centroids = array![[0.0f32, 0.0]];
+            let mut stages = vec![StageCodebook {
+                centroids: &mut centroids,
+                assignments: &assignments,
+            }];
+            let history = CodebookRefiner::new(weights, 1.0, 10).refine(&target, &mut stages);
+            (centroids, history)
+        };
+        let (plain, plain_history) = refine(None);
+        let (weighted, weighted_history) = refine(Some(&[0.5, 1.5]));
+        // The optimum is the column mean either way; only the loss is reweighted.
+        assert_eq!(plain, array![[5.0f32, 1.0]]);
+        assert_eq!(weighted, plain);
+        assert!((plain_history[0] - 26.0).abs() < 1e-5);
+        assert!((weighted_history[0] - 14.0).abs() < 1e-5);
+        assert!((*plain_history.last().unwrap() - 13.0).abs() < 1e-5);
+        assert!((*weighted_history.last().unwrap() - 7.0).abs() < 1e-5);
+    }
+}
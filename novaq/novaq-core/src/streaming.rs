@@ -0,0 +1,459 @@
+//! Row-block streaming quantization for layers too large to hold in memory.
+//!
+//! The layer is read three times:
+//! 1. layer statistics, a magnitude histogram for the outlier threshold and a
+//!    reservoir sample of rows;
+//! 2. per-column normalization statistics and the outliers themselves;
+//! 3. streaming assignment of every row against codebooks trained on the
+//!    sample, accumulating metrics along the way.
+//!
+//! Only the sample, the codes and per-column statistics are ever resident.
+
+use std::time::Instant;
+
+use ndarray::{s, Array2, ArrayView1, Axis};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tracing::{debug, instrument};
+
+use crate::analysis::AnalysisAccumulator;
+use crate::config::QuantizationConfig;
+use crate::error::{NovaQError, Result};
+use crate::metrics::MetricsAccumulator;
+use crate::model::{LayerAllocation, LayerTelemetry, NormalizationRecord, QuantizedLayer};
+use crate::normalization::{
+    destandardize, normalize_rows, ColumnStatsAccumulator, MagnitudeHistogram, Normalizer,
+};
+use crate::outliers::round_outliers;
+use crate::quantization::{encode_rows, CalibrationStats, ProductQuantizer};
+use crate::subspace::{permute_columns, restore_column_order, SubspacePlanner};
+
+/// Mixed into the layer seed so reservoir sampling does not consume the k-means stream.
+const RESERVOIR_SEED_SALT: u64 = 0x5eed_0f7e_5e77_0001;
+
+/// A layer that can be read as a sequence of row blocks, top to bottom, as many
+/// times as needed.
+///
+/// Any `FnMut() -> impl IntoIterator<Item = Result<Array2<f32>>>` closure is a
+/// source; each call must start a fresh pass over the same rows.
+pub trait RowBlockSource {
+    type Blocks: Iterator<Item = Result<Array2<f32>>>;
+
+    fn blocks(&mut self) -> Result<Self::Blocks>;
+}
+
+impl<F, I> RowBlockSource for F
+where
+    F: FnMut() -> I,
+    I: IntoIterator<Item = Result<Array2<f32>>>,
+{
+    type Blocks = I::IntoIter;
+
+    fn blocks(&mut self) -> Result<Self::Blocks> {
+        Ok(self().into_iter())
+    }
+}
+
+/// Uniform sample of rows (Algorithm R), kept in source order.
+struct RowReservoir {
+    capacity: usize,
+    seen: usize,
+    rows: Vec<(usize, Vec<f32>)>,
+    rng: StdRng,
+}
+
+impl RowReservoir {
+    fn new(capacity: usize, seed: u64) -> Self {
+        Self {
+            capacity,
+            seen: 0,
+            rows: Vec::new(),
+            rng: StdRng::seed_from_u64(seed ^ RESERVOIR_SEED_SALT),
+        }
+    }
+
+    fn offer(&mut self, row: ArrayView1<'_, f32>) {
+        let index = self.seen;
+        self.seen += 1;
+        if self.rows.len() < self.capacity {
+            self.rows.push((index, row.to_vec()));
+            return;
+        }
+        let slot = self.rng.gen_range(0..self.seen);
+        if slot < self.capacity {
+            self.rows[slot] = (index, row.to_vec());
+        }
+    }
+
+    fn into_sample(mut self, cols: usize) -> (Vec<usize>, Array2<f32>) {
+        self.rows.sort_unstable_by_key(|(index, _)| *index);
+        let indices = self.rows.iter().map(|(index, _)| *index).collect();
+        let values = self.rows.into_iter().flat_map(|(_, row)| row).collect();
+        let sample = Array2::from_shape_vec((self.seen.min(self.capacity), cols), values)
+            .expect("reservoir rows share the layer width");
+        (indices, sample)
+    }
+}
+
+fn replay_mismatch(pass: usize, expected: usize, found: usize) -> NovaQError {
+    NovaQError::InvalidInput {
+        reason: format!(
+            "row block source yielded {found} rows on pass {pass}, expected {expected}"
+        ),
+    }
+}
+
+/// Moves normalization statistics into permuted column order.
+fn permute_record(record: &NormalizationRecord, permutation: &[usize]) -> NormalizationRecord {
+    let mut position = vec![0usize; permutation.len()];
+    for (pos, &col) in permutation.iter().enumerate() {
+        position[col] = pos;
+    }
+    let mut outliers = record.outliers.clone();
+    for outlier in outliers.iter_mut() {
+        outlier.col = position[outlier.col];
+    }
+    outliers.sort_by_key(|outlier| (outlier.row, outlier.col));
+    NormalizationRecord {
+        column_means: permutation
+            .iter()
+            .map(|&c| record.column_means[c])
+            .collect(),
+        column_stds: permutation.iter().map(|&c| record.column_stds[c]).collect(),
+        outliers,
+    }
+}
+
+#[instrument(skip_all, fields(layer = name))]
+pub(crate) fn quantize_layer_streaming<S: RowBlockSource>(
+    base_config: &QuantizationConfig,
+    name: &str,
+    index: usize,
+    source: &mut S,
+    calibration: Option<&CalibrationStats>,
+    allocation: &LayerAllocation,
+) -> Result<QuantizedLayer> {
+    let start = Instant::now();
+    let config = allocation.apply(base_config);
+    let seed = base_config.layer_seed(name, index);
+
+    // Pass 1: layer analysis, magnitude histogram and row sample.
+    let mut stats: Option<AnalysisAccumulator> = None;
+    let mut histogram = MagnitudeHistogram::new();
+    let mut reservoir = RowReservoir::new(config.reservoir_rows, seed);
+    for block in source.blocks()? {
+        let block = block?;
+        if block.is_empty() {
+            continue;
+        }
+        stats
+            .get_or_insert_with(|| AnalysisAccumulator::new(block.ncols()))
+            .push(&block)?;
+        histogram.push(&block);
+        for row in block.axis_iter(Axis(0)) {
+            reservoir.offer(row);
+        }
+    }
+    let analysis = stats.ok_or(NovaQError::EmptyTensor)?.finish()?;
+    let rows = analysis.rows;
+    let cols = analysis.cols;
+    if let Some(calibration) = calibration {
+        calibration.check_columns(cols)?;
+    }
+
+    let planner = SubspacePlanner::new(&config);
+    let mut plan = planner.plan(&analysis);
+    if allocation.force_residual {
+        for spec in plan.iter_mut() {
+            spec.enable_residual = true;
+        }
+    }
+    let normalizer = Normalizer::new(config.outlier_percentile)?;
+    let cut = histogram.cut(normalizer.outlier_count(histogram.total(), Some(&analysis)));
+    drop(histogram);
+
+    // Pass 2: outliers and column statistics over the remaining values.
+    let mut column_stats = ColumnStatsAccumulator::new(cols, cut);
+    for block in source.blocks()? {
+        let block = block?;
+        if block.is_empty() {
+            continue;
+        }
+        if block.ncols() != cols {
+            return Err(NovaQError::DimensionMismatch {
+                expected: cols,
+                found: block.ncols(),
+            });
+        }
+        column_stats.push(&block);
+    }
+    if column_stats.rows() != rows {
+        return Err(replay_mismatch(2, rows, column_stats.rows()));
+    }
+    let (mut record, threshold) = column_stats.finish();
+    round_outliers(&mut record.outliers, config.outlier_precision);
+
+    // Train codebooks on the normalized sample.
+    let (sample_rows, sample) = reservoir.into_sample(cols);
+    debug!(
+        sampled = sample_rows.len(),
+        rows, "training codebooks on row sample"
+    );
+    let column_permutation = planner.permutation(&sample, &analysis, &plan);
+    let mut normalized_sample = normalize_rows(&sample, &record, threshold);
+    let stored_record = match column_permutation.as_deref() {
+        Some(permutation) => {
+            normalized_sample = permute_columns(&normalized_sample, permutation);
+            permute_record(&record, permutation)
+        }
+        None => record.clone(),
+    };
+    let permuted_calibration = column_permutation
+        .as_deref()
+        .zip(calibration)
+        .map(|(permutation, calibration)| calibration.permuted(permutation));
+    let working_calibration = permuted_calibration.as_ref().or(calibration);
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let pq = ProductQuantizer::new(&config)?;
+    let mut trained = pq.quantize(
+        &normalized_sample,
+        &plan,
+        &mut rng,
+        None,
+        working_calibration,
+    )?;
+    drop(normalized_sample);
+
+    // Pass 3: encode every row and measure the reconstruction.
+    let mut codes: Vec<Vec<Vec<u16>>> = trained
+        .subspaces
+        .iter()
+        .map(|subspace| vec![Vec::with_capacity(rows); subspace.stages.len()])
+        .collect();
+    let mut errors = vec![0.0f64; trained.subspaces.len()];
+    let mut metrics = MetricsAccumulator::new();
+    let mut offset = 0usize;
+    for block in source.blocks()? {
+        let block = block?;
+        if block.is_empty() {
+            continue;
+        }
+        if block.ncols() != cols {
+            return Err(NovaQError::DimensionMismatch {
+                expected: cols,
+                found: block.ncols(),
+            });
+        }
+        let block_rows = block.nrows();
+        if offset + block_rows > rows {
+            return Err(replay_mismatch(3, rows, offset + block_rows));
+        }
+        let mut normalized = normalize_rows(&block, &record, threshold);
+        if let Some(permutation) = &column_permutation {
+            normalized = permute_columns(&normalized, permutation);
+        }
+        let mut reconstruction = Array2::<f32>::zeros(normalized.raw_dim());
+        for ((subspace, subspace_codes), error) in trained
+            .subspaces
+            .iter()
+            .zip(codes.iter_mut())
+            .zip(errors.iter_mut())
+        {
+            let columns = subspace.columns.clone();
+            let column_weights =
+                working_calibration.map(|calibration| &calibration.importance()[columns.clone()]);
+            let (block_codes, block_error) = encode_rows(
+                subspace,
+                normalized.slice(s![.., columns.clone()]),
+                reconstruction.slice_mut(s![.., columns]),
+                column_weights,
+            );
+            for (stage_codes, new_codes) in subspace_codes.iter_mut().zip(block_codes) {
+                stage_codes.extend(new_codes);
+            }
+            *error += block_error;
+        }
+
+        let mut restored = destandardize(&reconstruction, &stored_record);
+        if let Some(permutation) = &column_permutation {
+            restored = restore_column_order(&restored, permutation);
+        }
+        for (value, &original) in restored.iter_mut().zip(block.iter()) {
+            if original.abs() >= threshold {
+                *value = config.outlier_precision.round(original);
+            }
+        }
+        metrics.push(
+            &block,
+            &restored,
+            calibration.map(CalibrationStats::importance),
+        );
+        offset += block_rows;
+    }
+    if offset != rows {
+        return Err(replay_mismatch(3, rows, offset));
+    }
+
+    for (((subspace, telemetry), subspace_codes), error) in trained
+        .subspaces
+        .iter_mut()
+        .zip(trained.telemetry.iter_mut())
+        .zip(codes)
+        .zip(errors)
+    {
+        for (stage, stage_codes) in subspace.stages.iter_mut().zip(subspace_codes) {
+            stage.assignments = stage_codes;
+        }
+        let width = subspace.columns.len().max(1);
+        subspace.residual_energy = (error / (rows * width) as f64) as f32;
+        telemetry.residual_energy = subspace.residual_energy;
+    }
+
+    let compressed_bits =
+        pq.estimate_compressed_bits(rows, &trained.subspaces) + stored_record.outlier_bits();
+    Ok(QuantizedLayer {
+        name: name.to_string(),
+        index,
+        rows,
+        cols,
+        seed,
+        normalization: stored_record,
+        subspaces: trained.subspaces,
+        metrics: metrics.finish(compressed_bits),
+        quantization_time_us: start.elapsed().as_micros() as u64,
+        telemetry: LayerTelemetry {
+            analysis,
+            subspaces: trained.telemetry,
+        },
+        allocation: allocation.clone(),
+        column_permutation,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Quantizer;
+
+    fn layer(rows: usize, cols: usize) -> Array2<f32> {
+        Array2::from_shape_fn((rows, cols), |(i, j)| {
+            ((i * cols + j) as f32 * 0.613).sin() * (1.0 + (j % 3) as f32) + 0.1 * (i % 5) as f32
+        })
+    }
+
+    fn blocks(weights: &Array2<f32>, block_rows: usize) -> Vec<Array2<f32>> {
+        weights
+            .axis_chunks_iter(Axis(0), block_rows)
+            .map(|block| block.to_owned())
+            .collect()
+    }
+
+    fn config() -> QuantizationConfig {
+        QuantizationConfig {
+            max_subspace_dim: 8,
+            level1_centroids: 8,
+            residual_centroids: vec![4],
+            use_parallel: false,
+            ..QuantizationConfig::default()
+        }
+    }
+
+    #[test]
+    fn streaming_matches_in_memory_when_sample_covers_layer() {
+        let weights = layer(96, 16);
+        let chunks = blocks(&weights, 10);
+        let quantizer = Quantizer::new(config()).unwrap();
+        let expected = quantizer.quantize_layer("embed", 3, &weights).unwrap();
+        let streamed = quantizer
+            .quantize_layer_streaming("embed", 3, || chunks.iter().cloned().map(Ok), None)
+            .unwrap();
+
+        assert_eq!(streamed.rows, expected.rows);
+        let positions = |layer: &QuantizedLayer| -> Vec<(usize, usize)> {
+            layer
+                .normalization
+                .outliers
+                .iter()
+                .map(|o| (o.row, o.col))
+                .collect()
+        };
+        assert_eq!(positions(&streamed), positions(&expected));
+        assert_eq!(streamed.subspaces.len(), expected.subspaces.len());
+        for (a, b) in streamed.subspaces.iter().zip(&expected.subspaces) {
+            assert_eq!(a.columns, b.columns);
+            assert_eq!(a.stages.len(), b.stages.len());
+            // Later stages are re-encoded greedily, so only stage 1 is identical.
+            assert_eq!(a.stages[0].assignments, b.stages[0].assignments);
+        }
+        assert!((streamed.metrics.mse - expected.metrics.mse).abs() <= 0.05 * expected.metrics.mse);
+        assert_eq!(
+            streamed.metrics.compressed_bits,
+            expected.metrics.compressed_bits
+        );
+
+        let restored = streamed.dequantize();
+        let mse = restored
+            .iter()
+            .zip(weights.iter())
+            .map(|(r, w)| (r - w) * (r - w))
+            .sum::<f32>()
+            / weights.len() as f32;
+        assert!((mse - streamed.metrics.mse).abs() <= 1e-5);
+    }
+
+    #[test]
+    fn streaming_trains_on_bounded_sample() {
+        let weights = layer(400, 16);
+        let chunks = blocks(&weights, 64);
+        let config = QuantizationConfig {
+            reservoir_rows: 48,
+            ..config()
+        };
+        let quantizer = Quantizer::new(config).unwrap();
+        let stats = CalibrationStats::from_diagonal_hessian(
+            (0..16).map(|col| 1.0 + (col % 4) as f32).collect(),
+        )
+        .unwrap();
+        let streamed = quantizer
+            .quantize_layer_streaming(
+                "lm_head",
+                0,
+                || chunks.iter().cloned().map(Ok),
+                Some(&stats),
+            )
+            .unwrap();
+
+        assert_eq!(streamed.rows, 400);
+        for subspace in &streamed.subspaces {
+            for stage in &subspace.stages {
+                assert_eq!(stage.assignments.len(), 400);
+            }
+        }
+        assert!(streamed.metrics.calibrated_mse.is_some());
+        assert!(streamed.metrics.cosine_similarity > 0.8);
+        let restored = streamed.dequantize();
+        let mse = restored
+            .iter()
+            .zip(weights.iter())
+            .map(|(r, w)| (r - w) * (r - w))
+            .sum::<f32>()
+            / weights.len() as f32;
+        assert!((mse - streamed.metrics.mse).abs() <= 1e-5);
+    }
+
+    #[test]
+    fn source_that_changes_between_passes_is_rejected() {
+        let weights = layer(40, 8);
+        let mut pass = 0;
+        let source = || {
+            pass += 1;
+            let rows = if pass == 1 { 40 } else { 32 };
+            vec![Ok(weights.slice(s![..rows, ..]).to_owned())]
+        };
+        let quantizer = Quantizer::new(config()).unwrap();
+        assert!(quantizer
+            .quantize_layer_streaming("layer", 0, source, None)
+            .is_err());
+    }
+}
@@ -1,17 +1,27 @@
 use std::ops::Range;
 
-use ndarray::Array2;
+use ndarray::{Array1, Array2, Axis};
+use rayon::prelude::*;
 use tracing::{debug, instrument};
 
 use crate::analysis::analyze_layer;
-use crate::config::QuantizationConfig;
+use crate::config::{ColumnPermutation, QuantizationConfig};
 use crate::error::Result;
 use crate::model::LayerAnalysis;
 
+/// Widest layer for which a full column correlation matrix is computed; wider
+/// layers fall back to variance ordering.
+const MAX_CORRELATION_COLUMNS: usize = 4096;
+
+/// Rows sampled when estimating column correlations.
+const CORRELATION_SAMPLE_ROWS: usize = 512;
+
+/// A contiguous block of columns; when a column permutation is in use the range
+/// refers to permuted column positions.
 #[derive(Debug, Clone)]
 pub struct SubspaceSpec {
     pub columns: Range<usize>,
-    pub enable_stage2: bool,
+    pub enable_residual: bool,
     pub refinement_steps: usize,
 }
 
@@ -55,8 +65,8 @@ impl<'a> SubspacePlanner<'a> {
                 width = remaining;
             }
 
-            let enable_stage2 = analysis.kurtosis > 3.5 || analysis.anisotropy > 4.0;
-            let refinement_steps = if enable_stage2 {
+            let enable_residual = analysis.kurtosis > 3.5 || analysis.anisotropy > 4.0;
+            let refinement_steps = if enable_residual {
                 self.config.max_refinement_steps
             } else {
                 (self.config.max_refinement_steps / 2).max(1)
@@ -64,13 +74,13 @@ impl<'a> SubspacePlanner<'a> {
 
             plan.push(SubspaceSpec {
                 columns: start..(start + width),
-                enable_stage2,
+                enable_residual,
                 refinement_steps,
             });
             debug!(
                 columns.start = start,
                 columns.end = start + width,
-                enable_stage2,
+                enable_residual,
                 refinement_steps,
                 "planned subspace"
             );
@@ -79,6 +89,133 @@ impl<'a> SubspacePlanner<'a> {
 
         plan
     }
+
+    /// Chooses a column order for `plan`, or `None` to keep columns in place.
+    ///
+    /// Position `j` of the returned order holds the original index of the
+    /// column that lands at permuted position `j`.
+    #[instrument(skip_all, fields(mode = ?self.config.column_permutation))]
+    pub fn permutation(
+        &self,
+        weights: &Array2<f32>,
+        analysis: &LayerAnalysis,
+        plan: &[SubspaceSpec],
+    ) -> Option<Vec<usize>> {
+        let order = match self.config.column_permutation {
+            ColumnPermutation::None => return None,
+            ColumnPermutation::Variance => variance_order(&analysis.column_variances),
+            ColumnPermutation::Correlation if analysis.cols > MAX_CORRELATION_COLUMNS => {
+                debug!(
+                    cols = analysis.cols,
+                    "too many columns for correlation grouping, ordering by variance"
+                );
+                variance_order(&analysis.column_variances)
+            }
+            ColumnPermutation::Correlation => {
+                correlation_order(weights, &analysis.column_variances, plan)
+            }
+        };
+        if order.iter().enumerate().all(|(pos, &col)| pos == col) {
+            return None;
+        }
+        Some(order)
+    }
+}
+
+fn variance_order(variances: &[f32]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..variances.len()).collect();
+    order.sort_by(|&a, &b| variances[b].total_cmp(&variances[a]));
+    order
+}
+
+/// Seeds each subspace with the highest-variance unplaced column, then adds the
+/// unplaced columns with the largest mean absolute correlation to the group.
+fn correlation_order(
+    weights: &Array2<f32>,
+    variances: &[f32],
+    plan: &[SubspaceSpec],
+) -> Vec<usize> {
+    let cols = weights.ncols();
+    let correlation = absolute_correlation(weights);
+    let by_variance = variance_order(variances);
+    let mut placed = vec![false; cols];
+    let mut order = Vec::with_capacity(cols);
+
+    for spec in plan {
+        let Some(&seed) = by_variance.iter().find(|&&col| !placed[col]) else {
+            break;
+        };
+        placed[seed] = true;
+        order.push(seed);
+        let mut affinity: Array1<f32> = correlation.row(seed).to_owned();
+        for _ in 1..spec.columns.len() {
+            let next = (0..cols)
+                .filter(|&col| !placed[col])
+                .max_by(|&a, &b| affinity[a].total_cmp(&affinity[b]).then(b.cmp(&a)));
+            let Some(next) = next else {
+                break;
+            };
+            placed[next] = true;
+            order.push(next);
+            affinity += &correlation.row(next);
+        }
+    }
+    order
+}
+
+fn absolute_correlation(weights: &Array2<f32>) -> Array2<f32> {
+    let rows = weights.nrows();
+    let stride = rows.div_ceil(CORRELATION_SAMPLE_ROWS).max(1);
+    let indices: Vec<usize> = (0..rows).step_by(stride).collect();
+    let mut sample = weights.select(Axis(0), &indices);
+    let samples = sample.nrows().max(1) as f32;
+
+    for mut column in sample.axis_iter_mut(Axis(1)) {
+        let mean = column.sum() / samples;
+        column -= mean;
+        let std = (column.iter().map(|v| v * v).sum::<f32>() / samples).sqrt();
+        if std > f32::EPSILON {
+            column /= std;
+        } else {
+            column.fill(0.0);
+        }
+    }
+
+    // Computed by hand rather than with `dot`, which would route through BLAS.
+    let columns = sample.t().as_standard_layout().into_owned();
+    let cols = columns.nrows();
+    let mut correlation = vec![0.0f32; cols * cols];
+    correlation
+        .par_chunks_mut(cols)
+        .enumerate()
+        .for_each(|(a, row)| {
+            let column_a = columns.row(a);
+            for (b, value) in row.iter_mut().enumerate() {
+                let dot: f32 = column_a
+                    .iter()
+                    .zip(columns.row(b))
+                    .map(|(x, y)| x * y)
+                    .sum();
+                *value = (dot / samples).abs();
+            }
+        });
+    Array2::from_shape_vec((cols, cols), correlation).expect("correlation buffer matches shape")
+}
+
+/// Gathers columns so column `j` of the result is column `permutation[j]` of `matrix`.
+pub(crate) fn permute_columns(matrix: &Array2<f32>, permutation: &[usize]) -> Array2<f32> {
+    matrix.select(Axis(1), permutation)
+}
+
+/// Inverse of [`permute_columns`].
+pub(crate) fn restore_column_order(matrix: &Array2<f32>, permutation: &[usize]) -> Array2<f32> {
+    let mut restored = Array2::<f32>::zeros(matrix.raw_dim());
+    for (position, &original) in permutation.iter().enumerate() {
+        restored
+            .column_mut(original)
+            .assign(&matrix.column(position));
+    }
+    restored
 }
 
 #[instrument(skip(config, weights))]
@@ -114,4 +251,37 @@ mod tests {
             .all(|spec| spec.columns.len() >= config.min_subspace_dim
                 || spec.columns.end == analysis.cols));
     }
+
+    #[test]
+    fn correlation_permutation_groups_related_columns() {
+        // Columns 0/2 follow one latent factor and 1/3 another.
+        let weights = Array2::from_shape_fn((64, 4), |(i, j)| {
+            let t = i as f32 * 0.37;
+            let latent = if j % 2 == 0 { t.sin() } else { (2.3 * t).cos() };
+            latent * (1.0 + j as f32 * 0.1)
+        });
+        let config = QuantizationConfig {
+            max_subspace_dim: 2,
+            min_subspace_dim: 2,
+            column_permutation: ColumnPermutation::Correlation,
+            ..QuantizationConfig::default()
+        };
+        let (analysis, plan) = plan_subspaces(&config, &weights).unwrap();
+        let order = SubspacePlanner::new(&config)
+            .permutation(&weights, &analysis, &plan)
+            .unwrap();
+        let mut groups: Vec<Vec<usize>> = order
+            .chunks(2)
+            .map(|pair| {
+                let mut pair = pair.to_vec();
+                pair.sort_unstable();
+                pair
+            })
+            .collect();
+        groups.sort();
+        assert_eq!(groups, vec![vec![0, 2], vec![1, 3]]);
+
+        let permuted = permute_columns(&weights, &order);
+        assert_eq!(restore_column_order(&permuted, &order), weights);
+    }
 }
@@ -79,28 +79,6 @@ pub fn validate_centroid_distinctness(
     Ok(())
 }
 
-/// Validates that all values in a probability distribution sum to approximately 1.0.
-///
-/// Used to verify that KL divergence calculations are operating on valid probability distributions.
-pub fn validate_probability_distribution(probs: &[f64], tolerance: f64) -> Result<()> {
-    let sum: f64 = probs.iter().sum();
-    if (sum - 1.0).abs() > tolerance {
-        return Err(NovaQError::InvariantViolation(format!(
-            "Probability distribution sums to {}, expected 1.0 (tolerance={})",
-            sum, tolerance
-        )));
-    }
-    for (idx, &p) in probs.iter().enumerate() {
-        if p < 0.0 {
-            return Err(NovaQError::InvariantViolation(format!(
-                "Negative probability {} at index {}",
-                p, idx
-            )));
-        }
-    }
-    Ok(())
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -140,25 +118,4 @@ mod tests {
         let result = validate_centroid_distinctness(&centroids, 0.1);
         assert!(result.is_err());
     }
-
-    #[test]
-    fn validate_probability_distribution_accepts_valid() {
-        let probs = vec![0.3, 0.3, 0.4];
-        assert!(validate_probability_distribution(&probs, 1e-6).is_ok());
-    }
-
-    #[test]
-    fn validate_probability_distribution_rejects_invalid_sum() {
-        let probs = vec![0.3, 0.3, 0.3];
-        let result = validate_probability_distribution(&probs, 1e-6);
-        assert!(result.is_err());
-    }
-
-    #[test]
-    fn validate_probability_distribution_rejects_negative() {
-        let probs = vec![0.5, -0.1, 0.6];
-        let result = validate_probability_distribution(&probs, 1e-6);
-        assert!(result.is_err());
-    }
 }
-
@@ -2,8 +2,9 @@ use criterion::{criterion_group, criterion_main, Criterion};
 use novaq_core::QuantizationConfig;
 use novaq_io::{ArtifactWriter, ArtifactWriterConfig, SafeTensorsLoader};
 use serde_json::json;
+use std::io::Cursor;
 use tempfile::tempdir;
-use tokio::{io::Cursor, runtime::Runtime};
+use tokio::runtime::Runtime;
 
 fn synthetic_payload() -> Vec<u8> {
     let rows = 4;
@@ -23,7 +24,7 @@ fn synthetic_payload() -> Vec<u8> {
     })
     .to_string();
     let mut bytes = Vec::new();
-    let header_len = header.as_bytes().len() as u64;
+    let header_len = header.len() as u64;
     bytes.extend_from_slice(&header_len.to_le_bytes());
     bytes.extend_from_slice(header.as_bytes());
     bytes.extend_from_slice(&data_bytes);
@@ -36,18 +37,21 @@ fn bench_safetensors_loader(c: &mut Criterion) {
     let loader = SafeTensorsLoader::new(QuantizationConfig::default()).unwrap();
 
     c.bench_function("safetensors_small", |b| {
-        b.to_async(&runtime).iter(|| async {
-            let dir = tempdir().unwrap();
-            let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
-                chunk_bytes: 1 << 20,
-                output_dir: dir.path().to_path_buf(),
-            });
-            let cursor = Cursor::new(payload.clone());
-            let mut reader = tokio::io::BufReader::new(cursor);
-            let _model = loader
-                .load_from_reader(&mut reader, &mut writer)
-                .await
-                .unwrap();
+        b.iter(|| {
+            runtime.block_on(async {
+                let dir = tempdir().unwrap();
+                let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+                    chunk_bytes: 1 << 20,
+                    output_dir: dir.path().to_path_buf(),
+                    ..ArtifactWriterConfig::default()
+                });
+                let cursor = Cursor::new(payload.clone());
+                let mut reader = tokio::io::BufReader::new(cursor);
+                let _model = loader
+                    .load_from_reader(&mut reader, &mut writer)
+                    .await
+                    .unwrap();
+            })
         });
     });
 }
@@ -0,0 +1,270 @@
+//! Model bundles shipped as `.tar`, `.tar.gz`/`.tgz` or `.zip` archives.
+//!
+//! Members are streamed out of the archive into the streaming loaders, so
+//! nothing is extracted to disk. Tar members are quantized in archive order,
+//! since a tar stream cannot be reordered without buffering it; zip members
+//! come from the central directory and are quantized in layer order of
+//! their names. Members that are not SafeTensors, GGUF or ONNX files, such
+//! as tokenizers and configs, are skipped.
+
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use async_compression::tokio::bufread::{DeflateDecoder, GzipDecoder};
+use futures::{StreamExt, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, info, instrument};
+
+use novaq_core::{QuantizationConfig, QuantizedLayer, QuantizedModel};
+
+use crate::artifact::ArtifactWriter;
+use crate::format::ModelFormat;
+use crate::onnx::OnnxLoader;
+use crate::safetensors_index::layer_order;
+use crate::streaming_gguf::StreamingGgufParser;
+use crate::streaming_safetensors_v2::StreamingSafeTensorsParserV2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl ArchiveKind {
+    pub fn detect(path: &str) -> Option<Self> {
+        let lowered = path.to_ascii_lowercase();
+        if lowered.ends_with(".tar.gz") || lowered.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if lowered.ends_with(".tar") {
+            Some(Self::Tar)
+        } else if lowered.ends_with(".zip") {
+            Some(Self::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// A zip member's compressed bytes, located through the central directory.
+#[derive(Debug)]
+struct ZipMember {
+    name: String,
+    format: ModelFormat,
+    deflated: bool,
+    data_start: u64,
+    compressed_size: u64,
+}
+
+pub struct ArchiveLoader {
+    config: QuantizationConfig,
+}
+
+impl ArchiveLoader {
+    pub fn new(config: QuantizationConfig) -> Result<Self> {
+        // Fail on a bad config now rather than at the first member.
+        novaq_core::Quantizer::new(config.clone())?;
+        Ok(Self { config })
+    }
+
+    #[instrument(skip(self, writer), fields(path = %path.display()))]
+    pub async fn load_from_path(
+        &self,
+        path: &Path,
+        writer: &mut ArtifactWriter,
+    ) -> Result<QuantizedModel> {
+        let kind = ArchiveKind::detect(&path.to_string_lossy())
+            .ok_or_else(|| anyhow!("{} is not a .tar, .tar.gz or .zip archive", path.display()))?;
+        let layers = match kind {
+            ArchiveKind::Tar | ArchiveKind::TarGz => {
+                let file = tokio::fs::File::open(path)
+                    .await
+                    .with_context(|| format!("open {}", path.display()))?;
+                let file = BufReader::new(file);
+                if kind == ArchiveKind::TarGz {
+                    self.load_tar(GzipDecoder::new(file), writer).await?
+                } else {
+                    self.load_tar(file, writer).await?
+                }
+            }
+            ArchiveKind::Zip => self.load_zip(path, writer).await?,
+        };
+        ensure!(
+            !layers.is_empty(),
+            "{} holds no SafeTensors, GGUF or ONNX members with weight matrices",
+            path.display()
+        );
+        Ok(QuantizedModel::from_layers(layers))
+    }
+
+    async fn load_tar<R>(
+        &self,
+        reader: R,
+        writer: &mut ArtifactWriter,
+    ) -> Result<Vec<QuantizedLayer>>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut archive = tokio_tar::Archive::new(reader);
+        let mut entries = archive.entries().context("read tar archive")?;
+        let mut layers = Vec::new();
+        while let Some(entry) = entries.next().await {
+            let mut entry = entry.context("read tar entry")?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry.path()?.to_string_lossy().into_owned();
+            let Some(format) = member_format(&name) else {
+                debug!(member = name, "skipping archive member");
+                continue;
+            };
+            let model = self
+                .load_member(&name, format, &mut entry, writer)
+                .await
+                .with_context(|| format!("load archive member {name}"))?;
+            layers.extend(model.layers);
+        }
+        Ok(layers)
+    }
+
+    async fn load_zip(
+        &self,
+        path: &Path,
+        writer: &mut ArtifactWriter,
+    ) -> Result<Vec<QuantizedLayer>> {
+        let mut members = zip_members(path)?;
+        members.sort_by(|a, b| layer_order(&a.name, &b.name));
+
+        let mut layers = Vec::new();
+        for member in &members {
+            let mut file = tokio::fs::File::open(path)
+                .await
+                .with_context(|| format!("open {}", path.display()))?;
+            file.seek(std::io::SeekFrom::Start(member.data_start))
+                .await?;
+            let data = BufReader::new(file.take(member.compressed_size));
+            let model = if member.deflated {
+                let mut data = DeflateDecoder::new(data);
+                self.load_member(&member.name, member.format, &mut data, writer)
+                    .await
+            } else {
+                let mut data = data;
+                self.load_member(&member.name, member.format, &mut data, writer)
+                    .await
+            }
+            .with_context(|| format!("load archive member {}", member.name))?;
+            layers.extend(model.layers);
+        }
+        Ok(layers)
+    }
+
+    /// Streams one member into the loader for its format.
+    async fn load_member<R>(
+        &self,
+        name: &str,
+        format: ModelFormat,
+        reader: &mut R,
+        writer: &mut ArtifactWriter,
+    ) -> Result<QuantizedModel>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        info!(member = name, ?format, "loading archive member");
+        match format {
+            ModelFormat::SafeTensors => {
+                StreamingSafeTensorsParserV2::new(self.config.clone(), None)?
+                    .parse_and_quantize(reader, writer)
+                    .await
+            }
+            ModelFormat::Gguf => {
+                let stream = ReaderStream::new(reader).map_err(anyhow::Error::from);
+                StreamingGgufParser::new(self.config.clone(), None)?
+                    .parse_and_quantize(Box::pin(stream), writer)
+                    .await
+            }
+            ModelFormat::Onnx => {
+                // The ONNX loader works on the whole protobuf anyway. External
+                // data files cannot be resolved from inside an archive.
+                let mut model = Vec::new();
+                reader.read_to_end(&mut model).await?;
+                OnnxLoader::new(self.config.clone())?
+                    .load_from_bytes(&model, None, writer)
+                    .await
+            }
+            other => bail!("{other:?} members are not supported"),
+        }
+    }
+}
+
+/// The formats worth loading out of an archive. Sharded SafeTensors members
+/// are loaded one by one, so their index file is not needed.
+fn member_format(name: &str) -> Option<ModelFormat> {
+    match ModelFormat::detect(name) {
+        format @ (ModelFormat::Gguf | ModelFormat::Onnx) => Some(format),
+        ModelFormat::SafeTensors if name.to_ascii_lowercase().ends_with(".safetensors") => {
+            Some(ModelFormat::SafeTensors)
+        }
+        _ => None,
+    }
+}
+
+/// Reads the central directory of the zip at `path`, which is small enough
+/// to go through the synchronous zip crate.
+fn zip_members(path: &Path) -> Result<Vec<ZipMember>> {
+    let file = std::fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut archive = zip::ZipArchive::new(std::io::BufReader::new(file))
+        .with_context(|| format!("read zip directory of {}", path.display()))?;
+    let mut members = Vec::new();
+    for index in 0..archive.len() {
+        let entry = archive.by_index_raw(index)?;
+        if !entry.is_file() {
+            continue;
+        }
+        let name = entry.name().to_string();
+        let Some(format) = member_format(&name) else {
+            debug!(member = name, "skipping archive member");
+            continue;
+        };
+        ensure!(!entry.encrypted(), "zip member {name} is encrypted");
+        let deflated = match entry.compression() {
+            zip::CompressionMethod::Stored => false,
+            zip::CompressionMethod::Deflated => true,
+            other => bail!("zip member {name} uses unsupported compression {other}"),
+        };
+        members.push(ZipMember {
+            name,
+            format,
+            deflated,
+            data_start: entry.data_start(),
+            compressed_size: entry.compressed_size(),
+        });
+    }
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_archive_kinds_and_members() {
+        assert_eq!(
+            ArchiveKind::detect("bundle.TAR.GZ"),
+            Some(ArchiveKind::TarGz)
+        );
+        assert_eq!(ArchiveKind::detect("bundle.tgz"), Some(ArchiveKind::TarGz));
+        assert_eq!(ArchiveKind::detect("bundle.tar"), Some(ArchiveKind::Tar));
+        assert_eq!(ArchiveKind::detect("bundle.zip"), Some(ArchiveKind::Zip));
+        assert_eq!(ArchiveKind::detect("model.safetensors"), None);
+
+        assert_eq!(
+            member_format("bundle/model-00001-of-00002.safetensors"),
+            Some(ModelFormat::SafeTensors)
+        );
+        assert_eq!(member_format("bundle/model.onnx"), Some(ModelFormat::Onnx));
+        assert_eq!(member_format("bundle/model.safetensors.index.json"), None);
+        assert_eq!(member_format("bundle/tokenizer.json"), None);
+        assert_eq!(member_format("bundle/pytorch_model.bin"), None);
+    }
+}
@@ -1,14 +1,24 @@
 use std::{collections::BTreeMap, io::Write};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use blake3::Hasher as Blake3;
+use novaq_core::QuantizedLayer;
+use novaq_manifest::{
+    CentroidPrecision, ChunkSpan, LayerEncoding, LayerFormat, SideFileEntry, SourceProvenance,
+};
 use sha2::{Digest, Sha256};
 use tracing::instrument;
 
+use crate::packed::{encode_layer, PACKED_LAYER_VERSION};
+
 #[derive(Debug, Clone)]
 pub struct ArtifactWriterConfig {
+    /// Size of every layer chunk but the last; layers are packed back to back
+    /// and split across chunks when larger than this.
     pub chunk_bytes: usize,
     pub output_dir: std::path::PathBuf,
+    /// Serialization used by [`ArtifactWriter::write_layer`].
+    pub layer_encoding: LayerEncoding,
 }
 
 impl Default for ArtifactWriterConfig {
@@ -16,6 +26,11 @@ impl Default for ArtifactWriterConfig {
         Self {
             chunk_bytes: 1 << 20,
             output_dir: std::env::temp_dir(),
+            layer_encoding: LayerEncoding {
+                format: LayerFormat::Packed,
+                version: PACKED_LAYER_VERSION,
+                centroid_precision: CentroidPrecision::F32,
+            },
         }
     }
 }
@@ -31,40 +46,90 @@ pub struct ChunkInfo {
 
 #[derive(Debug, Default, Clone)]
 pub struct ArtifactManifest {
+    pub layer_encoding: LayerEncoding,
+    pub chunk_bytes: usize,
     pub chunks: Vec<ChunkInfo>,
+    /// Byte ranges holding each layer written through [`ArtifactWriter::write_layer`].
+    pub layers: BTreeMap<String, Vec<ChunkSpan>>,
     pub metadata: BTreeMap<String, String>,
+    pub side_files: BTreeMap<String, SideFileEntry>,
+    pub source: Option<SourceProvenance>,
 }
 
 pub struct ArtifactWriter {
     cfg: ArtifactWriterConfig,
     manifest: ArtifactManifest,
+    pending: Vec<u8>,
 }
 
 impl ArtifactWriter {
     pub fn new(cfg: ArtifactWriterConfig) -> Self {
+        let manifest = ArtifactManifest {
+            layer_encoding: cfg.layer_encoding,
+            chunk_bytes: cfg.chunk_bytes,
+            ..ArtifactManifest::default()
+        };
         Self {
             cfg,
-            manifest: ArtifactManifest::default(),
+            manifest,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Serializes `layer` with the configured layer encoding and appends it to
+    /// the chunk being filled. A layer that fits in one chunk never straddles a
+    /// chunk boundary; larger layers continue across as many chunks as needed.
+    /// Layer names key the manifest, so writing a name twice is an error.
+    pub fn write_layer(&mut self, layer: &QuantizedLayer) -> Result<&[ChunkSpan]> {
+        if self.manifest.layers.contains_key(&layer.name) {
+            bail!("layer {} was already written to this artifact", layer.name);
+        }
+        let bytes = encode_layer(layer, &self.cfg.layer_encoding)
+            .with_context(|| format!("encode quantized layer {}", layer.name))?;
+        let chunk_bytes = self.cfg.chunk_bytes.max(1);
+        if bytes.len() <= chunk_bytes && self.pending.len() + bytes.len() > chunk_bytes {
+            self.flush()?;
+        }
+
+        let mut spans = Vec::new();
+        let mut remaining = bytes.as_slice();
+        while !remaining.is_empty() {
+            let take = (chunk_bytes - self.pending.len()).min(remaining.len());
+            spans.push(ChunkSpan {
+                chunk_index: self.manifest.chunks.len(),
+                offset: self.pending.len(),
+                len: take,
+            });
+            self.pending.extend_from_slice(&remaining[..take]);
+            remaining = &remaining[take..];
+            if self.pending.len() == chunk_bytes {
+                self.flush()?;
+            }
+        }
+
+        self.manifest.layers.insert(layer.name.clone(), spans);
+        Ok(&self.manifest.layers[&layer.name])
+    }
+
+    /// Writes out the partially filled chunk, if any. Loaders call this once
+    /// they have written their last layer.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
         }
+        let pending = std::mem::take(&mut self.pending);
+        self.write_chunk(&pending)?;
+        Ok(())
     }
 
+    /// Writes `bytes` as a chunk of its own, after any layers still pending.
     #[instrument(skip(self, bytes), fields(bytes = bytes.len()))]
     pub fn write_chunk(&mut self, bytes: &[u8]) -> Result<&ChunkInfo> {
+        self.flush()?;
         let index = self.manifest.chunks.len();
-        let mut sha = Sha256::new();
-        sha.update(bytes);
-        let mut blake = Blake3::new();
-        blake.update(bytes);
-
-        let sha_hex = hex::encode(sha.finalize());
-        let blake_hex = blake.finalize().to_hex().to_string();
-
+        let (sha_hex, blake_hex) = digests(bytes);
         let filename = format!("chunk-{:05}-{}.bin", index, &sha_hex[..16]);
-        let path = self.cfg.output_dir.join(&filename);
-        let mut file = std::fs::File::create(&path)
-            .with_context(|| format!("unable to create artifact chunk at {}", path.display()))?;
-        file.write_all(bytes)
-            .with_context(|| format!("unable to write artifact chunk at {}", path.display()))?;
+        self.create_file(&filename, bytes)?;
 
         self.manifest.chunks.push(ChunkInfo {
             index,
@@ -76,18 +141,56 @@ impl ArtifactWriter {
         Ok(self.manifest.chunks.last().expect("just pushed"))
     }
 
+    /// Writes `bytes` to `file_name` next to the chunks and records it in the
+    /// manifest under `role`, replacing any earlier file for that role.
+    pub fn write_side_file(
+        &mut self,
+        role: impl Into<String>,
+        file_name: &str,
+        bytes: &[u8],
+    ) -> Result<&SideFileEntry> {
+        let (sha256, blake3) = digests(bytes);
+        self.create_file(file_name, bytes)?;
+        let role = role.into();
+        self.manifest.side_files.insert(
+            role.clone(),
+            SideFileEntry {
+                path: file_name.to_string(),
+                bytes: bytes.len(),
+                sha256,
+                blake3,
+            },
+        );
+        Ok(&self.manifest.side_files[&role])
+    }
+
+    fn create_file(&self, file_name: &str, bytes: &[u8]) -> Result<()> {
+        let path = self.cfg.output_dir.join(file_name);
+        let mut file = std::fs::File::create(&path)
+            .with_context(|| format!("unable to create artifact file at {}", path.display()))?;
+        file.write_all(bytes)
+            .with_context(|| format!("unable to write artifact file at {}", path.display()))
+    }
+
     pub fn manifest(&self) -> &ArtifactManifest {
         &self.manifest
     }
 
-    pub fn into_manifest(self) -> ArtifactManifest {
-        self.manifest
+    /// Flushes any pending layers and returns the finished manifest.
+    pub fn into_manifest(mut self) -> Result<ArtifactManifest> {
+        self.flush()?;
+        Ok(self.manifest)
     }
 
     pub fn set_metadata(&mut self, key: impl Into<String>, value: impl Into<String>) {
         self.manifest.metadata.insert(key.into(), value.into());
     }
 
+    /// Records where the model being written came from.
+    pub fn set_source(&mut self, source: SourceProvenance) {
+        self.manifest.source = Some(source);
+    }
+
     pub fn config(&self) -> &ArtifactWriterConfig {
         &self.cfg
     }
@@ -97,9 +200,19 @@ impl ArtifactWriter {
     }
 }
 
+fn digests(bytes: &[u8]) -> (String, String) {
+    let sha = hex::encode(Sha256::digest(bytes));
+    let mut blake = Blake3::new();
+    blake.update(bytes);
+    (sha, blake.finalize().to_hex().to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::packed::decode_layer;
+    use ndarray::Array2;
+    use novaq_core::{QuantizationConfig, Quantizer};
     use tempfile::tempdir;
 
     #[test]
@@ -108,6 +221,7 @@ mod tests {
         let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
             chunk_bytes: 16,
             output_dir: dir.path().to_path_buf(),
+            ..ArtifactWriterConfig::default()
         });
         let info = writer.write_chunk(b"hello world").unwrap();
         assert_eq!(info.index, 0);
@@ -117,4 +231,78 @@ mod tests {
         let path = dir.path().join(&info.path);
         assert!(path.exists());
     }
+
+    #[test]
+    fn packs_small_layers_and_splits_large_ones() {
+        let quantizer = Quantizer::new(QuantizationConfig::default()).unwrap();
+        let layer = |name: &str, rows: usize| {
+            let weights =
+                Array2::from_shape_fn((rows, 8), |(r, c)| ((r * 5 + c) % 13) as f32 - 6.0);
+            quantizer.quantize_layer(name, 0, &weights).unwrap()
+        };
+        let layers = [
+            layer("small.a", 16),
+            layer("small.b", 16),
+            layer("large", 512),
+        ];
+        let sizes: Vec<usize> = layers
+            .iter()
+            .map(|l| encode_layer(l, &ArtifactWriterConfig::default().layer_encoding))
+            .map(|bytes| bytes.unwrap().len())
+            .collect();
+        let chunk_bytes = sizes[0] + sizes[1] + 8;
+        assert!(sizes[2] > 2 * chunk_bytes);
+
+        let dir = tempdir().unwrap();
+        let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+            chunk_bytes,
+            output_dir: dir.path().to_path_buf(),
+            ..ArtifactWriterConfig::default()
+        });
+        for layer in &layers {
+            writer.write_layer(layer).unwrap();
+        }
+        let manifest = writer.into_manifest().unwrap();
+
+        let (last, full) = manifest.chunks.split_last().unwrap();
+        assert!(full.iter().all(|chunk| chunk.bytes == chunk_bytes));
+        assert!(last.bytes <= chunk_bytes);
+        assert_eq!(manifest.layers["small.a"][0].chunk_index, 0);
+        assert_eq!(manifest.layers["small.b"][0].chunk_index, 0);
+        assert!(manifest.layers["large"].len() > 2);
+
+        let chunks: Vec<Vec<u8>> = manifest
+            .chunks
+            .iter()
+            .map(|chunk| std::fs::read(dir.path().join(&chunk.path)).unwrap())
+            .collect();
+        for layer in &layers {
+            let bytes: Vec<u8> = manifest.layers[&layer.name]
+                .iter()
+                .flat_map(|span| &chunks[span.chunk_index][span.offset..span.offset + span.len])
+                .copied()
+                .collect();
+            let decoded = decode_layer(&bytes, &manifest.layer_encoding).unwrap();
+            assert_eq!(decoded.dequantize(), layer.dequantize());
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_layer_names() {
+        let quantizer = Quantizer::new(QuantizationConfig::default()).unwrap();
+        let weights = Array2::from_shape_fn((16, 8), |(r, c)| ((r * 3 + c) % 7) as f32 - 3.0);
+        let layer = quantizer.quantize_layer("dup", 0, &weights).unwrap();
+
+        let dir = tempdir().unwrap();
+        let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+            output_dir: dir.path().to_path_buf(),
+            ..ArtifactWriterConfig::default()
+        });
+        let spans = writer.write_layer(&layer).unwrap().to_vec();
+        assert!(writer.write_layer(&layer).is_err());
+
+        let manifest = writer.into_manifest().unwrap();
+        assert_eq!(manifest.chunks.len(), 1);
+        assert_eq!(manifest.layers["dup"], spans);
+    }
 }
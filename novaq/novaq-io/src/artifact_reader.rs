@@ -0,0 +1,402 @@
+//! Loads quantized models back from an artifact directory written by
+//! [`ArtifactWriter`](crate::ArtifactWriter).
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use blake3::Hasher as Blake3;
+use novaq_core::{QuantizedLayer, QuantizedModel};
+use novaq_manifest::{ChunkEntry, ChunkSpan, LayerEncoding, LayerEntry, LayerFormat, Manifest};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::{debug, instrument};
+
+use crate::packed::decode_layer;
+
+/// File name of the manifest inside an artifact directory.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// A chunk whose bytes on disk do not match its manifest entry.
+#[derive(Debug, Error)]
+pub enum ChunkIntegrityError {
+    #[error("chunk {index} ({path}) is missing")]
+    Missing { index: usize, path: String },
+
+    #[error("chunk {index} ({path}) holds {found} bytes, manifest records {expected}")]
+    Length {
+        index: usize,
+        path: String,
+        expected: usize,
+        found: usize,
+    },
+
+    #[error("chunk {index} ({path}) failed {algorithm} verification: expected {expected}, found {found}")]
+    Digest {
+        index: usize,
+        path: String,
+        algorithm: &'static str,
+        expected: String,
+        found: String,
+    },
+}
+
+/// Opens an artifact directory and verifies every chunk it reads against the
+/// sha256 and blake3 digests in the manifest.
+#[derive(Debug, Clone)]
+pub struct ArtifactReader {
+    dir: PathBuf,
+    manifest: Manifest,
+}
+
+impl ArtifactReader {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let path = dir.join(MANIFEST_FILE);
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("unable to open manifest at {}", path.display()))?;
+        let manifest: Manifest = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("unable to parse manifest at {}", path.display()))?;
+        Ok(Self { dir, manifest })
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Layer names in the order they were written.
+    pub fn layer_names(&self) -> Vec<&str> {
+        let mut layers: Vec<(&String, &LayerEntry)> = self.manifest.layers.iter().collect();
+        layers.sort_by_key(|(_, entry)| entry.spans.first().map(|s| (s.chunk_index, s.offset)));
+        layers.into_iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// Reads and verifies every chunk, failing on the first corrupt one.
+    pub fn verify(&self) -> Result<()> {
+        for chunk in &self.manifest.chunks {
+            self.read_chunk(chunk.index)?;
+        }
+        Ok(())
+    }
+
+    /// Reads chunk `index` and checks its length and digests.
+    #[instrument(skip(self))]
+    pub fn read_chunk(&self, index: usize) -> Result<Vec<u8>> {
+        let entry = self.chunk_entry(index)?;
+        let path = self.artifact_path(&entry.path)?;
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(ChunkIntegrityError::Missing {
+                    index,
+                    path: entry.path.clone(),
+                }
+                .into());
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("unable to read chunk at {}", path.display()));
+            }
+        };
+        verify_chunk(entry, &bytes)?;
+        debug!(index, bytes = bytes.len(), "verified artifact chunk");
+        Ok(bytes)
+    }
+
+    /// Reads the side file recorded under `role`, checking it like a chunk.
+    pub fn read_side_file(&self, role: &str) -> Result<Vec<u8>> {
+        let entry = self
+            .manifest
+            .side_files
+            .get(role)
+            .ok_or_else(|| anyhow!("artifact has no {role} side file"))?;
+        let path = self.artifact_path(&entry.path)?;
+        let bytes = std::fs::read(&path)
+            .with_context(|| format!("unable to read {role} side file at {}", path.display()))?;
+        if bytes.len() != entry.bytes {
+            return Err(anyhow!(
+                "{role} side file {} holds {} bytes, manifest records {}",
+                entry.path,
+                bytes.len(),
+                entry.bytes
+            ));
+        }
+        if let Some((algorithm, expected, found)) =
+            digest_mismatch(&bytes, &entry.sha256, &entry.blake3)
+        {
+            return Err(anyhow!(
+                "{role} side file {} failed {algorithm} verification: expected {expected}, found {found}",
+                entry.path
+            ));
+        }
+        Ok(bytes)
+    }
+
+    /// Loads one layer, reading only the chunks its spans point into.
+    pub fn load_layer(&self, name: &str) -> Result<QuantizedLayer> {
+        let entry = self
+            .manifest
+            .layers
+            .get(name)
+            .ok_or_else(|| anyhow!("artifact has no layer named {name}"))?;
+        if entry.spans.is_empty() {
+            return self
+                .load_unindexed()?
+                .into_iter()
+                .find(|layer| layer.name == name)
+                .ok_or_else(|| anyhow!("no chunk holds layer {name}"));
+        }
+
+        let mut bytes = Vec::with_capacity(entry.spans.iter().map(|s| s.len).sum());
+        for span in &entry.spans {
+            let chunk = self.read_chunk(span.chunk_index)?;
+            bytes.extend_from_slice(span_bytes(&chunk, span)?);
+        }
+        decode_checked(&bytes, &self.manifest.layer_encoding)
+            .with_context(|| format!("decode layer {name}"))
+    }
+
+    /// Loads every layer in write order, reading each chunk once.
+    pub fn load_model(&self) -> Result<QuantizedModel> {
+        if self
+            .manifest
+            .layers
+            .values()
+            .all(|entry| entry.spans.is_empty())
+        {
+            return Ok(QuantizedModel::from_layers(self.load_unindexed()?));
+        }
+
+        let chunks = self
+            .manifest
+            .chunks
+            .iter()
+            .map(|chunk| self.read_chunk(chunk.index))
+            .collect::<Result<Vec<_>>>()?;
+        let mut layers = Vec::with_capacity(self.manifest.layers.len());
+        for name in self.layer_names() {
+            let entry = &self.manifest.layers[name];
+            let mut bytes = Vec::with_capacity(entry.spans.iter().map(|s| s.len).sum());
+            for span in &entry.spans {
+                let chunk = chunks.get(span.chunk_index).ok_or_else(|| {
+                    anyhow!("layer {name} points at missing chunk {}", span.chunk_index)
+                })?;
+                bytes.extend_from_slice(span_bytes(chunk, span)?);
+            }
+            let layer = decode_checked(&bytes, &self.manifest.layer_encoding)
+                .with_context(|| format!("decode layer {name}"))?;
+            layers.push(layer);
+        }
+        Ok(QuantizedModel::from_layers(layers))
+    }
+
+    /// Artifacts written before the span index hold one JSON layer per chunk.
+    fn load_unindexed(&self) -> Result<Vec<QuantizedLayer>> {
+        if self.manifest.layer_encoding.format != LayerFormat::Json {
+            return Err(anyhow!("manifest has no chunk spans for its packed layers"));
+        }
+        self.manifest
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let bytes = self.read_chunk(chunk.index)?;
+                decode_checked(&bytes, &self.manifest.layer_encoding)
+                    .with_context(|| format!("decode layer in chunk {}", chunk.index))
+            })
+            .collect()
+    }
+
+    fn chunk_entry(&self, index: usize) -> Result<&ChunkEntry> {
+        self.manifest
+            .chunks
+            .iter()
+            .find(|chunk| chunk.index == index)
+            .ok_or_else(|| anyhow!("manifest has no chunk {index}"))
+    }
+
+    fn artifact_path(&self, path: &str) -> Result<PathBuf> {
+        let relative = Path::new(path);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(anyhow!("path {path} escapes the artifact directory"));
+        }
+        Ok(self.dir.join(relative))
+    }
+}
+
+fn verify_chunk(entry: &ChunkEntry, bytes: &[u8]) -> Result<(), ChunkIntegrityError> {
+    if bytes.len() != entry.bytes {
+        return Err(ChunkIntegrityError::Length {
+            index: entry.index,
+            path: entry.path.clone(),
+            expected: entry.bytes,
+            found: bytes.len(),
+        });
+    }
+    match digest_mismatch(bytes, &entry.sha256, &entry.blake3) {
+        Some((algorithm, expected, found)) => Err(ChunkIntegrityError::Digest {
+            index: entry.index,
+            path: entry.path.clone(),
+            algorithm,
+            expected: expected.to_string(),
+            found,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// The first digest of `bytes` that differs from the recorded one.
+fn digest_mismatch<'a>(
+    bytes: &[u8],
+    sha256: &'a str,
+    blake3: &'a str,
+) -> Option<(&'static str, &'a str, String)> {
+    let sha = hex::encode(Sha256::digest(bytes));
+    let mut blake = Blake3::new();
+    blake.update(bytes);
+    let blake = blake.finalize().to_hex().to_string();
+    [("sha256", sha256, sha), ("blake3", blake3, blake)]
+        .into_iter()
+        .find(|(_, expected, found)| !expected.eq_ignore_ascii_case(found))
+}
+
+/// Decodes a layer and checks that it fits its own shape, so that callers can
+/// dequantize it without indexing out of bounds.
+fn decode_checked(bytes: &[u8], encoding: &LayerEncoding) -> Result<QuantizedLayer> {
+    let layer = decode_layer(bytes, encoding)?;
+    layer.validate()?;
+    Ok(layer)
+}
+
+fn span_bytes<'a>(chunk: &'a [u8], span: &ChunkSpan) -> Result<&'a [u8]> {
+    span.offset
+        .checked_add(span.len)
+        .and_then(|end| chunk.get(span.offset..end))
+        .ok_or_else(|| {
+            anyhow!(
+                "span {}..+{} exceeds chunk {}",
+                span.offset,
+                span.len,
+                span.chunk_index
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::artifact::{ArtifactWriter, ArtifactWriterConfig};
+    use crate::format::ModelLocator;
+    use crate::manifest::assemble_manifest;
+    use ndarray::Array2;
+    use novaq_core::{QuantizationConfig, Quantizer};
+    use tempfile::{tempdir, TempDir};
+
+    fn write_artifact(
+        chunk_bytes: usize,
+        tamper: impl Fn(&mut QuantizedLayer),
+    ) -> (TempDir, QuantizedModel) {
+        let config = QuantizationConfig::default();
+        let quantizer = Quantizer::new(config.clone()).unwrap();
+        let dir = tempdir().unwrap();
+        let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+            chunk_bytes,
+            output_dir: dir.path().to_path_buf(),
+            ..ArtifactWriterConfig::default()
+        });
+        let layers: Vec<QuantizedLayer> = [("z.first", 64), ("a.second", 16), ("m.third", 32)]
+            .into_iter()
+            .enumerate()
+            .map(|(index, (name, rows))| {
+                let weights = Array2::from_shape_fn((rows, 8), |(r, c)| {
+                    ((r * 3 + c * 7 + index) % 17) as f32 * 0.25 - 2.0
+                });
+                let mut layer = quantizer.quantize_layer(name, index, &weights).unwrap();
+                tamper(&mut layer);
+                writer.write_layer(&layer).unwrap();
+                layer
+            })
+            .collect();
+        writer.flush().unwrap();
+        let model = QuantizedModel::from_layers(layers);
+        let manifest = assemble_manifest(
+            &ModelLocator::new("synthetic.safetensors"),
+            "test",
+            &config,
+            &model,
+            writer.manifest(),
+        )
+        .unwrap();
+        let file = std::fs::File::create(dir.path().join(MANIFEST_FILE)).unwrap();
+        serde_json::to_writer(file, &manifest).unwrap();
+        (dir, model)
+    }
+
+    #[test]
+    fn loads_models_fully_and_by_layer() {
+        let (dir, model) = write_artifact(512, |_| {});
+        let reader = ArtifactReader::open(dir.path()).unwrap();
+        assert!(reader.manifest().chunks.len() > 1);
+        reader.verify().unwrap();
+        assert_eq!(reader.layer_names(), ["z.first", "a.second", "m.third"]);
+
+        let loaded = reader.load_model().unwrap();
+        for (original, loaded) in model.layers.iter().zip(&loaded.layers) {
+            assert_eq!(original.name, loaded.name);
+            assert_eq!(original.dequantize(), loaded.dequantize());
+        }
+        let layer = reader.load_layer("m.third").unwrap();
+        assert_eq!(layer.dequantize(), model.layers[2].dequantize());
+    }
+
+    #[test]
+    fn corrupt_chunks_are_named() {
+        let (dir, _) = write_artifact(1 << 20, |_| {});
+        let reader = ArtifactReader::open(dir.path()).unwrap();
+        let chunk = &reader.manifest().chunks[0];
+        let path = dir.path().join(&chunk.path);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[10] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        let err = reader.load_layer("a.second").unwrap_err();
+        match err.downcast_ref::<ChunkIntegrityError>() {
+            Some(ChunkIntegrityError::Digest {
+                index,
+                path,
+                algorithm,
+                ..
+            }) => {
+                assert_eq!(*index, 0);
+                assert_eq!(path, &chunk.path);
+                assert_eq!(*algorithm, "sha256");
+            }
+            other => panic!("unexpected error {other:?}"),
+        }
+        assert!(err.to_string().contains(&chunk.path));
+
+        std::fs::remove_file(&path).unwrap();
+        let err = reader.verify().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ChunkIntegrityError>(),
+            Some(ChunkIntegrityError::Missing { index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn layers_that_do_not_fit_their_shape_are_rejected() {
+        // Intact chunks holding a layer whose column order repeats a column.
+        let (dir, _) = write_artifact(1 << 20, |layer| {
+            if layer.name == "a.second" {
+                layer.column_permutation = Some(vec![0; layer.cols]);
+            }
+        });
+        let reader = ArtifactReader::open(dir.path()).unwrap();
+        reader.verify().unwrap();
+        reader.load_layer("m.third").unwrap();
+        let err = reader.load_layer("a.second").unwrap_err();
+        assert!(format!("{err:#}").contains("not a permutation"), "{err:#}");
+        assert!(reader.load_model().is_err());
+    }
+}
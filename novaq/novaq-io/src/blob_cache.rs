@@ -0,0 +1,180 @@
+//! Content-addressed cache for Hugging Face downloads.
+//!
+//! Files stored through Git LFS are kept under `blobs/sha256/<oid>`, named by
+//! the sha256 the Hub reports for them, so a blob is fetched once no matter
+//! how many repositories or revisions share it. Interrupted downloads stay
+//! next to it as `<oid>.partial` and resume from their length. Repository
+//! listings go to `repos/<repo_id>/<revision>.json` so offline runs can
+//! discover files without the Hub.
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::hf_api::ModelSpec;
+
+#[derive(Debug, Clone)]
+pub struct BlobCache {
+    root: PathBuf,
+}
+
+impl BlobCache {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Path a verified blob is stored at, whether or not it exists yet.
+    pub fn blob_path(&self, oid: &str) -> Result<PathBuf> {
+        ensure!(
+            oid.len() == 64 && oid.bytes().all(|b| b.is_ascii_hexdigit()),
+            "{oid:?} is not a sha256 oid"
+        );
+        Ok(self
+            .root
+            .join("blobs")
+            .join("sha256")
+            .join(oid.to_ascii_lowercase()))
+    }
+
+    pub fn contains(&self, oid: &str) -> bool {
+        self.blob_path(oid).is_ok_and(|path| path.is_file())
+    }
+
+    /// Path an in-progress download of `oid` is written to. Its parent
+    /// directory is created on the way.
+    pub(crate) fn partial_path(&self, oid: &str) -> Result<PathBuf> {
+        let path = self.blob_path(oid)?.with_extension("partial");
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("create cache directory {}", parent.display()))?;
+        }
+        Ok(path)
+    }
+
+    /// Checks a finished download against its oid and moves it into place.
+    /// A partial file that does not match is deleted, so the next attempt
+    /// starts over.
+    pub(crate) fn commit(&self, oid: &str) -> Result<PathBuf> {
+        let partial = self.partial_path(oid)?;
+        let actual = sha256_file(&partial)?;
+        if !actual.eq_ignore_ascii_case(oid) {
+            warn!(oid, actual, "discarding download with wrong sha256");
+            std::fs::remove_file(&partial)?;
+            return Err(anyhow!(
+                "downloaded blob has sha256 {actual}, expected {oid}"
+            ));
+        }
+        let blob = self.blob_path(oid)?;
+        std::fs::rename(&partial, &blob)
+            .with_context(|| format!("move {} into the cache", partial.display()))?;
+        debug!(oid, path = %blob.display(), "cached blob");
+        Ok(blob)
+    }
+
+    pub(crate) fn write_listing(&self, spec: &ModelSpec) -> Result<()> {
+        let path = self.listing_path(&spec.repo_id, &spec.revision)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let bytes = serde_json::to_vec_pretty(spec)?;
+        // Write then rename, so an interrupted run never leaves half a listing.
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    pub(crate) fn read_listing(&self, repo_id: &str, revision: &str) -> Result<ModelSpec> {
+        let path = self.listing_path(repo_id, revision)?;
+        let bytes = std::fs::read(&path).with_context(|| {
+            format!(
+                "{repo_id}@{revision} has not been cached in {}",
+                self.root.display()
+            )
+        })?;
+        serde_json::from_slice(&bytes).with_context(|| format!("parse {}", path.display()))
+    }
+
+    fn listing_path(&self, repo_id: &str, revision: &str) -> Result<PathBuf> {
+        let mut path = self.root.join("repos");
+        for part in repo_id.split('/') {
+            ensure!(
+                !part.is_empty() && part != "." && part != "..",
+                "invalid repository id {repo_id:?}"
+            );
+            path.push(part);
+        }
+        // Revisions such as `refs/pr/1` contain slashes of their own.
+        Ok(path.join(format!("{}.json", revision.replace('/', "--"))))
+    }
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut reader = BufReader::with_capacity(1 << 20, file);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hf_api::ModelFile;
+    use tempfile::tempdir;
+
+    #[test]
+    fn commits_only_matching_blobs() {
+        let dir = tempdir().unwrap();
+        let cache = BlobCache::new(dir.path());
+        let oid = hex::encode(Sha256::digest(b"weights"));
+
+        std::fs::write(cache.partial_path(&oid).unwrap(), b"weighs").unwrap();
+        assert!(cache.commit(&oid).is_err());
+        assert!(!cache.partial_path(&oid).unwrap().exists());
+
+        std::fs::write(cache.partial_path(&oid).unwrap(), b"weights").unwrap();
+        let blob = cache.commit(&oid).unwrap();
+        assert!(cache.contains(&oid));
+        assert_eq!(std::fs::read(blob).unwrap(), b"weights");
+        assert!(cache.blob_path("../../etc/passwd").is_err());
+    }
+
+    #[test]
+    fn round_trips_listings() {
+        let dir = tempdir().unwrap();
+        let cache = BlobCache::new(dir.path());
+        let spec = ModelSpec {
+            repo_id: "org/model".into(),
+            revision: "refs/pr/1".into(),
+            commit: None,
+            files: vec![ModelFile {
+                path: "model.safetensors".into(),
+                size: 7,
+                download_url: "https://example.invalid/model.safetensors".into(),
+                oid: Some("ab".repeat(32)),
+            }],
+            index: None,
+        };
+        cache.write_listing(&spec).unwrap();
+        let read = cache.read_listing("org/model", "refs/pr/1").unwrap();
+        assert_eq!(read.files[0].oid, spec.files[0].oid);
+        assert!(cache.read_listing("org/model", "main").is_err());
+        assert!(cache.read_listing("../model", "main").is_err());
+    }
+}
@@ -0,0 +1,325 @@
+//! Safetensors element types and their decoding to `f32`.
+
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use anyhow::{anyhow, ensure, Result};
+use half::{bf16, f16};
+use ndarray::Array2;
+
+/// Suffixes of the companion tensors holding a quantized weight's scales,
+/// e.g. `proj.weight_scale` for `proj.weight`. DeepSeek-V3 checkpoints name
+/// their block scales `weight_scale_inv`, but multiply by them all the same.
+const SCALE_SUFFIXES: [&str; 2] = ["_scale", "_scale_inv"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TensorDType {
+    F64,
+    F32,
+    F16,
+    BF16,
+    I8,
+    U8,
+    /// `float8_e4m3fn`: no infinities, a single NaN pattern per sign.
+    F8E4M3,
+    /// `float8_e5m2`: the upper byte of an IEEE half.
+    F8E5M2,
+}
+
+impl TensorDType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::F64 => "F64",
+            Self::F32 => "F32",
+            Self::F16 => "F16",
+            Self::BF16 => "BF16",
+            Self::I8 => "I8",
+            Self::U8 => "U8",
+            Self::F8E4M3 => "F8_E4M3",
+            Self::F8E5M2 => "F8_E5M2",
+        }
+    }
+
+    pub fn bytes_per_element(self) -> usize {
+        match self {
+            Self::F64 => 8,
+            Self::F32 => 4,
+            Self::F16 | Self::BF16 => 2,
+            Self::I8 | Self::U8 | Self::F8E4M3 | Self::F8E5M2 => 1,
+        }
+    }
+
+    /// Decodes little-endian elements into `out`, which must hold exactly
+    /// `bytes.len() / bytes_per_element()` values.
+    pub fn decode_into(self, bytes: &[u8], out: &mut [f32]) -> Result<()> {
+        let width = self.bytes_per_element();
+        ensure!(
+            bytes.len() == out.len() * width,
+            "{} bytes do not hold {} {} elements",
+            bytes.len(),
+            out.len(),
+            self.as_str()
+        );
+        let elements = bytes.chunks_exact(width).zip(out.iter_mut());
+        match self {
+            Self::F64 => elements
+                .for_each(|(b, o)| *o = f64::from_le_bytes(b.try_into().expect("8 bytes")) as f32),
+            Self::F32 => {
+                elements.for_each(|(b, o)| *o = f32::from_le_bytes(b.try_into().expect("4 bytes")))
+            }
+            Self::F16 => elements.for_each(|(b, o)| *o = f16::from_le_bytes([b[0], b[1]]).to_f32()),
+            Self::BF16 => {
+                elements.for_each(|(b, o)| *o = bf16::from_le_bytes([b[0], b[1]]).to_f32())
+            }
+            Self::I8 => elements.for_each(|(b, o)| *o = b[0] as i8 as f32),
+            Self::U8 => elements.for_each(|(b, o)| *o = b[0] as f32),
+            Self::F8E4M3 => elements.for_each(|(b, o)| *o = f8_e4m3_to_f32(b[0])),
+            Self::F8E5M2 => {
+                elements.for_each(|(b, o)| *o = f16::from_bits(u16::from(b[0]) << 8).to_f32())
+            }
+        }
+        Ok(())
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> Result<Vec<f32>> {
+        let mut values = vec![0.0; bytes.len() / self.bytes_per_element()];
+        self.decode_into(bytes, &mut values)?;
+        Ok(values)
+    }
+
+    pub fn decode_matrix(self, bytes: &[u8], rows: usize, cols: usize) -> Result<Array2<f32>> {
+        let len = rows
+            .checked_mul(cols)
+            .and_then(|n| n.checked_mul(self.bytes_per_element()))
+            .ok_or_else(|| anyhow!("tensor shape overflow"))?;
+        ensure!(
+            bytes.len() == len,
+            "tensor size mismatch: expected {len} bytes, got {}",
+            bytes.len()
+        );
+        let mut matrix = Array2::<f32>::zeros((rows, cols));
+        self.decode_into(
+            bytes,
+            matrix.as_slice_mut().expect("fresh arrays are contiguous"),
+        )?;
+        Ok(matrix)
+    }
+}
+
+impl FromStr for TensorDType {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "F64" => Ok(Self::F64),
+            "F32" => Ok(Self::F32),
+            "F16" => Ok(Self::F16),
+            "BF16" => Ok(Self::BF16),
+            "I8" => Ok(Self::I8),
+            "U8" => Ok(Self::U8),
+            "F8_E4M3" => Ok(Self::F8E4M3),
+            "F8_E5M2" => Ok(Self::F8E5M2),
+            other => Err(anyhow!("unsupported safetensors dtype: {other}")),
+        }
+    }
+}
+
+fn f8_e4m3_to_f32(byte: u8) -> f32 {
+    let sign = if byte & 0x80 != 0 { -1.0 } else { 1.0 };
+    let exponent = i32::from((byte >> 3) & 0x0F);
+    let mantissa = f32::from(byte & 0x07);
+    match exponent {
+        0x0F if mantissa == 7.0 => f32::NAN,
+        0 => sign * mantissa / 8.0 * 2f32.powi(-6),
+        _ => sign * (1.0 + mantissa / 8.0) * 2f32.powi(exponent - 7),
+    }
+}
+
+/// Pairs quantized weights with their `weight_scale` companions in whatever
+/// order the two arrive, handing back each weight once it is scaled.
+#[derive(Debug, Default)]
+pub(crate) struct ScalePairing {
+    /// Scale tensor name to the weight it scales.
+    scale_to_weight: HashMap<String, String>,
+    /// Weight name to its scale tensor.
+    weight_to_scale: HashMap<String, String>,
+    /// Weights waiting for their scale.
+    weights: HashMap<String, Array2<f32>>,
+    /// Scales waiting for their weight, with their shape.
+    scales: HashMap<String, (Vec<usize>, Vec<f32>)>,
+}
+
+impl ScalePairing {
+    pub(crate) fn new<'a>(names: impl IntoIterator<Item = &'a str>) -> Self {
+        let names: HashSet<&str> = names.into_iter().collect();
+        let mut pairing = Self::default();
+        for name in &names {
+            let weight = SCALE_SUFFIXES
+                .iter()
+                .find_map(|suffix| name.strip_suffix(suffix))
+                .filter(|weight| names.contains(weight));
+            if let Some(weight) = weight {
+                pairing
+                    .scale_to_weight
+                    .insert(name.to_string(), weight.to_string());
+                pairing
+                    .weight_to_scale
+                    .insert(weight.to_string(), name.to_string());
+            }
+        }
+        pairing
+    }
+
+    pub(crate) fn is_scale(&self, name: &str) -> bool {
+        self.scale_to_weight.contains_key(name)
+    }
+
+    /// Takes a decoded weight, returning it right away when it has no scale.
+    pub(crate) fn weight(
+        &mut self,
+        name: &str,
+        mut matrix: Array2<f32>,
+    ) -> Result<Option<Array2<f32>>> {
+        let Some(scale) = self.weight_to_scale.get(name) else {
+            return Ok(Some(matrix));
+        };
+        match self.scales.remove(scale) {
+            Some((shape, values)) => {
+                apply_scale(&mut matrix, &shape, &values)?;
+                Ok(Some(matrix))
+            }
+            None => {
+                self.weights.insert(name.to_string(), matrix);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Takes a decoded scale tensor, returning its weight with the scale
+    /// applied when the weight has already arrived.
+    pub(crate) fn scale(
+        &mut self,
+        name: &str,
+        shape: &[usize],
+        values: Vec<f32>,
+    ) -> Result<Option<(String, Array2<f32>)>> {
+        let weight = self
+            .scale_to_weight
+            .get(name)
+            .ok_or_else(|| anyhow!("{name} is not a scale tensor"))?
+            .clone();
+        match self.weights.remove(&weight) {
+            Some(mut matrix) => {
+                apply_scale(&mut matrix, shape, &values)?;
+                Ok(Some((weight, matrix)))
+            }
+            None => {
+                self.scales
+                    .insert(name.to_string(), (shape.to_vec(), values));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Fails if a weight never received its scale.
+    pub(crate) fn finish(&self) -> Result<()> {
+        match self.weights.keys().next() {
+            Some(weight) => Err(anyhow!("{weight} has a scale tensor that was never read")),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Multiplies a weight by its scales: one per tensor, one per row, or one per
+/// rectangular block as in DeepSeek-V3's 128x128 FP8 blocks.
+fn apply_scale(matrix: &mut Array2<f32>, shape: &[usize], values: &[f32]) -> Result<()> {
+    let (rows, cols) = matrix.dim();
+    let (scale_rows, scale_cols) = match *shape {
+        [] | [1] => (1, 1),
+        [n] if n == rows => (rows, 1),
+        [r, c] => (r, c),
+        _ => {
+            return Err(anyhow!(
+                "scale of shape {shape:?} does not fit a {rows}x{cols} weight"
+            ))
+        }
+    };
+    ensure!(
+        scale_rows > 0 && scale_cols > 0 && values.len() == scale_rows * scale_cols,
+        "scale of shape {shape:?} holds {} values",
+        values.len()
+    );
+    let block_rows = rows.div_ceil(scale_rows).max(1);
+    let block_cols = cols.div_ceil(scale_cols).max(1);
+    ensure!(
+        rows.div_ceil(block_rows) == scale_rows && cols.div_ceil(block_cols) == scale_cols,
+        "scale of shape {shape:?} does not tile a {rows}x{cols} weight"
+    );
+    for ((row, col), value) in matrix.indexed_iter_mut() {
+        *value *= values[(row / block_rows) * scale_cols + col / block_cols];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_fp8_formats() {
+        let e4m3 = TensorDType::F8E4M3
+            .decode(&[0x38, 0xB8, 0x7E, 0x01, 0x00, 0x7F])
+            .unwrap();
+        assert_eq!(&e4m3[..5], &[1.0, -1.0, 448.0, 2f32.powi(-9), 0.0]);
+        assert!(e4m3[5].is_nan());
+
+        let e5m2 = TensorDType::F8E5M2
+            .decode(&[0x3C, 0xC0, 0x7B, 0x7C])
+            .unwrap();
+        assert_eq!(e5m2, [1.0, -2.0, 57344.0, f32::INFINITY]);
+
+        let ints = TensorDType::I8.decode(&[0xFF, 0x7F]).unwrap();
+        assert_eq!(ints, [-1.0, 127.0]);
+        let wide = TensorDType::F64.decode(&0.5f64.to_le_bytes()).unwrap();
+        assert_eq!(wide, [0.5]);
+    }
+
+    #[test]
+    fn pairs_weights_with_scales_in_either_order() {
+        let names = [
+            "a.weight",
+            "a.weight_scale",
+            "b.weight",
+            "b.weight_scale_inv",
+            "c.weight",
+        ];
+        let mut pairing = ScalePairing::new(names);
+        assert!(pairing.is_scale("a.weight_scale"));
+        assert!(!pairing.is_scale("c.weight"));
+
+        let ones = Array2::ones((4, 4));
+        assert!(pairing.weight("a.weight", ones.clone()).unwrap().is_none());
+        let (name, scaled) = pairing
+            .scale("a.weight_scale", &[], vec![0.5])
+            .unwrap()
+            .unwrap();
+        assert_eq!(name, "a.weight");
+        assert!(scaled.iter().all(|&v| v == 0.5));
+
+        // 2x2 blocks of a 4x4 weight.
+        assert!(pairing
+            .scale("b.weight_scale_inv", &[2, 2], vec![1.0, 2.0, 3.0, 4.0])
+            .unwrap()
+            .is_none());
+        let scaled = pairing.weight("b.weight", ones.clone()).unwrap().unwrap();
+        assert_eq!(scaled[(0, 1)], 1.0);
+        assert_eq!(scaled[(1, 2)], 2.0);
+        assert_eq!(scaled[(3, 3)], 4.0);
+
+        assert_eq!(
+            pairing.weight("c.weight", ones.clone()).unwrap(),
+            Some(ones)
+        );
+        pairing.finish().unwrap();
+    }
+}
@@ -2,6 +2,8 @@ use std::{borrow::Cow, path::Path};
 
 use url::Url;
 
+use crate::safetensors_index::SAFETENSORS_INDEX_SUFFIX;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ModelFormat {
     SafeTensors,
@@ -18,16 +20,18 @@ impl ModelFormat {
         let lowered = path.to_ascii_lowercase();
         if lowered.contains("hf://") || lowered.contains("huggingface.co") {
             Self::HuggingFaceSnapshot
-        } else if lowered.ends_with(".safetensors") {
+        } else if lowered.ends_with(".safetensors") || lowered.ends_with(SAFETENSORS_INDEX_SUFFIX) {
             Self::SafeTensors
         } else if lowered.ends_with(".gguf") {
             Self::Gguf
         } else if lowered.ends_with(".onnx") {
             Self::Onnx
-        } else if lowered.ends_with(".bin") || lowered.ends_with(".pt") {
+        } else if lowered.ends_with(".bin") || lowered.ends_with(".pt") || lowered.ends_with(".pth")
+        {
             Self::PyTorchStateDict
         } else if lowered.ends_with(".tar")
             || lowered.ends_with(".tar.gz")
+            || lowered.ends_with(".tgz")
             || lowered.ends_with(".zip")
         {
             Self::Archive
@@ -89,6 +93,10 @@ mod tests {
             ModelFormat::detect("weights.safetensors"),
             ModelFormat::SafeTensors
         );
+        assert_eq!(
+            ModelFormat::detect("checkpoint/model.safetensors.index.json"),
+            ModelFormat::SafeTensors
+        );
         assert_eq!(ModelFormat::detect("model.gguf"), ModelFormat::Gguf);
         assert_eq!(
             ModelFormat::detect("snapshot.bin"),
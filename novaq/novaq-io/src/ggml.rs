@@ -0,0 +1,727 @@
+//! Dequantization of GGML tensor types as stored in GGUF files.
+//!
+//! Quantized types pack a fixed number of consecutive elements of a row into
+//! each block; rows therefore have to be a whole number of blocks long. The
+//! block layouts follow `ggml-quants.c`, with every multi-byte field little
+//! endian.
+
+use anyhow::{anyhow, ensure, Result};
+use half::{bf16, f16};
+use ndarray::Array2;
+
+/// Elements per block of the K-quant types.
+const QK_K: usize = 256;
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GgmlType {
+    F32,
+    F16,
+    BF16,
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q8_0,
+    Q2_K,
+    Q3_K,
+    Q4_K,
+    Q5_K,
+    Q6_K,
+}
+
+impl GgmlType {
+    pub fn from_id(id: u32) -> Option<Self> {
+        Some(match id {
+            0 => Self::F32,
+            1 => Self::F16,
+            2 => Self::Q4_0,
+            3 => Self::Q4_1,
+            6 => Self::Q5_0,
+            7 => Self::Q5_1,
+            8 => Self::Q8_0,
+            10 => Self::Q2_K,
+            11 => Self::Q3_K,
+            12 => Self::Q4_K,
+            13 => Self::Q5_K,
+            14 => Self::Q6_K,
+            30 => Self::BF16,
+            _ => return None,
+        })
+    }
+
+    pub fn id(self) -> u32 {
+        match self {
+            Self::F32 => 0,
+            Self::F16 => 1,
+            Self::Q4_0 => 2,
+            Self::Q4_1 => 3,
+            Self::Q5_0 => 6,
+            Self::Q5_1 => 7,
+            Self::Q8_0 => 8,
+            Self::Q2_K => 10,
+            Self::Q3_K => 11,
+            Self::Q4_K => 12,
+            Self::Q5_K => 13,
+            Self::Q6_K => 14,
+            Self::BF16 => 30,
+        }
+    }
+
+    /// Elements encoded by one block.
+    pub fn block_size(self) -> usize {
+        match self {
+            Self::F32 | Self::F16 | Self::BF16 => 1,
+            Self::Q4_0 | Self::Q4_1 | Self::Q5_0 | Self::Q5_1 | Self::Q8_0 => 32,
+            Self::Q2_K | Self::Q3_K | Self::Q4_K | Self::Q5_K | Self::Q6_K => QK_K,
+        }
+    }
+
+    /// Bytes occupied by one block.
+    pub fn type_size(self) -> usize {
+        match self {
+            Self::F32 => 4,
+            Self::F16 | Self::BF16 => 2,
+            Self::Q4_0 => 18,
+            Self::Q4_1 => 20,
+            Self::Q5_0 => 22,
+            Self::Q5_1 => 24,
+            Self::Q8_0 => 34,
+            Self::Q2_K => 84,
+            Self::Q3_K => 110,
+            Self::Q4_K => 144,
+            Self::Q5_K => 176,
+            Self::Q6_K => 210,
+        }
+    }
+
+    /// Bytes holding one row of `cols` elements.
+    pub fn row_bytes(self, cols: usize) -> Result<usize> {
+        ensure!(
+            cols.is_multiple_of(self.block_size()),
+            "{self:?} rows must be a multiple of {} elements, found {cols}",
+            self.block_size()
+        );
+        Ok(cols / self.block_size() * self.type_size())
+    }
+
+    pub fn tensor_bytes(self, rows: usize, cols: usize) -> Result<usize> {
+        self.row_bytes(cols)?
+            .checked_mul(rows)
+            .ok_or_else(|| anyhow!("tensor of {rows}x{cols} {self:?} overflows"))
+    }
+}
+
+/// `(rows, cols)` of a GGUF matrix. GGUF lists `ne[0]`, the contiguous
+/// dimension, first, so a `[cols, rows]` entry is a row-major `rows x cols`
+/// matrix.
+pub fn matrix_shape(dims: &[usize]) -> Option<(usize, usize)> {
+    match dims {
+        [cols, rows] => Some((*rows, *cols)),
+        _ => None,
+    }
+}
+
+/// Decodes one row of `out.len()` elements from `bytes`.
+pub fn dequantize_row(ty: GgmlType, bytes: &[u8], out: &mut [f32]) -> Result<()> {
+    let expected = ty.row_bytes(out.len())?;
+    ensure!(
+        bytes.len() == expected,
+        "{ty:?} row of {} elements needs {expected} bytes, found {}",
+        out.len(),
+        bytes.len()
+    );
+    let blocks = bytes
+        .chunks_exact(ty.type_size())
+        .zip(out.chunks_exact_mut(ty.block_size()));
+    match ty {
+        GgmlType::F32 => blocks.for_each(|(b, y)| y[0] = f32::from_le_bytes(le(b))),
+        GgmlType::F16 => blocks.for_each(|(b, y)| y[0] = f16::from_le_bytes(le(b)).to_f32()),
+        GgmlType::BF16 => blocks.for_each(|(b, y)| y[0] = bf16::from_le_bytes(le(b)).to_f32()),
+        GgmlType::Q4_0 => blocks.for_each(|(b, y)| dequantize_q4_0(b, y)),
+        GgmlType::Q4_1 => blocks.for_each(|(b, y)| dequantize_q4_1(b, y)),
+        GgmlType::Q5_0 => blocks.for_each(|(b, y)| dequantize_q5_0(b, y)),
+        GgmlType::Q5_1 => blocks.for_each(|(b, y)| dequantize_q5_1(b, y)),
+        GgmlType::Q8_0 => blocks.for_each(|(b, y)| dequantize_q8_0(b, y)),
+        GgmlType::Q2_K => blocks.for_each(|(b, y)| dequantize_q2_k(b, y)),
+        GgmlType::Q3_K => blocks.for_each(|(b, y)| dequantize_q3_k(b, y)),
+        GgmlType::Q4_K => blocks.for_each(|(b, y)| dequantize_q4_k(b, y)),
+        GgmlType::Q5_K => blocks.for_each(|(b, y)| dequantize_q5_k(b, y)),
+        GgmlType::Q6_K => blocks.for_each(|(b, y)| dequantize_q6_k(b, y)),
+    }
+    Ok(())
+}
+
+/// Decodes a full row-major `rows x cols` tensor.
+pub fn dequantize_matrix(
+    ty: GgmlType,
+    bytes: &[u8],
+    rows: usize,
+    cols: usize,
+) -> Result<Array2<f32>> {
+    let expected = ty.tensor_bytes(rows, cols)?;
+    ensure!(
+        bytes.len() == expected,
+        "{ty:?} tensor of {rows}x{cols} needs {expected} bytes, found {}",
+        bytes.len()
+    );
+    let mut matrix = Array2::<f32>::zeros((rows, cols));
+    if cols == 0 {
+        return Ok(matrix);
+    }
+    let row_bytes = ty.row_bytes(cols)?;
+    for (row, mut out) in bytes.chunks_exact(row_bytes).zip(matrix.outer_iter_mut()) {
+        dequantize_row(ty, row, out.as_slice_mut().expect("standard layout"))?;
+    }
+    Ok(matrix)
+}
+
+fn le<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    bytes[..N].try_into().expect("block length checked")
+}
+
+fn half(bytes: &[u8]) -> f32 {
+    f16::from_le_bytes(le(bytes)).to_f32()
+}
+
+fn dequantize_q4_0(block: &[u8], y: &mut [f32]) {
+    let d = half(&block[0..]);
+    let qs = &block[2..18];
+    for (j, &q) in qs.iter().enumerate() {
+        y[j] = ((q & 0x0f) as i32 - 8) as f32 * d;
+        y[j + 16] = ((q >> 4) as i32 - 8) as f32 * d;
+    }
+}
+
+fn dequantize_q4_1(block: &[u8], y: &mut [f32]) {
+    let d = half(&block[0..]);
+    let m = half(&block[2..]);
+    let qs = &block[4..20];
+    for (j, &q) in qs.iter().enumerate() {
+        y[j] = (q & 0x0f) as f32 * d + m;
+        y[j + 16] = (q >> 4) as f32 * d + m;
+    }
+}
+
+fn dequantize_q5_0(block: &[u8], y: &mut [f32]) {
+    let d = half(&block[0..]);
+    let qh = u32::from_le_bytes(le(&block[2..]));
+    let qs = &block[6..22];
+    for (j, &q) in qs.iter().enumerate() {
+        let high0 = ((qh >> j) << 4) & 0x10;
+        let high1 = (qh >> (j + 12)) & 0x10;
+        y[j] = (((q & 0x0f) as u32 | high0) as i32 - 16) as f32 * d;
+        y[j + 16] = (((q >> 4) as u32 | high1) as i32 - 16) as f32 * d;
+    }
+}
+
+fn dequantize_q5_1(block: &[u8], y: &mut [f32]) {
+    let d = half(&block[0..]);
+    let m = half(&block[2..]);
+    let qh = u32::from_le_bytes(le(&block[4..]));
+    let qs = &block[8..24];
+    for (j, &q) in qs.iter().enumerate() {
+        let high0 = ((qh >> j) << 4) & 0x10;
+        let high1 = (qh >> (j + 12)) & 0x10;
+        y[j] = ((q & 0x0f) as u32 | high0) as f32 * d + m;
+        y[j + 16] = ((q >> 4) as u32 | high1) as f32 * d + m;
+    }
+}
+
+fn dequantize_q8_0(block: &[u8], y: &mut [f32]) {
+    let d = half(&block[0..]);
+    for (out, &q) in y.iter_mut().zip(&block[2..34]) {
+        *out = q as i8 as f32 * d;
+    }
+}
+
+fn dequantize_q2_k(block: &[u8], y: &mut [f32]) {
+    let scales = &block[0..16];
+    let qs = &block[16..80];
+    let d = half(&block[80..]);
+    let dmin = half(&block[82..]);
+    let mut is = 0;
+    let mut out = 0;
+    for q in qs.chunks_exact(32) {
+        for shift in (0..8).step_by(2) {
+            for half_block in q.chunks_exact(16) {
+                let sc = scales[is];
+                is += 1;
+                let dl = d * (sc & 0x0f) as f32;
+                let ml = dmin * (sc >> 4) as f32;
+                for &byte in half_block {
+                    y[out] = dl * ((byte >> shift) & 3) as f32 - ml;
+                    out += 1;
+                }
+            }
+        }
+    }
+}
+
+fn dequantize_q3_k(block: &[u8], y: &mut [f32]) {
+    const KMASK1: u32 = 0x0303_0303;
+    const KMASK2: u32 = 0x0f0f_0f0f;
+    let hmask = &block[0..32];
+    let qs = &block[32..96];
+    let packed = &block[96..108];
+    let d = half(&block[108..]);
+
+    let mut aux = [
+        u32::from_le_bytes(le(&packed[0..])),
+        u32::from_le_bytes(le(&packed[4..])),
+        u32::from_le_bytes(le(&packed[8..])),
+        0,
+    ];
+    let tmp = aux[2];
+    aux[2] = ((aux[0] >> 4) & KMASK2) | (((tmp >> 4) & KMASK1) << 4);
+    aux[3] = ((aux[1] >> 4) & KMASK2) | (((tmp >> 6) & KMASK1) << 4);
+    aux[0] = (aux[0] & KMASK2) | ((tmp & KMASK1) << 4);
+    aux[1] = (aux[1] & KMASK2) | (((tmp >> 2) & KMASK1) << 4);
+    let scales: Vec<i8> = aux
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .map(|byte| byte as i8)
+        .collect();
+
+    let mut is = 0;
+    let mut out = 0;
+    let mut m = 1u8;
+    for q in qs.chunks_exact(32) {
+        for shift in (0..8).step_by(2) {
+            for (half_q, half_h) in q.chunks_exact(16).zip(hmask.chunks_exact(16)) {
+                let dl = d * (scales[is] as i32 - 32) as f32;
+                is += 1;
+                for (&byte, &high) in half_q.iter().zip(half_h) {
+                    let low = ((byte >> shift) & 3) as i32;
+                    let value = low - if high & m != 0 { 0 } else { 4 };
+                    y[out] = dl * value as f32;
+                    out += 1;
+                }
+            }
+            m <<= 1;
+        }
+    }
+}
+
+/// Unpacks the 6-bit scale and min of sub-block `j` from the 12-byte K-quant
+/// scale array.
+fn scale_min_k4(j: usize, q: &[u8]) -> (u8, u8) {
+    if j < 4 {
+        (q[j] & 63, q[j + 4] & 63)
+    } else {
+        (
+            (q[j + 4] & 0x0f) | ((q[j - 4] >> 6) << 4),
+            (q[j + 4] >> 4) | ((q[j] >> 6) << 4),
+        )
+    }
+}
+
+fn dequantize_q4_k(block: &[u8], y: &mut [f32]) {
+    let d = half(&block[0..]);
+    let dmin = half(&block[2..]);
+    let scales = &block[4..16];
+    let qs = &block[16..144];
+    for (chunk, (q, out)) in qs.chunks_exact(32).zip(y.chunks_exact_mut(64)).enumerate() {
+        let (sc1, m1) = scale_min_k4(2 * chunk, scales);
+        let (sc2, m2) = scale_min_k4(2 * chunk + 1, scales);
+        let (d1, min1) = (d * sc1 as f32, dmin * m1 as f32);
+        let (d2, min2) = (d * sc2 as f32, dmin * m2 as f32);
+        for (l, &byte) in q.iter().enumerate() {
+            out[l] = d1 * (byte & 0x0f) as f32 - min1;
+            out[l + 32] = d2 * (byte >> 4) as f32 - min2;
+        }
+    }
+}
+
+fn dequantize_q5_k(block: &[u8], y: &mut [f32]) {
+    let d = half(&block[0..]);
+    let dmin = half(&block[2..]);
+    let scales = &block[4..16];
+    let qh = &block[16..48];
+    let qs = &block[48..176];
+    for (chunk, (ql, out)) in qs.chunks_exact(32).zip(y.chunks_exact_mut(64)).enumerate() {
+        let (sc1, m1) = scale_min_k4(2 * chunk, scales);
+        let (sc2, m2) = scale_min_k4(2 * chunk + 1, scales);
+        let (d1, min1) = (d * sc1 as f32, dmin * m1 as f32);
+        let (d2, min2) = (d * sc2 as f32, dmin * m2 as f32);
+        let u1 = 1u8 << (2 * chunk);
+        let u2 = 2u8 << (2 * chunk);
+        for (l, (&low, &high)) in ql.iter().zip(qh).enumerate() {
+            let hi1 = if high & u1 != 0 { 16 } else { 0 };
+            let hi2 = if high & u2 != 0 { 16 } else { 0 };
+            out[l] = d1 * ((low & 0x0f) + hi1) as f32 - min1;
+            out[l + 32] = d2 * ((low >> 4) + hi2) as f32 - min2;
+        }
+    }
+}
+
+fn dequantize_q6_k(block: &[u8], y: &mut [f32]) {
+    let ql = &block[0..128];
+    let qh = &block[128..192];
+    let scales = &block[192..208];
+    let d = half(&block[208..]);
+    for (n, out) in y.chunks_exact_mut(128).enumerate() {
+        let ql = &ql[64 * n..64 * (n + 1)];
+        let qh = &qh[32 * n..32 * (n + 1)];
+        let sc = &scales[8 * n..8 * (n + 1)];
+        for l in 0..32 {
+            let is = l / 16;
+            let q1 = ((ql[l] & 0x0f) | ((qh[l] & 3) << 4)) as i32 - 32;
+            let q2 = ((ql[l + 32] & 0x0f) | (((qh[l] >> 2) & 3) << 4)) as i32 - 32;
+            let q3 = ((ql[l] >> 4) | (((qh[l] >> 4) & 3) << 4)) as i32 - 32;
+            let q4 = ((ql[l + 32] >> 4) | (((qh[l] >> 6) & 3) << 4)) as i32 - 32;
+            out[l] = d * (sc[is] as i8) as f32 * q1 as f32;
+            out[l + 32] = d * (sc[is + 2] as i8) as f32 * q2 as f32;
+            out[l + 64] = d * (sc[is + 4] as i8) as f32 * q3 as f32;
+            out[l + 96] = d * (sc[is + 6] as i8) as f32 * q4 as f32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packed blocks together with the values their codes stand for.
+    struct Encoded {
+        bytes: Vec<u8>,
+        expected: Vec<f32>,
+    }
+
+    fn sample(len: usize, seed: u64) -> Vec<f32> {
+        let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..len)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let unit = (state >> 40) as f32 / (1u64 << 24) as f32;
+                // Drift the mean between blocks so the min/offset fields matter.
+                (unit * 2.0 - 1.0) * 1.5 + ((i / 64) % 3) as f32 * 0.4
+            })
+            .collect()
+    }
+
+    fn f16_round(value: f32) -> f32 {
+        f16::from_f32(value).to_f32()
+    }
+
+    fn quantize(x: f32, scale: f32, lo: i32, hi: i32) -> i32 {
+        if scale == 0.0 {
+            return lo.max(0).min(hi);
+        }
+        ((x / scale).round() as i32).clamp(lo, hi)
+    }
+
+    fn check(ty: GgmlType, encode: fn(&[f32]) -> Encoded, tolerance: f32) {
+        let rows = 3;
+        let cols = 2 * ty.block_size().max(32);
+        let values = sample(rows * cols, ty.id() as u64 + 1);
+        let mut bytes = Vec::new();
+        let mut expected = Vec::new();
+        for block in values.chunks_exact(ty.block_size()) {
+            let encoded = encode(block);
+            assert_eq!(encoded.bytes.len(), ty.type_size());
+            bytes.extend(encoded.bytes);
+            expected.extend(encoded.expected);
+        }
+
+        let matrix = dequantize_matrix(ty, &bytes, rows, cols).unwrap();
+        let amax = values.iter().fold(0.0f32, |acc, v| acc.max(v.abs()));
+        for ((decoded, expected), original) in matrix.iter().zip(&expected).zip(&values) {
+            assert!(
+                (decoded - expected).abs() <= 1e-5 * expected.abs().max(1.0),
+                "{ty:?}: decoded {decoded}, codes give {expected}"
+            );
+            assert!(
+                (decoded - original).abs() <= tolerance * amax,
+                "{ty:?}: {original} came back as {decoded}"
+            );
+        }
+    }
+
+    fn encode_q4_0(x: &[f32]) -> Encoded {
+        let amax = x.iter().fold(0.0f32, |acc, v| acc.max(v.abs()));
+        let d = f16_round(amax / 7.0);
+        let q: Vec<i32> = x.iter().map(|&v| quantize(v, d, -8, 7) + 8).collect();
+        let mut bytes = f16::from_f32(d).to_le_bytes().to_vec();
+        bytes.extend((0..16).map(|j| (q[j] | (q[j + 16] << 4)) as u8));
+        let expected = q.iter().map(|&q| (q - 8) as f32 * d).collect();
+        Encoded { bytes, expected }
+    }
+
+    fn encode_q4_1(x: &[f32]) -> Encoded {
+        let min = f16_round(x.iter().copied().fold(f32::MAX, f32::min));
+        let max = x.iter().copied().fold(f32::MIN, f32::max);
+        let d = f16_round((max - min) / 15.0);
+        let q: Vec<i32> = x.iter().map(|&v| quantize(v - min, d, 0, 15)).collect();
+        let mut bytes = f16::from_f32(d).to_le_bytes().to_vec();
+        bytes.extend(f16::from_f32(min).to_le_bytes());
+        bytes.extend((0..16).map(|j| (q[j] | (q[j + 16] << 4)) as u8));
+        let expected = q.iter().map(|&q| q as f32 * d + min).collect();
+        Encoded { bytes, expected }
+    }
+
+    fn pack_q5(q: &[i32]) -> (u32, Vec<u8>) {
+        let mut qh = 0u32;
+        for (j, &value) in q.iter().enumerate() {
+            qh |= ((value as u32 >> 4) & 1) << j;
+        }
+        let qs = (0..16)
+            .map(|j| ((q[j] & 0x0f) | ((q[j + 16] & 0x0f) << 4)) as u8)
+            .collect();
+        (qh, qs)
+    }
+
+    fn encode_q5_0(x: &[f32]) -> Encoded {
+        let amax = x.iter().fold(0.0f32, |acc, v| acc.max(v.abs()));
+        let d = f16_round(amax / 15.0);
+        let q: Vec<i32> = x.iter().map(|&v| quantize(v, d, -16, 15) + 16).collect();
+        let (qh, qs) = pack_q5(&q);
+        let mut bytes = f16::from_f32(d).to_le_bytes().to_vec();
+        bytes.extend(qh.to_le_bytes());
+        bytes.extend(qs);
+        let expected = q.iter().map(|&q| (q - 16) as f32 * d).collect();
+        Encoded { bytes, expected }
+    }
+
+    fn encode_q5_1(x: &[f32]) -> Encoded {
+        let min = f16_round(x.iter().copied().fold(f32::MAX, f32::min));
+        let max = x.iter().copied().fold(f32::MIN, f32::max);
+        let d = f16_round((max - min) / 31.0);
+        let q: Vec<i32> = x.iter().map(|&v| quantize(v - min, d, 0, 31)).collect();
+        let (qh, qs) = pack_q5(&q);
+        let mut bytes = f16::from_f32(d).to_le_bytes().to_vec();
+        bytes.extend(f16::from_f32(min).to_le_bytes());
+        bytes.extend(qh.to_le_bytes());
+        bytes.extend(qs);
+        let expected = q.iter().map(|&q| q as f32 * d + min).collect();
+        Encoded { bytes, expected }
+    }
+
+    fn encode_q8_0(x: &[f32]) -> Encoded {
+        let amax = x.iter().fold(0.0f32, |acc, v| acc.max(v.abs()));
+        let d = f16_round(amax / 127.0);
+        let q: Vec<i32> = x.iter().map(|&v| quantize(v, d, -127, 127)).collect();
+        let mut bytes = f16::from_f32(d).to_le_bytes().to_vec();
+        bytes.extend(q.iter().map(|&q| q as i8 as u8));
+        let expected = q.iter().map(|&q| q as f32 * d).collect();
+        Encoded { bytes, expected }
+    }
+
+    /// Per-sub-block `(scale, offset)` with `x ~ scale * q - offset` for
+    /// `q` in `0..=levels`, then the super-block `(d, dmin)` for `bits`-wide
+    /// sub-block codes.
+    fn k_scales(x: &[f32], sub: usize, levels: f32, bits: u32) -> (f32, f32, Vec<(i32, i32)>) {
+        let raw: Vec<(f32, f32)> = x
+            .chunks_exact(sub)
+            .map(|block| {
+                let min = block.iter().copied().fold(f32::MAX, f32::min);
+                let max = block.iter().copied().fold(f32::MIN, f32::max);
+                let offset = (-min).max(0.0);
+                ((max + offset) / levels, offset)
+            })
+            .collect();
+        let top = ((1 << bits) - 1) as f32;
+        let d = f16_round(raw.iter().fold(0.0f32, |acc, r| acc.max(r.0)) / top);
+        let dmin = f16_round(raw.iter().fold(0.0f32, |acc, r| acc.max(r.1)) / top);
+        let max_code = (1 << bits) - 1;
+        let codes = raw
+            .iter()
+            .map(|&(scale, offset)| {
+                (
+                    quantize(scale, d, 0, max_code),
+                    quantize(offset, dmin, 0, max_code),
+                )
+            })
+            .collect();
+        (d, dmin, codes)
+    }
+
+    fn encode_q2_k(x: &[f32]) -> Encoded {
+        let (d, dmin, codes) = k_scales(x, 16, 3.0, 4);
+        let mut scales = [0u8; 16];
+        let mut qs = [0u8; 64];
+        let mut expected = vec![0.0; QK_K];
+        for (p, &v) in x.iter().enumerate() {
+            let sub = p / 16;
+            let (sc, m) = codes[sub];
+            scales[sub] = (sc | (m << 4)) as u8;
+            let dl = d * sc as f32;
+            let ml = dmin * m as f32;
+            let q = quantize(v + ml, dl, 0, 3);
+            let (n, shift, h, l) = (sub / 8, (sub % 8) / 2, sub % 2, p % 16);
+            qs[n * 32 + h * 16 + l] |= (q as u8) << (2 * shift);
+            expected[p] = dl * q as f32 - ml;
+        }
+        let mut bytes = scales.to_vec();
+        bytes.extend(qs);
+        bytes.extend(f16::from_f32(d).to_le_bytes());
+        bytes.extend(f16::from_f32(dmin).to_le_bytes());
+        Encoded { bytes, expected }
+    }
+
+    fn encode_q3_k(x: &[f32]) -> Encoded {
+        let subs: Vec<f32> = x
+            .chunks_exact(16)
+            .map(|b| b.iter().fold(0.0f32, |acc, v| acc.max(v.abs())) / 3.0)
+            .collect();
+        let d = f16_round(subs.iter().fold(0.0f32, |acc, &s| acc.max(s)) / 31.0);
+        let codes: Vec<i32> = subs.iter().map(|&s| quantize(s, d, -32, 31)).collect();
+
+        let mut packed = [0u8; 12];
+        for (j, &code) in codes.iter().enumerate() {
+            let l = (code + 32) as u8;
+            if j < 8 {
+                packed[j] = l & 0x0f;
+            } else {
+                packed[j - 8] |= (l & 0x0f) << 4;
+            }
+            packed[j % 4 + 8] |= (l >> 4) << (2 * (j / 4));
+        }
+
+        let mut hmask = [0u8; 32];
+        let mut qs = [0u8; 64];
+        let mut expected = vec![0.0; QK_K];
+        for (p, &v) in x.iter().enumerate() {
+            let sub = p / 16;
+            let dl = d * codes[sub] as f32;
+            let q = quantize(v, dl, -4, 3);
+            let stored = (q + 4) as u8;
+            let (n, shift, h, l) = (sub / 8, (sub % 8) / 2, sub % 2, p % 16);
+            qs[n * 32 + h * 16 + l] |= (stored & 3) << (2 * shift);
+            hmask[h * 16 + l] |= (stored >> 2) << (n * 4 + shift);
+            expected[p] = dl * q as f32;
+        }
+        let mut bytes = hmask.to_vec();
+        bytes.extend(qs);
+        bytes.extend(packed);
+        bytes.extend(f16::from_f32(d).to_le_bytes());
+        Encoded { bytes, expected }
+    }
+
+    fn pack_k4_scales(codes: &[(i32, i32)]) -> [u8; 12] {
+        let mut scales = [0u8; 12];
+        for (j, &(ls, lm)) in codes.iter().enumerate() {
+            let (ls, lm) = (ls as u8, lm as u8);
+            if j < 4 {
+                scales[j] = ls;
+                scales[j + 4] = lm;
+            } else {
+                scales[j + 4] = (ls & 0x0f) | ((lm & 0x0f) << 4);
+                scales[j - 4] |= (ls >> 4) << 6;
+                scales[j] |= (lm >> 4) << 6;
+            }
+        }
+        scales
+    }
+
+    fn encode_q45_k(x: &[f32], high_bit: bool) -> Encoded {
+        let levels = if high_bit { 31 } else { 15 };
+        let (d, dmin, codes) = k_scales(x, 32, levels as f32, 6);
+        let mut qh = [0u8; 32];
+        let mut qs = [0u8; 128];
+        let mut expected = vec![0.0; QK_K];
+        for (p, &v) in x.iter().enumerate() {
+            let sub = p / 32;
+            let (sc, m) = codes[sub];
+            let dl = d * sc as f32;
+            let ml = dmin * m as f32;
+            let q = quantize(v + ml, dl, 0, levels);
+            let (chunk, h, l) = (sub / 2, sub % 2, p % 32);
+            qs[chunk * 32 + l] |= ((q & 0x0f) as u8) << (4 * h);
+            qh[l] |= ((q >> 4) as u8) << sub;
+            expected[p] = dl * q as f32 - ml;
+        }
+        let mut bytes = f16::from_f32(d).to_le_bytes().to_vec();
+        bytes.extend(f16::from_f32(dmin).to_le_bytes());
+        bytes.extend(pack_k4_scales(&codes));
+        if high_bit {
+            bytes.extend(qh);
+        }
+        bytes.extend(qs);
+        Encoded { bytes, expected }
+    }
+
+    fn encode_q6_k(x: &[f32]) -> Encoded {
+        let subs: Vec<f32> = x
+            .chunks_exact(16)
+            .map(|b| b.iter().fold(0.0f32, |acc, v| acc.max(v.abs())) / 31.0)
+            .collect();
+        let d = f16_round(subs.iter().fold(0.0f32, |acc, &s| acc.max(s)) / 127.0);
+        let codes: Vec<i32> = subs.iter().map(|&s| quantize(s, d, -128, 127)).collect();
+
+        let mut ql = [0u8; 128];
+        let mut qh = [0u8; 64];
+        let mut expected = vec![0.0; QK_K];
+        for (p, &v) in x.iter().enumerate() {
+            let dl = d * codes[p / 16] as f32;
+            let q = quantize(v, dl, -32, 31);
+            let stored = (q + 32) as u8;
+            let (n, quarter, l) = (p / 128, (p % 128) / 32, p % 32);
+            let low = n * 64 + l + if quarter % 2 == 1 { 32 } else { 0 };
+            ql[low] |= (stored & 0x0f) << if quarter >= 2 { 4 } else { 0 };
+            qh[n * 32 + l] |= (stored >> 4) << (2 * quarter);
+            expected[p] = dl * q as f32;
+        }
+        let mut bytes = ql.to_vec();
+        bytes.extend(qh);
+        bytes.extend(codes.iter().map(|&c| c as i8 as u8));
+        bytes.extend(f16::from_f32(d).to_le_bytes());
+        Encoded { bytes, expected }
+    }
+
+    #[test]
+    fn float_types_round_trip() {
+        check(
+            GgmlType::F32,
+            |x| Encoded {
+                bytes: x[0].to_le_bytes().to_vec(),
+                expected: x.to_vec(),
+            },
+            0.0,
+        );
+        check(
+            GgmlType::F16,
+            |x| Encoded {
+                bytes: f16::from_f32(x[0]).to_le_bytes().to_vec(),
+                expected: vec![f16_round(x[0])],
+            },
+            1e-3,
+        );
+        check(
+            GgmlType::BF16,
+            |x| Encoded {
+                bytes: bf16::from_f32(x[0]).to_le_bytes().to_vec(),
+                expected: vec![bf16::from_f32(x[0]).to_f32()],
+            },
+            1e-2,
+        );
+    }
+
+    #[test]
+    fn legacy_block_types_round_trip() {
+        check(GgmlType::Q4_0, encode_q4_0, 0.1);
+        check(GgmlType::Q4_1, encode_q4_1, 0.1);
+        check(GgmlType::Q5_0, encode_q5_0, 0.05);
+        check(GgmlType::Q5_1, encode_q5_1, 0.05);
+        check(GgmlType::Q8_0, encode_q8_0, 0.01);
+    }
+
+    #[test]
+    fn k_quant_types_round_trip() {
+        check(GgmlType::Q2_K, encode_q2_k, 0.4);
+        check(GgmlType::Q3_K, encode_q3_k, 0.25);
+        check(GgmlType::Q4_K, |x| encode_q45_k(x, false), 0.1);
+        check(GgmlType::Q5_K, |x| encode_q45_k(x, true), 0.05);
+        check(GgmlType::Q6_K, encode_q6_k, 0.03);
+    }
+
+    #[test]
+    fn rows_must_be_whole_blocks() {
+        assert!(GgmlType::Q4_K.row_bytes(300).is_err());
+        assert_eq!(GgmlType::Q4_K.row_bytes(512).unwrap(), 288);
+        assert_eq!(GgmlType::from_id(12), Some(GgmlType::Q4_K));
+        assert_eq!(GgmlType::from_id(9), None);
+    }
+}
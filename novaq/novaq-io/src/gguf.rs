@@ -1,19 +1,19 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use ndarray::Array2;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, SeekFrom};
 use tracing::{debug, instrument};
 
 use novaq_core::{QuantizationConfig, QuantizedModel, Quantizer};
 
-const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+use crate::ggml::{dequantize_row, matrix_shape, GgmlType};
+use crate::gguf_export::{codebook_tensor_len, decode_codebook_matrix, NOVAQ_GGUF_TYPE_ID};
+use crate::gguf_metadata::{
+    align_offset, read_counts, read_kv, read_tensor_descriptor, GgufCursor, GgufMetadata,
+    TensorDescriptor, GGUF_MAGIC,
+};
 
-#[derive(Debug)]
-struct TensorDescriptor {
-    name: String,
-    dims: Vec<usize>,
-    offset: u64,
-    type_id: u32,
-}
+/// Smallest read issued while buffering the header.
+const HEADER_READ_BYTES: usize = 64 * 1024;
 
 pub struct GgufLoader {
     quantizer: Quantizer,
@@ -26,15 +26,32 @@ impl GgufLoader {
         })
     }
 
-    #[instrument(skip(self, reader, writer))]
+    /// Quantizes every matrix in the file and records its metadata in
+    /// `writer`; see [`GgufMetadata::record`].
     pub async fn load_from_reader<R>(
         &self,
-        mut reader: R,
+        reader: R,
         writer: &mut crate::artifact::ArtifactWriter,
     ) -> Result<QuantizedModel>
     where
-        R: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin + Send,
+        R: AsyncRead + AsyncSeek + Unpin + Send,
+    {
+        let (model, _) = self.load_with_metadata(reader, writer).await?;
+        Ok(model)
+    }
+
+    /// Like [`load_from_reader`](Self::load_from_reader), also returning the
+    /// parsed header metadata.
+    #[instrument(skip(self, reader, writer))]
+    pub async fn load_with_metadata<R>(
+        &self,
+        mut reader: R,
+        writer: &mut crate::artifact::ArtifactWriter,
+    ) -> Result<(QuantizedModel, GgufMetadata)>
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send,
     {
+        let origin = reader.stream_position().await?;
         let mut magic = [0u8; 4];
         reader.read_exact(&mut magic).await?;
         if &magic != GGUF_MAGIC {
@@ -45,163 +62,173 @@ impl GgufLoader {
             return Err(anyhow!("unsupported GGUF version {version}"));
         }
 
-        let tensor_count = reader.read_u64_le().await?;
-        let kv_count = reader.read_u64_le().await?;
+        let mut header = HeaderBuffer::new(&mut reader, version);
+        let (tensor_count, kv_count) = header.next(|cursor| Ok(read_counts(cursor))).await?;
 
+        let mut metadata = GgufMetadata::new(version);
         for _ in 0..kv_count {
-            skip_kv(&mut reader).await?;
+            let (key, value) = header.next(read_kv).await?;
+            metadata.insert(key, value);
         }
 
-        let mut descriptors = Vec::with_capacity(tensor_count as usize);
+        let mut descriptors = Vec::with_capacity(tensor_count.min(1 << 16) as usize);
         for _ in 0..tensor_count {
-            let name = read_string(&mut reader).await?;
-            let n_dims = reader.read_u32_le().await? as usize;
-            let mut dims = Vec::with_capacity(n_dims);
-            for _ in 0..n_dims {
-                dims.push(reader.read_u64_le().await? as usize);
-            }
-            let type_id = reader.read_u32_le().await?;
-            let offset = reader.read_u64_le().await?;
-            descriptors.push(TensorDescriptor {
-                name,
-                dims,
-                offset,
-                type_id,
-            });
+            descriptors.push(header.next(read_tensor_descriptor).await?);
         }
 
-        let data_start = reader.seek(SeekFrom::Current(0)).await?;
-        let mut layers = Vec::with_capacity(descriptors.len());
+        let header_len = 8 + header.consumed;
+        let data_start = origin + align_offset(header_len, metadata.alignment()?);
+        debug!(
+            version,
+            kv_count,
+            tensor_count,
+            data_start,
+            architecture = metadata.architecture(),
+            "read GGUF header"
+        );
+        metadata.record(writer)?;
+        let file_end = reader.seek(SeekFrom::End(0)).await?;
 
-        for (index, desc) in descriptors.into_iter().enumerate() {
-            if desc.dims.len() != 2 {
+        let mut layers = Vec::with_capacity(descriptors.len());
+        for desc in descriptors {
+            let Some((rows, cols)) = matrix_shape(&desc.dims) else {
                 debug!(tensor = %desc.name, dims = ?desc.dims, "skipping non-matrix tensor");
                 continue;
-            }
-            if desc.type_id != 0 {
-                return Err(anyhow!(
-                    "unsupported GGUF tensor type {} for {}",
-                    desc.type_id,
-                    desc.name
-                ));
-            }
-
-            let matrix = read_tensor_matrix(&mut reader, &desc, data_start).await?;
-            let quantized = self.quantizer.quantize_layer(&desc.name, index, &matrix)?;
+            };
+            let matrix = if desc.type_id == NOVAQ_GGUF_TYPE_ID {
+                read_codebook_matrix(&mut reader, &desc, (rows, cols), data_start, file_end)
+                    .await
+                    .with_context(|| format!("read codebook tensor {}", desc.name))?
+            } else {
+                let ty = GgmlType::from_id(desc.type_id).ok_or_else(|| {
+                    anyhow!(
+                        "unsupported GGUF tensor type {} for {}",
+                        desc.type_id,
+                        desc.name
+                    )
+                })?;
+                read_tensor_matrix(&mut reader, &desc, ty, (rows, cols), data_start, file_end)
+                    .await
+                    .with_context(|| format!("read {ty:?} tensor {}", desc.name))?
+            };
+            // Index across everything written so far, matching the streaming
+            // parser so skipped tensors and archive members number alike.
+            let layer_idx = writer.manifest().layers.len();
+            let quantized = self
+                .quantizer
+                .quantize_layer(&desc.name, layer_idx, &matrix)?;
             debug!(tensor = %desc.name, subspaces = quantized.subspaces.len(), "tensor quantized");
-            let serialized = serde_json::to_vec(&quantized).context("serialize quantized layer")?;
-            writer.write_chunk(&serialized)?;
+            writer.write_layer(&quantized)?;
             layers.push(quantized);
         }
 
-        Ok(QuantizedModel::from_layers(layers))
+        writer.flush()?;
+
+        Ok((QuantizedModel::from_layers(layers), metadata))
+    }
+}
+
+/// Buffers header bytes from the reader as the record parsers ask for them.
+struct HeaderBuffer<'r, R> {
+    reader: &'r mut R,
+    version: u32,
+    data: Vec<u8>,
+    pos: usize,
+    /// Header bytes parsed so far, after the magic and version.
+    consumed: u64,
+}
+
+impl<'r, R: AsyncRead + Unpin> HeaderBuffer<'r, R> {
+    fn new(reader: &'r mut R, version: u32) -> Self {
+        Self {
+            reader,
+            version,
+            data: Vec::new(),
+            pos: 0,
+            consumed: 0,
+        }
+    }
+
+    /// Runs `parse` on the buffered bytes, reading more until the record it
+    /// wants has fully arrived.
+    async fn next<T>(
+        &mut self,
+        mut parse: impl FnMut(&mut GgufCursor<'_>) -> Result<Option<T>>,
+    ) -> Result<T> {
+        loop {
+            let mut cursor = GgufCursor::new(&self.data[self.pos..], self.version);
+            if let Some(record) = parse(&mut cursor)? {
+                self.pos += cursor.position();
+                self.consumed += cursor.position() as u64;
+                return Ok(record);
+            }
+
+            self.data.drain(..self.pos);
+            self.pos = 0;
+            // Doubling the read keeps re-parsing a large tokenizer array linear.
+            let want = self.data.len().max(HEADER_READ_BYTES) as u64;
+            let read = (&mut *self.reader)
+                .take(want)
+                .read_to_end(&mut self.data)
+                .await?;
+            ensure!(read > 0, "GGUF header is truncated");
+        }
     }
 }
 
 async fn read_tensor_matrix<R>(
     reader: &mut R,
     desc: &TensorDescriptor,
+    ty: GgmlType,
+    (rows, cols): (usize, usize),
     data_start: u64,
+    file_end: u64,
 ) -> Result<Array2<f32>>
 where
-    R: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin,
+    R: AsyncRead + AsyncSeek + Unpin,
 {
-    let rows = desc.dims[0];
-    let cols = desc.dims[1];
-    let _expected_bytes = rows
-        .checked_mul(cols)
-        .ok_or_else(|| anyhow!("tensor shape overflow"))?
-        * std::mem::size_of::<f32>();
-    reader
-        .seek(SeekFrom::Start(data_start + desc.offset))
-        .await?;
+    // The header's dims are untrusted: the data they describe must actually
+    // be in the file before the matrix is allocated.
+    let start = data_start + desc.offset;
+    ensure_within_file(start, ty.tensor_bytes(rows, cols)?, file_end)?;
+    reader.seek(SeekFrom::Start(start)).await?;
     let mut matrix = Array2::<f32>::zeros((rows, cols));
-    let mut row_bytes = vec![0u8; cols * std::mem::size_of::<f32>()];
-    for row in 0..rows {
+    let mut row_bytes = vec![0u8; ty.row_bytes(cols)?];
+    for mut row in matrix.outer_iter_mut() {
         reader.read_exact(&mut row_bytes).await?;
-        for (col, chunk) in row_bytes.chunks_exact(4).enumerate() {
-            let value = f32::from_le_bytes(chunk.try_into().unwrap());
-            matrix[(row, col)] = value;
-        }
+        dequantize_row(ty, &row_bytes, row.as_slice_mut().expect("standard layout"))?;
     }
-    debug!(tensor_offset = desc.offset, "loaded gguf tensor data");
+    debug!(tensor_offset = desc.offset, ?ty, "loaded gguf tensor data");
     Ok(matrix)
 }
 
-async fn skip_kv<R>(reader: &mut R) -> Result<()>
-where
-    R: tokio::io::AsyncRead + Unpin,
-{
-    let key = read_string(reader).await?;
-    let value_type = reader.read_u32_le().await?;
-    skip_value(reader, value_type)
-        .await
-        .with_context(|| format!("failed to skip kv value for key {key}"))
-}
-
-async fn skip_value<R>(reader: &mut R, value_type: u32) -> Result<()>
-where
-    R: tokio::io::AsyncRead + Unpin,
-{
-    let mut stack = vec![value_type];
-    while let Some(vt) = stack.pop() {
-        match vt {
-            0 => {
-                reader.read_u8().await?;
-            }
-            1 => {
-                reader.read_i8().await?;
-            }
-            2 => {
-                reader.read_u16_le().await?;
-            }
-            3 => {
-                reader.read_i16_le().await?;
-            }
-            4 => {
-                reader.read_u32_le().await?;
-            }
-            5 => {
-                reader.read_i32_le().await?;
-            }
-            6 => {
-                reader.read_f32_le().await?;
-            }
-            7 => {
-                reader.read_u8().await?;
-            }
-            8 => {
-                let _ = read_string(reader).await?;
-            }
-            9 => {
-                let elem_type = reader.read_u32_le().await?;
-                let len = reader.read_u64_le().await?;
-                for _ in 0..len {
-                    stack.push(elem_type);
-                }
-            }
-            10 => {
-                reader.read_u64_le().await?;
-            }
-            11 => {
-                reader.read_i64_le().await?;
-            }
-            12 => {
-                reader.read_f64_le().await?;
-            }
-            _ => return Err(anyhow!("unsupported gguf value type {vt}")),
-        }
-    }
+/// Fails unless the `len` bytes at `start` end at or before `file_end`.
+fn ensure_within_file(start: u64, len: usize, file_end: u64) -> Result<()> {
+    let end = start.checked_add(len as u64);
+    ensure!(
+        end.is_some_and(|end| end <= file_end),
+        "tensor data of {len} bytes at offset {start} runs past the end of the file ({file_end} bytes)"
+    );
     Ok(())
 }
 
-async fn read_string<R>(reader: &mut R) -> Result<String>
+/// Reconstructs a tensor written by [`GgufExportMode::Codebook`](crate::GgufExportMode).
+async fn read_codebook_matrix<R>(
+    reader: &mut R,
+    desc: &TensorDescriptor,
+    shape: (usize, usize),
+    data_start: u64,
+    file_end: u64,
+) -> Result<Array2<f32>>
 where
-    R: tokio::io::AsyncRead + Unpin,
+    R: AsyncRead + AsyncSeek + Unpin,
 {
-    let len = reader.read_u32_le().await? as usize;
-    let mut buf = vec![0u8; len];
-    reader.read_exact(&mut buf).await?;
-    let s = String::from_utf8(buf)?;
-    Ok(s)
+    let start = data_start + desc.offset;
+    ensure_within_file(start, 8, file_end)?;
+    reader.seek(SeekFrom::Start(start)).await?;
+    let mut prefix = [0u8; 8];
+    reader.read_exact(&mut prefix).await?;
+    let mut packed = vec![0u8; codebook_tensor_len(prefix, file_end - (start + 8))?];
+    reader.read_exact(&mut packed).await?;
+    decode_codebook_matrix(&packed, shape)
 }
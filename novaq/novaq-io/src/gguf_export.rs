@@ -0,0 +1,346 @@
+//! Writes quantized models back out as GGUF v3 files.
+//!
+//! [`GgufExportMode::F16`] stores every quantized layer dequantized to
+//! standard F16 tensors that any GGUF reader can parse. Only the 2-D layers
+//! NOVAQ quantized are written, though: norms, biases and other tensors the
+//! loaders skip are not in the artifact, so runtimes such as llama.cpp cannot
+//! run the file as a model without them. [`GgufExportMode::Codebook`] keeps
+//! the NOVAQ codebooks and bit-packed assignments instead, as tensors of the
+//! custom type [`NOVAQ_GGUF_TYPE_ID`]; only NOVAQ's own loaders read those.
+
+use std::io::Write;
+
+use anyhow::{ensure, Context, Result};
+use half::f16;
+use ndarray::Array2;
+use novaq_core::{QuantizedLayer, QuantizedModel};
+use novaq_manifest::{CentroidPrecision, LayerEncoding, LayerFormat};
+use tracing::{debug, instrument};
+
+use crate::ggml::GgmlType;
+use crate::gguf_metadata::{align_offset, GgufMetadata, GgufValue, GGUF_MAGIC};
+use crate::packed::{decode_layer, encode_layer, PACKED_LAYER_VERSION};
+
+/// Tensor type id of codebook tensors, chosen well clear of the GGML ids.
+/// The tensor data is a little-endian `u64` byte length followed by one
+/// layer in the packed NOVAQ container.
+pub const NOVAQ_GGUF_TYPE_ID: u32 = 0x4E51_0001;
+
+const GGUF_VERSION: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GgufExportMode {
+    /// Dequantized weights as F16 tensors.
+    F16,
+    /// Codebooks and packed assignments as [`NOVAQ_GGUF_TYPE_ID`] tensors.
+    Codebook,
+}
+
+impl GgufExportMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::F16 => "f16",
+            Self::Codebook => "codebook",
+        }
+    }
+}
+
+pub struct GgufExporter {
+    mode: GgufExportMode,
+    metadata: GgufMetadata,
+    centroid_precision: CentroidPrecision,
+}
+
+impl GgufExporter {
+    pub fn new(mode: GgufExportMode) -> Self {
+        Self {
+            mode,
+            metadata: GgufMetadata::new(GGUF_VERSION),
+            centroid_precision: CentroidPrecision::F32,
+        }
+    }
+
+    /// Metadata to carry over, usually what the source GGUF was parsed with.
+    pub fn with_metadata(mut self, metadata: GgufMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Codebook precision of [`GgufExportMode::Codebook`] tensors.
+    pub fn with_centroid_precision(mut self, precision: CentroidPrecision) -> Self {
+        self.centroid_precision = precision;
+        self
+    }
+
+    #[instrument(skip(self, model, out), fields(mode = self.mode.as_str()))]
+    pub fn write<W: Write>(&self, model: &QuantizedModel, mut out: W) -> Result<()> {
+        let alignment = self.metadata.alignment()?;
+        let mut metadata = self.metadata.clone();
+        metadata.version = GGUF_VERSION;
+        metadata.insert(
+            "novaq.export_mode",
+            GgufValue::String(self.mode.as_str().into()),
+        );
+
+        let tensors = model
+            .layers
+            .iter()
+            .map(|layer| self.tensor_data(layer))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut header = Vec::new();
+        header.extend_from_slice(GGUF_MAGIC);
+        header.extend_from_slice(&GGUF_VERSION.to_le_bytes());
+        header.extend_from_slice(&(tensors.len() as u64).to_le_bytes());
+        header.extend_from_slice(&(metadata.len() as u64).to_le_bytes());
+        for (key, value) in &metadata.entries {
+            write_string(&mut header, key);
+            header.extend_from_slice(&value.value_type().id().to_le_bytes());
+            write_value(&mut header, value);
+        }
+
+        let mut offset = 0u64;
+        for (layer, (type_id, data)) in model.layers.iter().zip(&tensors) {
+            write_string(&mut header, &layer.name);
+            header.extend_from_slice(&2u32.to_le_bytes());
+            header.extend_from_slice(&(layer.cols as u64).to_le_bytes());
+            header.extend_from_slice(&(layer.rows as u64).to_le_bytes());
+            header.extend_from_slice(&type_id.to_le_bytes());
+            header.extend_from_slice(&offset.to_le_bytes());
+            offset = align_offset(offset + data.len() as u64, alignment);
+        }
+        header.resize(align_offset(header.len() as u64, alignment) as usize, 0);
+        out.write_all(&header).context("write GGUF header")?;
+
+        let mut padding = Vec::new();
+        for (layer, (_, data)) in model.layers.iter().zip(&tensors) {
+            out.write_all(data)
+                .with_context(|| format!("write tensor {}", layer.name))?;
+            let end = align_offset(data.len() as u64, alignment) as usize;
+            padding.resize(end - data.len(), 0);
+            out.write_all(&padding)?;
+        }
+        debug!(
+            tensors = tensors.len(),
+            bytes = header.len() as u64 + offset,
+            "wrote GGUF"
+        );
+        Ok(())
+    }
+
+    fn tensor_data(&self, layer: &QuantizedLayer) -> Result<(u32, Vec<u8>)> {
+        match self.mode {
+            GgufExportMode::F16 => {
+                let weights = layer.dequantize();
+                let mut data = Vec::with_capacity(weights.len() * 2);
+                for value in weights.iter() {
+                    data.extend_from_slice(&f16::from_f32(*value).to_le_bytes());
+                }
+                Ok((GgmlType::F16.id(), data))
+            }
+            GgufExportMode::Codebook => {
+                let packed = encode_layer(layer, &self.codebook_encoding())
+                    .with_context(|| format!("encode layer {}", layer.name))?;
+                let mut data = Vec::with_capacity(packed.len() + 8);
+                data.extend_from_slice(&(packed.len() as u64).to_le_bytes());
+                data.extend_from_slice(&packed);
+                Ok((NOVAQ_GGUF_TYPE_ID, data))
+            }
+        }
+    }
+
+    fn codebook_encoding(&self) -> LayerEncoding {
+        LayerEncoding {
+            format: LayerFormat::Packed,
+            version: PACKED_LAYER_VERSION,
+            centroid_precision: self.centroid_precision,
+        }
+    }
+}
+
+/// Decodes the packed layer of a [`NOVAQ_GGUF_TYPE_ID`] tensor, without its
+/// length prefix.
+pub(crate) fn decode_codebook_tensor(packed: &[u8]) -> Result<QuantizedLayer> {
+    // The container header records the centroid precision it was written at.
+    decode_layer(
+        packed,
+        &LayerEncoding {
+            format: LayerFormat::Packed,
+            ..LayerEncoding::default()
+        },
+    )
+}
+
+/// Reconstructs the weights of a codebook tensor whose tensor info gave
+/// shape `(rows, cols)`.
+pub(crate) fn decode_codebook_matrix(
+    packed: &[u8],
+    (rows, cols): (usize, usize),
+) -> Result<Array2<f32>> {
+    let layer = decode_codebook_tensor(packed)?;
+    ensure!(
+        (layer.rows, layer.cols) == (rows, cols),
+        "codebook layer is {}x{}, tensor info says {rows}x{cols}",
+        layer.rows,
+        layer.cols
+    );
+    Ok(layer.dequantize())
+}
+
+/// Reads the byte length that prefixes a [`NOVAQ_GGUF_TYPE_ID`] tensor,
+/// rejecting lengths beyond the `available` bytes that follow the prefix.
+pub(crate) fn codebook_tensor_len(prefix: [u8; 8], available: u64) -> Result<usize> {
+    let len = u64::from_le_bytes(prefix);
+    ensure!(
+        len <= available && len <= isize::MAX as u64,
+        "codebook tensor of {len} bytes, but only {available} bytes follow it"
+    );
+    Ok(len as usize)
+}
+
+fn write_string(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn write_value(out: &mut Vec<u8>, value: &GgufValue) {
+    match value {
+        GgufValue::U8(v) => out.push(*v),
+        GgufValue::I8(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::U16(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::I16(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::U32(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::I32(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::F32(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::Bool(v) => out.push(u8::from(*v)),
+        GgufValue::String(v) => write_string(out, v),
+        GgufValue::Array(element_type, values) => {
+            out.extend_from_slice(&element_type.id().to_le_bytes());
+            out.extend_from_slice(&(values.len() as u64).to_le_bytes());
+            for value in values {
+                debug_assert_eq!(value.value_type(), *element_type);
+                write_value(out, value);
+            }
+        }
+        GgufValue::U64(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::I64(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::F64(v) => out.extend_from_slice(&v.to_le_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::artifact::{ArtifactWriter, ArtifactWriterConfig};
+    use crate::gguf::GgufLoader;
+    use crate::gguf_metadata::{
+        read_counts, read_kv, read_tensor_descriptor, GgufCursor, GgufValueType,
+    };
+    use crate::tests::quantized_model;
+    use novaq_core::QuantizationConfig;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    fn model() -> QuantizedModel {
+        quantized_model(
+            &[
+                ("blk.0.attn_q.weight", 16, 32),
+                ("blk.0.ffn_up.weight", 8, 64),
+            ],
+            |index, r, c| ((r * 7 + c * 3 + index) % 23) as f32 * 0.125 - 1.5,
+        )
+    }
+
+    fn source_metadata() -> GgufMetadata {
+        let mut metadata = GgufMetadata::new(3);
+        metadata.insert("general.architecture", GgufValue::String("llama".into()));
+        metadata.insert("llama.context_length", GgufValue::U32(4096));
+        metadata.insert(
+            "tokenizer.ggml.tokens",
+            GgufValue::Array(
+                GgufValueType::String,
+                vec![
+                    GgufValue::String("<s>".into()),
+                    GgufValue::String("a".into()),
+                ],
+            ),
+        );
+        metadata.insert(
+            "tokenizer.ggml.merges",
+            GgufValue::Array(GgufValueType::String, Vec::new()),
+        );
+        metadata
+    }
+
+    async fn reload(bytes: Vec<u8>) -> (QuantizedModel, GgufMetadata) {
+        let dir = tempdir().unwrap();
+        let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+            output_dir: dir.path().to_path_buf(),
+            ..ArtifactWriterConfig::default()
+        });
+        let loader = GgufLoader::new(QuantizationConfig::default()).unwrap();
+        loader
+            .load_with_metadata(Cursor::new(bytes), &mut writer)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn f16_export_reloads_with_metadata() {
+        let model = model();
+        let mut bytes = Vec::new();
+        GgufExporter::new(GgufExportMode::F16)
+            .with_metadata(source_metadata())
+            .write(&model, &mut bytes)
+            .unwrap();
+
+        let (reloaded, metadata) = reload(bytes).await;
+        for (key, value) in &source_metadata().entries {
+            assert_eq!(metadata.get(key), Some(value), "{key}");
+        }
+        assert_eq!(
+            metadata
+                .get("novaq.export_mode")
+                .and_then(GgufValue::as_str),
+            Some("f16")
+        );
+        assert_eq!(reloaded.layers.len(), model.layers.len());
+        for (original, reloaded) in model.layers.iter().zip(&reloaded.layers) {
+            assert_eq!(original.name, reloaded.name);
+            assert_eq!(
+                (original.rows, original.cols),
+                (reloaded.rows, reloaded.cols)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn codebook_export_keeps_layers_exact() {
+        let model = model();
+        let mut bytes = Vec::new();
+        GgufExporter::new(GgufExportMode::Codebook)
+            .write(&model, &mut bytes)
+            .unwrap();
+        assert_eq!(bytes.len() % 32, 0);
+
+        let (reloaded, _) = reload(bytes.clone()).await;
+        assert_eq!(reloaded.layers.len(), model.layers.len());
+
+        let mut cursor = GgufCursor::new(&bytes[8..], 3);
+        assert_eq!(read_counts(&mut cursor), Some((2, 1)));
+        read_kv(&mut cursor).unwrap().unwrap();
+        let descriptors: Vec<_> = (0..2)
+            .map(|_| read_tensor_descriptor(&mut cursor).unwrap().unwrap())
+            .collect();
+        let data_start = align_offset(8 + cursor.position() as u64, 32);
+        for (original, desc) in model.layers.iter().zip(&descriptors) {
+            assert_eq!(desc.type_id, NOVAQ_GGUF_TYPE_ID);
+            assert_eq!(desc.dims, [original.cols, original.rows]);
+            let start = (data_start + desc.offset) as usize;
+            let prefix = bytes[start..start + 8].try_into().unwrap();
+            let len = codebook_tensor_len(prefix, (bytes.len() - start - 8) as u64).unwrap();
+            let layer = decode_codebook_tensor(&bytes[start + 8..start + 8 + len]).unwrap();
+            assert_eq!(layer.dequantize(), original.dequantize());
+        }
+    }
+}
@@ -0,0 +1,745 @@
+//! Typed GGUF metadata and the header records shared by the GGUF loaders.
+//!
+//! GGUF v1 stores counts, string and array lengths, and tensor dimensions as
+//! `u32`; v2 widened all of them to `u64`. The readers here work on buffered
+//! bytes and return `Ok(None)` when a record has not fully arrived yet, so the
+//! streaming parser can retry once more data is buffered.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::artifact::ArtifactWriter;
+use crate::artifact_reader::ArtifactReader;
+
+pub const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+/// Alignment of the tensor data section when `general.alignment` is absent.
+pub const GGUF_DEFAULT_ALIGNMENT: u64 = 32;
+
+/// Side file holding the `tokenizer.*` entries of a GGUF source.
+pub const GGUF_TOKENIZER_FILE: &str = "tokenizer.gguf.json";
+
+/// Side file holding every other entry of a GGUF source with its value type.
+pub const GGUF_METADATA_FILE: &str = "gguf-metadata.json";
+
+/// Prefix of the manifest metadata keys copied from a GGUF source.
+const MANIFEST_PREFIX: &str = "gguf.";
+
+/// Deepest nesting of array values accepted in a header. llama.cpp itself
+/// writes at most one level; the cap keeps hostile headers from recursing
+/// without bound.
+const MAX_ARRAY_DEPTH: usize = 8;
+
+/// Value type ids as stored in GGUF headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GgufValueType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    Bool,
+    String,
+    Array,
+    U64,
+    I64,
+    F64,
+}
+
+impl GgufValueType {
+    const ALL: [Self; 13] = [
+        Self::U8,
+        Self::I8,
+        Self::U16,
+        Self::I16,
+        Self::U32,
+        Self::I32,
+        Self::F32,
+        Self::Bool,
+        Self::String,
+        Self::Array,
+        Self::U64,
+        Self::I64,
+        Self::F64,
+    ];
+
+    pub fn from_id(id: u32) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+
+    pub fn id(self) -> u32 {
+        self as u32
+    }
+
+    /// Encoded size of fixed-size values.
+    fn fixed_size(self) -> Option<usize> {
+        match self {
+            Self::U8 | Self::I8 | Self::Bool => Some(1),
+            Self::U16 | Self::I16 => Some(2),
+            Self::U32 | Self::I32 | Self::F32 => Some(4),
+            Self::U64 | Self::I64 | Self::F64 => Some(8),
+            Self::String | Self::Array => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    /// Homogeneous array; the element type is kept so empty arrays round-trip.
+    Array(GgufValueType, Vec<GgufValue>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl GgufValue {
+    pub fn value_type(&self) -> GgufValueType {
+        match self {
+            Self::U8(_) => GgufValueType::U8,
+            Self::I8(_) => GgufValueType::I8,
+            Self::U16(_) => GgufValueType::U16,
+            Self::I16(_) => GgufValueType::I16,
+            Self::U32(_) => GgufValueType::U32,
+            Self::I32(_) => GgufValueType::I32,
+            Self::F32(_) => GgufValueType::F32,
+            Self::Bool(_) => GgufValueType::Bool,
+            Self::String(_) => GgufValueType::String,
+            Self::Array(..) => GgufValueType::Array,
+            Self::U64(_) => GgufValueType::U64,
+            Self::I64(_) => GgufValueType::I64,
+            Self::F64(_) => GgufValueType::F64,
+        }
+    }
+
+    /// The value of a non-negative integer of any width.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Self::U8(v) => Some(v.into()),
+            Self::U16(v) => Some(v.into()),
+            Self::U32(v) => Some(v.into()),
+            Self::U64(v) => Some(v),
+            Self::I8(v) => u64::try_from(v).ok(),
+            Self::I16(v) => u64::try_from(v).ok(),
+            Self::I32(v) => u64::try_from(v).ok(),
+            Self::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    /// The value of any float or integer.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Self::F32(v) => Some(v.into()),
+            Self::F64(v) => Some(v),
+            Self::I8(v) => Some(v.into()),
+            Self::I16(v) => Some(v.into()),
+            Self::I32(v) => Some(v.into()),
+            Self::I64(v) => Some(v as f64),
+            _ => self.as_u64().map(|v| v as f64),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Self::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            Self::Array(_, values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// JSON form of the value. Arrays become plain lists, or objects naming
+    /// their element type when `typed_arrays` is set so they can be read back.
+    fn to_json(&self, typed_arrays: bool) -> Value {
+        match self {
+            Self::U8(v) => Value::from(*v),
+            Self::I8(v) => Value::from(*v),
+            Self::U16(v) => Value::from(*v),
+            Self::I16(v) => Value::from(*v),
+            Self::U32(v) => Value::from(*v),
+            Self::I32(v) => Value::from(*v),
+            // Going through the shortest decimal keeps `0.1f32` as 0.1.
+            Self::F32(v) => Value::from(v.to_string().parse::<f64>().unwrap_or(f64::NAN)),
+            Self::Bool(v) => Value::from(*v),
+            Self::String(v) => Value::from(v.as_str()),
+            Self::Array(element_type, values) => {
+                let values = values.iter().map(|v| v.to_json(typed_arrays)).collect();
+                if typed_arrays {
+                    json!({ "element_type": element_type, "values": Value::Array(values) })
+                } else {
+                    Value::Array(values)
+                }
+            }
+            Self::U64(v) => Value::from(*v),
+            Self::I64(v) => Value::from(*v),
+            Self::F64(v) => Value::from(*v),
+        }
+    }
+
+    /// Inverse of `to_json(true)`. Non-finite floats were written as null.
+    fn from_json(value_type: GgufValueType, value: &Value) -> Result<Self> {
+        let mismatch = || anyhow!("{value} is not a GGUF {value_type:?}");
+        let int = |value: &Value| value.as_i64().ok_or_else(mismatch);
+        let float = |value: &Value| Ok::<_, anyhow::Error>(value.as_f64().unwrap_or(f64::NAN));
+        Ok(match value_type {
+            GgufValueType::U8 => Self::U8(int(value)?.try_into()?),
+            GgufValueType::I8 => Self::I8(int(value)?.try_into()?),
+            GgufValueType::U16 => Self::U16(int(value)?.try_into()?),
+            GgufValueType::I16 => Self::I16(int(value)?.try_into()?),
+            GgufValueType::U32 => Self::U32(int(value)?.try_into()?),
+            GgufValueType::I32 => Self::I32(int(value)?.try_into()?),
+            GgufValueType::F32 => Self::F32(float(value)? as f32),
+            GgufValueType::Bool => Self::Bool(value.as_bool().ok_or_else(mismatch)?),
+            GgufValueType::String => Self::String(value.as_str().ok_or_else(mismatch)?.into()),
+            GgufValueType::Array => {
+                let element_type: GgufValueType =
+                    serde_json::from_value(value["element_type"].clone())?;
+                let values = value["values"]
+                    .as_array()
+                    .ok_or_else(mismatch)?
+                    .iter()
+                    .map(|v| Self::from_json(element_type, v))
+                    .collect::<Result<_>>()?;
+                Self::Array(element_type, values)
+            }
+            GgufValueType::U64 => Self::U64(value.as_u64().ok_or_else(mismatch)?),
+            GgufValueType::I64 => Self::I64(int(value)?),
+            GgufValueType::F64 => Self::F64(float(value)?),
+        })
+    }
+}
+
+/// Strings are written bare; everything else as JSON.
+impl fmt::Display for GgufValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::String(v) => f.write_str(v),
+            other => write!(f, "{}", other.to_json(false)),
+        }
+    }
+}
+
+/// Key/value metadata from a GGUF header, in key order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GgufMetadata {
+    pub version: u32,
+    pub entries: BTreeMap<String, GgufValue>,
+}
+
+impl GgufMetadata {
+    pub fn new(version: u32) -> Self {
+        Self {
+            version,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.entries.get(key)
+    }
+
+    pub fn insert(&mut self, key: impl Into<String>, value: GgufValue) {
+        self.entries.insert(key.into(), value);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// `general.architecture`, e.g. `llama`.
+    pub fn architecture(&self) -> Option<&str> {
+        self.get("general.architecture")?.as_str()
+    }
+
+    /// Looks up a key scoped to the architecture, e.g. `context_length` for
+    /// `llama.context_length`.
+    pub fn architecture_value(&self, suffix: &str) -> Option<&GgufValue> {
+        self.get(&format!("{}.{suffix}", self.architecture()?))
+    }
+
+    pub fn context_length(&self) -> Option<u64> {
+        self.architecture_value("context_length")?.as_u64()
+    }
+
+    /// Alignment of the tensor data section, in bytes.
+    pub fn alignment(&self) -> Result<u64> {
+        let Some(value) = self.get("general.alignment") else {
+            return Ok(GGUF_DEFAULT_ALIGNMENT);
+        };
+        let alignment = value
+            .as_u64()
+            .ok_or_else(|| anyhow!("general.alignment is not an integer: {value}"))?;
+        ensure!(
+            alignment.is_power_of_two(),
+            "general.alignment must be a power of two, found {alignment}"
+        );
+        Ok(alignment)
+    }
+
+    /// The `tokenizer.*` entries: vocabulary, merges, scores and special
+    /// token ids.
+    pub fn tokenizer(&self) -> BTreeMap<&str, &GgufValue> {
+        self.entries
+            .iter()
+            .filter(|(key, _)| key.starts_with("tokenizer."))
+            .map(|(key, value)| (key.as_str(), value))
+            .collect()
+    }
+
+    /// Copies the scalar and non-tokenizer entries into the artifact metadata
+    /// under `gguf.` so the artifact keeps track of the model it was
+    /// compressed from. The entries are also stored with their value types as
+    /// two side files, `tokenizer` and `gguf_metadata`, from which
+    /// [`from_artifact`](Self::from_artifact) rebuilds the map.
+    pub fn record(&self, writer: &mut ArtifactWriter) -> Result<()> {
+        writer.set_metadata(
+            format!("{MANIFEST_PREFIX}version"),
+            self.version.to_string(),
+        );
+        for (key, value) in &self.entries {
+            if key.starts_with("tokenizer.") && value.as_array().is_some() {
+                continue;
+            }
+            writer.set_metadata(format!("{MANIFEST_PREFIX}{key}"), value.to_string());
+        }
+
+        let (tokenizer, rest): (Vec<_>, Vec<_>) = self
+            .entries
+            .iter()
+            .partition(|(key, _)| key.starts_with("tokenizer."));
+        for (role, file_name, entries) in [
+            ("tokenizer", GGUF_TOKENIZER_FILE, tokenizer),
+            ("gguf_metadata", GGUF_METADATA_FILE, rest),
+        ] {
+            if entries.is_empty() {
+                continue;
+            }
+            let bytes = serde_json::to_vec(&self.typed_json(entries))
+                .with_context(|| format!("serialize GGUF {role}"))?;
+            writer.write_side_file(role, file_name, &bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the metadata [`record`](Self::record) stored with an artifact,
+    /// or `None` when the artifact was not compressed from a GGUF file.
+    pub fn from_artifact(reader: &ArtifactReader) -> Result<Option<Self>> {
+        let mut metadata: Option<Self> = None;
+        for role in ["tokenizer", "gguf_metadata"] {
+            if !reader.manifest().side_files.contains_key(role) {
+                continue;
+            }
+            let json: Value = serde_json::from_slice(&reader.read_side_file(role)?)
+                .with_context(|| format!("parse GGUF {role} side file"))?;
+            let version = json["version"]
+                .as_u64()
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| anyhow!("GGUF {role} side file has no version"))?;
+            let metadata = metadata.get_or_insert_with(|| Self::new(version));
+            let entries = json["entries"]
+                .as_object()
+                .ok_or_else(|| anyhow!("GGUF {role} side file has no entries"))?;
+            for (key, entry) in entries {
+                let value_type: GgufValueType = serde_json::from_value(entry["type"].clone())
+                    .with_context(|| format!("GGUF key {key}"))?;
+                let value = GgufValue::from_json(value_type, &entry["value"])
+                    .with_context(|| format!("GGUF key {key}"))?;
+                metadata.insert(key.clone(), value);
+            }
+        }
+        Ok(metadata)
+    }
+
+    fn typed_json(&self, entries: Vec<(&String, &GgufValue)>) -> Value {
+        let entries: serde_json::Map<String, Value> = entries
+            .into_iter()
+            .map(|(key, value)| {
+                let entry = json!({ "type": value.value_type(), "value": value.to_json(true) });
+                (key.clone(), entry)
+            })
+            .collect();
+        json!({ "version": self.version, "entries": entries })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TensorDescriptor {
+    pub name: String,
+    pub dims: Vec<usize>,
+    pub type_id: u32,
+    pub offset: u64,
+}
+
+/// Rounds a header length up to the start of the tensor data section.
+pub(crate) fn align_offset(offset: u64, alignment: u64) -> u64 {
+    offset.div_ceil(alignment) * alignment
+}
+
+/// Cursor over buffered header bytes; reads yield `None` past the end.
+#[derive(Debug, Clone)]
+pub(crate) struct GgufCursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+    wide: bool,
+}
+
+impl<'a> GgufCursor<'a> {
+    pub fn new(data: &'a [u8], version: u32) -> Self {
+        Self {
+            data,
+            pos: 0,
+            wide: version >= 2,
+        }
+    }
+
+    /// Bytes read so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(n)?;
+        let bytes = self.data.get(self.pos..end)?;
+        self.pos = end;
+        Some(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N).map(|b| b.try_into().expect("N bytes"))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    /// A count, length or dimension: `u32` in v1, `u64` afterwards.
+    pub fn length(&mut self) -> Option<u64> {
+        if self.wide {
+            self.u64()
+        } else {
+            self.u32().map(u64::from)
+        }
+    }
+
+    fn string(&mut self) -> Option<Result<String>> {
+        let len = usize::try_from(self.length()?).ok()?;
+        let bytes = self.take(len)?;
+        Some(String::from_utf8(bytes.to_vec()).context("GGUF string is not UTF-8"))
+    }
+}
+
+/// Reads the tensor and kv counts that follow the magic and version.
+pub(crate) fn read_counts(cursor: &mut GgufCursor<'_>) -> Option<(u64, u64)> {
+    Some((cursor.length()?, cursor.length()?))
+}
+
+/// Reads one metadata entry once all of its bytes are buffered.
+pub(crate) fn read_kv(cursor: &mut GgufCursor<'_>) -> Result<Option<(String, GgufValue)>> {
+    let mut probe = cursor.clone();
+    let Some(key) = probe.string() else {
+        return Ok(None);
+    };
+    let key = key?;
+    let Some(value_type) = probe.u32() else {
+        return Ok(None);
+    };
+    let value_type = value_type_of(value_type).with_context(|| format!("GGUF key {key}"))?;
+    // Check cheaply that the whole value arrived before decoding it; large
+    // tokenizer arrays can take many chunks to arrive.
+    let value_start = probe.clone();
+    if !skip_value(&mut probe, value_type, 0).with_context(|| format!("GGUF key {key}"))? {
+        return Ok(None);
+    }
+    let mut value_cursor = value_start;
+    let value =
+        read_value(&mut value_cursor, value_type, 0).with_context(|| format!("GGUF key {key}"))?;
+    *cursor = probe;
+    Ok(Some((key, value)))
+}
+
+/// Reads one tensor info record once all of its bytes are buffered.
+pub(crate) fn read_tensor_descriptor(
+    cursor: &mut GgufCursor<'_>,
+) -> Result<Option<TensorDescriptor>> {
+    let mut probe = cursor.clone();
+    let record = (|| {
+        let name = probe.string()?;
+        let n_dims = probe.u32()?;
+        let dims = (0..n_dims)
+            .map(|_| probe.length().map(|d| d as usize))
+            .collect::<Option<Vec<_>>>()?;
+        Some((name, dims, probe.u32()?, probe.u64()?))
+    })();
+    let Some((name, dims, type_id, offset)) = record else {
+        return Ok(None);
+    };
+    let name = name.context("invalid tensor name")?;
+    *cursor = probe;
+    Ok(Some(TensorDescriptor {
+        name,
+        dims,
+        type_id,
+        offset,
+    }))
+}
+
+fn value_type_of(id: u32) -> Result<GgufValueType> {
+    GgufValueType::from_id(id).ok_or_else(|| anyhow!("unsupported GGUF value type: {}", id))
+}
+
+/// Fails once arrays nest deeper than [`MAX_ARRAY_DEPTH`].
+fn check_depth(depth: usize) -> Result<()> {
+    ensure!(
+        depth < MAX_ARRAY_DEPTH,
+        "GGUF arrays nest deeper than {MAX_ARRAY_DEPTH} levels"
+    );
+    Ok(())
+}
+
+/// Skips one value nested in `depth` arrays, returning `false` when it has
+/// not fully arrived yet.
+fn skip_value(
+    cursor: &mut GgufCursor<'_>,
+    value_type: GgufValueType,
+    depth: usize,
+) -> Result<bool> {
+    if let Some(size) = value_type.fixed_size() {
+        return Ok(cursor.take(size).is_some());
+    }
+    match value_type {
+        GgufValueType::String => {
+            let Some(len) = cursor.length() else {
+                return Ok(false);
+            };
+            Ok(usize::try_from(len).is_ok_and(|len| cursor.take(len).is_some()))
+        }
+        _ => {
+            check_depth(depth)?;
+            let (Some(elem_type), Some(len)) = (cursor.u32(), cursor.length()) else {
+                return Ok(false);
+            };
+            let elem_type = value_type_of(elem_type)?;
+            if let Some(size) = elem_type.fixed_size() {
+                let total = usize::try_from(len)
+                    .ok()
+                    .and_then(|len| len.checked_mul(size))
+                    .ok_or_else(|| anyhow!("GGUF array of {len} elements overflows"))?;
+                return Ok(cursor.take(total).is_some());
+            }
+            for _ in 0..len {
+                if !skip_value(cursor, elem_type, depth + 1)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// Decodes a value nested in `depth` arrays that [`skip_value`] found complete.
+fn read_value(
+    cursor: &mut GgufCursor<'_>,
+    value_type: GgufValueType,
+    depth: usize,
+) -> Result<GgufValue> {
+    let truncated = || anyhow!("GGUF {value_type:?} value is truncated");
+    Ok(match value_type {
+        GgufValueType::U8 => {
+            GgufValue::U8(u8::from_le_bytes(cursor.array().ok_or_else(truncated)?))
+        }
+        GgufValueType::I8 => {
+            GgufValue::I8(i8::from_le_bytes(cursor.array().ok_or_else(truncated)?))
+        }
+        GgufValueType::U16 => {
+            GgufValue::U16(u16::from_le_bytes(cursor.array().ok_or_else(truncated)?))
+        }
+        GgufValueType::I16 => {
+            GgufValue::I16(i16::from_le_bytes(cursor.array().ok_or_else(truncated)?))
+        }
+        GgufValueType::U32 => GgufValue::U32(cursor.u32().ok_or_else(truncated)?),
+        GgufValueType::I32 => {
+            GgufValue::I32(i32::from_le_bytes(cursor.array().ok_or_else(truncated)?))
+        }
+        GgufValueType::F32 => {
+            GgufValue::F32(f32::from_le_bytes(cursor.array().ok_or_else(truncated)?))
+        }
+        GgufValueType::Bool => {
+            let [byte] = cursor.array().ok_or_else(truncated)?;
+            ensure!(byte <= 1, "GGUF bool holds {byte}");
+            GgufValue::Bool(byte == 1)
+        }
+        GgufValueType::String => GgufValue::String(cursor.string().ok_or_else(truncated)??),
+        GgufValueType::Array => {
+            check_depth(depth)?;
+            let elem_type = value_type_of(cursor.u32().ok_or_else(truncated)?)?;
+            let len = cursor.length().ok_or_else(truncated)?;
+            // Every element takes at least a byte, which bounds the allocation
+            // by the buffered data rather than by the untrusted length.
+            let remaining = cursor.data.len() - cursor.pos;
+            let mut values = Vec::with_capacity((len as usize).min(remaining));
+            for _ in 0..len {
+                values.push(read_value(cursor, elem_type, depth + 1)?);
+            }
+            GgufValue::Array(elem_type, values)
+        }
+        GgufValueType::U64 => GgufValue::U64(cursor.u64().ok_or_else(truncated)?),
+        GgufValueType::I64 => {
+            GgufValue::I64(i64::from_le_bytes(cursor.array().ok_or_else(truncated)?))
+        }
+        GgufValueType::F64 => {
+            GgufValue::F64(f64::from_le_bytes(cursor.array().ok_or_else(truncated)?))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_string(bytes: &mut Vec<u8>, value: &str) {
+        bytes.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(value.as_bytes());
+    }
+
+    fn kv_bytes() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        push_string(&mut bytes, "general.architecture");
+        bytes.extend_from_slice(&8u32.to_le_bytes());
+        push_string(&mut bytes, "llama");
+
+        push_string(&mut bytes, "llama.context_length");
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        bytes.extend_from_slice(&4096u32.to_le_bytes());
+
+        push_string(&mut bytes, "llama.rope.freq_base");
+        bytes.extend_from_slice(&6u32.to_le_bytes());
+        bytes.extend_from_slice(&10000f32.to_le_bytes());
+
+        push_string(&mut bytes, "tokenizer.ggml.scores");
+        bytes.extend_from_slice(&9u32.to_le_bytes());
+        bytes.extend_from_slice(&6u32.to_le_bytes());
+        bytes.extend_from_slice(&2u64.to_le_bytes());
+        bytes.extend_from_slice(&0.5f32.to_le_bytes());
+        bytes.extend_from_slice(&(-1f32).to_le_bytes());
+
+        push_string(&mut bytes, "nested");
+        bytes.extend_from_slice(&9u32.to_le_bytes());
+        bytes.extend_from_slice(&9u32.to_le_bytes());
+        bytes.extend_from_slice(&1u64.to_le_bytes());
+        bytes.extend_from_slice(&7u32.to_le_bytes());
+        bytes.extend_from_slice(&2u64.to_le_bytes());
+        bytes.extend_from_slice(&[1, 0]);
+        bytes
+    }
+
+    #[test]
+    fn reads_every_entry_and_waits_for_partial_ones() {
+        let bytes = kv_bytes();
+        let mut metadata = GgufMetadata::new(3);
+        let mut cursor = GgufCursor::new(&bytes, 3);
+        while let Some((key, value)) = read_kv(&mut cursor).unwrap() {
+            metadata.insert(key, value);
+        }
+        assert_eq!(cursor.position(), bytes.len());
+        assert_eq!(metadata.len(), 5);
+        assert_eq!(metadata.architecture(), Some("llama"));
+        assert_eq!(metadata.context_length(), Some(4096));
+        assert_eq!(
+            metadata
+                .architecture_value("rope.freq_base")
+                .and_then(GgufValue::as_f64),
+            Some(10000.0)
+        );
+        assert_eq!(metadata.alignment().unwrap(), GGUF_DEFAULT_ALIGNMENT);
+        assert_eq!(
+            metadata.get("nested").unwrap().to_string(),
+            "[[true,false]]"
+        );
+        assert_eq!(
+            metadata.tokenizer().keys().collect::<Vec<_>>(),
+            [&"tokenizer.ggml.scores"]
+        );
+
+        for end in 0..bytes.len() {
+            let mut cursor = GgufCursor::new(&bytes[..end], 3);
+            while read_kv(&mut cursor).unwrap().is_some() {}
+            assert!(cursor.position() <= end);
+        }
+    }
+
+    #[test]
+    fn v1_headers_use_narrow_lengths() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&3u32.to_le_bytes());
+        bytes.extend_from_slice(b"key");
+        bytes.extend_from_slice(&8u32.to_le_bytes());
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(b"v1");
+        let mut cursor = GgufCursor::new(&bytes, 1);
+        let (key, value) = read_kv(&mut cursor).unwrap().unwrap();
+        assert_eq!((key.as_str(), value.as_str()), ("key", Some("v1")));
+    }
+
+    #[test]
+    fn deeply_nested_arrays_are_rejected() {
+        let nested = |levels: usize| {
+            let mut bytes = Vec::new();
+            push_string(&mut bytes, "nested");
+            bytes.extend_from_slice(&9u32.to_le_bytes());
+            for _ in 1..levels {
+                bytes.extend_from_slice(&9u32.to_le_bytes());
+                bytes.extend_from_slice(&1u64.to_le_bytes());
+            }
+            bytes.extend_from_slice(&7u32.to_le_bytes());
+            bytes.extend_from_slice(&1u64.to_le_bytes());
+            bytes.push(1);
+            bytes
+        };
+
+        let bytes = nested(MAX_ARRAY_DEPTH);
+        let (_, value) = read_kv(&mut GgufCursor::new(&bytes, 3)).unwrap().unwrap();
+        assert!(value.to_string().starts_with(&"[".repeat(MAX_ARRAY_DEPTH)));
+
+        let bytes = nested(MAX_ARRAY_DEPTH + 1);
+        let err = read_kv(&mut GgufCursor::new(&bytes, 3)).unwrap_err();
+        assert!(format!("{err:#}").contains("nest deeper"), "{err:#}");
+        // The cap applies before the value has fully arrived.
+        assert!(read_kv(&mut GgufCursor::new(&bytes[..bytes.len() - 1], 3)).is_err());
+    }
+}
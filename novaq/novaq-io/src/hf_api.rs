@@ -1,29 +1,44 @@
 use anyhow::{anyhow, Context, Result};
-use futures::TryStreamExt;
-use reqwest::header::{AUTHORIZATION, USER_AGENT};
-use reqwest::{Client, StatusCode};
+use futures::{StreamExt, TryStreamExt};
+use reqwest::header::{AUTHORIZATION, LOCATION, RANGE, USER_AGENT};
+use reqwest::{Client, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio_util::io::StreamReader;
 use tracing::{debug, info, instrument, warn};
 
+use crate::blob_cache::BlobCache;
+use crate::safetensors_index::{is_safetensors_index, SafeTensorsIndex};
+
 const HF_API_BASE: &str = "https://huggingface.co";
 const MAX_RETRIES: u32 = 5;
+const MAX_REDIRECTS: usize = 5;
 const INITIAL_RETRY_DELAY_MS: u64 = 500;
+/// Upper bound on a shard index; real ones are well under a megabyte.
+const MAX_INDEX_BYTES: u64 = 64 << 20;
 const USER_AGENT_VALUE: &str = "novaq/0.1.0 (+https://github.com/OHMS-DeAI/ohms-2.0)";
 
 #[derive(Debug, Clone)]
 pub struct HuggingFaceApiClient {
     client: Client,
     token: Option<String>,
+    endpoint: String,
+    cache: Option<BlobCache>,
+    offline: bool,
 }
 
+/// A download answered with a status that is not worth retrying, or that
+/// was retried too often.
+#[derive(Debug, thiserror::Error)]
+#[error("download failed with status {0}")]
+struct DownloadStatus(StatusCode);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RepoFile {
     #[serde(rename = "type")]
     pub file_type: Option<String>,
-    #[serde(alias = "rfilename")]
     pub path: Option<String>,
     pub size: Option<u64>,
     pub lfs: Option<LfsInfo>,
@@ -49,38 +64,84 @@ pub struct RepoInfo {
     pub siblings: Vec<RepoFile>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelSpec {
     pub repo_id: String,
     pub revision: String,
+    /// Commit `revision` resolved to, when the Hub reported one. Files are
+    /// downloaded from this commit, so a branch moving mid-run cannot mix
+    /// files from two commits.
+    #[serde(default)]
+    pub commit: Option<String>,
     pub files: Vec<ModelFile>,
+    /// Weight map of a sharded checkpoint, fetched with the listing so
+    /// offline runs order tensors across shards the same way.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<SafeTensorsIndex>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelFile {
     pub path: String,
     pub size: u64,
     pub download_url: String,
+    /// sha256 of the content, for files stored through Git LFS.
+    #[serde(default)]
+    pub oid: Option<String>,
 }
 
 impl HuggingFaceApiClient {
     pub fn new(token: Option<String>) -> Result<Self> {
         let client = Client::builder()
             .user_agent(USER_AGENT_VALUE)
-            .timeout(Duration::from_secs(3600))
+            // No overall deadline: a multi-gigabyte file may take hours. A
+            // stalled connection still fails once no byte arrives for a minute.
             .connect_timeout(Duration::from_secs(30))
+            .read_timeout(Duration::from_secs(60))
             .pool_idle_timeout(Duration::from_secs(90))
             .pool_max_idle_per_host(0)
             .redirect(reqwest::redirect::Policy::none())
             .build()
             .context("failed to build HTTP client")?;
 
-        Ok(Self { client, token })
+        Ok(Self {
+            client,
+            token,
+            endpoint: HF_API_BASE.to_string(),
+            cache: None,
+            offline: false,
+        })
+    }
+
+    /// Talks to another Hub-compatible server, such as a mirror.
+    pub fn with_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = endpoint.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Keeps LFS files and repository listings in `cache`.
+    pub fn with_cache(mut self, cache: BlobCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Serves listings and files from the cache only, never touching the
+    /// network. Requires a cache.
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
     }
 
     #[instrument(skip(self))]
     pub async fn get_repo_info(&self, repo_id: &str, revision: &str) -> Result<RepoInfo> {
-        let url = format!("{}/api/models/{}", HF_API_BASE, repo_id);
+        // `blobs=true` adds the LFS oid and size of every file. Revisions
+        // such as `refs/pr/1` go into a single path segment.
+        let url = format!(
+            "{}/api/models/{}/revision/{}?blobs=true",
+            self.endpoint,
+            repo_id,
+            revision.replace('/', "%2F")
+        );
         let mut retries = 0;
 
         loop {
@@ -154,7 +215,21 @@ impl HuggingFaceApiClient {
         pattern: Option<&str>,
     ) -> Result<ModelSpec> {
         info!(repo_id, revision, "discovering model files");
+        if self.offline {
+            let mut spec = self
+                .cache
+                .as_ref()
+                .ok_or_else(|| anyhow!("offline mode needs a cache directory"))?
+                .read_listing(repo_id, revision)?;
+            if let Some(pattern) = pattern {
+                spec.files.retain(|file| file.path.contains(pattern));
+            }
+            info!(files = spec.files.len(), "using cached repository listing");
+            return Ok(spec);
+        }
         let repo_info = self.get_repo_info(repo_id, revision).await?;
+        let commit = repo_info.sha.clone();
+        let pinned = commit.as_deref().unwrap_or(revision);
 
         let mut files = Vec::new();
         for sibling in &repo_info.siblings {
@@ -187,7 +262,7 @@ impl HuggingFaceApiClient {
 
             let download_url = format!(
                 "{}/{}/resolve/{}/{}",
-                HF_API_BASE, repo_id, revision, file_path
+                self.endpoint, repo_id, pinned, file_path
             );
 
             info!("adding file: {} (size: {} bytes, url: {})", file_path, size, download_url);
@@ -196,14 +271,35 @@ impl HuggingFaceApiClient {
                 path: file_path.clone(),
                 size,
                 download_url,
+                oid: sibling.lfs.as_ref().and_then(|lfs| lfs.oid.clone()),
             });
         }
 
-        Ok(ModelSpec {
+        let index = match files.iter().find(|file| is_top_level_index(&file.path)) {
+            Some(file) => Some(self.fetch_index(file).await?),
+            None => None,
+        };
+
+        let spec = ModelSpec {
             repo_id: repo_id.to_string(),
             revision: revision.to_string(),
+            commit,
             files,
-        })
+            index,
+        };
+        // A filtered listing would hide files from later offline runs. The
+        // listing is also kept under its commit, so runs pinned to that
+        // commit work offline too.
+        if let (Some(cache), None) = (&self.cache, pattern) {
+            cache.write_listing(&spec)?;
+            if let Some(commit) = spec.commit.as_deref().filter(|c| *c != revision) {
+                cache.write_listing(&ModelSpec {
+                    revision: commit.to_string(),
+                    ..spec.clone()
+                })?;
+            }
+        }
+        Ok(spec)
     }
 
     #[instrument(skip(self))]
@@ -212,7 +308,35 @@ impl HuggingFaceApiClient {
         repo_id: &str,
         revision: &str,
     ) -> Result<Vec<ModelFile>> {
-        let spec = self.discover_model_files(repo_id, revision, None).await?;
+        Ok(self.detect_model_snapshot(repo_id, revision).await?.files)
+    }
+
+    /// Like [`Self::detect_model_shards`], but keeps the resolved commit
+    /// alongside the shards.
+    #[instrument(skip(self))]
+    pub async fn detect_model_snapshot(&self, repo_id: &str, revision: &str) -> Result<ModelSpec> {
+        let mut spec = self.discover_model_files(repo_id, revision, None).await?;
+
+        if let Some(index) = &spec.index {
+            let shard_files = index.shard_files();
+            let shards: Vec<_> = spec
+                .files
+                .iter()
+                .filter(|f| shard_files.contains(f.path.as_str()))
+                .cloned()
+                .collect();
+            if let Some(missing) = shard_files
+                .iter()
+                .find(|file| !shards.iter().any(|shard| shard.path == **file))
+            {
+                return Err(anyhow!(
+                    "shard {missing} named by the index is not in the repository"
+                ));
+            }
+            info!(count = shards.len(), "detected indexed safetensors shards");
+            spec.files = shards;
+            return Ok(spec);
+        }
 
         let safetensors_shards: Vec<_> = spec
             .files
@@ -235,12 +359,14 @@ impl HuggingFaceApiClient {
             let mut sorted = safetensors_shards;
             sorted.sort_by(|a, b| a.path.cmp(&b.path));
             info!(count = sorted.len(), "detected safetensors shards");
-            return Ok(sorted);
+            spec.files = sorted;
+            return Ok(spec);
         }
 
         if !gguf_files.is_empty() {
             info!(count = gguf_files.len(), "detected GGUF files");
-            return Ok(gguf_files);
+            spec.files = gguf_files;
+            return Ok(spec);
         }
 
         Err(anyhow!(
@@ -300,7 +426,7 @@ impl HuggingFaceApiClient {
                     if response.status().is_success() {
                         let stream = response
                             .bytes_stream()
-                            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
+                            .map_err(std::io::Error::other);
                         
                         let reader = StreamReader::new(stream);
                         
@@ -334,6 +460,176 @@ impl HuggingFaceApiClient {
         }
     }
 
+    /// Downloads the shard index of a sharded checkpoint. It is a plain git
+    /// file rather than an LFS one, so it is read into memory instead of the
+    /// blob cache.
+    async fn fetch_index(&self, file: &ModelFile) -> Result<SafeTensorsIndex> {
+        let mut bytes = Vec::new();
+        self.download_file_streaming(&file.download_url)
+            .await?
+            .take(MAX_INDEX_BYTES + 1)
+            .read_to_end(&mut bytes)
+            .await
+            .with_context(|| format!("download {}", file.path))?;
+        if bytes.len() as u64 > MAX_INDEX_BYTES {
+            return Err(anyhow!(
+                "{} is larger than {MAX_INDEX_BYTES} bytes",
+                file.path
+            ));
+        }
+        SafeTensorsIndex::from_slice(&bytes).with_context(|| format!("parse {}", file.path))
+    }
+
+    /// Path of an LFS file in the cache, downloading it first if needed.
+    /// Unlike [`Self::open_file`] there is no streaming fallback, so the
+    /// client needs a cache and the file an oid.
+    pub async fn cached_file(&self, file: &ModelFile) -> Result<PathBuf> {
+        let cache = self
+            .cache
+            .as_ref()
+            .ok_or_else(|| anyhow!("{} can only be read through a cache directory", file.path))?;
+        let oid = file
+            .oid
+            .as_deref()
+            .ok_or_else(|| anyhow!("{} is not stored through Git LFS", file.path))?;
+        self.download_to_cache(cache, file, oid).await
+    }
+
+    /// Opens a repository file for reading. LFS files go through the cache
+    /// when there is one; anything else is streamed from the Hub.
+    pub async fn open_file(&self, file: &ModelFile) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        if self.cache.is_some() && file.oid.is_some() {
+            let path = self.cached_file(file).await?;
+            let blob = tokio::fs::File::open(&path)
+                .await
+                .with_context(|| format!("open cached blob {}", path.display()))?;
+            return Ok(Box::new(tokio::io::BufReader::new(blob)));
+        }
+        if self.offline {
+            return Err(anyhow!("{} is not in the cache and offline mode is on", file.path));
+        }
+        Ok(Box::new(self.download_file_streaming(&file.download_url).await?))
+    }
+
+    /// Downloads an LFS file into the cache and returns the verified blob's
+    /// path. A dropped connection resumes with a `Range` request from the
+    /// bytes already on disk, including those left by an earlier run.
+    #[instrument(skip(self, cache, file), fields(path = %file.path))]
+    pub async fn download_to_cache(
+        &self,
+        cache: &BlobCache,
+        file: &ModelFile,
+        oid: &str,
+    ) -> Result<PathBuf> {
+        if cache.contains(oid) {
+            debug!(oid, "blob already cached");
+            return cache.blob_path(oid);
+        }
+        if self.offline {
+            return Err(anyhow!("{} is not in the cache and offline mode is on", file.path));
+        }
+
+        let partial = cache.partial_path(oid)?;
+        let mut retries = 0;
+        loop {
+            let offset = file_len(&partial).await;
+            if file.size > 0 && offset >= file.size {
+                break;
+            }
+            match self.download_range(&file.download_url, offset, &partial).await {
+                Ok(()) => break,
+                Err(err) if Self::is_transient(&err) => {
+                    // Only attempts that add nothing count against the
+                    // budget, so a long download can drop any number of times.
+                    if file_len(&partial).await > offset {
+                        retries = 0;
+                    }
+                    if retries == MAX_RETRIES {
+                        return Err(err);
+                    }
+                    retries += 1;
+                    let delay = Self::calculate_retry_delay(retries);
+                    warn!(error = %err, offset, retry = retries, "download interrupted, resuming");
+                    tokio::time::sleep(delay).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        // Hashing a multi-gigabyte blob would stall the runtime's workers.
+        let (cache, oid) = (cache.clone(), oid.to_string());
+        tokio::task::spawn_blocking(move || cache.commit(&oid)).await?
+    }
+
+    /// Appends `url`'s content from byte `offset` on to `partial`.
+    async fn download_range(&self, url: &str, offset: u64, partial: &Path) -> Result<()> {
+        let response = self.send_download(url, offset).await?;
+        let status = response.status();
+        let mut out = if status == StatusCode::PARTIAL_CONTENT {
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(partial)
+                .await?
+        } else if status == StatusCode::RANGE_NOT_SATISFIABLE && offset > 0 {
+            // Everything is on disk already; the sha256 check has the last word.
+            return Ok(());
+        } else if status.is_success() {
+            if offset > 0 {
+                debug!(offset, "server ignored the range, starting over");
+            }
+            tokio::fs::File::create(partial).await?
+        } else {
+            return Err(DownloadStatus(status).into());
+        };
+
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            out.write_all(&chunk?).await?;
+        }
+        out.flush().await?;
+        Ok(())
+    }
+
+    /// Sends a download request starting at `offset`, following redirects
+    /// to the storage backend. Like the streaming path, the token is only
+    /// sent to the Hub itself.
+    async fn send_download(&self, url: &str, offset: u64) -> Result<Response> {
+        let mut url = reqwest::Url::parse(url).context("invalid download url")?;
+        for redirect in 0..=MAX_REDIRECTS {
+            let mut request = self
+                .client
+                .get(url.clone())
+                .header(USER_AGENT, USER_AGENT_VALUE)
+                .header("Accept", "application/octet-stream");
+            if offset > 0 {
+                request = request.header(RANGE, format!("bytes={offset}-"));
+            }
+            if let (Some(token), 0) = (&self.token, redirect) {
+                request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+            }
+
+            let response = request.send().await?;
+            if !response.status().is_redirection() {
+                return Ok(response);
+            }
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .ok_or_else(|| anyhow!("redirect response missing location header"))?
+                .to_str()
+                .context("invalid redirect location")?;
+            url = url.join(location).context("invalid redirect location")?;
+            debug!(%url, "following redirect");
+        }
+        Err(anyhow!("too many redirects downloading {url}"))
+    }
+
+    fn is_transient(err: &anyhow::Error) -> bool {
+        match err.downcast_ref::<DownloadStatus>() {
+            Some(DownloadStatus(status)) => Self::should_retry(*status),
+            None => err.is::<reqwest::Error>() || err.is::<std::io::Error>(),
+        }
+    }
+
     fn should_retry(status: StatusCode) -> bool {
         matches!(
             status,
@@ -353,14 +649,29 @@ impl HuggingFaceApiClient {
     }
 }
 
+/// Length of a partial download, or 0 if there is none yet.
+async fn file_len(path: &Path) -> u64 {
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    }
+}
+
+/// Whether `path` is the shard index of a checkpoint at the repository root,
+/// where the shards it names are looked up.
+fn is_top_level_index(path: &str) -> bool {
+    !path.contains('/') && is_safetensors_index(path)
+}
+
 pub fn parse_repo_spec(locator: &str) -> Result<(String, String)> {
     if let Some(url) = locator.strip_prefix("hf://") {
-        let parts: Vec<&str> = url.split('@').collect();
-        let repo_part = parts[0];
-        let revision = parts.get(1).unwrap_or(&"main").to_string();
-
-        let repo_id = repo_part.replace('/', "/");
-        Ok((repo_id, revision))
+        let (repo_id, revision) = url.split_once('@').unwrap_or((url, "main"));
+        if repo_id.is_empty() || revision.is_empty() || revision.contains('@') {
+            return Err(anyhow!(
+                "invalid locator {locator:?}, expected hf://org/repo or hf://org/repo@revision"
+            ));
+        }
+        Ok((repo_id.to_string(), revision.to_string()))
     } else if locator.starts_with("https://huggingface.co/") {
         let without_prefix = locator
             .strip_prefix("https://huggingface.co/")
@@ -404,6 +715,20 @@ mod tests {
         assert_eq!(rev, "main");
     }
 
+    #[test]
+    fn test_parse_hf_scheme_pinned_revision() {
+        let sha = "0123456789abcdef0123456789abcdef01234567";
+        let (repo, rev) = parse_repo_spec(&format!("hf://openai/gpt-2@{sha}")).unwrap();
+        assert_eq!(repo, "openai/gpt-2");
+        assert_eq!(rev, sha);
+        let (_, rev) = parse_repo_spec("hf://openai/gpt-2@refs/pr/1").unwrap();
+        assert_eq!(rev, "refs/pr/1");
+
+        for invalid in ["hf://openai/gpt-2@", "hf://@main", "hf://openai/gpt-2@a@b"] {
+            assert!(parse_repo_spec(invalid).is_err(), "{invalid}");
+        }
+    }
+
     #[test]
     fn test_parse_https_url() {
         let (repo, rev) =
@@ -1,20 +1,30 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use reqwest::Client;
 use tracing::{info, instrument};
 
 use crate::artifact::ArtifactWriter;
+use crate::blob_cache::BlobCache;
+use crate::dtype::ScalePairing;
 use crate::format::ModelLocator;
-use crate::hf_api::{parse_repo_spec, HuggingFaceApiClient};
+use crate::hf_api::{parse_repo_spec, HuggingFaceApiClient, ModelFile};
 use crate::progress::{BandwidthMonitor, ProgressTracker};
-use crate::streaming_safetensors_v2::StreamingSafeTensorsParserV2;
+use crate::safetensors_index::{check_shard_header, layer_indices, SafeTensorsIndex};
+use crate::streaming_safetensors_v2::{ShardHeader, StreamingSafeTensorsParserV2};
 use novaq_core::{QuantizationConfig, QuantizedModel};
+use novaq_manifest::{SourceFile, SourceProvenance};
 
 #[derive(Debug, Clone)]
 pub struct HuggingFaceConfig {
     pub client: Client,
     pub token: Option<String>,
+    /// Hub to download from; `None` means huggingface.co.
+    pub endpoint: Option<String>,
+    /// Content-addressed cache for LFS files and repository listings.
+    pub cache_dir: Option<PathBuf>,
+    /// Load from `cache_dir` only, without network access.
+    pub offline: bool,
 }
 
 impl Default for HuggingFaceConfig {
@@ -27,6 +37,9 @@ impl Default for HuggingFaceConfig {
         Self {
             client,
             token: None,
+            endpoint: None,
+            cache_dir: None,
+            offline: false,
         }
     }
 }
@@ -42,7 +55,17 @@ impl HuggingFaceLoader {
         hf_config: HuggingFaceConfig,
         quant_config: QuantizationConfig,
     ) -> Result<Self> {
-        let api_client = HuggingFaceApiClient::new(hf_config.token.clone())?;
+        if hf_config.offline && hf_config.cache_dir.is_none() {
+            return Err(anyhow!("offline mode needs a cache directory"));
+        }
+        let mut api_client =
+            HuggingFaceApiClient::new(hf_config.token.clone())?.with_offline(hf_config.offline);
+        if let Some(endpoint) = hf_config.endpoint {
+            api_client = api_client.with_endpoint(endpoint);
+        }
+        if let Some(cache_dir) = hf_config.cache_dir {
+            api_client = api_client.with_cache(BlobCache::new(cache_dir));
+        }
         Ok(Self {
             api_client,
             config: quant_config,
@@ -64,17 +87,36 @@ impl HuggingFaceLoader {
         let (repo_id, revision) = parse_repo_spec(locator.as_str())?;
         info!(repo_id, revision, "loading model from HuggingFace");
 
-        let shards = self
+        let snapshot = self
             .api_client
-            .detect_model_shards(&repo_id, &revision)
+            .detect_model_snapshot(&repo_id, &revision)
             .await?;
+        let shards = &snapshot.files;
 
         if shards.is_empty() {
             return Err(anyhow!("no model files found in repository"));
         }
+        info!(commit = ?snapshot.commit, "resolved revision");
+        writer.set_source(SourceProvenance {
+            repo_id: snapshot.repo_id.clone(),
+            revision: snapshot.revision.clone(),
+            commit: snapshot.commit.clone(),
+            files: shards
+                .iter()
+                .map(|file| SourceFile {
+                    path: file.path.clone(),
+                    size: file.size,
+                    oid: file.oid.clone(),
+                })
+                .collect(),
+        });
 
         info!(shard_count = shards.len(), "detected model shards");
 
+        if let Some(index) = &snapshot.index {
+            return self.load_indexed_shards(index, shards, writer).await;
+        }
+
         let mut all_layers = Vec::new();
         let bandwidth_monitor = BandwidthMonitor::new();
 
@@ -87,16 +129,12 @@ impl HuggingFaceLoader {
                 "processing shard"
             );
 
-            let progress_bar = if let Some(ref progress) = self.progress {
-                Some(progress.add_download_bar(&shard.path, shard.size))
-            } else {
-                None
-            };
+            let progress_bar = self
+                .progress
+                .as_ref()
+                .map(|progress| progress.add_download_bar(&shard.path, shard.size));
 
-            let mut reader = self
-                .api_client
-                .download_file_streaming(&shard.download_url)
-                .await?;
+            let mut reader = self.api_client.open_file(shard).await?;
 
             let extension = Path::new(&shard.path)
                 .extension()
@@ -137,6 +175,70 @@ impl HuggingFaceLoader {
         Ok(QuantizedModel::from_layers(all_layers))
     }
 
+    /// Loads a sharded checkpoint, numbering its layers in layer order across
+    /// shards as [`SafeTensorsLoader::load_from_index`] does. The numbers
+    /// depend on every shard's tensors, so all headers are read before the
+    /// shards are streamed one after another.
+    ///
+    /// [`SafeTensorsLoader::load_from_index`]: crate::safetensors::SafeTensorsLoader::load_from_index
+    async fn load_indexed_shards(
+        &self,
+        index: &SafeTensorsIndex,
+        shards: &[ModelFile],
+        writer: &mut ArtifactWriter,
+    ) -> Result<QuantizedModel> {
+        let mut tensors = Vec::new();
+        for shard in shards {
+            let mut reader = self.api_client.open_file(shard).await?;
+            let header = ShardHeader::read(&mut reader)
+                .await
+                .with_context(|| format!("read header of shard {}", shard.path))?;
+            check_shard_header(index, &shard.path, &header.entries)?;
+            tensors.extend(
+                header
+                    .tensors()
+                    .filter(|(name, _)| index.weight_map.get(*name) == Some(&shard.path))
+                    .map(|(name, ndim)| (name.to_string(), ndim)),
+            );
+        }
+        let mut pairing = ScalePairing::new(index.weight_map.keys().map(String::as_str));
+        let indices = layer_indices(
+            tensors.iter().map(|(name, ndim)| (name.as_str(), *ndim)),
+            &pairing,
+            writer.manifest().layers.len(),
+        );
+
+        let parser = StreamingSafeTensorsParserV2::new(self.config.clone(), self.progress.clone())?;
+        let mut layers = Vec::new();
+        for (idx, shard) in shards.iter().enumerate() {
+            info!(
+                shard = idx + 1,
+                total = shards.len(),
+                file = shard.path,
+                size_mb = shard.size / 1_048_576,
+                "processing shard"
+            );
+            let progress_bar = self
+                .progress
+                .as_ref()
+                .map(|progress| progress.add_download_bar(&shard.path, shard.size));
+            let mut reader = self.api_client.open_file(shard).await?;
+            let header = ShardHeader::read(&mut reader).await?;
+            layers.extend(
+                parser
+                    .quantize_shard(&mut reader, &header, &indices, &mut pairing, writer)
+                    .await?,
+            );
+            if let Some(ref pb) = progress_bar {
+                pb.finish_with_message(format!("Completed {}", shard.path));
+            }
+        }
+        pairing.finish()?;
+        writer.flush()?;
+        layers.sort_by_key(|layer| layer.index);
+        Ok(QuantizedModel::from_layers(layers))
+    }
+
     #[instrument(skip(self, writer))]
     pub async fn load_snapshot(
         &self,
@@ -1,26 +1,56 @@
 //! Streaming model ingestion and artifact emission for NOVAQ.
 
+pub mod archive;
 pub mod artifact;
+pub mod artifact_reader;
+pub mod blob_cache;
+pub mod dtype;
 pub mod format;
+pub mod ggml;
 pub mod gguf;
+pub mod gguf_export;
+pub mod gguf_metadata;
 pub mod hf_api;
 pub mod huggingface;
 pub mod manifest;
+pub mod onnx;
+pub mod packed;
 pub mod progress;
+pub mod pytorch;
 pub mod safetensors;
+pub mod safetensors_export;
+pub mod safetensors_index;
 pub mod streaming_gguf;
 pub mod streaming_reader;
 pub mod streaming_safetensors;
 pub mod streaming_safetensors_v2;
 
+pub use archive::{ArchiveKind, ArchiveLoader};
 pub use artifact::{ArtifactManifest, ArtifactWriter, ArtifactWriterConfig, ChunkInfo};
+pub use artifact_reader::{ArtifactReader, ChunkIntegrityError, MANIFEST_FILE};
+pub use blob_cache::BlobCache;
+pub use dtype::TensorDType;
 pub use format::{ModelFormat, ModelLocator};
+pub use ggml::GgmlType;
 pub use gguf::GgufLoader;
+pub use gguf_export::{GgufExportMode, GgufExporter, NOVAQ_GGUF_TYPE_ID};
+pub use gguf_metadata::{
+    GgufMetadata, GgufValue, GgufValueType, GGUF_METADATA_FILE, GGUF_TOKENIZER_FILE,
+};
 pub use hf_api::{HuggingFaceApiClient, ModelFile, ModelSpec};
 pub use huggingface::{HuggingFaceConfig, HuggingFaceLoader};
-pub use manifest::assemble_manifest;
+pub use manifest::{assemble_manifest, check_reproduction};
+pub use onnx::OnnxLoader;
+pub use packed::{
+    decode_layer, encode_layer, PackedLayerReader, PackedLayerWriter, PACKED_LAYER_VERSION,
+};
 pub use progress::{BandwidthMonitor, ProgressTracker};
+pub use pytorch::PyTorchLoader;
 pub use safetensors::SafeTensorsLoader;
+pub use safetensors_export::{
+    SafeTensorsExportMode, SafeTensorsExporter, NOVAQ_SAFETENSORS_FORMAT,
+};
+pub use safetensors_index::{is_safetensors_index, SafeTensorsIndex, SAFETENSORS_INDEX_SUFFIX};
 pub use streaming_gguf::StreamingGgufParser;
 pub use streaming_safetensors::StreamingSafeTensorsParser;
 pub use streaming_safetensors_v2::StreamingSafeTensorsParserV2;
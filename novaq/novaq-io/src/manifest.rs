@@ -1,4 +1,4 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use novaq_core::{QuantizationConfig, QuantizedModel};
 use novaq_manifest::{ChunkEntry, LayerEntry, Manifest, QuantizationSection};
 use serde_json::Value;
@@ -19,6 +19,9 @@ pub fn assemble_manifest(
     };
 
     let mut manifest = Manifest::new("1.0.0", generator, locator.as_str(), quant_section);
+    manifest.layer_encoding = artifact.layer_encoding;
+    manifest.chunk_bytes = Some(artifact.chunk_bytes);
+    manifest.source = artifact.source.clone();
 
     for chunk in &artifact.chunks {
         manifest.add_chunk(ChunkEntry {
@@ -31,6 +34,7 @@ pub fn assemble_manifest(
     }
 
     manifest.metadata.extend(artifact.metadata.clone());
+    manifest.side_files.extend(artifact.side_files.clone());
 
     for layer in &model.layers {
         let residual_avg = if layer.telemetry.subspaces.is_empty() {
@@ -60,9 +64,46 @@ pub fn assemble_manifest(
                 residual_energy: residual_avg,
                 bits_per_weight: layer.metrics.bits_per_weight,
                 subspaces,
+                spans: artifact
+                    .layers
+                    .get(&layer.name)
+                    .cloned()
+                    .unwrap_or_default(),
             },
         );
     }
 
     Ok(manifest)
 }
+
+/// Checks that `reproduced` holds the same bytes as `original`: the same
+/// chunks with the same digests and, where both record one, the same source
+/// files.
+pub fn check_reproduction(original: &Manifest, reproduced: &Manifest) -> Result<()> {
+    if let (Some(expected), Some(found)) = (&original.source, &reproduced.source) {
+        if expected.files != found.files {
+            return Err(anyhow!(
+                "source files of {} differ from those recorded in the manifest",
+                found.repo_id
+            ));
+        }
+    }
+    if original.chunks.len() != reproduced.chunks.len() {
+        return Err(anyhow!(
+            "reproduction wrote {} chunks, manifest records {}",
+            reproduced.chunks.len(),
+            original.chunks.len()
+        ));
+    }
+    for (expected, found) in original.chunks.iter().zip(&reproduced.chunks) {
+        if expected.sha256 != found.sha256 {
+            return Err(anyhow!(
+                "chunk {} has sha256 {}, manifest records {}",
+                expected.index,
+                found.sha256,
+                expected.sha256
+            ));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,560 @@
+//! ONNX models, read straight from their protobuf encoding.
+//!
+//! Only the weights are of interest, so the reader walks `ModelProto.graph`
+//! down to its `initializer` tensors and skips every other field by wire
+//! type. Initializers can keep their bytes inline, in the typed `float_data`,
+//! `int32_data` or `double_data` fields, or in an external data file next to
+//! the model, as exporters do for models past protobuf's 2 GiB limit.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use half::{bf16, f16};
+use ndarray::Array2;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::{debug, info, instrument};
+
+use novaq_core::{QuantizationConfig, QuantizedModel, Quantizer};
+
+use crate::artifact::ArtifactWriter;
+use crate::dtype::TensorDType;
+use crate::safetensors_index::layer_order;
+
+// Field numbers from onnx.proto.
+const MODEL_GRAPH: u32 = 7;
+const GRAPH_INITIALIZER: u32 = 5;
+const TENSOR_DIMS: u32 = 1;
+const TENSOR_DATA_TYPE: u32 = 2;
+const TENSOR_SEGMENT: u32 = 3;
+const TENSOR_FLOAT_DATA: u32 = 4;
+const TENSOR_INT32_DATA: u32 = 5;
+const TENSOR_NAME: u32 = 8;
+const TENSOR_RAW_DATA: u32 = 9;
+const TENSOR_DOUBLE_DATA: u32 = 10;
+const TENSOR_EXTERNAL_DATA: u32 = 13;
+const TENSOR_DATA_LOCATION: u32 = 14;
+
+// `TensorProto.DataType` values of the floating point types we decode.
+const DATA_TYPE_FLOAT: i32 = 1;
+const DATA_TYPE_FLOAT16: i32 = 10;
+const DATA_TYPE_DOUBLE: i32 = 11;
+const DATA_TYPE_BFLOAT16: i32 = 16;
+
+const DATA_LOCATION_EXTERNAL: u64 = 1;
+
+pub struct OnnxLoader {
+    quantizer: Quantizer,
+}
+
+impl OnnxLoader {
+    pub fn new(config: QuantizationConfig) -> Result<Self> {
+        Ok(Self {
+            quantizer: Quantizer::new(config)?,
+        })
+    }
+
+    /// Loads the initializers of the model at `path`. External data files
+    /// are looked up relative to the model's directory.
+    #[instrument(skip(self, writer), fields(path = %path.display()))]
+    pub async fn load_from_path(
+        &self,
+        path: &Path,
+        writer: &mut ArtifactWriter,
+    ) -> Result<QuantizedModel> {
+        let bytes = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("read {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        self.load_from_bytes(&bytes, Some(dir), writer).await
+    }
+
+    /// Loads the initializers of an ONNX model held in memory, resolving
+    /// external data against `dir`. Without a directory, models that keep
+    /// data in external files are rejected.
+    pub async fn load_from_bytes(
+        &self,
+        model: &[u8],
+        dir: Option<&Path>,
+        writer: &mut ArtifactWriter,
+    ) -> Result<QuantizedModel> {
+        let mut initializers = graph_initializers(model)?;
+        // Same order as the SafeTensors loader, so layer indices and seeds
+        // match those of the same weights exported either way.
+        initializers.sort_by(|a, b| layer_order(&a.name, &b.name));
+        info!(initializers = initializers.len(), "loading onnx model");
+
+        let mut external_files: HashMap<PathBuf, tokio::fs::File> = HashMap::new();
+        let mut layers = Vec::new();
+        for tensor in &initializers {
+            let [rows, cols] = tensor.dims[..] else {
+                debug!(tensor = tensor.name, dims = ?tensor.dims, "skipping non-matrix tensor");
+                continue;
+            };
+            let Some(dtype) = float_dtype(tensor.data_type) else {
+                debug!(
+                    tensor = tensor.name,
+                    data_type = tensor.data_type,
+                    "skipping non-float tensor"
+                );
+                continue;
+            };
+
+            let matrix = match &tensor.external {
+                Some(external) => {
+                    let dir = dir.ok_or_else(|| {
+                        anyhow!(
+                            "{} keeps its data in {}, which cannot be resolved here",
+                            tensor.name,
+                            external.location
+                        )
+                    })?;
+                    let path = external_path(dir, &external.location)?;
+                    let file = match external_files.entry(path) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => {
+                            let file =
+                                tokio::fs::File::open(entry.key()).await.with_context(|| {
+                                    format!("open external data {}", entry.key().display())
+                                })?;
+                            entry.insert(file)
+                        }
+                    };
+                    let bytes = read_external(file, external, rows, cols, dtype)
+                        .await
+                        .with_context(|| format!("read external data of {}", tensor.name))?;
+                    dtype.decode_matrix(&bytes, rows, cols)?
+                }
+                None => tensor
+                    .inline_matrix(dtype, rows, cols)
+                    .with_context(|| format!("decode initializer {}", tensor.name))?,
+            };
+
+            let quantized = self.quantizer.quantize_layer(
+                &tensor.name,
+                writer.manifest().layers.len(),
+                &matrix,
+            )?;
+            debug!(
+                tensor = tensor.name,
+                subspaces = quantized.subspaces.len(),
+                "tensor quantized"
+            );
+            writer.write_layer(&quantized)?;
+            layers.push(quantized);
+        }
+
+        writer.flush()?;
+        Ok(QuantizedModel::from_layers(layers))
+    }
+}
+
+/// One `TensorProto` from `graph.initializer`, borrowing its inline bytes
+/// from the model.
+#[derive(Debug, Default)]
+struct Initializer<'a> {
+    name: String,
+    dims: Vec<usize>,
+    data_type: i32,
+    raw_data: Option<&'a [u8]>,
+    float_data: Vec<f32>,
+    int32_data: Vec<i32>,
+    double_data: Vec<f64>,
+    external: Option<ExternalData>,
+}
+
+#[derive(Debug, Default)]
+struct ExternalData {
+    location: String,
+    offset: u64,
+    length: Option<u64>,
+}
+
+impl Initializer<'_> {
+    fn inline_matrix(&self, dtype: TensorDType, rows: usize, cols: usize) -> Result<Array2<f32>> {
+        if let Some(raw) = self.raw_data {
+            return dtype.decode_matrix(raw, rows, cols);
+        }
+        let values: Vec<f32> = match dtype {
+            TensorDType::F32 => self.float_data.clone(),
+            TensorDType::F64 => self.double_data.iter().map(|&v| v as f32).collect(),
+            // Half types keep their bit patterns in the low 16 bits of int32_data.
+            TensorDType::F16 => self
+                .int32_data
+                .iter()
+                .map(|&v| f16::from_bits(v as u16).to_f32())
+                .collect(),
+            TensorDType::BF16 => self
+                .int32_data
+                .iter()
+                .map(|&v| bf16::from_bits(v as u16).to_f32())
+                .collect(),
+            other => bail!("{} initializers are not decoded", other.as_str()),
+        };
+        let len = values.len();
+        Array2::from_shape_vec((rows, cols), values)
+            .map_err(|_| anyhow!("{len} values do not fill a {rows}x{cols} tensor"))
+    }
+}
+
+fn float_dtype(data_type: i32) -> Option<TensorDType> {
+    match data_type {
+        DATA_TYPE_FLOAT => Some(TensorDType::F32),
+        DATA_TYPE_FLOAT16 => Some(TensorDType::F16),
+        DATA_TYPE_DOUBLE => Some(TensorDType::F64),
+        DATA_TYPE_BFLOAT16 => Some(TensorDType::BF16),
+        _ => None,
+    }
+}
+
+/// Resolves an external data location, which must stay inside `dir`.
+fn external_path(dir: &Path, location: &str) -> Result<PathBuf> {
+    let relative = Path::new(location);
+    ensure!(
+        !location.is_empty()
+            && relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_))),
+        "external data location {location:?} is not a path inside the model directory"
+    );
+    Ok(dir.join(relative))
+}
+
+async fn read_external(
+    file: &mut tokio::fs::File,
+    external: &ExternalData,
+    rows: usize,
+    cols: usize,
+    dtype: TensorDType,
+) -> Result<Vec<u8>> {
+    let len = rows
+        .checked_mul(cols)
+        .and_then(|n| n.checked_mul(dtype.bytes_per_element()))
+        .ok_or_else(|| anyhow!("tensor shape overflow"))?;
+    if let Some(length) = external.length {
+        ensure!(
+            length == len as u64,
+            "external data holds {length} bytes, a {rows}x{cols} {} tensor needs {len}",
+            dtype.as_str()
+        );
+    }
+    // Check against the file before allocating, so a forged shape cannot
+    // ask for more memory than the data file could ever fill.
+    let file_len = file.metadata().await?.len();
+    ensure!(
+        external
+            .offset
+            .checked_add(len as u64)
+            .is_some_and(|end| end <= file_len),
+        "a {rows}x{cols} {} tensor at offset {} runs past the end of its {file_len} byte file",
+        dtype.as_str(),
+        external.offset
+    );
+    file.seek(std::io::SeekFrom::Start(external.offset)).await?;
+    let mut bytes = vec![0u8; len];
+    file.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+/// Collects the initializers of a serialized `ModelProto`.
+fn graph_initializers(model: &[u8]) -> Result<Vec<Initializer<'_>>> {
+    let mut graph = None;
+    let mut fields = Fields::new(model);
+    while let Some((field, value)) = fields.next_field().context("invalid onnx model")? {
+        if field == MODEL_GRAPH {
+            graph = Some(value.bytes()?);
+        }
+    }
+    let graph = graph.ok_or_else(|| anyhow!("onnx model has no graph"))?;
+
+    let mut initializers = Vec::new();
+    let mut fields = Fields::new(graph);
+    while let Some((field, value)) = fields.next_field().context("invalid onnx graph")? {
+        if field == GRAPH_INITIALIZER {
+            let tensor = parse_tensor(value.bytes()?).with_context(|| {
+                format!("invalid initializer #{} in onnx graph", initializers.len())
+            })?;
+            initializers.push(tensor);
+        }
+    }
+    Ok(initializers)
+}
+
+fn parse_tensor(bytes: &[u8]) -> Result<Initializer<'_>> {
+    let mut tensor = Initializer::default();
+    let mut data_location = 0;
+    let mut fields = Fields::new(bytes);
+    while let Some((field, value)) = fields.next_field()? {
+        match field {
+            TENSOR_DIMS => value.for_each_varint(|dim| {
+                // Dims are int64; a negative one would wrap to a huge value.
+                ensure!(dim <= i64::MAX as u64, "negative tensor dimension");
+                tensor.dims.push(dim as usize);
+                Ok(())
+            })?,
+            TENSOR_DATA_TYPE => tensor.data_type = value.varint()? as i32,
+            TENSOR_SEGMENT => bail!("segmented tensors are not supported"),
+            TENSOR_FLOAT_DATA => value.for_each_fixed32(|bits| {
+                tensor.float_data.push(f32::from_bits(bits));
+                Ok(())
+            })?,
+            TENSOR_INT32_DATA => value.for_each_varint(|v| {
+                tensor.int32_data.push(v as i32);
+                Ok(())
+            })?,
+            TENSOR_NAME => {
+                tensor.name = std::str::from_utf8(value.bytes()?)
+                    .context("tensor name is not UTF-8")?
+                    .to_string();
+            }
+            TENSOR_RAW_DATA => tensor.raw_data = Some(value.bytes()?),
+            TENSOR_DOUBLE_DATA => value.for_each_fixed64(|bits| {
+                tensor.double_data.push(f64::from_bits(bits));
+                Ok(())
+            })?,
+            TENSOR_EXTERNAL_DATA => {
+                let (key, entry) = parse_string_entry(value.bytes()?)?;
+                let external = tensor.external.get_or_insert_with(ExternalData::default);
+                match key {
+                    "location" => external.location = entry.to_string(),
+                    "offset" => external.offset = entry.parse().context("invalid offset")?,
+                    "length" => external.length = Some(entry.parse().context("invalid length")?),
+                    // `checksum` and anything newer are not needed to read.
+                    _ => {}
+                }
+            }
+            TENSOR_DATA_LOCATION => data_location = value.varint()?,
+            _ => {}
+        }
+    }
+    if data_location == DATA_LOCATION_EXTERNAL {
+        ensure!(
+            tensor.external.is_some(),
+            "{} is marked external but has no external_data",
+            tensor.name
+        );
+    } else {
+        tensor.external = None;
+    }
+    Ok(tensor)
+}
+
+/// Parses a `StringStringEntryProto` into its key and value.
+fn parse_string_entry(bytes: &[u8]) -> Result<(&str, &str)> {
+    let (mut key, mut value) = ("", "");
+    let mut fields = Fields::new(bytes);
+    while let Some((field, wire)) = fields.next_field()? {
+        let text = || std::str::from_utf8(wire.bytes()?).context("external_data is not UTF-8");
+        match field {
+            1 => key = text()?,
+            2 => value = text()?,
+            _ => {}
+        }
+    }
+    Ok((key, value))
+}
+
+/// A protobuf field value, by wire type.
+#[derive(Debug, Clone, Copy)]
+enum WireValue<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> WireValue<'a> {
+    fn varint(self) -> Result<u64> {
+        match self {
+            Self::Varint(value) => Ok(value),
+            other => Err(anyhow!("expected a varint, found {other:?}")),
+        }
+    }
+
+    fn bytes(self) -> Result<&'a [u8]> {
+        match self {
+            Self::Bytes(bytes) => Ok(bytes),
+            other => Err(anyhow!(
+                "expected a length-delimited field, found {other:?}"
+            )),
+        }
+    }
+
+    /// Visits a repeated varint field, packed or not.
+    fn for_each_varint(self, mut f: impl FnMut(u64) -> Result<()>) -> Result<()> {
+        match self {
+            Self::Varint(value) => f(value),
+            Self::Bytes(bytes) => {
+                let mut fields = Fields::new(bytes);
+                while !fields.is_empty() {
+                    f(fields.varint()?)?;
+                }
+                Ok(())
+            }
+            other => Err(anyhow!("expected varints, found {other:?}")),
+        }
+    }
+
+    /// Visits a repeated 32-bit field such as `float`, packed or not.
+    fn for_each_fixed32(self, mut f: impl FnMut(u32) -> Result<()>) -> Result<()> {
+        match self {
+            Self::Fixed32(value) => f(value),
+            Self::Bytes(bytes) => {
+                ensure!(
+                    bytes.len() % 4 == 0,
+                    "packed fixed32 field has a partial element"
+                );
+                bytes
+                    .chunks_exact(4)
+                    .try_for_each(|b| f(u32::from_le_bytes(b.try_into().expect("4 bytes"))))
+            }
+            other => Err(anyhow!("expected fixed32 values, found {other:?}")),
+        }
+    }
+
+    /// Visits a repeated 64-bit field such as `double`, packed or not.
+    fn for_each_fixed64(self, mut f: impl FnMut(u64) -> Result<()>) -> Result<()> {
+        match self {
+            Self::Fixed64(value) => f(value),
+            Self::Bytes(bytes) => {
+                ensure!(
+                    bytes.len() % 8 == 0,
+                    "packed fixed64 field has a partial element"
+                );
+                bytes
+                    .chunks_exact(8)
+                    .try_for_each(|b| f(u64::from_le_bytes(b.try_into().expect("8 bytes"))))
+            }
+            other => Err(anyhow!("expected fixed64 values, found {other:?}")),
+        }
+    }
+}
+
+/// Iterates over the fields of an encoded protobuf message.
+struct Fields<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn next_field(&mut self) -> Result<Option<(u32, WireValue<'a>)>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let field = u32::try_from(key >> 3).context("field number out of range")?;
+        let value = match key & 7 {
+            0 => WireValue::Varint(self.varint()?),
+            1 => WireValue::Fixed64(u64::from_le_bytes(
+                self.take(8)?.try_into().expect("8 bytes"),
+            )),
+            2 => {
+                let len = usize::try_from(self.varint()?).context("field length out of range")?;
+                WireValue::Bytes(self.take(len)?)
+            }
+            5 => WireValue::Fixed32(u32::from_le_bytes(
+                self.take(4)?.try_into().expect("4 bytes"),
+            )),
+            wire_type => bail!("unsupported wire type {wire_type} for field {field}"),
+        };
+        Ok(Some((field, value)))
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self
+                .bytes
+                .get(self.pos)
+                .ok_or_else(|| anyhow!("truncated varint"))?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(anyhow!("varint longer than 10 bytes"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| anyhow!("field runs past the end of its message"))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_packed_and_unpacked_fields() {
+        #[rustfmt::skip]
+        let tensor = [
+            // dims 2 and 3, unpacked
+            0x08, 0x02, 0x08, 0x03,
+            // data_type FLOAT
+            0x10, 0x01,
+            // name "w"
+            0x42, 0x01, b'w',
+            // float_data 1.0 unpacked, then 2.0 and -1.0 packed
+            0x25, 0x00, 0x00, 0x80, 0x3F,
+            0x22, 0x08, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x80, 0xBF,
+            // an unknown field 99 as a varint
+            0x98, 0x06, 0x01,
+        ];
+        let tensor = parse_tensor(&tensor).unwrap();
+        assert_eq!(tensor.name, "w");
+        assert_eq!(tensor.dims, [2, 3]);
+        assert_eq!(tensor.float_data, [1.0, 2.0, -1.0]);
+        assert!(tensor.inline_matrix(TensorDType::F32, 2, 3).is_err());
+
+        let truncated = [0x42, 0x05, b'w'];
+        assert!(parse_tensor(&truncated).is_err());
+    }
+
+    #[tokio::test]
+    async fn external_data_must_fit_its_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.onnx.data");
+        std::fs::write(&path, [0u8; 64]).unwrap();
+        let mut file = tokio::fs::File::open(&path).await.unwrap();
+        let at = |offset| ExternalData {
+            offset,
+            ..ExternalData::default()
+        };
+
+        let bytes = read_external(&mut file, &at(0), 4, 4, TensorDType::F32).await;
+        assert_eq!(bytes.unwrap().len(), 64);
+        for (external, rows) in [(at(8), 4), (at(0), 1 << 20), (at(u64::MAX), 1)] {
+            let read = read_external(&mut file, &external, rows, 4, TensorDType::F32).await;
+            assert!(read.is_err(), "{rows} rows at {}", external.offset);
+        }
+    }
+
+    #[test]
+    fn keeps_external_data_inside_the_model_directory() {
+        let dir = Path::new("/models/net");
+        assert_eq!(
+            external_path(dir, "weights/data.bin").unwrap(),
+            dir.join("weights/data.bin")
+        );
+        for location in ["", "../secrets", "/etc/passwd", "a/../../b"] {
+            assert!(external_path(dir, location).is_err(), "{location}");
+        }
+    }
+}
@@ -0,0 +1,691 @@
+//! Packed binary container for quantized layers.
+//!
+//! A packed layer starts with a fixed header: the `NVQL` magic, the
+//! little-endian container version and the centroid precision tag. The body
+//! follows in declaration order of [`QuantizedLayer`]:
+//!
+//! * layer identity (name, index, shape, seed, and a timing slot written as
+//!   zero) and the optional column permutation, with integers as LEB128
+//!   varints;
+//! * normalization data as raw f32 column statistics plus the outliers in
+//!   their [`SparseOutliers`] form;
+//! * per subspace and stage, the codebook at the header precision followed
+//!   by the assignments bit-packed LSB first at [`bits_for_indices`] width;
+//! * the analysis variances and per-subspace telemetry in binary, then a
+//!   length-prefixed JSON section with the scalar metrics, analysis and
+//!   allocation.
+
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use half::f16;
+use ndarray::Array2;
+use novaq_core::{
+    bits_for_indices, CodebookStage, LayerAllocation, LayerMetrics, LayerTelemetry,
+    NormalizationRecord, OutlierLayout, OutlierPrecision, QuantizedLayer, QuantizedSubspace,
+    SparseOutliers, StageTelemetry, SubspaceTelemetry,
+};
+use novaq_manifest::{CentroidPrecision, LayerEncoding, LayerFormat};
+use serde::{Deserialize, Serialize};
+
+pub const PACKED_LAYER_MAGIC: [u8; 4] = *b"NVQL";
+
+/// Newest container version this crate reads and the one it writes.
+pub const PACKED_LAYER_VERSION: u16 = 1;
+
+#[derive(Serialize)]
+struct DescriptiveRef<'a> {
+    metrics: &'a LayerMetrics,
+    telemetry: &'a LayerTelemetry,
+    allocation: &'a LayerAllocation,
+}
+
+#[derive(Deserialize)]
+struct Descriptive {
+    metrics: LayerMetrics,
+    telemetry: LayerTelemetry,
+    allocation: LayerAllocation,
+}
+
+/// Serializes a layer chunk according to `encoding`.
+pub fn encode_layer(layer: &QuantizedLayer, encoding: &LayerEncoding) -> Result<Vec<u8>> {
+    match encoding.format {
+        LayerFormat::Json => {
+            // Like the packed writer, leave out the run-specific timing.
+            let layer = QuantizedLayer {
+                quantization_time_us: 0,
+                ..layer.clone()
+            };
+            serde_json::to_vec(&layer).context("serialize quantized layer")
+        }
+        LayerFormat::Packed => {
+            ensure!(
+                encoding.version == PACKED_LAYER_VERSION,
+                "cannot write packed layer version {}",
+                encoding.version
+            );
+            let mut bytes = Vec::new();
+            PackedLayerWriter::new(encoding.centroid_precision).write(layer, &mut bytes)?;
+            Ok(bytes)
+        }
+    }
+}
+
+/// Parses a layer chunk written with `encoding`.
+pub fn decode_layer(bytes: &[u8], encoding: &LayerEncoding) -> Result<QuantizedLayer> {
+    match encoding.format {
+        LayerFormat::Json => serde_json::from_slice(bytes).context("parse quantized layer"),
+        LayerFormat::Packed => PackedLayerReader::new(bytes).read(),
+    }
+}
+
+/// Writes layers in the packed container format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PackedLayerWriter {
+    centroid_precision: CentroidPrecision,
+}
+
+impl PackedLayerWriter {
+    pub fn new(centroid_precision: CentroidPrecision) -> Self {
+        Self { centroid_precision }
+    }
+
+    pub fn write<W: Write>(&self, layer: &QuantizedLayer, out: &mut W) -> Result<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&PACKED_LAYER_MAGIC);
+        buf.extend_from_slice(&PACKED_LAYER_VERSION.to_le_bytes());
+        buf.push(precision_tag(self.centroid_precision));
+
+        write_bytes(&mut buf, layer.name.as_bytes());
+        for value in [layer.index, layer.rows, layer.cols] {
+            write_varint(&mut buf, value as u64);
+        }
+        buf.extend_from_slice(&layer.seed.to_le_bytes());
+        // Always zero: a wall-clock reading would make two runs over the same
+        // input write different chunks.
+        write_varint(&mut buf, 0);
+        match &layer.column_permutation {
+            Some(permutation) => {
+                buf.push(1);
+                write_varint(&mut buf, permutation.len() as u64);
+                for &col in permutation {
+                    write_varint(&mut buf, col as u64);
+                }
+            }
+            None => buf.push(0),
+        }
+
+        self.write_normalization(&mut buf, &layer.normalization);
+
+        write_varint(&mut buf, layer.subspaces.len() as u64);
+        for subspace in &layer.subspaces {
+            write_varint(&mut buf, subspace.columns.start as u64);
+            write_varint(&mut buf, subspace.columns.end as u64);
+            buf.extend_from_slice(&subspace.residual_energy.to_le_bytes());
+            write_varint(&mut buf, subspace.stages.len() as u64);
+            for stage in &subspace.stages {
+                self.write_stage(&mut buf, stage)
+                    .with_context(|| format!("pack stage {} of {}", stage.stage_id, layer.name))?;
+            }
+        }
+
+        let mut telemetry = layer.telemetry.clone();
+        write_f32s(
+            &mut buf,
+            &std::mem::take(&mut telemetry.analysis.column_variances),
+        );
+        write_f32s(
+            &mut buf,
+            &std::mem::take(&mut telemetry.analysis.row_variances),
+        );
+        let subspace_telemetry = std::mem::take(&mut telemetry.subspaces);
+        write_varint(&mut buf, subspace_telemetry.len() as u64);
+        for subspace in &subspace_telemetry {
+            write_subspace_telemetry(&mut buf, subspace);
+        }
+        let descriptive = serde_json::to_vec(&DescriptiveRef {
+            metrics: &layer.metrics,
+            telemetry: &telemetry,
+            allocation: &layer.allocation,
+        })
+        .context("serialize layer metrics and telemetry")?;
+        write_bytes(&mut buf, &descriptive);
+
+        out.write_all(&buf).context("write packed layer")
+    }
+
+    fn write_normalization(&self, buf: &mut Vec<u8>, record: &NormalizationRecord) {
+        write_f32s(buf, &record.column_means);
+        write_f32s(buf, &record.column_stds);
+        let outliers = SparseOutliers::encode(&record.outliers);
+        let layout = match outliers.layout {
+            OutlierLayout::Coordinate => 0,
+            OutlierLayout::CompressedRows => 1,
+        };
+        let precision = match outliers.precision {
+            OutlierPrecision::F32 => 0,
+            OutlierPrecision::F16 => 2,
+        };
+        buf.push(layout | precision);
+        write_varint(buf, outliers.count as u64);
+        write_varint(buf, outliers.cols as u64);
+        write_bytes(buf, &outliers.positions);
+        buf.extend_from_slice(&outliers.values);
+    }
+
+    fn write_stage(&self, buf: &mut Vec<u8>, stage: &CodebookStage) -> Result<()> {
+        let (k, dim) = stage.centroids.dim();
+        buf.push(stage.stage_id);
+        write_varint(buf, k as u64);
+        write_varint(buf, dim as u64);
+        write_varint(buf, stage.iterations as u64);
+        buf.extend_from_slice(&stage.inertia.to_le_bytes());
+        for &value in stage.centroids.iter() {
+            match self.centroid_precision {
+                CentroidPrecision::F32 => buf.extend_from_slice(&value.to_le_bytes()),
+                CentroidPrecision::F16 => {
+                    buf.extend_from_slice(&f16::from_f32(value).to_le_bytes())
+                }
+            }
+        }
+
+        write_varint(buf, stage.assignments.len() as u64);
+        buf.extend_from_slice(&pack_assignments(&stage.assignments, k)?);
+        Ok(())
+    }
+}
+
+/// Reads one layer back from a packed container.
+pub struct PackedLayerReader<R> {
+    input: R,
+}
+
+impl<'a> PackedLayerReader<&'a [u8]> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { input: bytes }
+    }
+}
+
+impl<R: Read> PackedLayerReader<R> {
+    pub fn from_reader(input: R) -> Self {
+        Self { input }
+    }
+
+    pub fn read(mut self) -> Result<QuantizedLayer> {
+        let magic: [u8; 4] = self.array()?;
+        ensure!(magic == PACKED_LAYER_MAGIC, "not a packed novaq layer");
+        let version = u16::from_le_bytes(self.array()?);
+        ensure!(
+            version <= PACKED_LAYER_VERSION,
+            "packed layer version {version} is newer than supported version {PACKED_LAYER_VERSION}"
+        );
+        let centroid_precision = match self.byte()? {
+            0 => CentroidPrecision::F32,
+            1 => CentroidPrecision::F16,
+            tag => bail!("unknown centroid precision tag {tag}"),
+        };
+
+        let name = String::from_utf8(self.bytes()?).context("layer name is not utf-8")?;
+        let index = self.usize()?;
+        let rows = self.usize()?;
+        let cols = self.usize()?;
+        let seed = u64::from_le_bytes(self.array()?);
+        let quantization_time_us = self.varint()?;
+        let column_permutation = match self.byte()? {
+            0 => None,
+            1 => {
+                let len = self.usize()?;
+                Some((0..len).map(|_| self.usize()).collect::<Result<_>>()?)
+            }
+            tag => bail!("unknown column permutation tag {tag}"),
+        };
+
+        let normalization = self
+            .normalization()
+            .with_context(|| format!("read normalization of {name}"))?;
+
+        let subspace_count = self.usize()?;
+        let mut subspaces = Vec::with_capacity(subspace_count.min(cols));
+        for _ in 0..subspace_count {
+            let columns = self.usize()?..self.usize()?;
+            let residual_energy = self.f32()?;
+            let stage_count = self.usize()?;
+            let stages = (0..stage_count)
+                .map(|_| self.stage(centroid_precision, rows))
+                .collect::<Result<_>>()
+                .with_context(|| format!("read subspace {columns:?} of {name}"))?;
+            subspaces.push(QuantizedSubspace {
+                columns,
+                stages,
+                residual_energy,
+            });
+        }
+
+        let column_variances = self.f32_vec()?;
+        let row_variances = self.f32_vec()?;
+        let telemetry_count = self.usize()?;
+        let subspace_telemetry = (0..telemetry_count)
+            .map(|_| self.subspace_telemetry())
+            .collect::<Result<_>>()
+            .context("read subspace telemetry")?;
+        let mut descriptive: Descriptive =
+            serde_json::from_slice(&self.bytes()?).context("parse layer metrics and telemetry")?;
+        descriptive.telemetry.analysis.column_variances = column_variances;
+        descriptive.telemetry.analysis.row_variances = row_variances;
+        descriptive.telemetry.subspaces = subspace_telemetry;
+
+        let layer = QuantizedLayer {
+            name,
+            index,
+            rows,
+            cols,
+            seed,
+            normalization,
+            subspaces,
+            metrics: descriptive.metrics,
+            quantization_time_us,
+            telemetry: descriptive.telemetry,
+            allocation: descriptive.allocation,
+            column_permutation,
+        };
+        layer.validate()?;
+        Ok(layer)
+    }
+
+    fn normalization(&mut self) -> Result<NormalizationRecord> {
+        let column_means = self.f32_vec()?;
+        let column_stds = self.f32_vec()?;
+        let tag = self.byte()?;
+        ensure!(tag < 4, "unknown outlier tag {tag}");
+        let layout = match tag & 1 {
+            0 => OutlierLayout::Coordinate,
+            _ => OutlierLayout::CompressedRows,
+        };
+        let (precision, width) = match tag & 2 {
+            0 => (OutlierPrecision::F32, 4),
+            _ => (OutlierPrecision::F16, 2),
+        };
+        let count = self.usize()?;
+        let cols = self.usize()?;
+        let positions = self.bytes()?;
+        let values = self.exact(
+            count
+                .checked_mul(width)
+                .ok_or_else(|| anyhow!("outlier count {count} overflows"))?,
+        )?;
+        let outliers = SparseOutliers {
+            layout,
+            precision,
+            count,
+            cols,
+            positions,
+            values,
+        }
+        .decode()?;
+        Ok(NormalizationRecord {
+            column_means,
+            column_stds,
+            outliers,
+        })
+    }
+
+    fn stage(&mut self, precision: CentroidPrecision, rows: usize) -> Result<CodebookStage> {
+        let stage_id = self.byte()?;
+        let k = self.usize()?;
+        let dim = self.usize()?;
+        let iterations = self.usize()?;
+        let inertia = self.f32()?;
+        let width = match precision {
+            CentroidPrecision::F32 => 4,
+            CentroidPrecision::F16 => 2,
+        };
+        let len = k
+            .checked_mul(dim)
+            .and_then(|len| len.checked_mul(width))
+            .ok_or_else(|| anyhow!("codebook of {k}x{dim} overflows"))?;
+        let bytes = self.exact(len)?;
+        let values: Vec<f32> = match precision {
+            CentroidPrecision::F32 => bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            CentroidPrecision::F16 => bytes
+                .chunks_exact(2)
+                .map(|b| f16::from_le_bytes([b[0], b[1]]).to_f32())
+                .collect(),
+        };
+        let centroids = Array2::from_shape_vec((k, dim), values)?;
+
+        let count = self.usize()?;
+        ensure!(
+            count == rows,
+            "stage has {count} assignments for {rows} rows"
+        );
+        let packed = self.exact(packed_assignment_bytes(count, k))?;
+        let assignments = unpack_assignments(&packed, count, k)?;
+
+        Ok(CodebookStage {
+            stage_id,
+            centroids,
+            assignments,
+            iterations,
+            inertia,
+        })
+    }
+
+    fn subspace_telemetry(&mut self) -> Result<SubspaceTelemetry> {
+        let columns = self.usize()?..self.usize()?;
+        let stage_count = self.usize()?;
+        let stages = (0..stage_count)
+            .map(|_| {
+                Ok(StageTelemetry {
+                    stage_id: self.byte()?,
+                    iterations: self.usize()?,
+                    inertia: self.f32()?,
+                    residual_energy: self.f32()?,
+                    utilization: self.f32()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(SubspaceTelemetry {
+            columns,
+            stages,
+            rejected_stages: self.usize()?,
+            residual_energy: self.f32()?,
+            refinement_loss: self.f32_vec()?,
+        })
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    fn f32_vec(&mut self) -> Result<Vec<f32>> {
+        let len = self.usize()?;
+        let bytes = self.exact(
+            len.checked_mul(4)
+                .ok_or_else(|| anyhow!("length {len} overflows"))?,
+        )?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect())
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.usize()?;
+        self.exact(len)
+    }
+
+    fn exact(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        (&mut self.input)
+            .take(len as u64)
+            .read_to_end(&mut out)
+            .context("read packed layer")?;
+        ensure!(
+            out.len() == len,
+            "packed layer truncated: expected {len} bytes, found {}",
+            out.len()
+        );
+        Ok(out)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut out = [0u8; N];
+        self.input
+            .read_exact(&mut out)
+            .context("packed layer truncated")?;
+        Ok(out)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn usize(&mut self) -> Result<usize> {
+        let value = self.varint()?;
+        usize::try_from(value).map_err(|_| anyhow!("value {value} does not fit in usize"))
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("varint longer than 64 bits")
+    }
+}
+
+/// Bytes holding `count` assignments into a codebook of `k` centroids.
+pub(crate) fn packed_assignment_bytes(count: usize, k: usize) -> usize {
+    (count as u64 * bits_for_indices(k as u64)).div_ceil(8) as usize
+}
+
+/// Bit-packs assignments LSB first at [`bits_for_indices`]`(k)` bits each.
+pub(crate) fn pack_assignments(assignments: &[u16], k: usize) -> Result<Vec<u8>> {
+    let width = bits_for_indices(k as u64) as u32;
+    let mut packed = Vec::with_capacity(packed_assignment_bytes(assignments.len(), k));
+    let mut acc = 0u64;
+    let mut filled = 0u32;
+    for &assignment in assignments {
+        ensure!(
+            (assignment as usize) < k,
+            "assignment {assignment} outside codebook of {k}"
+        );
+        acc |= (assignment as u64) << filled;
+        filled += width;
+        while filled >= 8 {
+            packed.push(acc as u8);
+            acc >>= 8;
+            filled -= 8;
+        }
+    }
+    if filled > 0 {
+        packed.push(acc as u8);
+    }
+    Ok(packed)
+}
+
+/// Inverse of [`pack_assignments`].
+pub(crate) fn unpack_assignments(packed: &[u8], count: usize, k: usize) -> Result<Vec<u16>> {
+    let width = bits_for_indices(k as u64) as u32;
+    ensure!(
+        width <= 16,
+        "codebook of {k} centroids exceeds u16 assignments"
+    );
+    ensure!(
+        packed.len() == packed_assignment_bytes(count, k),
+        "{} bytes cannot hold {count} assignments of {width} bits",
+        packed.len()
+    );
+    let mask = (1u64 << width) - 1;
+    let mut assignments = Vec::with_capacity(count);
+    let mut bytes = packed.iter();
+    let mut acc = 0u64;
+    let mut filled = 0u32;
+    for _ in 0..count {
+        while filled < width {
+            let byte = bytes.next().expect("packed length checked");
+            acc |= (*byte as u64) << filled;
+            filled += 8;
+        }
+        let assignment = (acc & mask) as usize;
+        ensure!(
+            assignment < k.max(1),
+            "assignment {assignment} outside codebook of {k}"
+        );
+        assignments.push(assignment as u16);
+        acc >>= width;
+        filled -= width;
+    }
+    Ok(assignments)
+}
+
+fn precision_tag(precision: CentroidPrecision) -> u8 {
+    match precision {
+        CentroidPrecision::F32 => 0,
+        CentroidPrecision::F16 => 1,
+    }
+}
+
+fn write_subspace_telemetry(buf: &mut Vec<u8>, telemetry: &SubspaceTelemetry) {
+    write_varint(buf, telemetry.columns.start as u64);
+    write_varint(buf, telemetry.columns.end as u64);
+    write_varint(buf, telemetry.stages.len() as u64);
+    for stage in &telemetry.stages {
+        buf.push(stage.stage_id);
+        write_varint(buf, stage.iterations as u64);
+        for value in [stage.inertia, stage.residual_energy, stage.utilization] {
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    write_varint(buf, telemetry.rejected_stages as u64);
+    buf.extend_from_slice(&telemetry.residual_energy.to_le_bytes());
+    write_f32s(buf, &telemetry.refinement_loss);
+}
+
+fn write_f32s(buf: &mut Vec<u8>, values: &[f32]) {
+    write_varint(buf, values.len() as u64);
+    for value in values {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array2;
+    use novaq_core::{ColumnPermutation, QuantizationConfig, Quantizer};
+
+    fn layer() -> QuantizedLayer {
+        let weights = Array2::from_shape_fn((256, 12), |(r, c)| {
+            ((r * 7 + c * 3) % 11) as f32 * 0.1 - 0.5 + if r == 5 && c == 2 { 9.0 } else { 0.0 }
+        });
+        let config = QuantizationConfig {
+            level1_centroids: 8,
+            residual_centroids: vec![4],
+            column_permutation: ColumnPermutation::Variance,
+            outlier_percentile: 0.02,
+            max_subspace_dim: 4,
+            min_subspace_dim: 2,
+            ..QuantizationConfig::default()
+        };
+        Quantizer::new(config)
+            .unwrap()
+            .quantize_layer("blk.0.attn_q.weight", 3, &weights)
+            .unwrap()
+    }
+
+    fn packed(centroid_precision: CentroidPrecision) -> LayerEncoding {
+        LayerEncoding {
+            format: LayerFormat::Packed,
+            version: PACKED_LAYER_VERSION,
+            centroid_precision,
+        }
+    }
+
+    #[test]
+    fn packed_layers_round_trip_and_beat_json() {
+        let layer = layer();
+        assert!(!layer.normalization.outliers.is_empty());
+        let bytes = encode_layer(&layer, &packed(CentroidPrecision::F32)).unwrap();
+        let json = encode_layer(&layer, &LayerEncoding::default()).unwrap();
+        assert!(
+            bytes.len() * 2 < json.len(),
+            "{} vs {}",
+            bytes.len(),
+            json.len()
+        );
+
+        let decoded = decode_layer(&bytes, &packed(CentroidPrecision::F32)).unwrap();
+        assert_eq!(serde_json::to_vec(&decoded).unwrap(), json);
+        assert_eq!(decoded.dequantize(), layer.dequantize());
+    }
+
+    #[test]
+    fn half_precision_centroids_round_on_write() {
+        let layer = layer();
+        let bytes = encode_layer(&layer, &packed(CentroidPrecision::F16)).unwrap();
+        let full = encode_layer(&layer, &packed(CentroidPrecision::F32)).unwrap();
+        assert!(bytes.len() < full.len());
+
+        let decoded = decode_layer(&bytes, &packed(CentroidPrecision::F16)).unwrap();
+        for (original, stored) in layer.subspaces.iter().zip(&decoded.subspaces) {
+            for (a, b) in original.stages.iter().zip(&stored.stages) {
+                assert_eq!(a.assignments, b.assignments);
+                for (x, y) in a.centroids.iter().zip(b.centroids.iter()) {
+                    assert_eq!(f16::from_f32(*x).to_f32(), *y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_versions_and_truncation() {
+        let mut bytes = encode_layer(&layer(), &packed(CentroidPrecision::F32)).unwrap();
+        let truncated = &bytes[..bytes.len() - 3];
+        assert!(decode_layer(truncated, &packed(CentroidPrecision::F32)).is_err());
+
+        bytes[4..6].copy_from_slice(&(PACKED_LAYER_VERSION + 1).to_le_bytes());
+        let err = decode_layer(&bytes, &packed(CentroidPrecision::F32)).unwrap_err();
+        assert!(err.to_string().contains("newer"), "{err}");
+    }
+
+    #[test]
+    fn rejects_malformed_layers() {
+        // A 1x1 layer whose only codebook claims 2^62 one-wide centroids.
+        let mut bytes = PACKED_LAYER_MAGIC.to_vec();
+        bytes.extend_from_slice(&PACKED_LAYER_VERSION.to_le_bytes());
+        bytes.push(precision_tag(CentroidPrecision::F32));
+        write_bytes(&mut bytes, b"w");
+        for value in [0, 1, 1] {
+            write_varint(&mut bytes, value);
+        }
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        write_varint(&mut bytes, 0);
+        bytes.push(0);
+        write_f32s(&mut bytes, &[0.0]);
+        write_f32s(&mut bytes, &[1.0]);
+        bytes.push(0);
+        write_varint(&mut bytes, 0);
+        write_varint(&mut bytes, 1);
+        write_bytes(&mut bytes, &[]);
+        for value in [1, 0, 1] {
+            write_varint(&mut bytes, value);
+        }
+        bytes.extend_from_slice(&0f32.to_le_bytes());
+        write_varint(&mut bytes, 1);
+        bytes.push(1);
+        for value in [1 << 62, 1, 0] {
+            write_varint(&mut bytes, value);
+        }
+        bytes.extend_from_slice(&0f32.to_le_bytes());
+        let err = decode_layer(&bytes, &packed(CentroidPrecision::F32)).unwrap_err();
+        assert!(format!("{err:#}").contains("overflows"), "{err:#}");
+
+        // A well-formed container whose column order repeats a column.
+        let mut layer = layer();
+        layer.column_permutation = Some(vec![0; layer.cols]);
+        let bytes = encode_layer(&layer, &packed(CentroidPrecision::F32)).unwrap();
+        let err = decode_layer(&bytes, &packed(CentroidPrecision::F32)).unwrap_err();
+        assert!(err.to_string().contains("not a permutation"), "{err}");
+    }
+}
@@ -0,0 +1,775 @@
+//! PyTorch zip checkpoints (`torch.save` since 1.6), such as `pytorch_model.bin`.
+//!
+//! A checkpoint is a zip holding `<archive>/data.pkl` and one
+//! `<archive>/data/<key>` entry per storage. The pickle only describes
+//! tensors: it names their storage through persistent ids and rebuilds them
+//! with `torch._utils._rebuild_tensor_v2`. [`Unpickler`] runs just the
+//! opcodes those pickles use and resolves only an allowlist of globals, so a
+//! checkpoint can never make it call arbitrary code the way `pickle.load`
+//! would.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use ndarray::Array2;
+use tracing::{debug, info, instrument};
+
+use novaq_core::{QuantizationConfig, QuantizedModel, Quantizer};
+
+use crate::artifact::ArtifactWriter;
+use crate::dtype::TensorDType;
+use crate::safetensors_index::layer_order;
+
+const ZIP_MAGIC: &[u8; 4] = b"PK\x03\x04";
+/// Largest buffer reserved up front for a zip entry. Sizes come from the
+/// untrusted zip header; bigger entries grow the buffer as they are read.
+const MAX_ENTRY_PREALLOC: u64 = 64 << 20;
+/// Bytes the unpickler may spend copying values into and out of the memo.
+/// Each MEMOIZE and GET copies a value, so without a bound a small pickle
+/// that recalls a list into a longer list, level after level, grows
+/// exponentially.
+const MEMO_COPY_BUDGET: usize = 256 << 20;
+
+pub struct PyTorchLoader {
+    quantizer: Quantizer,
+}
+
+impl PyTorchLoader {
+    pub fn new(config: QuantizationConfig) -> Result<Self> {
+        Ok(Self {
+            quantizer: Quantizer::new(config)?,
+        })
+    }
+
+    /// Loads the floating point matrices of the checkpoint at `path`.
+    #[instrument(skip(self, writer), fields(path = %path.display()))]
+    pub fn load_from_path(
+        &self,
+        path: &Path,
+        writer: &mut ArtifactWriter,
+    ) -> Result<QuantizedModel> {
+        let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+        self.load_from_reader(BufReader::new(file), writer)
+    }
+
+    /// Loads a checkpoint from any seekable reader. The zip crate reads
+    /// synchronously, so unlike the streaming loaders this one is not async.
+    pub fn load_from_reader<R: Read + Seek>(
+        &self,
+        mut reader: R,
+        writer: &mut ArtifactWriter,
+    ) -> Result<QuantizedModel> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        ensure!(
+            &magic == ZIP_MAGIC,
+            "not a zip-based PyTorch checkpoint; legacy torch.save files must be re-saved with torch >= 1.6"
+        );
+        reader.rewind()?;
+
+        let mut archive = zip::ZipArchive::new(reader).context("invalid PyTorch zip")?;
+        let pickle_name = archive
+            .file_names()
+            .find(|name| *name == "data.pkl" || name.ends_with("/data.pkl"))
+            .ok_or_else(|| anyhow!("PyTorch zip has no data.pkl"))?
+            .to_string();
+        let prefix = pickle_name
+            .strip_suffix("data.pkl")
+            .unwrap_or_default()
+            .to_string();
+        if let Ok(byteorder) = read_entry(&mut archive, &format!("{prefix}byteorder")) {
+            ensure!(
+                byteorder == b"little",
+                "big-endian PyTorch checkpoints are not supported"
+            );
+        }
+
+        let pickle = read_entry(&mut archive, &pickle_name)?;
+        let root = Unpickler::new(&pickle)
+            .load()
+            .context("unpickle data.pkl")?;
+        let mut tensors = Vec::new();
+        collect_tensors(String::new(), root, &mut tensors);
+        tensors.sort_by(|a, b| layer_order(&a.0, &b.0));
+        info!(tensors = tensors.len(), "loading pytorch checkpoint");
+
+        // Tied weights share a storage; keep the last one read around.
+        let mut storage: Option<(String, Vec<u8>)> = None;
+        let mut layers = Vec::new();
+        for (name, tensor) in &tensors {
+            let [rows, cols] = tensor.size[..] else {
+                debug!(tensor = name, size = ?tensor.size, "skipping non-matrix tensor");
+                continue;
+            };
+            let Some(dtype) = tensor.storage.dtype else {
+                debug!(
+                    tensor = name,
+                    storage = tensor.storage.kind,
+                    "skipping non-float tensor"
+                );
+                continue;
+            };
+
+            let key = &tensor.storage.key;
+            if storage.as_ref().is_none_or(|(cached, _)| cached != key) {
+                let bytes = read_entry(&mut archive, &format!("{prefix}data/{key}"))
+                    .with_context(|| format!("read storage {key} of {name}"))?;
+                storage = Some((key.clone(), bytes));
+            }
+            let (_, bytes) = storage.as_ref().expect("storage was just read");
+            let matrix = tensor
+                .materialize(bytes, dtype, rows, cols)
+                .with_context(|| format!("rebuild tensor {name}"))?;
+
+            let quantized =
+                self.quantizer
+                    .quantize_layer(name, writer.manifest().layers.len(), &matrix)?;
+            debug!(
+                tensor = name,
+                subspaces = quantized.subspaces.len(),
+                "tensor quantized"
+            );
+            writer.write_layer(&quantized)?;
+            layers.push(quantized);
+        }
+
+        writer.flush()?;
+        Ok(QuantizedModel::from_layers(layers))
+    }
+}
+
+fn read_entry<R: Read + Seek>(archive: &mut zip::ZipArchive<R>, name: &str) -> Result<Vec<u8>> {
+    let mut entry = archive
+        .by_name(name)
+        .with_context(|| format!("PyTorch zip has no {name}"))?;
+    let mut bytes = Vec::with_capacity(entry.size().min(MAX_ENTRY_PREALLOC) as usize);
+    entry.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Flattens nested dictionaries, such as `{"state_dict": {...}}` training
+/// checkpoints, into dotted tensor names.
+fn collect_tensors(prefix: String, value: Value, out: &mut Vec<(String, TensorRef)>) {
+    match value {
+        Value::Tensor(tensor) => out.push((prefix, tensor)),
+        Value::Dict(items) => {
+            for (key, value) in items {
+                let key = match key {
+                    Value::Str(key) => key,
+                    Value::Int(key) => key.to_string(),
+                    _ => continue,
+                };
+                let name = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                collect_tensors(name, value, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// A storage named by a persistent id: `('storage', torch.FloatStorage,
+/// key, location, numel)`.
+#[derive(Debug, Clone, PartialEq)]
+struct StorageRef {
+    /// Storage class, such as `FloatStorage`.
+    kind: String,
+    /// Element type, for the floating point storages we decode.
+    dtype: Option<TensorDType>,
+    key: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct TensorRef {
+    storage: StorageRef,
+    /// Offset into the storage, in elements.
+    offset: usize,
+    size: Vec<usize>,
+    stride: Vec<usize>,
+}
+
+impl TensorRef {
+    /// Gathers the tensor's elements from its storage, honouring the stride,
+    /// so transposed views come out right.
+    fn materialize(
+        &self,
+        storage: &[u8],
+        dtype: TensorDType,
+        rows: usize,
+        cols: usize,
+    ) -> Result<Array2<f32>> {
+        let [row_stride, col_stride] = self.stride[..] else {
+            bail!(
+                "stride {:?} does not match size {:?}",
+                self.stride,
+                self.size
+            );
+        };
+        let width = dtype.bytes_per_element();
+        ensure!(
+            storage.len().is_multiple_of(width),
+            "storage of {} bytes holds partial {} elements",
+            storage.len(),
+            dtype.as_str()
+        );
+        let numel = storage.len() / width;
+        if rows == 0 || cols == 0 {
+            return Ok(Array2::zeros((rows, cols)));
+        }
+        let last = (rows - 1)
+            .checked_mul(row_stride)
+            .and_then(|n| n.checked_add((cols - 1).checked_mul(col_stride)?))
+            .and_then(|n| n.checked_add(self.offset))
+            .ok_or_else(|| anyhow!("tensor view overflows"))?;
+        ensure!(
+            last < numel,
+            "tensor view reaches element {last} of a {numel} element storage"
+        );
+
+        if col_stride == 1 && row_stride == cols {
+            let start = self.offset * width;
+            return dtype.decode_matrix(&storage[start..start + rows * cols * width], rows, cols);
+        }
+        let values = dtype.decode(&storage[self.offset * width..(last + 1) * width])?;
+        Ok(Array2::from_shape_fn((rows, cols), |(row, col)| {
+            values[row * row_stride + col * col_stride]
+        }))
+    }
+}
+
+/// Pickle values the restricted VM can build.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+    Tuple(Vec<Value>),
+    List(Vec<Value>),
+    Dict(Vec<(Value, Value)>),
+    Global(Global),
+    Storage(StorageRef),
+    Tensor(TensorRef),
+}
+
+/// The globals a state dict pickle may reference.
+#[derive(Debug, Clone, PartialEq)]
+enum Global {
+    OrderedDict,
+    RebuildTensorV2,
+    RebuildParameter,
+    Storage(String),
+}
+
+impl Global {
+    fn resolve(module: &str, name: &str) -> Result<Self> {
+        match (module, name) {
+            ("collections", "OrderedDict") => Ok(Self::OrderedDict),
+            ("torch._utils", "_rebuild_tensor_v2") => Ok(Self::RebuildTensorV2),
+            ("torch._utils", "_rebuild_parameter") => Ok(Self::RebuildParameter),
+            ("torch", storage) if storage.ends_with("Storage") => {
+                Ok(Self::Storage(storage.to_string()))
+            }
+            _ => Err(anyhow!("refusing to load global {module}.{name}")),
+        }
+    }
+
+    fn call(&self, args: Vec<Value>) -> Result<Value> {
+        match self {
+            Self::OrderedDict => {
+                ensure!(args.is_empty(), "OrderedDict takes no arguments here");
+                Ok(Value::Dict(Vec::new()))
+            }
+            // (storage, storage_offset, size, stride, requires_grad,
+            //  backward_hooks[, metadata])
+            Self::RebuildTensorV2 => {
+                ensure!(
+                    args.len() >= 4,
+                    "_rebuild_tensor_v2 takes at least 4 arguments"
+                );
+                let mut args = args.into_iter();
+                let Some(Value::Storage(storage)) = args.next() else {
+                    bail!("_rebuild_tensor_v2 expects a storage");
+                };
+                let offset = args.next().expect("checked length").as_usize()?;
+                let size = args.next().expect("checked length").as_usizes()?;
+                let stride = args.next().expect("checked length").as_usizes()?;
+                ensure!(
+                    size.len() == stride.len(),
+                    "tensor size {size:?} and stride {stride:?} disagree"
+                );
+                Ok(Value::Tensor(TensorRef {
+                    storage,
+                    offset,
+                    size,
+                    stride,
+                }))
+            }
+            // (data, requires_grad, backward_hooks): a parameter is its tensor.
+            Self::RebuildParameter => match args.into_iter().next() {
+                Some(tensor @ Value::Tensor(_)) => Ok(tensor),
+                _ => Err(anyhow!("_rebuild_parameter expects a tensor")),
+            },
+            Self::Storage(kind) => Err(anyhow!("{kind} cannot be called")),
+        }
+    }
+}
+
+impl Value {
+    /// Approximate memory the value takes: one `Value` per node plus the
+    /// contents of strings and byte strings.
+    fn footprint(&self) -> usize {
+        let contents = match self {
+            Self::Str(text) => text.len(),
+            Self::Bytes(bytes) => bytes.len(),
+            Self::Tuple(items) | Self::List(items) => items.iter().map(Self::footprint).sum(),
+            Self::Dict(items) => items
+                .iter()
+                .map(|(key, value)| key.footprint() + value.footprint())
+                .sum(),
+            _ => 0,
+        };
+        std::mem::size_of::<Self>() + contents
+    }
+
+    /// Clones the value, charging its footprint to `budget`.
+    fn clone_within(&self, budget: &mut usize) -> Result<Self> {
+        let footprint = self.footprint();
+        ensure!(
+            footprint <= *budget,
+            "pickle memo copies exceed {MEMO_COPY_BUDGET} bytes"
+        );
+        *budget -= footprint;
+        Ok(self.clone())
+    }
+
+    fn as_usize(&self) -> Result<usize> {
+        match *self {
+            Self::Int(value) => usize::try_from(value).context("negative tensor dimension"),
+            ref other => Err(anyhow!("expected an integer, found {other:?}")),
+        }
+    }
+
+    fn as_usizes(&self) -> Result<Vec<usize>> {
+        match self {
+            Self::Tuple(values) | Self::List(values) => values.iter().map(Self::as_usize).collect(),
+            other => Err(anyhow!("expected a tuple of integers, found {other:?}")),
+        }
+    }
+}
+
+/// A pickle VM that knows the opcodes `torch.save` emits for state dicts
+/// and nothing else.
+struct Unpickler<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    stack: Vec<Value>,
+    /// Stack lengths at each MARK.
+    marks: Vec<usize>,
+    memo: HashMap<u32, Value>,
+    /// What is left of [`MEMO_COPY_BUDGET`].
+    memo_budget: usize,
+}
+
+impl<'a> Unpickler<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            pos: 0,
+            stack: Vec::new(),
+            marks: Vec::new(),
+            memo: HashMap::new(),
+            memo_budget: MEMO_COPY_BUDGET,
+        }
+    }
+
+    fn load(mut self) -> Result<Value> {
+        loop {
+            let opcode = self.take(1)?[0];
+            match opcode {
+                0x80 => {
+                    let protocol = self.take(1)?[0];
+                    ensure!(protocol <= 5, "unsupported pickle protocol {protocol}");
+                }
+                // FRAME: a length hint for buffered readers.
+                0x95 => {
+                    self.take(8)?;
+                }
+                b'.' => return self.pop(),
+                b'(' => self.marks.push(self.stack.len()),
+                b'N' => self.stack.push(Value::None),
+                0x88 => self.stack.push(Value::Bool(true)),
+                0x89 => self.stack.push(Value::Bool(false)),
+                b'K' => {
+                    let value = self.take(1)?[0];
+                    self.stack.push(Value::Int(value.into()));
+                }
+                b'M' => {
+                    let value = u16::from_le_bytes(self.array()?);
+                    self.stack.push(Value::Int(value.into()));
+                }
+                b'J' => {
+                    let value = i32::from_le_bytes(self.array()?);
+                    self.stack.push(Value::Int(value.into()));
+                }
+                0x8a => {
+                    let len = self.take(1)?[0] as usize;
+                    let value = long_from_le(self.take(len)?)?;
+                    self.stack.push(Value::Int(value));
+                }
+                b'G' => {
+                    let value = f64::from_be_bytes(self.array()?);
+                    self.stack.push(Value::Float(value));
+                }
+                b'X' => {
+                    let len = u32::from_le_bytes(self.array()?) as usize;
+                    let text = self.text(len)?;
+                    self.stack.push(Value::Str(text));
+                }
+                0x8c => {
+                    let len = self.take(1)?[0] as usize;
+                    let text = self.text(len)?;
+                    self.stack.push(Value::Str(text));
+                }
+                0x8d => {
+                    let len = usize::try_from(u64::from_le_bytes(self.array()?))?;
+                    let text = self.text(len)?;
+                    self.stack.push(Value::Str(text));
+                }
+                b'U' => {
+                    let len = self.take(1)?[0] as usize;
+                    let bytes = self.take(len)?.to_vec();
+                    self.stack.push(Value::Bytes(bytes));
+                }
+                b'T' => {
+                    let len = u32::from_le_bytes(self.array()?) as usize;
+                    let bytes = self.take(len)?.to_vec();
+                    self.stack.push(Value::Bytes(bytes));
+                }
+                b'B' => {
+                    let len = u32::from_le_bytes(self.array()?) as usize;
+                    let bytes = self.take(len)?.to_vec();
+                    self.stack.push(Value::Bytes(bytes));
+                }
+                b'C' => {
+                    let len = self.take(1)?[0] as usize;
+                    let bytes = self.take(len)?.to_vec();
+                    self.stack.push(Value::Bytes(bytes));
+                }
+                b')' => self.stack.push(Value::Tuple(Vec::new())),
+                b't' => {
+                    let items = self.pop_mark()?;
+                    self.stack.push(Value::Tuple(items));
+                }
+                0x85..=0x87 => {
+                    let len = usize::from(opcode - 0x84);
+                    ensure!(self.stack.len() >= len, "stack underflow building a tuple");
+                    let items = self.stack.split_off(self.stack.len() - len);
+                    self.stack.push(Value::Tuple(items));
+                }
+                b']' => self.stack.push(Value::List(Vec::new())),
+                b'l' => {
+                    let items = self.pop_mark()?;
+                    self.stack.push(Value::List(items));
+                }
+                b'a' => {
+                    let item = self.pop()?;
+                    self.top_list()?.push(item);
+                }
+                b'e' => {
+                    let items = self.pop_mark()?;
+                    self.top_list()?.extend(items);
+                }
+                b'}' => self.stack.push(Value::Dict(Vec::new())),
+                b'd' => {
+                    let items = self.pop_mark()?;
+                    let dict = pairs(items)?;
+                    self.stack.push(Value::Dict(dict));
+                }
+                b's' => {
+                    let value = self.pop()?;
+                    let key = self.pop()?;
+                    self.top_dict()?.push((key, value));
+                }
+                b'u' => {
+                    let items = self.pop_mark()?;
+                    let items = pairs(items)?;
+                    self.top_dict()?.extend(items);
+                }
+                b'q' => {
+                    let index = self.take(1)?[0].into();
+                    self.memoize(index)?;
+                }
+                b'r' => {
+                    let index = u32::from_le_bytes(self.array()?);
+                    self.memoize(index)?;
+                }
+                0x94 => {
+                    let index = self.memo.len() as u32;
+                    self.memoize(index)?;
+                }
+                b'h' => {
+                    let index = self.take(1)?[0].into();
+                    self.recall(index)?;
+                }
+                b'j' => {
+                    let index = u32::from_le_bytes(self.array()?);
+                    self.recall(index)?;
+                }
+                b'c' => {
+                    let module = self.line()?;
+                    let name = self.line()?;
+                    self.stack
+                        .push(Value::Global(Global::resolve(&module, &name)?));
+                }
+                0x93 => {
+                    let name = self.pop()?;
+                    let module = self.pop()?;
+                    let (Value::Str(module), Value::Str(name)) = (module, name) else {
+                        bail!("STACK_GLOBAL expects two strings");
+                    };
+                    self.stack
+                        .push(Value::Global(Global::resolve(&module, &name)?));
+                }
+                b'Q' => {
+                    let pid = self.pop()?;
+                    self.stack.push(persistent_load(pid)?);
+                }
+                b'R' => {
+                    let args = match self.pop()? {
+                        Value::Tuple(args) => args,
+                        other => bail!("REDUCE expects an argument tuple, found {other:?}"),
+                    };
+                    let value = match self.pop()? {
+                        Value::Global(global) => global.call(args)?,
+                        other => bail!("REDUCE expects a global, found {other:?}"),
+                    };
+                    self.stack.push(value);
+                }
+                // BUILD: state such as an OrderedDict's `_metadata`, which
+                // does not affect the tensors.
+                b'b' => {
+                    self.pop()?;
+                    ensure!(!self.stack.is_empty(), "BUILD without an object");
+                }
+                other => bail!(
+                    "unsupported pickle opcode {:#04x} at byte {}",
+                    other,
+                    self.pos - 1
+                ),
+            }
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| anyhow!("pickle ends unexpectedly"))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+
+    fn text(&mut self, len: usize) -> Result<String> {
+        let bytes = self.take(len)?;
+        Ok(std::str::from_utf8(bytes)
+            .context("pickle string is not UTF-8")?
+            .to_string())
+    }
+
+    fn line(&mut self) -> Result<String> {
+        let rest = &self.bytes[self.pos..];
+        let len = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| anyhow!("unterminated GLOBAL"))?;
+        let text = self.text(len)?;
+        self.pos += 1;
+        Ok(text)
+    }
+
+    fn pop(&mut self) -> Result<Value> {
+        self.stack
+            .pop()
+            .ok_or_else(|| anyhow!("pickle stack underflow"))
+    }
+
+    fn pop_mark(&mut self) -> Result<Vec<Value>> {
+        let mark = self
+            .marks
+            .pop()
+            .ok_or_else(|| anyhow!("pickle has no MARK to pop to"))?;
+        ensure!(mark <= self.stack.len(), "pickle stack underflow");
+        Ok(self.stack.split_off(mark))
+    }
+
+    fn top_list(&mut self) -> Result<&mut Vec<Value>> {
+        match self.stack.last_mut() {
+            Some(Value::List(items)) => Ok(items),
+            _ => Err(anyhow!("APPEND expects a list")),
+        }
+    }
+
+    fn top_dict(&mut self) -> Result<&mut Vec<(Value, Value)>> {
+        match self.stack.last_mut() {
+            Some(Value::Dict(items)) => Ok(items),
+            _ => Err(anyhow!("SETITEM expects a dict")),
+        }
+    }
+
+    fn memoize(&mut self, index: u32) -> Result<()> {
+        let value = self
+            .stack
+            .last()
+            .ok_or_else(|| anyhow!("memoizing an empty stack"))?
+            .clone_within(&mut self.memo_budget)?;
+        self.memo.insert(index, value);
+        Ok(())
+    }
+
+    fn recall(&mut self, index: u32) -> Result<()> {
+        let value = self
+            .memo
+            .get(&index)
+            .ok_or_else(|| anyhow!("pickle memo has no entry {index}"))?
+            .clone_within(&mut self.memo_budget)?;
+        self.stack.push(value);
+        Ok(())
+    }
+}
+
+fn pairs(items: Vec<Value>) -> Result<Vec<(Value, Value)>> {
+    ensure!(items.len().is_multiple_of(2), "dict items come in pairs");
+    let mut items = items.into_iter();
+    let mut pairs = Vec::new();
+    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+        pairs.push((key, value));
+    }
+    Ok(pairs)
+}
+
+/// Two's complement little-endian integer of a LONG1 opcode.
+fn long_from_le(bytes: &[u8]) -> Result<i64> {
+    ensure!(
+        bytes.len() <= 8,
+        "integer of {} bytes is too large",
+        bytes.len()
+    );
+    if bytes.is_empty() {
+        return Ok(0);
+    }
+    let fill = if bytes[bytes.len() - 1] & 0x80 != 0 {
+        0xFF
+    } else {
+        0
+    };
+    let mut buf = [fill; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    Ok(i64::from_le_bytes(buf))
+}
+
+/// Resolves `('storage', storage_type, key, location, numel)`.
+fn persistent_load(pid: Value) -> Result<Value> {
+    let Value::Tuple(items) = pid else {
+        bail!("persistent id is not a tuple");
+    };
+    let [Value::Str(tag), Value::Global(Global::Storage(kind)), Value::Str(key), ..] = &items[..]
+    else {
+        bail!("unsupported persistent id {items:?}");
+    };
+    ensure!(tag == "storage", "unsupported persistent id tag {tag:?}");
+    let dtype = match kind.as_str() {
+        "FloatStorage" => Some(TensorDType::F32),
+        "HalfStorage" => Some(TensorDType::F16),
+        "BFloat16Storage" => Some(TensorDType::BF16),
+        "DoubleStorage" => Some(TensorDType::F64),
+        _ => None,
+    };
+    Ok(Value::Storage(StorageRef {
+        kind: kind.clone(),
+        dtype,
+        key: key.clone(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_globals_outside_the_allowlist() {
+        // cos\nsystem\n(S'echo hi'\ntR. in protocol 2 form.
+        let mut pickle = b"\x80\x02cos\nsystem\n".to_vec();
+        pickle.extend_from_slice(b"(X\x07\x00\x00\x00echo hitR.");
+        let err = Unpickler::new(&pickle).load().unwrap_err();
+        assert!(err.to_string().contains("os.system"), "{err}");
+
+        let mut pickle = b"\x80\x02ctorch._utils\n_rebuild_tensor_v2\n".to_vec();
+        pickle.push(b'.');
+        assert_eq!(
+            Unpickler::new(&pickle).load().unwrap(),
+            Value::Global(Global::RebuildTensorV2)
+        );
+    }
+
+    #[test]
+    fn bounds_memo_copies() {
+        // An empty list, then lists of ten copies of the previous one.
+        let mut pickle = b"\x80\x02]q\x00".to_vec();
+        for level in 1..=12u8 {
+            pickle.push(b'(');
+            for _ in 0..10 {
+                pickle.extend_from_slice(&[b'h', level - 1]);
+            }
+            pickle.extend_from_slice(&[b'l', b'q', level]);
+        }
+        pickle.push(b'.');
+        let err = Unpickler::new(&pickle).load().unwrap_err();
+        assert!(err.to_string().contains("memo copies"), "{err}");
+    }
+
+    #[test]
+    fn gathers_strided_views() {
+        let storage: Vec<u8> = (0..6u8).flat_map(|v| f32::from(v).to_le_bytes()).collect();
+        let tensor = TensorRef {
+            storage: StorageRef {
+                kind: "FloatStorage".into(),
+                dtype: Some(TensorDType::F32),
+                key: "0".into(),
+            },
+            offset: 0,
+            size: vec![3, 2],
+            stride: vec![1, 3],
+        };
+        // The transpose of [[0, 1, 2], [3, 4, 5]].
+        let matrix = tensor
+            .materialize(&storage, TensorDType::F32, 3, 2)
+            .unwrap();
+        assert_eq!(matrix.row(0).to_vec(), [0.0, 3.0]);
+        assert_eq!(matrix.row(2).to_vec(), [2.0, 5.0]);
+
+        let out_of_bounds = TensorRef {
+            offset: 1,
+            size: vec![2, 3],
+            stride: vec![3, 1],
+            ..tensor
+        };
+        assert!(out_of_bounds
+            .materialize(&storage, TensorDType::F32, 2, 3)
+            .is_err());
+    }
+}
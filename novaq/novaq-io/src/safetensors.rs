@@ -1,13 +1,27 @@
-use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, ensure, Context, Result};
 use ndarray::Array2;
-use half::{bf16, f16};
-use serde_json::Value;
+use serde_json::{Map, Value};
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
-use tracing::{debug, instrument};
+use tracing::{debug, info, instrument};
 
-use novaq_core::{QuantizationConfig, QuantizedModel, Quantizer};
+use novaq_core::{QuantizationConfig, QuantizedLayer, QuantizedModel, Quantizer};
 
 use crate::artifact::ArtifactWriter;
+use crate::dtype::{ScalePairing, TensorDType};
+use crate::safetensors_export::{decode_codebook_weights, is_codebook_header};
+use crate::safetensors_index::{
+    check_shard_header, layer_index, layer_indices, layer_order, SafeTensorsIndex,
+};
+
+/// An open shard of a sharded checkpoint.
+struct Shard {
+    reader: tokio::io::BufReader<tokio::fs::File>,
+    header: Map<String, Value>,
+    data_start: u64,
+}
 
 #[derive(Debug)]
 struct TensorInfo {
@@ -16,31 +30,6 @@ struct TensorInfo {
     dtype: TensorDType,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum TensorDType {
-    F32,
-    F16,
-    BF16,
-}
-
-impl TensorDType {
-    fn from_str(value: &str) -> Result<Self> {
-        match value {
-            "F32" => Ok(Self::F32),
-            "F16" => Ok(Self::F16),
-            "BF16" => Ok(Self::BF16),
-            other => Err(anyhow!("unsupported safetensors dtype: {other}")),
-        }
-    }
-
-    fn bytes_per_element(self) -> usize {
-        match self {
-            Self::F32 => 4,
-            Self::F16 | Self::BF16 => 2,
-        }
-    }
-}
-
 pub struct SafeTensorsLoader {
     quantizer: Quantizer,
 }
@@ -61,53 +50,168 @@ impl SafeTensorsLoader {
     where
         R: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin + Send,
     {
-        let header_size = reader.read_u64_le().await?;
-        let mut header_bytes = vec![0u8; header_size as usize];
-        reader.read_exact(&mut header_bytes).await?;
-        let metadata: Value = serde_json::from_slice(&header_bytes)
-            .context("invalid safetensors header")?;
-        let metadata = metadata
-            .as_object()
-            .ok_or_else(|| anyhow!("safetensors header is not a JSON object"))?;
-        let mut data_start = 8 + header_size;
-        let padding = data_start % 8;
-        if padding != 0 {
-            data_start += 8 - padding;
-        }
+        let (metadata, data_start) = read_header(&mut reader).await?;
+        let metadata = &metadata;
 
         let mut layers = Vec::new();
-        for (tensor_name, value) in metadata.iter() {
-            if tensor_name == "__metadata__" {
-                continue;
+        if is_codebook_header(metadata) {
+            // NOVAQ codebook exports hold components rather than weight
+            // matrices; rebuild the weights before quantizing them again.
+            reader.seek(std::io::SeekFrom::Start(data_start)).await?;
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).await?;
+            for (tensor_name, matrix) in decode_codebook_weights(metadata, &data)? {
+                let index = writer.manifest().layers.len();
+                layers.push(self.quantize_tensor(&tensor_name, index, &matrix, writer)?);
             }
-            let info = TensorInfo::from_value(value)
-                .with_context(|| format!("invalid tensor header for {tensor_name}"))?;
+            writer.flush()?;
+            return Ok(QuantizedModel::from_layers(layers));
+        }
+
+        let mut tensors = metadata
+            .iter()
+            .filter(|(tensor_name, _)| *tensor_name != "__metadata__")
+            .map(|(tensor_name, value)| {
+                let info = TensorInfo::from_value(value)
+                    .with_context(|| format!("invalid tensor header for {tensor_name}"))?;
+                Ok((tensor_name.as_str(), info))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        tensors.sort_by(|a, b| layer_order(a.0, b.0));
+        let mut pairing = ScalePairing::new(tensors.iter().map(|(name, _)| *name));
+        let indices = layer_indices(
+            tensors.iter().map(|(name, info)| (*name, info.shape.len())),
+            &pairing,
+            writer.manifest().layers.len(),
+        );
+        for (tensor_name, info) in &tensors {
             debug!(tensor = tensor_name, "loading tensor from safetensors");
-            if info.shape.len() != 2 {
-                debug!(tensor = tensor_name, shape = ?info.shape, "skipping non-matrix tensor");
-                continue;
+            let weight =
+                read_weight(&mut reader, tensor_name, info, data_start, &mut pairing).await?;
+            if let Some((weight, matrix)) = weight {
+                let index = layer_index(&indices, &weight)?;
+                layers.push(self.quantize_tensor(&weight, index, &matrix, writer)?);
             }
+        }
+        pairing.finish()?;
 
-            let matrix = read_tensor_matrix(&mut reader, &info, data_start).await?;
+        writer.flush()?;
 
-            let quantized = self.quantizer.quantize_layer(
-                tensor_name,
-                writer.manifest().chunks.len(),
-                &matrix,
-            )?;
-            debug!(
-                tensor = tensor_name,
-                subspaces = quantized.subspaces.len(),
-                "tensor quantized"
+        Ok(QuantizedModel::from_layers(layers))
+    }
+
+    /// Loads a sharded checkpoint from its `model.safetensors.index.json`.
+    /// Tensors are quantized in layer order across all shards, so the layer
+    /// indices, and with them the layer seeds, do not depend on the split.
+    #[instrument(skip(self, writer), fields(index = %index_path.display()))]
+    pub async fn load_from_index(
+        &self,
+        index_path: &Path,
+        writer: &mut ArtifactWriter,
+    ) -> Result<QuantizedModel> {
+        let bytes = tokio::fs::read(index_path)
+            .await
+            .with_context(|| format!("read {}", index_path.display()))?;
+        let index = SafeTensorsIndex::from_slice(&bytes)?;
+        let dir = index_path.parent().unwrap_or(Path::new("."));
+        self.load_shards(&index, |file| Ok(dir.join(file)), writer)
+            .await
+    }
+
+    /// Quantizes the tensors of `index` in layer order, opening each shard
+    /// at the path `shard_path` gives for its file name.
+    pub async fn load_shards(
+        &self,
+        index: &SafeTensorsIndex,
+        shard_path: impl Fn(&str) -> Result<PathBuf>,
+        writer: &mut ArtifactWriter,
+    ) -> Result<QuantizedModel> {
+        info!(
+            shards = index.shard_files().len(),
+            tensors = index.weight_map.len(),
+            "loading sharded safetensors"
+        );
+
+        let mut shards: HashMap<&str, Shard> = HashMap::new();
+        for file in index.shard_files() {
+            let path = shard_path(file)?;
+            let file_handle = tokio::fs::File::open(&path)
+                .await
+                .with_context(|| format!("open shard {}", path.display()))?;
+            let mut reader = tokio::io::BufReader::new(file_handle);
+            let (header, data_start) = read_header(&mut reader)
+                .await
+                .with_context(|| format!("read header of shard {file}"))?;
+            if is_codebook_header(&header) {
+                return Err(anyhow!("shard {file} is a NOVAQ codebook export"));
+            }
+            check_shard_header(index, file, &header)?;
+            debug!(shard = file, "opened shard");
+            shards.insert(
+                file,
+                Shard {
+                    reader,
+                    header,
+                    data_start,
+                },
             );
+        }
 
-            let serialized = serde_json::to_vec(&quantized).context("serialize quantized layer")?;
-            writer.write_chunk(&serialized)?;
-            layers.push(quantized);
+        let mut tensors = Vec::with_capacity(index.weight_map.len());
+        for (tensor_name, file) in index.tensors_in_layer_order() {
+            let info = TensorInfo::from_value(&shards[file].header[tensor_name])
+                .with_context(|| format!("invalid tensor header for {tensor_name}"))?;
+            tensors.push((tensor_name, file, info));
         }
+        let mut pairing = ScalePairing::new(index.weight_map.keys().map(String::as_str));
+        let indices = layer_indices(
+            tensors
+                .iter()
+                .map(|(tensor_name, _, info)| (*tensor_name, info.shape.len())),
+            &pairing,
+            writer.manifest().layers.len(),
+        );
+        let mut layers = Vec::new();
+        for (tensor_name, file, info) in &tensors {
+            let shard = shards.get_mut(file).expect("every shard was opened");
+            let weight = read_weight(
+                &mut shard.reader,
+                tensor_name,
+                info,
+                shard.data_start,
+                &mut pairing,
+            )
+            .await
+            .with_context(|| format!("read {tensor_name} from {file}"))?;
+            if let Some((weight, matrix)) = weight {
+                let index = layer_index(&indices, &weight)?;
+                layers.push(self.quantize_tensor(&weight, index, &matrix, writer)?);
+            }
+        }
+        pairing.finish()?;
+
+        writer.flush()?;
 
         Ok(QuantizedModel::from_layers(layers))
     }
+
+    fn quantize_tensor(
+        &self,
+        tensor_name: &str,
+        index: usize,
+        matrix: &Array2<f32>,
+        writer: &mut ArtifactWriter,
+    ) -> Result<QuantizedLayer> {
+        let quantized = self.quantizer.quantize_layer(tensor_name, index, matrix)?;
+        debug!(
+            tensor = tensor_name,
+            subspaces = quantized.subspaces.len(),
+            "tensor quantized"
+        );
+
+        writer.write_layer(&quantized)?;
+        Ok(quantized)
+    }
 }
 
 impl TensorInfo {
@@ -153,9 +257,83 @@ impl TensorInfo {
         Ok(Self {
             shape,
             data_offsets: [start, end],
-            dtype: TensorDType::from_str(dtype)?,
+            dtype: dtype.parse::<TensorDType>()?,
         })
     }
+
+    /// Where the tensor's data starts in the file and how many bytes it
+    /// spans, after checking that span against its shape and dtype.
+    fn byte_range(&self, data_start: u64) -> Result<(u64, usize)> {
+        let [start, end] = self.data_offsets;
+        ensure!(end >= start, "tensor data_offsets end before they start");
+        let len = self
+            .shape
+            .iter()
+            .try_fold(self.dtype.bytes_per_element(), |len, &dim| {
+                len.checked_mul(dim)
+            })
+            .ok_or_else(|| anyhow!("tensor shape {:?} overflows", self.shape))?;
+        ensure!(
+            end - start == len as u64,
+            "tensor byte length {} does not match shape {:?}",
+            end - start,
+            self.shape
+        );
+        let start = data_start
+            .checked_add(start)
+            .ok_or_else(|| anyhow!("tensor data offset {start} overflows"))?;
+        Ok((start, len))
+    }
+}
+
+/// Reads the JSON header of a safetensors file, returning it with the offset
+/// its data section starts at.
+async fn read_header<R>(reader: &mut R) -> Result<(Map<String, Value>, u64)>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let header_size = reader.read_u64_le().await?;
+    let mut header_bytes = vec![0u8; header_size as usize];
+    reader.read_exact(&mut header_bytes).await?;
+    let metadata: Value =
+        serde_json::from_slice(&header_bytes).context("invalid safetensors header")?;
+    let Value::Object(metadata) = metadata else {
+        return Err(anyhow!("safetensors header is not a JSON object"));
+    };
+    let mut data_start = 8 + header_size;
+    let padding = data_start % 8;
+    if padding != 0 {
+        data_start += 8 - padding;
+    }
+    Ok((metadata, data_start))
+}
+
+/// Reads one tensor, returning a weight matrix once it is complete. Weights
+/// with a `weight_scale` companion wait for their scale and vice versa; other
+/// non-matrix tensors are skipped.
+async fn read_weight<R>(
+    reader: &mut R,
+    tensor_name: &str,
+    info: &TensorInfo,
+    data_start: u64,
+    pairing: &mut ScalePairing,
+) -> Result<Option<(String, Array2<f32>)>>
+where
+    R: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin,
+{
+    if pairing.is_scale(tensor_name) {
+        let values = read_tensor_values(reader, info, data_start).await?;
+        return pairing.scale(tensor_name, &info.shape, values);
+    }
+    if info.shape.len() != 2 {
+        debug!(tensor = tensor_name, shape = ?info.shape, "skipping non-matrix tensor");
+        return Ok(None);
+    }
+
+    let matrix = read_tensor_matrix(reader, info, data_start).await?;
+    Ok(pairing
+        .weight(tensor_name, matrix)?
+        .map(|matrix| (tensor_name.to_string(), matrix)))
 }
 
 async fn read_tensor_matrix<R>(
@@ -166,51 +344,38 @@ async fn read_tensor_matrix<R>(
 where
     R: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin,
 {
-    let start = data_start + info.data_offsets[0];
-    let end = data_start + info.data_offsets[1];
-    let len = (end - start) as usize;
+    let (start, _) = info.byte_range(data_start)?;
     let rows = info.shape[0];
     let cols = info.shape[1];
     let bytes_per_element = info.dtype.bytes_per_element();
-    let expected_len = rows
-        .checked_mul(cols)
-        .ok_or_else(|| anyhow!("tensor shape overflow"))?
-        * bytes_per_element;
-    if len != expected_len {
-        return Err(anyhow!(
-            "tensor byte length {} does not match shape {:?}",
-            len,
-            info.shape
-        ));
-    }
 
     reader.seek(std::io::SeekFrom::Start(start)).await?;
     let mut matrix = Array2::<f32>::zeros((rows, cols));
     let mut row_bytes = vec![0u8; cols * bytes_per_element];
     for row in 0..rows {
         reader.read_exact(&mut row_bytes).await?;
-        match info.dtype {
-            TensorDType::F32 => {
-                for (col, chunk) in row_bytes.chunks_exact(4).enumerate() {
-                    let value = f32::from_le_bytes(chunk.try_into().unwrap());
-                    matrix[(row, col)] = value;
-                }
-            }
-            TensorDType::F16 => {
-                for (col, chunk) in row_bytes.chunks_exact(2).enumerate() {
-                    let bits = u16::from_le_bytes(chunk.try_into().unwrap());
-                    let value = f16::from_bits(bits).to_f32();
-                    matrix[(row, col)] = value;
-                }
-            }
-            TensorDType::BF16 => {
-                for (col, chunk) in row_bytes.chunks_exact(2).enumerate() {
-                    let bits = u16::from_le_bytes(chunk.try_into().unwrap());
-                    let value = bf16::from_bits(bits).to_f32();
-                    matrix[(row, col)] = value;
-                }
-            }
-        }
+        let row_values = matrix
+            .row_mut(row)
+            .into_slice()
+            .expect("rows are contiguous");
+        info.dtype.decode_into(&row_bytes, row_values)?;
     }
     Ok(matrix)
 }
+
+/// Reads a tensor of any shape as a flat vector, for small companion tensors
+/// such as weight scales.
+async fn read_tensor_values<R>(
+    reader: &mut R,
+    info: &TensorInfo,
+    data_start: u64,
+) -> Result<Vec<f32>>
+where
+    R: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin,
+{
+    let (start, len) = info.byte_range(data_start)?;
+    reader.seek(std::io::SeekFrom::Start(start)).await?;
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes).await?;
+    info.dtype.decode(&bytes)
+}
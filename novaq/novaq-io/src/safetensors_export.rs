@@ -0,0 +1,550 @@
+//! Writes quantized models as `.safetensors` files.
+//!
+//! The dequantized modes store every layer as an F16 or BF16 matrix under its
+//! own name, ready for Hugging Face tooling. [`SafeTensorsExportMode::Codebook`]
+//! stores the NOVAQ components instead, as tensors named after the layer:
+//!
+//! - `{layer}.novaq.column_means` and `{layer}.novaq.column_stds`: F32 `[cols]`
+//! - `{layer}.novaq.outlier_positions`: I64 `[n]` flat `row * cols + col`
+//!   indices, and `{layer}.novaq.outlier_values`: F32 `[n]`, when the layer
+//!   has outliers
+//! - `{layer}.novaq.column_permutation`: I64 `[cols]`, when permuted
+//! - `{layer}.novaq.subspace{s}.stage{t}.centroids`: F32 `[k, dim]`
+//! - `{layer}.novaq.subspace{s}.stage{t}.assignments`: U8, one index per row
+//!   bit-packed LSB first at `ceil(log2 k)` bits
+//!
+//! The `__metadata__` header marks such files with `format = novaq` and lists
+//! each layer's shape and subspace column ranges under `novaq.layers`.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use half::{bf16, f16};
+use ndarray::Array2;
+use novaq_core::{
+    reconstruct_weights, CodebookStage, NormalizationRecord, OutlierEntry, QuantizedLayer,
+    QuantizedModel, QuantizedSubspace,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tracing::{debug, instrument};
+
+use crate::packed::{pack_assignments, unpack_assignments};
+
+/// `__metadata__["format"]` of codebook exports.
+pub const NOVAQ_SAFETENSORS_FORMAT: &str = "novaq";
+
+/// Version of the codebook tensor naming and packing scheme.
+const SCHEME_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SafeTensorsExportMode {
+    /// Dequantized weights as F16 matrices.
+    F16,
+    /// Dequantized weights as BF16 matrices.
+    BF16,
+    /// Codebooks, packed assignments and normalization statistics.
+    Codebook,
+}
+
+/// One layer's entry in `novaq.layers`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LayerScheme {
+    name: String,
+    rows: usize,
+    cols: usize,
+    /// `[start, end)` permuted column range of each subspace.
+    subspaces: Vec<[usize; 2]>,
+    /// Codebook stages of each subspace.
+    stages: Vec<usize>,
+}
+
+pub struct SafeTensorsExporter {
+    mode: SafeTensorsExportMode,
+    metadata: BTreeMap<String, String>,
+}
+
+impl SafeTensorsExporter {
+    pub fn new(mode: SafeTensorsExportMode) -> Self {
+        Self {
+            mode,
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    /// Extra `__metadata__` entries, such as the manifest metadata of the
+    /// artifact being exported.
+    pub fn with_metadata(mut self, metadata: BTreeMap<String, String>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    #[instrument(skip(self, model, out), fields(mode = ?self.mode))]
+    pub fn write<W: Write>(&self, model: &QuantizedModel, mut out: W) -> Result<()> {
+        let mut tensors = TensorTable::default();
+        let mut metadata = self.metadata.clone();
+        match self.mode {
+            SafeTensorsExportMode::F16 | SafeTensorsExportMode::BF16 => {
+                metadata.insert("format".into(), "pt".into());
+                for layer in &model.layers {
+                    self.push_dequantized(&mut tensors, layer);
+                }
+            }
+            SafeTensorsExportMode::Codebook => {
+                let schemes = model
+                    .layers
+                    .iter()
+                    .map(|layer| push_codebook_layer(&mut tensors, layer))
+                    .collect::<Result<Vec<_>>>()?;
+                metadata.insert("format".into(), NOVAQ_SAFETENSORS_FORMAT.into());
+                metadata.insert("novaq.scheme_version".into(), SCHEME_VERSION.to_string());
+                metadata.insert(
+                    "novaq.layers".into(),
+                    serde_json::to_string(&schemes).context("serialize layer schemes")?,
+                );
+            }
+        }
+
+        let mut header = Map::new();
+        header.insert("__metadata__".into(), json!(metadata));
+        let mut offset = 0usize;
+        for tensor in &tensors.entries {
+            let end = offset + tensor.data.len();
+            header.insert(
+                tensor.name.clone(),
+                json!({ "dtype": tensor.dtype, "shape": tensor.shape, "data_offsets": [offset, end] }),
+            );
+            offset = end;
+        }
+        let mut header = serde_json::to_vec(&header).context("serialize safetensors header")?;
+        // Readers expect the data section to start 8-byte aligned; the format
+        // pads the header with spaces for that.
+        header.resize(header.len().next_multiple_of(8), b' ');
+
+        out.write_all(&(header.len() as u64).to_le_bytes())?;
+        out.write_all(&header)?;
+        for tensor in &tensors.entries {
+            out.write_all(&tensor.data)
+                .with_context(|| format!("write tensor {}", tensor.name))?;
+        }
+        debug!(
+            tensors = tensors.entries.len(),
+            bytes = 8 + header.len() + offset,
+            "wrote safetensors"
+        );
+        Ok(())
+    }
+
+    fn push_dequantized(&self, tensors: &mut TensorTable, layer: &QuantizedLayer) {
+        let weights = layer.dequantize();
+        let mut data = Vec::with_capacity(weights.len() * 2);
+        let dtype = match self.mode {
+            SafeTensorsExportMode::BF16 => {
+                for value in weights.iter() {
+                    data.extend_from_slice(&bf16::from_f32(*value).to_le_bytes());
+                }
+                "BF16"
+            }
+            _ => {
+                for value in weights.iter() {
+                    data.extend_from_slice(&f16::from_f32(*value).to_le_bytes());
+                }
+                "F16"
+            }
+        };
+        tensors.push(&layer.name, dtype, vec![layer.rows, layer.cols], data);
+    }
+}
+
+#[derive(Default)]
+struct TensorTable {
+    entries: Vec<TensorEntry>,
+}
+
+struct TensorEntry {
+    name: String,
+    dtype: &'static str,
+    shape: Vec<usize>,
+    data: Vec<u8>,
+}
+
+impl TensorTable {
+    fn push(&mut self, name: &str, dtype: &'static str, shape: Vec<usize>, data: Vec<u8>) {
+        self.entries.push(TensorEntry {
+            name: name.to_string(),
+            dtype,
+            shape,
+            data,
+        });
+    }
+
+    fn push_f32(&mut self, name: &str, shape: Vec<usize>, values: impl IntoIterator<Item = f32>) {
+        let data = values.into_iter().flat_map(f32::to_le_bytes).collect();
+        self.push(name, "F32", shape, data);
+    }
+
+    fn push_i64(&mut self, name: &str, values: &[usize]) {
+        let data = values
+            .iter()
+            .flat_map(|&v| (v as i64).to_le_bytes())
+            .collect();
+        self.push(name, "I64", vec![values.len()], data);
+    }
+}
+
+fn push_codebook_layer(tensors: &mut TensorTable, layer: &QuantizedLayer) -> Result<LayerScheme> {
+    let name = &layer.name;
+    let norm = &layer.normalization;
+    tensors.push_f32(
+        &format!("{name}.novaq.column_means"),
+        vec![norm.column_means.len()],
+        norm.column_means.iter().copied(),
+    );
+    tensors.push_f32(
+        &format!("{name}.novaq.column_stds"),
+        vec![norm.column_stds.len()],
+        norm.column_stds.iter().copied(),
+    );
+    if !norm.outliers.is_empty() {
+        let positions: Vec<usize> = norm
+            .outliers
+            .iter()
+            .map(|o| o.row * layer.cols + o.col)
+            .collect();
+        tensors.push_i64(&format!("{name}.novaq.outlier_positions"), &positions);
+        tensors.push_f32(
+            &format!("{name}.novaq.outlier_values"),
+            vec![positions.len()],
+            norm.outliers.iter().map(|o| o.value),
+        );
+    }
+    if let Some(permutation) = &layer.column_permutation {
+        tensors.push_i64(&format!("{name}.novaq.column_permutation"), permutation);
+    }
+
+    for (s, subspace) in layer.subspaces.iter().enumerate() {
+        for (t, stage) in subspace.stages.iter().enumerate() {
+            let prefix = format!("{name}.novaq.subspace{s}.stage{t}");
+            let (k, dim) = stage.centroids.dim();
+            tensors.push_f32(
+                &format!("{prefix}.centroids"),
+                vec![k, dim],
+                stage.centroids.iter().copied(),
+            );
+            let packed = pack_assignments(&stage.assignments, k)
+                .with_context(|| format!("pack assignments of {prefix}"))?;
+            let len = packed.len();
+            tensors.push(&format!("{prefix}.assignments"), "U8", vec![len], packed);
+        }
+    }
+
+    Ok(LayerScheme {
+        name: name.clone(),
+        rows: layer.rows,
+        cols: layer.cols,
+        subspaces: layer
+            .subspaces
+            .iter()
+            .map(|s| [s.columns.start, s.columns.end])
+            .collect(),
+        stages: layer.subspaces.iter().map(|s| s.stages.len()).collect(),
+    })
+}
+
+/// Whether a parsed safetensors header belongs to a codebook export.
+pub(crate) fn is_codebook_header(header: &Map<String, Value>) -> bool {
+    header
+        .get("__metadata__")
+        .and_then(|m| m.get("format"))
+        .and_then(Value::as_str)
+        == Some(NOVAQ_SAFETENSORS_FORMAT)
+}
+
+/// Rebuilds the dense weights of every layer in a codebook export from its
+/// header and data section, in the order the layers were written.
+pub(crate) fn decode_codebook_weights(
+    header: &Map<String, Value>,
+    data: &[u8],
+) -> Result<Vec<(String, Array2<f32>)>> {
+    let metadata = header
+        .get("__metadata__")
+        .ok_or_else(|| anyhow!("codebook safetensors without __metadata__"))?;
+    let version = metadata
+        .get("novaq.scheme_version")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("codebook safetensors without novaq.scheme_version"))?;
+    ensure!(
+        version == SCHEME_VERSION.to_string(),
+        "unsupported NOVAQ safetensors scheme version {version}"
+    );
+    let schemes: Vec<LayerScheme> = serde_json::from_str(
+        metadata
+            .get("novaq.layers")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("codebook safetensors without novaq.layers"))?,
+    )
+    .context("parse novaq.layers")?;
+
+    let tensors = TensorView { header, data };
+    schemes
+        .into_iter()
+        .map(|scheme| {
+            let weights = tensors
+                .layer_weights(&scheme)
+                .with_context(|| format!("decode codebook layer {}", scheme.name))?;
+            Ok((scheme.name, weights))
+        })
+        .collect()
+}
+
+struct TensorView<'a> {
+    header: &'a Map<String, Value>,
+    data: &'a [u8],
+}
+
+impl<'a> TensorView<'a> {
+    fn tensor(&self, name: &str, dtype: &str) -> Result<Option<(Vec<usize>, &'a [u8])>> {
+        let Some(entry) = self.header.get(name) else {
+            return Ok(None);
+        };
+        let found = entry.get("dtype").and_then(Value::as_str);
+        ensure!(
+            found == Some(dtype),
+            "{name} is {found:?}, expected {dtype}"
+        );
+        let shape: Vec<usize> = serde_json::from_value(entry["shape"].clone())
+            .with_context(|| format!("shape of {name}"))?;
+        let [start, end]: [usize; 2] = serde_json::from_value(entry["data_offsets"].clone())
+            .with_context(|| format!("data_offsets of {name}"))?;
+        let bytes = self
+            .data
+            .get(start..end)
+            .filter(|_| start <= end)
+            .ok_or_else(|| anyhow!("{name} spans {start}..{end} past the data section"))?;
+        Ok(Some((shape, bytes)))
+    }
+
+    fn required(&self, name: &str, dtype: &str) -> Result<(Vec<usize>, &'a [u8])> {
+        self.tensor(name, dtype)?
+            .ok_or_else(|| anyhow!("missing tensor {name}"))
+    }
+
+    fn f32s(&self, name: &str) -> Result<Option<Vec<f32>>> {
+        Ok(self.tensor(name, "F32")?.map(|(_, bytes)| {
+            bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().expect("4 bytes")))
+                .collect()
+        }))
+    }
+
+    fn indices(&self, name: &str) -> Result<Option<Vec<usize>>> {
+        let Some((_, bytes)) = self.tensor(name, "I64")? else {
+            return Ok(None);
+        };
+        bytes
+            .chunks_exact(8)
+            .map(|b| {
+                let value = i64::from_le_bytes(b.try_into().expect("8 bytes"));
+                usize::try_from(value).map_err(|_| anyhow!("negative index {value} in {name}"))
+            })
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+
+    fn layer_weights(&self, scheme: &LayerScheme) -> Result<Array2<f32>> {
+        let name = &scheme.name;
+        let (rows, cols) = (scheme.rows, scheme.cols);
+        let column = |suffix: &str| -> Result<Vec<f32>> {
+            let values = self
+                .f32s(&format!("{name}.novaq.{suffix}"))?
+                .ok_or_else(|| anyhow!("missing tensor {name}.novaq.{suffix}"))?;
+            ensure!(
+                values.len() == cols,
+                "{suffix} holds {} values",
+                values.len()
+            );
+            Ok(values)
+        };
+        let positions = self
+            .indices(&format!("{name}.novaq.outlier_positions"))?
+            .unwrap_or_default();
+        let values = self
+            .f32s(&format!("{name}.novaq.outlier_values"))?
+            .unwrap_or_default();
+        ensure!(
+            positions.len() == values.len(),
+            "{} outlier positions for {} values",
+            positions.len(),
+            values.len()
+        );
+        let outliers = positions
+            .into_iter()
+            .zip(values)
+            .map(|(position, value)| {
+                ensure!(
+                    position < rows * cols,
+                    "outlier position {position} out of range"
+                );
+                Ok(OutlierEntry {
+                    row: position / cols,
+                    col: position % cols,
+                    value,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let normalization = NormalizationRecord {
+            column_means: column("column_means")?,
+            column_stds: column("column_stds")?,
+            outliers,
+        };
+        let permutation = self.indices(&format!("{name}.novaq.column_permutation"))?;
+        if let Some(permutation) = &permutation {
+            ensure!(
+                permutation.len() == cols,
+                "column permutation of wrong length"
+            );
+        }
+
+        ensure!(
+            scheme.subspaces.len() == scheme.stages.len(),
+            "subspace and stage lists differ in length"
+        );
+        let mut subspaces = Vec::with_capacity(scheme.subspaces.len());
+        for (s, (&[start, end], &stage_count)) in
+            scheme.subspaces.iter().zip(&scheme.stages).enumerate()
+        {
+            ensure!(
+                start <= end && end <= cols,
+                "subspace {start}..{end} out of range"
+            );
+            let stages = (0..stage_count)
+                .map(|t| {
+                    let prefix = format!("{name}.novaq.subspace{s}.stage{t}");
+                    let (shape, bytes) = self.required(&format!("{prefix}.centroids"), "F32")?;
+                    let [k, dim] = shape[..] else {
+                        return Err(anyhow!("{prefix}.centroids is not a matrix"));
+                    };
+                    ensure!(dim == end - start, "{prefix} centroids have dim {dim}");
+                    let centroids = bytes
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes(b.try_into().expect("4 bytes")))
+                        .collect();
+                    let (_, packed) = self.required(&format!("{prefix}.assignments"), "U8")?;
+                    Ok(CodebookStage {
+                        stage_id: t as u8 + 1,
+                        centroids: Array2::from_shape_vec((k, dim), centroids)?,
+                        assignments: unpack_assignments(packed, rows, k)?,
+                        iterations: 0,
+                        inertia: 0.0,
+                    })
+                })
+                .collect::<Result<_>>()?;
+            subspaces.push(QuantizedSubspace {
+                columns: start..end,
+                stages,
+                residual_energy: 0.0,
+            });
+        }
+
+        Ok(reconstruct_weights(
+            (rows, cols),
+            &normalization,
+            &subspaces,
+            permutation.as_deref(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::artifact::{ArtifactWriter, ArtifactWriterConfig};
+    use crate::safetensors::SafeTensorsLoader;
+    use crate::tests::quantized_model;
+    use novaq_core::QuantizationConfig;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    fn model() -> QuantizedModel {
+        quantized_model(
+            &[
+                ("layers.0.q_proj.weight", 24, 32),
+                ("layers.0.up_proj.weight", 16, 48),
+            ],
+            |index, r, c| {
+                let spike = if (r + c) % 41 == 0 { 12.0 } else { 0.0 };
+                ((r * 5 + c * 11 + index) % 19) as f32 * 0.1 - 0.9 + spike
+            },
+        )
+    }
+
+    fn parse(bytes: &[u8]) -> (Map<String, Value>, &[u8]) {
+        let len = u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize;
+        assert_eq!(len % 8, 0);
+        let header: Value = serde_json::from_slice(&bytes[8..8 + len]).unwrap();
+        (header.as_object().unwrap().clone(), &bytes[8 + len..])
+    }
+
+    #[test]
+    fn codebook_export_reconstructs_layers_exactly() {
+        let model = model();
+        assert!(model
+            .layers
+            .iter()
+            .any(|layer| !layer.normalization.outliers.is_empty()));
+        let mut bytes = Vec::new();
+        SafeTensorsExporter::new(SafeTensorsExportMode::Codebook)
+            .with_metadata(BTreeMap::from([("source".into(), "test".into())]))
+            .write(&model, &mut bytes)
+            .unwrap();
+
+        let (header, data) = parse(&bytes);
+        assert!(is_codebook_header(&header));
+        assert_eq!(header["__metadata__"]["source"], "test");
+        let decoded = decode_codebook_weights(&header, data).unwrap();
+        assert_eq!(decoded.len(), model.layers.len());
+        for ((name, weights), layer) in decoded.iter().zip(&model.layers) {
+            assert_eq!(name, &layer.name);
+            assert_eq!(weights, layer.dequantize());
+        }
+    }
+
+    #[tokio::test]
+    async fn exports_reload_through_the_loader() {
+        let model = model();
+        for mode in [
+            SafeTensorsExportMode::F16,
+            SafeTensorsExportMode::BF16,
+            SafeTensorsExportMode::Codebook,
+        ] {
+            let mut bytes = Vec::new();
+            SafeTensorsExporter::new(mode)
+                .write(&model, &mut bytes)
+                .unwrap();
+
+            let dir = tempdir().unwrap();
+            let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+                output_dir: dir.path().to_path_buf(),
+                ..ArtifactWriterConfig::default()
+            });
+            let loader = SafeTensorsLoader::new(QuantizationConfig::default()).unwrap();
+            let reloaded = loader
+                .load_from_reader(Cursor::new(bytes), &mut writer)
+                .await
+                .unwrap();
+            let mut names: Vec<_> = reloaded.layers.iter().map(|l| l.name.as_str()).collect();
+            names.sort_unstable();
+            assert_eq!(
+                names,
+                ["layers.0.q_proj.weight", "layers.0.up_proj.weight"],
+                "{mode:?}"
+            );
+            for layer in &reloaded.layers {
+                let original = model.layers.iter().find(|l| l.name == layer.name).unwrap();
+                assert_eq!((layer.rows, layer.cols), (original.rows, original.cols));
+            }
+        }
+    }
+}
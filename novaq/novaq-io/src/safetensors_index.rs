@@ -0,0 +1,196 @@
+//! `model.safetensors.index.json` files of sharded checkpoints.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Component, Path};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::dtype::ScalePairing;
+
+/// Suffix of the index file that ships next to `model-0000X-of-0000N.safetensors` shards.
+pub const SAFETENSORS_INDEX_SUFFIX: &str = ".safetensors.index.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SafeTensorsIndex {
+    /// Free-form index metadata, usually just `total_size`.
+    #[serde(default)]
+    pub metadata: Map<String, Value>,
+    /// Tensor name to the shard file holding it, relative to the index.
+    pub weight_map: BTreeMap<String, String>,
+}
+
+impl SafeTensorsIndex {
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        let index: Self = serde_json::from_slice(bytes).context("invalid safetensors index")?;
+        ensure!(
+            !index.weight_map.is_empty(),
+            "safetensors index has an empty weight_map"
+        );
+        for file in index.shard_files() {
+            let mut components = Path::new(file).components();
+            ensure!(
+                matches!(components.next(), Some(Component::Normal(_)))
+                    && components.next().is_none(),
+                "shard {file:?} is not a file name next to the index"
+            );
+        }
+        Ok(index)
+    }
+
+    /// Distinct shard files, sorted by name.
+    pub fn shard_files(&self) -> BTreeSet<&str> {
+        self.weight_map.values().map(String::as_str).collect()
+    }
+
+    /// `(tensor, shard)` pairs in layer order, independent of how the
+    /// tensors are split across shards.
+    pub fn tensors_in_layer_order(&self) -> Vec<(&str, &str)> {
+        let mut tensors: Vec<_> = self
+            .weight_map
+            .iter()
+            .map(|(tensor, file)| (tensor.as_str(), file.as_str()))
+            .collect();
+        tensors.sort_by(|a, b| layer_order(a.0, b.0));
+        tensors
+    }
+
+    /// `total_size` from the metadata, when present.
+    pub fn total_size(&self) -> Option<u64> {
+        self.metadata.get("total_size").and_then(Value::as_u64)
+    }
+}
+
+/// Whether a locator names a sharded checkpoint index.
+pub fn is_safetensors_index(path: &str) -> bool {
+    path.to_ascii_lowercase()
+        .ends_with(SAFETENSORS_INDEX_SUFFIX)
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Segment<'a> {
+    Number(u64),
+    Text(&'a str),
+}
+
+/// Orders tensor names the way layers stack: dot-separated segments compare
+/// numerically when both are numbers, so `layers.2` sorts before `layers.10`.
+pub(crate) fn layer_order(a: &str, b: &str) -> Ordering {
+    segments(a).cmp(segments(b))
+}
+
+/// Numbers the tensors that become layers, the weight matrices, in layer
+/// order starting at `base`. Scales and tensors that are not matrices get no
+/// number. Every safetensors loader numbers layers this way, whatever order
+/// it reads the data in, so a checkpoint gets the same layer indices, and
+/// with them the same layer seeds, however it is split or fetched.
+pub(crate) fn layer_indices<'a>(
+    tensors: impl IntoIterator<Item = (&'a str, usize)>,
+    pairing: &ScalePairing,
+    base: usize,
+) -> HashMap<String, usize> {
+    let mut weights: Vec<&str> = tensors
+        .into_iter()
+        .filter(|&(name, ndim)| ndim == 2 && !pairing.is_scale(name))
+        .map(|(name, _)| name)
+        .collect();
+    weights.sort_by(|a, b| layer_order(a, b));
+    weights
+        .into_iter()
+        .enumerate()
+        .map(|(rank, name)| (name.to_string(), base + rank))
+        .collect()
+}
+
+/// The index [`layer_indices`] gave a weight.
+pub(crate) fn layer_index(indices: &HashMap<String, usize>, name: &str) -> Result<usize> {
+    indices
+        .get(name)
+        .copied()
+        .ok_or_else(|| anyhow!("{name} was not numbered as a layer"))
+}
+
+fn segments(name: &str) -> impl Iterator<Item = Segment<'_>> {
+    name.split('.').map(|segment| match segment.parse() {
+        Ok(number) => Segment::Number(number),
+        Err(_) => Segment::Text(segment),
+    })
+}
+
+/// Checks that a shard's header holds every tensor the index maps to it.
+pub(crate) fn check_shard_header(
+    index: &SafeTensorsIndex,
+    shard: &str,
+    header: &Map<String, Value>,
+) -> Result<()> {
+    let missing: Vec<_> = index
+        .weight_map
+        .iter()
+        .filter(|(tensor, file)| *file == shard && !header.contains_key(tensor.as_str()))
+        .map(|(tensor, _)| tensor.as_str())
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "shard {shard} is missing {} indexed tensors, such as {}",
+            missing.len(),
+            missing[0]
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_tensors_by_layer_number() {
+        let index = SafeTensorsIndex::from_slice(
+            br#"{
+                "metadata": {"total_size": 96},
+                "weight_map": {
+                    "model.layers.10.mlp.weight": "model-00001-of-00002.safetensors",
+                    "model.layers.2.mlp.weight": "model-00002-of-00002.safetensors",
+                    "model.layers.2.attn.weight": "model-00001-of-00002.safetensors",
+                    "model.embed_tokens.weight": "model-00002-of-00002.safetensors"
+                }
+            }"#,
+        )
+        .unwrap();
+        let names: Vec<_> = index
+            .tensors_in_layer_order()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "model.embed_tokens.weight",
+                "model.layers.2.attn.weight",
+                "model.layers.2.mlp.weight",
+                "model.layers.10.mlp.weight",
+            ]
+        );
+        assert_eq!(index.shard_files().len(), 2);
+        assert_eq!(index.total_size(), Some(96));
+    }
+
+    #[test]
+    fn rejects_shards_outside_the_index_directory() {
+        for file in [
+            "../model.safetensors",
+            "/tmp/model.safetensors",
+            "sub/model.safetensors",
+        ] {
+            let json = format!(r#"{{"weight_map": {{"w": "{file}"}}}}"#);
+            assert!(
+                SafeTensorsIndex::from_slice(json.as_bytes()).is_err(),
+                "{file}"
+            );
+        }
+        assert!(SafeTensorsIndex::from_slice(br#"{"weight_map": {}}"#).is_err());
+    }
+}
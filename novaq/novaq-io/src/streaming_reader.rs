@@ -61,7 +61,7 @@ where
                         self.total_read += filled.len() as u64;
                         self.chunk_count += 1;
                         
-                        if self.chunk_count % 1000 == 0 {
+                        if self.chunk_count.is_multiple_of(1000) {
                             debug!(
                                 chunks = self.chunk_count,
                                 bytes = self.total_read,
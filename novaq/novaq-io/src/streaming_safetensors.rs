@@ -1,16 +1,18 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use bytes::Bytes;
 use futures::{Stream, StreamExt};
-use half::{bf16, f16};
 use ndarray::Array2;
 use serde_json::Value;
+use std::collections::HashMap;
 use std::pin::Pin;
 use tracing::{debug, instrument};
 
 use novaq_core::{QuantizationConfig, QuantizedModel, Quantizer};
 
 use crate::artifact::ArtifactWriter;
+use crate::dtype::{ScalePairing, TensorDType};
 use crate::progress::ProgressTracker;
+use crate::safetensors_index::{layer_index, layer_indices};
 
 #[derive(Debug, Clone)]
 struct TensorMetadata {
@@ -20,31 +22,6 @@ struct TensorMetadata {
     data_offsets: [u64; 2],
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum TensorDType {
-    F32,
-    F16,
-    BF16,
-}
-
-impl TensorDType {
-    fn from_str(s: &str) -> Result<Self> {
-        match s {
-            "F32" => Ok(Self::F32),
-            "F16" => Ok(Self::F16),
-            "BF16" => Ok(Self::BF16),
-            other => Err(anyhow!("unsupported dtype: {}", other)),
-        }
-    }
-
-    fn bytes_per_element(self) -> usize {
-        match self {
-            Self::F32 => 4,
-            Self::F16 | Self::BF16 => 2,
-        }
-    }
-}
-
 pub struct StreamingSafeTensorsParser {
     quantizer: Quantizer,
     progress: Option<ProgressTracker>,
@@ -69,10 +46,10 @@ impl StreamingSafeTensorsParser {
     {
         let mut buffer = ByteBuffer::new();
         let mut state = ParseState::ReadingHeaderSize;
-        let mut header_size = 0u64;
         let mut tensors_metadata: Vec<TensorMetadata> = Vec::new();
-        let mut data_start = 0u64;
         let mut current_offset = 0u64;
+        let mut pairing = ScalePairing::default();
+        let mut indices = HashMap::new();
         let mut layers = Vec::new();
         let mut total_bytes_received = 0u64;
         let mut chunk_count = 0u64;
@@ -82,7 +59,7 @@ impl StreamingSafeTensorsParser {
             chunk_count += 1;
             total_bytes_received += chunk.len() as u64;
             
-            if chunk_count % 100 == 0 {
+            if chunk_count.is_multiple_of(100) {
                 debug!(
                     chunks_received = chunk_count,
                     bytes_received = total_bytes_received,
@@ -96,7 +73,7 @@ impl StreamingSafeTensorsParser {
             match state {
                 ParseState::ReadingHeaderSize => {
                     if buffer.len() >= 8 {
-                        header_size = buffer.read_u64_le();
+                        let header_size = buffer.read_u64_le();
                         current_offset = 8;
                         state = ParseState::ReadingHeader { header_size };
                         debug!(header_size, "read header size");
@@ -119,9 +96,19 @@ impl StreamingSafeTensorsParser {
                             let metadata = parse_tensor_metadata(name, value)?;
                             tensors_metadata.push(metadata);
                         }
+                        sort_by_data_offset(&mut tensors_metadata)?;
+                        pairing =
+                            ScalePairing::new(tensors_metadata.iter().map(|t| t.name.as_str()));
+                        indices = layer_indices(
+                            tensors_metadata
+                                .iter()
+                                .map(|t| (t.name.as_str(), t.shape.len())),
+                            &pairing,
+                            writer.manifest().layers.len(),
+                        );
 
                         current_offset += header_size;
-                        data_start = current_offset;
+                        let mut data_start = current_offset;
                         let padding = data_start % 8;
                         if padding != 0 {
                             let pad_size = 8 - padding;
@@ -188,39 +175,39 @@ impl StreamingSafeTensorsParser {
                         let tensor_bytes = buffer.read_bytes(tensor_size);
                         current_offset += tensor_size as u64;
 
-                        if tensor_meta.shape.len() == 2 {
-                            let matrix =
-                                decode_tensor(&tensor_bytes, &tensor_meta.shape, &tensor_meta.dtype)?;
-
-                            let layer_idx = layers.len();
-                            let quantized = self.quantizer.quantize_layer(
-                                &tensor_meta.name,
-                                layer_idx,
-                                &matrix,
-                            )?;
-
-                            let serialized = serde_json::to_vec(&quantized)
-                                .context("failed to serialize quantized layer")?;
-                            writer.write_chunk(&serialized)?;
-                            layers.push(quantized);
-
+                        let dtype = tensor_meta.dtype.parse::<TensorDType>()?;
+                        let ready = if pairing.is_scale(&tensor_meta.name) {
+                            let values = dtype.decode(&tensor_bytes)?;
+                            pairing.scale(&tensor_meta.name, &tensor_meta.shape, values)?
+                        } else if tensor_meta.shape.len() == 2 {
+                            let matrix = decode_tensor(&tensor_bytes, &tensor_meta.shape, dtype)?;
+                            pairing
+                                .weight(&tensor_meta.name, matrix)?
+                                .map(|matrix| (tensor_meta.name.clone(), matrix))
+                        } else {
                             debug!(
                                 tensor = tensor_meta.name,
                                 shape = ?tensor_meta.shape,
-                                "quantized tensor"
+                                "skipping non-matrix tensor"
                             );
+                            None
+                        };
+
+                        if let Some((name, matrix)) = ready {
+                            let layer_idx = layer_index(&indices, &name)?;
+                            let quantized =
+                                self.quantizer.quantize_layer(&name, layer_idx, &matrix)?;
+
+                            writer.write_layer(&quantized)?;
+                            layers.push(quantized);
+
+                            debug!(tensor = name, shape = ?matrix.dim(), "quantized tensor");
 
                             if let Some(ref progress) = self.progress {
                                 let bar = progress
                                     .add_file_processing_bar("safetensors", tensors.len() as u64);
                                 bar.set_position((current_tensor_idx + 1) as u64);
                             }
-                        } else {
-                            debug!(
-                                tensor = tensor_meta.name,
-                                shape = ?tensor_meta.shape,
-                                "skipping non-matrix tensor"
-                            );
                         }
 
                         state = ParseState::ReadingTensors {
@@ -242,11 +229,16 @@ impl StreamingSafeTensorsParser {
             "stream ended"
         );
 
+        pairing.finish()?;
+        writer.flush()?;
+
+        layers.sort_by_key(|layer| layer.index);
         Ok(QuantizedModel::from_layers(layers))
     }
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 enum ParseState {
     ReadingHeaderSize,
     ReadingHeader { header_size: u64 },
@@ -325,6 +317,7 @@ fn parse_tensor_metadata(name: &str, value: &Value) -> Result<TensorMetadata> {
     let end = offsets[1]
         .as_u64()
         .ok_or_else(|| anyhow!("end offset is not a number"))?;
+    ensure!(start <= end, "data_offsets end before they start");
 
     let dtype = obj
         .get("dtype")
@@ -341,62 +334,25 @@ fn parse_tensor_metadata(name: &str, value: &Value) -> Result<TensorMetadata> {
     })
 }
 
-fn decode_tensor(bytes: &[u8], shape: &[usize], dtype_str: &str) -> Result<Array2<f32>> {
-    if shape.len() != 2 {
-        return Err(anyhow!("expected 2D tensor, got shape {:?}", shape));
-    }
-
-    let rows = shape[0];
-    let cols = shape[1];
-    let dtype = TensorDType::from_str(dtype_str)?;
-    let bytes_per_elem = dtype.bytes_per_element();
-    let expected_size = rows * cols * bytes_per_elem;
-
-    if bytes.len() != expected_size {
-        return Err(anyhow!(
-            "tensor size mismatch: expected {} bytes, got {}",
-            expected_size,
-            bytes.len()
-        ));
+/// Sorts tensors by where their data starts, the order a forward-only reader
+/// meets them in. The header is keyed by name, but writers lay the data out
+/// in their own order, often grouped by dtype.
+fn sort_by_data_offset(tensors: &mut [TensorMetadata]) -> Result<()> {
+    tensors.sort_by_key(|t| t.data_offsets);
+    for pair in tensors.windows(2) {
+        ensure!(
+            pair[0].data_offsets[1] <= pair[1].data_offsets[0],
+            "data of tensors {} and {} overlaps",
+            pair[0].name,
+            pair[1].name
+        );
     }
+    Ok(())
+}
 
-    let mut matrix = Array2::<f32>::zeros((rows, cols));
-
-    match dtype {
-        TensorDType::F32 => {
-            for row in 0..rows {
-                for col in 0..cols {
-                    let idx = (row * cols + col) * 4;
-                    let value = f32::from_le_bytes([
-                        bytes[idx],
-                        bytes[idx + 1],
-                        bytes[idx + 2],
-                        bytes[idx + 3],
-                    ]);
-                    matrix[(row, col)] = value;
-                }
-            }
-        }
-        TensorDType::F16 => {
-            for row in 0..rows {
-                for col in 0..cols {
-                    let idx = (row * cols + col) * 2;
-                    let bits = u16::from_le_bytes([bytes[idx], bytes[idx + 1]]);
-                    matrix[(row, col)] = f16::from_bits(bits).to_f32();
-                }
-            }
-        }
-        TensorDType::BF16 => {
-            for row in 0..rows {
-                for col in 0..cols {
-                    let idx = (row * cols + col) * 2;
-                    let bits = u16::from_le_bytes([bytes[idx], bytes[idx + 1]]);
-                    matrix[(row, col)] = bf16::from_bits(bits).to_f32();
-                }
-            }
-        }
+fn decode_tensor(bytes: &[u8], shape: &[usize], dtype: TensorDType) -> Result<Array2<f32>> {
+    if shape.len() != 2 {
+        return Err(anyhow!("expected 2D tensor, got shape {:?}", shape));
     }
-
-    Ok(matrix)
+    dtype.decode_matrix(bytes, shape[0], shape[1])
 }
-
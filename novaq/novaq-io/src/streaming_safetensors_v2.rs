@@ -1,14 +1,17 @@
-use anyhow::{anyhow, Context, Result};
-use half::{bf16, f16};
+use std::collections::HashMap;
+
+use anyhow::{anyhow, ensure, Context, Result};
 use ndarray::Array2;
-use serde_json::Value;
+use serde_json::{Map, Value};
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::{debug, instrument};
 
-use novaq_core::{QuantizationConfig, QuantizedModel, Quantizer};
+use novaq_core::{QuantizationConfig, QuantizedLayer, QuantizedModel, Quantizer};
 
 use crate::artifact::ArtifactWriter;
+use crate::dtype::{ScalePairing, TensorDType};
 use crate::progress::ProgressTracker;
+use crate::safetensors_index::{layer_index, layer_indices};
 
 #[derive(Debug, Clone)]
 struct TensorMetadata {
@@ -18,31 +21,6 @@ struct TensorMetadata {
     data_offsets: [u64; 2],
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum TensorDType {
-    F32,
-    F16,
-    BF16,
-}
-
-impl TensorDType {
-    fn from_str(s: &str) -> Result<Self> {
-        match s {
-            "F32" => Ok(Self::F32),
-            "F16" => Ok(Self::F16),
-            "BF16" => Ok(Self::BF16),
-            other => Err(anyhow!("unsupported dtype: {}", other)),
-        }
-    }
-
-    fn bytes_per_element(self) -> usize {
-        match self {
-            Self::F32 => 4,
-            Self::F16 | Self::BF16 => 2,
-        }
-    }
-}
-
 pub struct StreamingSafeTensorsParserV2 {
     quantizer: Quantizer,
     progress: Option<ProgressTracker>,
@@ -65,55 +43,50 @@ impl StreamingSafeTensorsParserV2 {
     where
         R: AsyncRead + Unpin,
     {
-        let header_size = reader.read_u64_le().await?;
-        debug!(header_size, "reading header");
-
-        let mut header_bytes = vec![0u8; header_size as usize];
-        reader.read_exact(&mut header_bytes).await?;
-
-        let metadata: Value =
-            serde_json::from_slice(&header_bytes).context("invalid safetensors header")?;
-        let metadata = metadata
-            .as_object()
-            .ok_or_else(|| anyhow!("safetensors header is not a JSON object"))?;
-
-        let mut data_start = 8 + header_size;
-        let padding = data_start % 8;
-        if padding != 0 {
-            data_start += 8 - padding;
-        }
-
-        let mut tensors_metadata = Vec::new();
-        for (name, value) in metadata.iter() {
-            if name == "__metadata__" {
-                continue;
-            }
-            let tensor_meta = parse_tensor_metadata(name, value)?;
-            tensors_metadata.push(tensor_meta);
-        }
-
-        debug!(
-            tensor_count = tensors_metadata.len(),
-            data_start,
-            "parsed header"
-        );
+        let header = ShardHeader::read(reader).await?;
+        let mut pairing = ScalePairing::new(header.tensors.iter().map(|t| t.name.as_str()));
+        // Layers are numbered in layer order even though they are read in
+        // data order, like every other safetensors loader numbers them.
+        let indices = layer_indices(header.tensors(), &pairing, writer.manifest().layers.len());
+        let mut layers = self
+            .quantize_shard(reader, &header, &indices, &mut pairing, writer)
+            .await?;
+
+        pairing.finish()?;
+        debug!(total_layers = layers.len(), "completed quantization");
+        writer.flush()?;
+        layers.sort_by_key(|layer| layer.index);
+        Ok(QuantizedModel::from_layers(layers))
+    }
 
+    /// Reads the data of a shard whose header has just been read, quantizing
+    /// the tensors `indices` numbers as it meets them. Scales are paired
+    /// through `pairing`, which may span several shards.
+    pub(crate) async fn quantize_shard<R>(
+        &self,
+        reader: &mut R,
+        header: &ShardHeader,
+        indices: &HashMap<String, usize>,
+        pairing: &mut ScalePairing,
+        writer: &mut ArtifactWriter,
+    ) -> Result<Vec<QuantizedLayer>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let tensors_metadata = &header.tensors;
+        let data_start = header.data_start;
         let mut layers = Vec::new();
-        let mut current_offset = 8 + header_size;
-
-        if padding != 0 {
-            let pad_size = 8 - padding;
-            let mut pad_buf = vec![0u8; pad_size as usize];
-            reader.read_exact(&mut pad_buf).await?;
-            current_offset += pad_size;
-        }
+        let mut current_offset = header.end;
+        let progress_bar = self.progress.as_ref().map(|progress| {
+            progress.add_file_processing_bar("safetensors", tensors_metadata.len() as u64)
+        });
 
         for (idx, tensor_meta) in tensors_metadata.iter().enumerate() {
-            if tensor_meta.shape.len() != 2 {
+            if !indices.contains_key(&tensor_meta.name) && !pairing.is_scale(&tensor_meta.name) {
                 debug!(
                     tensor = tensor_meta.name,
                     shape = ?tensor_meta.shape,
-                    "skipping non-matrix tensor"
+                    "skipping tensor that is not a layer"
                 );
                 
                 let start_offset = data_start + tensor_meta.data_offsets[0];
@@ -129,6 +102,9 @@ impl StreamingSafeTensorsParserV2 {
                     remaining -= to_skip;
                 }
                 current_offset = end_offset;
+                if let Some(ref bar) = progress_bar {
+                    bar.inc(1);
+                }
                 continue;
             }
 
@@ -152,17 +128,28 @@ impl StreamingSafeTensorsParserV2 {
             reader.read_exact(&mut tensor_bytes).await?;
             current_offset += tensor_size as u64;
 
-            let matrix = decode_tensor(&tensor_bytes, &tensor_meta.shape, &tensor_meta.dtype)?;
-
-            let layer_idx = layers.len();
-            let quantized = self
-                .quantizer
-                .quantize_layer(&tensor_meta.name, layer_idx, &matrix)?;
+            let dtype = tensor_meta.dtype.parse::<TensorDType>()?;
+            let ready = if pairing.is_scale(&tensor_meta.name) {
+                let values = dtype.decode(&tensor_bytes)?;
+                pairing.scale(&tensor_meta.name, &tensor_meta.shape, values)?
+            } else {
+                let matrix = decode_tensor(&tensor_bytes, &tensor_meta.shape, dtype)?;
+                pairing
+                    .weight(&tensor_meta.name, matrix)?
+                    .map(|matrix| (tensor_meta.name.clone(), matrix))
+            };
+
+            if let Some((name, matrix)) = ready {
+                let layer_idx = layer_index(indices, &name)?;
+                let quantized = self.quantizer.quantize_layer(&name, layer_idx, &matrix)?;
+
+                writer.write_layer(&quantized)?;
+                layers.push(quantized);
+            }
 
-            let serialized =
-                serde_json::to_vec(&quantized).context("failed to serialize quantized layer")?;
-            writer.write_chunk(&serialized)?;
-            layers.push(quantized);
+            if let Some(ref bar) = progress_bar {
+                bar.inc(1);
+            }
 
             if idx % 10 == 0 || idx == tensors_metadata.len() - 1 {
                 debug!(
@@ -174,8 +161,68 @@ impl StreamingSafeTensorsParserV2 {
             }
         }
 
-        debug!(total_layers = layers.len(), "completed quantization");
-        Ok(QuantizedModel::from_layers(layers))
+        Ok(layers)
+    }
+}
+
+/// The header of a safetensors file, with its tensors in data order.
+pub(crate) struct ShardHeader {
+    /// The raw header entries, keyed by tensor name.
+    pub(crate) entries: Map<String, Value>,
+    tensors: Vec<TensorMetadata>,
+    /// Offset just past the header, where a forward-only reader stands.
+    end: u64,
+    data_start: u64,
+}
+
+impl ShardHeader {
+    pub(crate) async fn read<R>(reader: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let header_size = reader.read_u64_le().await?;
+        debug!(header_size, "reading header");
+
+        let mut header_bytes = vec![0u8; header_size as usize];
+        reader.read_exact(&mut header_bytes).await?;
+
+        let metadata: Value =
+            serde_json::from_slice(&header_bytes).context("invalid safetensors header")?;
+        let Value::Object(entries) = metadata else {
+            return Err(anyhow!("safetensors header is not a JSON object"));
+        };
+
+        let end = 8 + header_size;
+        let mut data_start = end;
+        let padding = data_start % 8;
+        if padding != 0 {
+            data_start += 8 - padding;
+        }
+
+        let mut tensors = Vec::new();
+        for (name, value) in entries.iter() {
+            if name == "__metadata__" {
+                continue;
+            }
+            let tensor_meta = parse_tensor_metadata(name, value)?;
+            tensors.push(tensor_meta);
+        }
+        sort_by_data_offset(&mut tensors)?;
+
+        debug!(tensor_count = tensors.len(), data_start, "parsed header");
+        Ok(Self {
+            entries,
+            tensors,
+            end,
+            data_start,
+        })
+    }
+
+    /// Names and ranks of the tensors.
+    pub(crate) fn tensors(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.tensors
+            .iter()
+            .map(|t| (t.name.as_str(), t.shape.len()))
     }
 }
 
@@ -213,6 +260,7 @@ fn parse_tensor_metadata(name: &str, value: &Value) -> Result<TensorMetadata> {
     let end = offsets[1]
         .as_u64()
         .ok_or_else(|| anyhow!("end offset is not a number"))?;
+    ensure!(start <= end, "data_offsets end before they start");
 
     let dtype = obj
         .get("dtype")
@@ -229,62 +277,25 @@ fn parse_tensor_metadata(name: &str, value: &Value) -> Result<TensorMetadata> {
     })
 }
 
-fn decode_tensor(bytes: &[u8], shape: &[usize], dtype_str: &str) -> Result<Array2<f32>> {
-    if shape.len() != 2 {
-        return Err(anyhow!("expected 2D tensor, got shape {:?}", shape));
-    }
-
-    let rows = shape[0];
-    let cols = shape[1];
-    let dtype = TensorDType::from_str(dtype_str)?;
-    let bytes_per_elem = dtype.bytes_per_element();
-    let expected_size = rows * cols * bytes_per_elem;
-
-    if bytes.len() != expected_size {
-        return Err(anyhow!(
-            "tensor size mismatch: expected {} bytes, got {}",
-            expected_size,
-            bytes.len()
-        ));
+/// Sorts tensors by where their data starts, the order a forward-only reader
+/// meets them in. The header is keyed by name, but writers lay the data out
+/// in their own order, often grouped by dtype.
+fn sort_by_data_offset(tensors: &mut [TensorMetadata]) -> Result<()> {
+    tensors.sort_by_key(|t| t.data_offsets);
+    for pair in tensors.windows(2) {
+        ensure!(
+            pair[0].data_offsets[1] <= pair[1].data_offsets[0],
+            "data of tensors {} and {} overlaps",
+            pair[0].name,
+            pair[1].name
+        );
     }
+    Ok(())
+}
 
-    let mut matrix = Array2::<f32>::zeros((rows, cols));
-
-    match dtype {
-        TensorDType::F32 => {
-            for row in 0..rows {
-                for col in 0..cols {
-                    let idx = (row * cols + col) * 4;
-                    let value = f32::from_le_bytes([
-                        bytes[idx],
-                        bytes[idx + 1],
-                        bytes[idx + 2],
-                        bytes[idx + 3],
-                    ]);
-                    matrix[(row, col)] = value;
-                }
-            }
-        }
-        TensorDType::F16 => {
-            for row in 0..rows {
-                for col in 0..cols {
-                    let idx = (row * cols + col) * 2;
-                    let bits = u16::from_le_bytes([bytes[idx], bytes[idx + 1]]);
-                    matrix[(row, col)] = f16::from_bits(bits).to_f32();
-                }
-            }
-        }
-        TensorDType::BF16 => {
-            for row in 0..rows {
-                for col in 0..cols {
-                    let idx = (row * cols + col) * 2;
-                    let bits = u16::from_le_bytes([bytes[idx], bytes[idx + 1]]);
-                    matrix[(row, col)] = bf16::from_bits(bits).to_f32();
-                }
-            }
-        }
+fn decode_tensor(bytes: &[u8], shape: &[usize], dtype: TensorDType) -> Result<Array2<f32>> {
+    if shape.len() != 2 {
+        return Err(anyhow!("expected 2D tensor, got shape {:?}", shape));
     }
-
-    Ok(matrix)
+    dtype.decode_matrix(bytes, shape[0], shape[1])
 }
-
@@ -1,14 +1,48 @@
 use anyhow::Result;
-use novaq_core::QuantizationConfig;
+use ndarray::Array2;
+use novaq_core::{QuantizationConfig, QuantizedModel, Quantizer};
+use sha2::{Digest, Sha256};
 use std::io::Cursor;
+use std::sync::{Arc, Mutex};
 use tempfile::tempdir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::artifact::{ArtifactWriter, ArtifactWriterConfig};
+use crate::artifact_reader::{ArtifactReader, MANIFEST_FILE};
+use crate::blob_cache::BlobCache;
 use crate::gguf::GgufLoader;
-use crate::manifest::assemble_manifest;
+use crate::gguf_export::{GgufExportMode, GgufExporter, NOVAQ_GGUF_TYPE_ID};
+use crate::gguf_metadata::GgufMetadata;
+use crate::huggingface::{HuggingFaceConfig, HuggingFaceLoader};
+use crate::manifest::{assemble_manifest, check_reproduction};
+use crate::packed::decode_layer;
 use crate::safetensors::SafeTensorsLoader;
+use crate::safetensors_index::SAFETENSORS_INDEX_SUFFIX;
+use crate::streaming_gguf::StreamingGgufParser;
+use crate::streaming_safetensors::StreamingSafeTensorsParser;
+use crate::streaming_safetensors_v2::StreamingSafeTensorsParserV2;
+use novaq_manifest::LayerFormat;
 use serde_json::json;
 
+/// Quantizes one layer per `(name, rows, cols)` with the default config,
+/// filling layer `index` with `weight(index, row, col)`. Shared by the export
+/// tests, which each want their own tensor names and value patterns.
+pub(crate) fn quantized_model(
+    layers: &[(&str, usize, usize)],
+    weight: impl Fn(usize, usize, usize) -> f32,
+) -> QuantizedModel {
+    let quantizer = Quantizer::new(QuantizationConfig::default()).unwrap();
+    let layers = layers
+        .iter()
+        .enumerate()
+        .map(|(index, &(name, rows, cols))| {
+            let weights = Array2::from_shape_fn((rows, cols), |(r, c)| weight(index, r, c));
+            quantizer.quantize_layer(name, index, &weights).unwrap()
+        })
+        .collect();
+    QuantizedModel::from_layers(layers)
+}
+
 fn synthetic_safetensors() -> Vec<u8> {
     let rows = 4;
     let cols = 4;
@@ -29,11 +63,11 @@ fn synthetic_safetensors() -> Vec<u8> {
     .to_string();
 
     let mut bytes = Vec::new();
-    let header_len = header.as_bytes().len() as u64;
+    let header_len = header.len() as u64;
     bytes.extend_from_slice(&header_len.to_le_bytes());
     bytes.extend_from_slice(header.as_bytes());
     let pad = (8 - (bytes.len() % 8)) % 8;
-    bytes.extend(std::iter::repeat(0u8).take(pad));
+    bytes.extend(std::iter::repeat_n(0u8, pad));
     bytes.extend_from_slice(&data_bytes);
     bytes
 }
@@ -44,6 +78,7 @@ async fn safetensors_loader_quantizes() -> Result<()> {
     let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
         chunk_bytes: 1 << 20,
         output_dir: dir.path().to_path_buf(),
+        ..ArtifactWriterConfig::default()
     });
     let loader = SafeTensorsLoader::new(QuantizationConfig::default())?;
     let data = synthetic_safetensors();
@@ -62,26 +97,1097 @@ async fn safetensors_loader_quantizes() -> Result<()> {
     )?;
     assert_eq!(manifest.layers.len(), 1);
     assert_eq!(manifest.chunks.len(), 1);
+    assert_eq!(manifest.layer_encoding.format, LayerFormat::Packed);
+
+    let entry = &manifest.layers["linear.weight"];
+    assert_eq!(entry.spans.len(), 1);
+    let span = entry.spans[0];
+    let chunk = std::fs::read(dir.path().join(&manifest.chunks[span.chunk_index].path))?;
+    let layer = decode_layer(
+        &chunk[span.offset..span.offset + span.len],
+        &manifest.layer_encoding,
+    )?;
+    assert_eq!(layer.name, "linear.weight");
+    assert_eq!(layer.dequantize(), model.layers[0].dequantize());
+    Ok(())
+}
+
+#[tokio::test]
+async fn reproductions_match_their_manifest() -> Result<()> {
+    let tensors: Vec<_> = (0..3)
+        .map(|layer| {
+            let values = (0..16 * 16)
+                .map(|i| ((i * 5 + layer) % 11) as f32 * 0.5 - 2.5)
+                .collect();
+            (format!("model.layers.{layer}.weight"), vec![16, 16], values)
+        })
+        .collect();
+    let file = safetensors_file(&tensors);
+    let compress = |chunk_bytes: usize, config: QuantizationConfig| {
+        let file = file.clone();
+        async move {
+            let dir = tempdir()?;
+            let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+                chunk_bytes,
+                output_dir: dir.path().to_path_buf(),
+                ..ArtifactWriterConfig::default()
+            });
+            let model = SafeTensorsLoader::new(config.clone())?
+                .load_from_reader(Cursor::new(file), &mut writer)
+                .await?;
+            assemble_manifest(
+                &crate::format::ModelLocator::new("model.safetensors"),
+                "test",
+                &config,
+                &model,
+                writer.manifest(),
+            )
+        }
+    };
+
+    let original = compress(512, QuantizationConfig::default()).await?;
+    assert_eq!(original.chunk_bytes, Some(512));
+    assert!(original.chunks.len() > 1);
+
+    let config = serde_json::from_value(original.quantization.config.clone())?;
+    let reproduced = compress(original.chunk_bytes.unwrap(), config).await?;
+    check_reproduction(&original, &reproduced)?;
+
+    let rechunked = compress(1 << 20, QuantizationConfig::default()).await?;
+    assert!(check_reproduction(&original, &rechunked).is_err());
+    let mut tampered = reproduced.clone();
+    tampered.chunks[0].sha256 = "0".repeat(64);
+    assert!(check_reproduction(&original, &tampered).is_err());
+    Ok(())
+}
+
+/// Serializes F32 tensors as a safetensors file.
+fn safetensors_file(tensors: &[(String, Vec<usize>, Vec<f32>)]) -> Vec<u8> {
+    let tensors: Vec<_> = tensors
+        .iter()
+        .map(|(name, shape, values)| {
+            let bytes = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+            (name.clone(), "F32", shape.clone(), bytes)
+        })
+        .collect();
+    raw_safetensors_file(&tensors)
+}
+
+/// Serializes already encoded tensors of any dtype as a safetensors file.
+fn raw_safetensors_file(tensors: &[(String, &str, Vec<usize>, Vec<u8>)]) -> Vec<u8> {
+    let mut header = serde_json::Map::new();
+    let mut data = Vec::new();
+    for (name, dtype, shape, bytes) in tensors {
+        let start = data.len();
+        data.extend_from_slice(bytes);
+        header.insert(
+            name.clone(),
+            json!({ "dtype": dtype, "shape": shape, "data_offsets": [start, data.len()] }),
+        );
+    }
+    let header = serde_json::to_vec(&header).unwrap();
+    let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+    bytes.extend_from_slice(&header);
+    bytes.resize(bytes.len().next_multiple_of(8), b' ');
+    bytes.extend_from_slice(&data);
+    bytes
+}
+
+#[tokio::test]
+async fn sharded_safetensors_match_a_single_file() -> Result<()> {
+    let mut tensors: Vec<_> = (0..12)
+        .map(|layer| {
+            let values = (0..8 * 16)
+                .map(|i| ((i * 7 + layer * 5) % 17) as f32 * 0.25 - 2.0)
+                .collect();
+            (
+                format!("model.layers.{layer}.mlp.weight"),
+                vec![8, 16],
+                values,
+            )
+        })
+        .collect();
+    tensors.push(("model.norm.weight".to_string(), vec![16], vec![1.0; 16]));
+
+    let load = |files: Vec<(String, Vec<u8>)>| async move {
+        let dir = tempdir()?;
+        for (name, bytes) in &files {
+            std::fs::write(dir.path().join(name), bytes)?;
+        }
+        let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+            output_dir: dir.path().to_path_buf(),
+            ..ArtifactWriterConfig::default()
+        });
+        let loader = SafeTensorsLoader::new(QuantizationConfig::default())?;
+        let (name, bytes) = &files[0];
+        let model = if name.ends_with(SAFETENSORS_INDEX_SUFFIX) {
+            loader
+                .load_from_index(&dir.path().join(name), &mut writer)
+                .await?
+        } else {
+            loader
+                .load_from_reader(Cursor::new(bytes.clone()), &mut writer)
+                .await?
+        };
+        anyhow::Ok(model)
+    };
+
+    let single = load(vec![(
+        "model.safetensors".into(),
+        safetensors_file(&tensors),
+    )])
+    .await?;
+
+    // Spread the layers round-robin, so no shard holds a contiguous run.
+    let mut files = vec![(String::new(), Vec::new())];
+    let mut weight_map = serde_json::Map::new();
+    for shard in 0..3 {
+        let name = format!("model-{:05}-of-00003.safetensors", shard + 1);
+        let part: Vec<_> = tensors.iter().skip(shard).step_by(3).cloned().collect();
+        for (tensor, _, _) in &part {
+            weight_map.insert(tensor.clone(), json!(name));
+        }
+        files.push((name, safetensors_file(&part)));
+    }
+    files[0] = (
+        format!("model{SAFETENSORS_INDEX_SUFFIX}"),
+        serde_json::to_vec(&json!({ "metadata": {}, "weight_map": weight_map }))?,
+    );
+    let sharded = load(files).await?;
+
+    let names: Vec<_> = sharded.layers.iter().map(|l| l.name.as_str()).collect();
+    assert_eq!(names.len(), 12);
+    assert_eq!(names[2], "model.layers.2.mlp.weight");
+    assert_eq!(names[10], "model.layers.10.mlp.weight");
+    assert_eq!(single.layers.len(), sharded.layers.len());
+    for (expected, layer) in single.layers.iter().zip(&sharded.layers) {
+        assert_eq!(layer.name, expected.name);
+        assert_eq!(layer.index, expected.index);
+        assert_eq!(layer.seed, expected.seed);
+        assert_eq!(layer.dequantize(), expected.dequantize());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn safetensors_loaders_decode_extended_dtypes() -> Result<()> {
+    // FP8 E4M3 codes of magnitude 0.25 to 8, exact in every dtype below.
+    let (rows, cols) = (8, 16);
+    let codes: Vec<u8> = (0..rows * cols)
+        .map(|i| (((i % 5 + 5) << 3) | (i % 8)) as u8 | if i % 3 == 0 { 0x80 } else { 0 })
+        .collect();
+    let fp8 = crate::dtype::TensorDType::F8E4M3.decode(&codes)?;
+    let row_scales: Vec<f32> = (0..rows).map(|r| 0.25 * (r + 1) as f32).collect();
+    let scaled: Vec<f32> = fp8
+        .iter()
+        .enumerate()
+        .map(|(i, v)| v * row_scales[i / cols])
+        .collect();
+    let ints: Vec<i8> = (0..rows * cols)
+        .map(|i| (i as i32 % 31 - 15) as i8)
+        .collect();
+
+    let mut tensors = vec![
+        ("a.weight".into(), "F8_E4M3", vec![rows, cols], codes),
+        (
+            "a.weight_scale".into(),
+            "F32",
+            vec![rows],
+            row_scales.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        ),
+        (
+            "b.weight".into(),
+            "F64",
+            vec![rows, cols],
+            fp8.iter()
+                .flat_map(|v| f64::from(*v).to_le_bytes())
+                .collect(),
+        ),
+        (
+            "c.weight".into(),
+            "I8",
+            vec![rows, cols],
+            ints.iter().map(|v| *v as u8).collect(),
+        ),
+    ];
+    let extended = raw_safetensors_file(&tensors);
+    // Writers that group data by dtype put the F32 scale ahead of its FP8
+    // weight, while the header still lists the weight first.
+    tensors.swap(0, 1);
+    let dtype_sorted = raw_safetensors_file(&tensors);
+    let reference = safetensors_file(&[
+        ("a.weight".into(), vec![rows, cols], scaled),
+        ("b.weight".into(), vec![rows, cols], fp8),
+        (
+            "c.weight".into(),
+            vec![rows, cols],
+            ints.iter().map(|v| f32::from(*v)).collect(),
+        ),
+    ]);
+
+    // Separate artifacts, so every load numbers its layers from zero.
+    let dirs = [tempdir()?, tempdir()?, tempdir()?, tempdir()?, tempdir()?];
+    let mut writers = dirs.each_ref().map(|dir| {
+        ArtifactWriter::new(ArtifactWriterConfig {
+            output_dir: dir.path().to_path_buf(),
+            ..ArtifactWriterConfig::default()
+        })
+    });
+    let [reference_writer, writer, stream_writer, sorted_writer, sorted_stream_writer] =
+        &mut writers;
+    let loader = SafeTensorsLoader::new(QuantizationConfig::default())?;
+    let streamer = StreamingSafeTensorsParserV2::new(QuantizationConfig::default(), None)?;
+    let expected = loader
+        .load_from_reader(Cursor::new(reference), reference_writer)
+        .await?;
+    let loaded = loader
+        .load_from_reader(Cursor::new(extended.clone()), writer)
+        .await?;
+    let streamed = streamer
+        .parse_and_quantize(&mut Cursor::new(extended), stream_writer)
+        .await?;
+    let sorted_loaded = loader
+        .load_from_reader(Cursor::new(dtype_sorted.clone()), sorted_writer)
+        .await?;
+    let sorted_streamed = streamer
+        .parse_and_quantize(&mut Cursor::new(dtype_sorted), sorted_stream_writer)
+        .await?;
+
+    assert_eq!(expected.layers.len(), 3);
+    for model in [&loaded, &streamed, &sorted_loaded, &sorted_streamed] {
+        let names: Vec<_> = model.layers.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, ["a.weight", "b.weight", "c.weight"]);
+        for (layer, reference) in model.layers.iter().zip(&expected.layers) {
+            assert_eq!(layer.dequantize(), reference.dequantize(), "{}", layer.name);
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn streaming_safetensors_rejects_overlapping_data() -> Result<()> {
+    let header = json!({
+        "a.weight": { "dtype": "F32", "shape": [4, 4], "data_offsets": [0, 64] },
+        "b.weight": { "dtype": "F32", "shape": [4, 4], "data_offsets": [32, 96] },
+    })
+    .to_string();
+    let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+    bytes.extend_from_slice(header.as_bytes());
+    bytes.resize(bytes.len().next_multiple_of(8) + 96, 0);
+
+    let dir = tempdir()?;
+    let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+        output_dir: dir.path().to_path_buf(),
+        ..ArtifactWriterConfig::default()
+    });
+    let err = StreamingSafeTensorsParserV2::new(QuantizationConfig::default(), None)?
+        .parse_and_quantize(&mut Cursor::new(bytes.clone()), &mut writer)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("overlaps"), "{err:#}");
+
+    let chunks: Vec<_> = bytes
+        .chunks(8)
+        .map(|chunk| anyhow::Ok(bytes::Bytes::copy_from_slice(chunk)))
+        .collect();
+    let err = StreamingSafeTensorsParser::new(QuantizationConfig::default(), None)?
+        .parse_and_quantize(Box::pin(futures::stream::iter(chunks)), &mut writer)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("overlaps"), "{err:#}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn safetensors_loader_rejects_impossible_tensor_spans() -> Result<()> {
+    let huge = 1u64 << 62;
+    let cases = [
+        (
+            json!({ "a.weight": { "dtype": "F32", "shape": [huge, 1], "data_offsets": [0, 16] } }),
+            "overflows",
+        ),
+        (
+            json!({
+                "a.weight": { "dtype": "F32", "shape": [2, 2], "data_offsets": [0, 16] },
+                "a.weight_scale": {
+                    "dtype": "F32", "shape": [1u64 << 32, 1u64 << 32], "data_offsets": [16, 16]
+                },
+            }),
+            "overflows",
+        ),
+        (
+            json!({ "a.weight": { "dtype": "F32", "shape": [2, 2], "data_offsets": [16, 0] } }),
+            "end before they start",
+        ),
+    ];
+    for (header, message) in cases {
+        let header = header.to_string();
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.resize(bytes.len().next_multiple_of(8) + 16, 0);
+
+        let dir = tempdir()?;
+        let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+            output_dir: dir.path().to_path_buf(),
+            ..ArtifactWriterConfig::default()
+        });
+        let err = SafeTensorsLoader::new(QuantizationConfig::default())?
+            .load_from_reader(Cursor::new(bytes), &mut writer)
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains(message), "{err:#}");
+    }
+    Ok(())
+}
+
+/// A stand-in for the Hub on a local port. It serves a listing with one LFS
+/// file, redirects the file to a storage path that honours `Range`, and cuts
+/// the first `drops` downloads off halfway through. Logs the range of each
+/// download. `main` resolves to [`HUB_COMMIT`].
+async fn hub_stand_in(file: Vec<u8>, drops: usize) -> Result<(String, Arc<Mutex<Vec<u64>>>)> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let endpoint = format!("http://{}", listener.local_addr()?);
+    let oid = hex::encode(Sha256::digest(&file));
+    let listing = json!({
+        "sha": HUB_COMMIT,
+        "siblings": [{
+            "rfilename": "model.safetensors",
+            "size": file.len(),
+            "lfs": { "oid": oid, "size": file.len() }
+        }]
+    })
+    .to_string();
+    let ranges = Arc::new(Mutex::new(Vec::new()));
+    let log = ranges.clone();
+
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let (path, start) = read_hub_request(&mut socket).await;
+            let path = path.as_str();
+
+            let (head, body) = if path.starts_with("/api/models/org/model/revision/main?") {
+                ("200 OK".to_string(), listing.clone().into_bytes())
+            } else if path == format!("/org/model/resolve/{HUB_COMMIT}/model.safetensors") {
+                (
+                    "302 Found\r\nlocation: /storage/blob".to_string(),
+                    Vec::new(),
+                )
+            } else if path == "/storage/blob" {
+                let attempt = {
+                    let mut log = log.lock().unwrap();
+                    log.push(start);
+                    log.len()
+                };
+                let body = file[start as usize..].to_vec();
+                let head = if start == 0 {
+                    "200 OK".to_string()
+                } else {
+                    format!(
+                        "206 Partial Content\r\ncontent-range: bytes {start}-{}/{}",
+                        file.len() - 1,
+                        file.len()
+                    )
+                };
+                if attempt <= drops {
+                    // Promise the whole body, then hang up halfway.
+                    let head = format!(
+                        "HTTP/1.1 {head}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    let _ = socket.write_all(head.as_bytes()).await;
+                    let _ = socket.write_all(&body[..body.len() / 2]).await;
+                    continue;
+                }
+                (head, body)
+            } else {
+                ("404 Not Found".to_string(), Vec::new())
+            };
+            write_hub_response(&mut socket, &head, &body).await;
+        }
+    });
+    Ok((endpoint, ranges))
+}
+
+/// Reads a request off `socket` and returns its lowercased path and the
+/// start of its `Range`, or 0 without one.
+async fn read_hub_request(socket: &mut tokio::net::TcpStream) -> (String, u64) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match socket.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    let request = String::from_utf8_lossy(&request).to_ascii_lowercase();
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let start = request
+        .lines()
+        .find_map(|line| line.strip_prefix("range: bytes="))
+        .and_then(|range| range.trim().trim_end_matches('-').parse().ok())
+        .unwrap_or(0);
+    (path.to_string(), start)
+}
+
+async fn write_hub_response(socket: &mut tokio::net::TcpStream, head: &str, body: &[u8]) {
+    let head = format!(
+        "HTTP/1.1 {head}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+        body.len()
+    );
+    let _ = socket.write_all(head.as_bytes()).await;
+    let _ = socket.write_all(body).await;
+}
+
+/// A stand-in for the Hub serving `files` at [`HUB_COMMIT`] of `org/model`,
+/// listed in the order given. `.safetensors` files are listed as LFS files.
+async fn hub_serving(files: Vec<(String, Vec<u8>)>) -> Result<String> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let endpoint = format!("http://{}", listener.local_addr()?);
+    let siblings: Vec<_> = files
+        .iter()
+        .map(|(name, bytes)| {
+            let mut sibling = json!({ "rfilename": name, "size": bytes.len() });
+            if name.ends_with(".safetensors") {
+                let oid = hex::encode(Sha256::digest(bytes));
+                sibling["lfs"] = json!({ "oid": oid, "size": bytes.len() });
+            }
+            sibling
+        })
+        .collect();
+    let listing = json!({ "sha": HUB_COMMIT, "siblings": siblings }).to_string();
+
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let (path, _) = read_hub_request(&mut socket).await;
+            let file = path
+                .strip_prefix(&format!("/org/model/resolve/{HUB_COMMIT}/"))
+                .and_then(|name| files.iter().find(|(n, _)| n.to_ascii_lowercase() == name));
+            let (head, body) = if path.starts_with("/api/models/org/model/revision/main?") {
+                ("200 OK", listing.as_bytes())
+            } else if let Some((_, bytes)) = file {
+                ("200 OK", bytes.as_slice())
+            } else {
+                ("404 Not Found", &[][..])
+            };
+            write_hub_response(&mut socket, head, body).await;
+        }
+    });
+    Ok(endpoint)
+}
+
+const HUB_COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+
+#[tokio::test]
+async fn hub_downloads_resume_and_serve_offline_runs() -> Result<()> {
+    let tensors: Vec<_> = (0..2)
+        .map(|layer| {
+            let values = (0..32 * 32)
+                .map(|i| ((i * 3 + layer) % 13) as f32 * 0.5 - 3.0)
+                .collect();
+            (format!("model.layers.{layer}.weight"), vec![32, 32], values)
+        })
+        .collect();
+    let file = safetensors_file(&tensors);
+    let oid = hex::encode(Sha256::digest(&file));
+    let (endpoint, ranges) = hub_stand_in(file.clone(), 1).await?;
+    let cache = tempdir()?;
+
+    let load = |locator: String, endpoint: String, offline: bool| {
+        let cache_dir = cache.path().to_path_buf();
+        async move {
+            let dir = tempdir()?;
+            let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+                output_dir: dir.path().to_path_buf(),
+                ..ArtifactWriterConfig::default()
+            });
+            let config = HuggingFaceConfig {
+                endpoint: Some(endpoint),
+                cache_dir: Some(cache_dir),
+                offline,
+                ..HuggingFaceConfig::default()
+            };
+            let locator = crate::format::ModelLocator::new(locator);
+            let model = HuggingFaceLoader::new(config, QuantizationConfig::default())?
+                .load_from_repo(&locator, &mut writer)
+                .await?;
+            anyhow::Ok((model, writer.into_manifest()?.source))
+        }
+    };
+
+    let (online, source) = load("hf://org/model".into(), endpoint, false).await?;
+    assert_eq!(*ranges.lock().unwrap(), [0, file.len() as u64 / 2]);
+    let blob = BlobCache::new(cache.path()).blob_path(&oid)?;
+    assert_eq!(std::fs::read(blob)?, file);
+    let source = source.expect("hub loads record their source");
+    assert_eq!(source.revision, "main");
+    assert_eq!(source.commit.as_deref(), Some(HUB_COMMIT));
+    assert_eq!(source.files.len(), 1);
+    assert_eq!(source.files[0].size, file.len() as u64);
+    assert_eq!(source.files[0].oid.as_deref(), Some(oid.as_str()));
+
+    // Nothing listens on the discard port: everything must come from the
+    // cache, including the listing of the pinned commit.
+    let pinned = format!("hf://org/model@{HUB_COMMIT}");
+    let (offline, offline_source) = load(pinned, "http://127.0.0.1:9".into(), true).await?;
+    assert_eq!(offline_source.map(|s| s.files), Some(source.files));
+    assert_eq!(ranges.lock().unwrap().len(), 2);
+    assert_eq!(offline.layers.len(), 2);
+    for (a, b) in online.layers.iter().zip(&offline.layers) {
+        assert_eq!(a.name, b.name);
+        assert_eq!(a.dequantize(), b.dequantize());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn hub_downloads_keep_retrying_while_they_progress() -> Result<()> {
+    let file: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+    // More drops than the retry budget, each after some progress.
+    let (endpoint, ranges) = hub_stand_in(file.clone(), 7).await?;
+    let cache = tempdir()?;
+    let client = crate::hf_api::HuggingFaceApiClient::new(None)?
+        .with_endpoint(endpoint)
+        .with_cache(BlobCache::new(cache.path()));
+    let spec = client
+        .discover_model_files("org/model", "main", None)
+        .await?;
+
+    let blob = client.cached_file(&spec.files[0]).await?;
+    assert_eq!(std::fs::read(blob)?, file);
+    let ranges = ranges.lock().unwrap();
+    assert_eq!(ranges.len(), 8);
+    assert!(
+        ranges.windows(2).all(|pair| pair[0] < pair[1]),
+        "{ranges:?}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn hub_shards_load_in_layer_order() -> Result<()> {
+    let tensors: Vec<_> = (0..4)
+        .map(|layer| {
+            let values = (0..8 * 16)
+                .map(|i| ((i * 5 + layer * 3) % 11) as f32 * 0.5 - 2.5)
+                .collect();
+            (format!("model.layers.{layer}.weight"), vec![8, 16], values)
+        })
+        .collect();
+    // The first shard holds the odd layers, so shard by shard the layers
+    // would come out as 1, 3, 0, 2.
+    let mut files = Vec::new();
+    let mut weight_map = serde_json::Map::new();
+    for shard in 0..2 {
+        let name = format!("model-{:05}-of-00002.safetensors", shard + 1);
+        let part: Vec<_> = tensors.iter().skip(1 - shard).step_by(2).cloned().collect();
+        for (tensor, _, _) in &part {
+            weight_map.insert(tensor.clone(), json!(name));
+        }
+        files.push((name, safetensors_file(&part)));
+    }
+    let index_name = format!("model{SAFETENSORS_INDEX_SUFFIX}");
+    files.push((
+        index_name.clone(),
+        serde_json::to_vec(&json!({ "metadata": {}, "weight_map": weight_map }))?,
+    ));
+
+    let local_dir = tempdir()?;
+    for (name, bytes) in &files {
+        std::fs::write(local_dir.path().join(name), bytes)?;
+    }
+    let artifact_dir = tempdir()?;
+    let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+        output_dir: artifact_dir.path().to_path_buf(),
+        ..ArtifactWriterConfig::default()
+    });
+    let local = SafeTensorsLoader::new(QuantizationConfig::default())?
+        .load_from_index(&local_dir.path().join(index_name), &mut writer)
+        .await?;
+    let names: Vec<_> = local.layers.iter().map(|l| l.name.clone()).collect();
+    let expected: Vec<_> = (0..4).map(|l| format!("model.layers.{l}.weight")).collect();
+    assert_eq!(names, expected);
+
+    let endpoint = hub_serving(files).await?;
+    let cache = tempdir()?;
+    let cache_dir = Some(cache.path().to_path_buf());
+    let load = |endpoint: String, cache_dir: Option<std::path::PathBuf>, offline: bool| async move {
+        let dir = tempdir()?;
+        let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+            output_dir: dir.path().to_path_buf(),
+            ..ArtifactWriterConfig::default()
+        });
+        let config = HuggingFaceConfig {
+            endpoint: Some(endpoint),
+            cache_dir,
+            offline,
+            ..HuggingFaceConfig::default()
+        };
+        let locator = crate::format::ModelLocator::new("hf://org/model");
+        HuggingFaceLoader::new(config, QuantizationConfig::default())?
+            .load_from_repo(&locator, &mut writer)
+            .await
+    };
+
+    let online = load(endpoint.clone(), cache_dir.clone(), false).await?;
+    // The index is kept with the listing, so offline runs order alike.
+    let offline = load("http://127.0.0.1:9".into(), cache_dir, true).await?;
+    for model in [&online, &offline] {
+        assert_eq!(model.layers.len(), local.layers.len());
+        for (layer, expected) in model.layers.iter().zip(&local.layers) {
+            assert_eq!(layer.name, expected.name);
+            assert_eq!(layer.index, expected.index);
+            assert_eq!(layer.seed, expected.seed);
+            assert_eq!(layer.dequantize(), expected.dequantize());
+        }
+    }
+    // Without a cache the shards are streamed, numbered the same way.
+    let streamed = load(endpoint, None, false).await?;
+    for (layer, expected) in streamed.layers.iter().zip(&local.layers) {
+        assert_eq!(layer.name, expected.name);
+        assert_eq!(layer.index, expected.index);
+        assert_eq!(layer.seed, expected.seed);
+    }
+    assert_eq!(streamed.layers.len(), local.layers.len());
+    Ok(())
+}
+
+#[tokio::test]
+async fn safetensors_paths_number_layers_alike() -> Result<()> {
+    // The data runs layers.10, a norm, layers.2, layers.1: neither header
+    // nor data order is layer order.
+    let tensors: Vec<(String, Vec<usize>, Vec<f32>)> = [10, 2, 1]
+        .into_iter()
+        .map(|layer| {
+            let values = (0..8 * 16)
+                .map(|i| ((i * 3 + layer * 5) % 13) as f32 * 0.5 - 3.0)
+                .collect();
+            (format!("model.layers.{layer}.weight"), vec![8, 16], values)
+        })
+        .collect();
+    let mut ordered = tensors[..1].to_vec();
+    ordered.push(("model.norm.weight".into(), vec![16], vec![1.0; 16]));
+    ordered.extend_from_slice(&tensors[1..]);
+    let file = safetensors_file(&ordered);
+
+    let new_writer = || -> Result<(tempfile::TempDir, ArtifactWriter)> {
+        let dir = tempdir()?;
+        let writer = ArtifactWriter::new(ArtifactWriterConfig {
+            output_dir: dir.path().to_path_buf(),
+            ..ArtifactWriterConfig::default()
+        });
+        Ok((dir, writer))
+    };
+
+    let (_dir, mut writer) = new_writer()?;
+    let local = SafeTensorsLoader::new(QuantizationConfig::default())?
+        .load_from_reader(Cursor::new(file.clone()), &mut writer)
+        .await?;
+    let names: Vec<_> = local.layers.iter().map(|l| l.name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "model.layers.1.weight",
+            "model.layers.2.weight",
+            "model.layers.10.weight"
+        ]
+    );
+
+    let endpoint = hub_serving(vec![("model.safetensors".into(), file.clone())]).await?;
+    let (_dir, mut writer) = new_writer()?;
+    let config = HuggingFaceConfig {
+        endpoint: Some(endpoint),
+        ..HuggingFaceConfig::default()
+    };
+    let hub = HuggingFaceLoader::new(config, QuantizationConfig::default())?
+        .load_from_repo(
+            &crate::format::ModelLocator::new("hf://org/model"),
+            &mut writer,
+        )
+        .await?;
+
+    let mut tar = tokio_tar::Builder::new(Vec::new());
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_size(file.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append_data(&mut header, "bundle/model.safetensors", file.as_slice())
+        .await?;
+    let (dir, mut writer) = new_writer()?;
+    let path = dir.path().join("bundle.tar");
+    std::fs::write(&path, tar.into_inner().await?)?;
+    let archive = crate::archive::ArchiveLoader::new(QuantizationConfig::default())?
+        .load_from_path(&path, &mut writer)
+        .await?;
+
+    for model in [&hub, &archive] {
+        assert_eq!(model.layers.len(), local.layers.len());
+        for (layer, expected) in model.layers.iter().zip(&local.layers) {
+            assert_eq!(layer.name, expected.name);
+            assert_eq!(layer.index, expected.index);
+            assert_eq!(layer.seed, expected.seed);
+            assert_eq!(layer.dequantize(), expected.dequantize());
+        }
+    }
+    Ok(())
+}
+
+fn proto_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn proto_varint_field(out: &mut Vec<u8>, field: u64, value: u64) {
+    proto_varint(out, field << 3);
+    proto_varint(out, value);
+}
+
+fn proto_bytes_field(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    proto_varint(out, (field << 3) | 2);
+    proto_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Encodes a `TensorProto` header; callers append the data fields.
+fn onnx_tensor(name: &str, dims: &[usize], data_type: u64) -> Vec<u8> {
+    let mut tensor = Vec::new();
+    for &dim in dims {
+        proto_varint_field(&mut tensor, 1, dim as u64);
+    }
+    proto_varint_field(&mut tensor, 2, data_type);
+    proto_bytes_field(&mut tensor, 8, name.as_bytes());
+    tensor
+}
+
+#[tokio::test]
+async fn onnx_initializers_match_safetensors() -> Result<()> {
+    // Values on a quarter grid survive the trip through f16 exactly.
+    let tensors: Vec<(String, Vec<usize>, Vec<f32>)> = (0..4)
+        .map(|layer| {
+            let values = (0..16 * 16)
+                .map(|i| ((i * 3 + layer * 7) % 15) as f32 * 0.25 - 1.75)
+                .collect();
+            (format!("model.layers.{layer}.weight"), vec![16, 16], values)
+        })
+        .collect();
+    let dir = tempdir()?;
+
+    // Each layer stores its data a different way: raw_data, packed
+    // float_data, FLOAT16 bits in int32_data, and an external file.
+    let mut graph = Vec::new();
+    let f32_bytes =
+        |values: &[f32]| -> Vec<u8> { values.iter().flat_map(|v| v.to_le_bytes()).collect() };
+    let mut tensor = onnx_tensor(&tensors[0].0, &[16, 16], 1);
+    proto_bytes_field(&mut tensor, 9, &f32_bytes(&tensors[0].2));
+    proto_bytes_field(&mut graph, 5, &tensor);
+
+    let mut tensor = onnx_tensor(&tensors[1].0, &[16, 16], 1);
+    proto_bytes_field(&mut tensor, 4, &f32_bytes(&tensors[1].2));
+    proto_bytes_field(&mut graph, 5, &tensor);
+
+    let mut tensor = onnx_tensor(&tensors[2].0, &[16, 16], 10);
+    let mut halves = Vec::new();
+    for &value in &tensors[2].2 {
+        proto_varint(&mut halves, u64::from(half::f16::from_f32(value).to_bits()));
+    }
+    proto_bytes_field(&mut tensor, 5, &halves);
+    proto_bytes_field(&mut graph, 5, &tensor);
+
+    let mut external = vec![0u8; 8];
+    external.extend(f32_bytes(&tensors[3].2));
+    std::fs::write(dir.path().join("model.onnx.data"), &external)?;
+    let mut tensor = onnx_tensor(&tensors[3].0, &[16, 16], 1);
+    for (key, value) in [
+        ("location", "model.onnx.data".to_string()),
+        ("offset", "8".to_string()),
+        ("length", (external.len() - 8).to_string()),
+    ] {
+        let mut entry = Vec::new();
+        proto_bytes_field(&mut entry, 1, key.as_bytes());
+        proto_bytes_field(&mut entry, 2, value.as_bytes());
+        proto_bytes_field(&mut tensor, 13, &entry);
+    }
+    proto_varint_field(&mut tensor, 14, 1);
+    proto_bytes_field(&mut graph, 5, &tensor);
+
+    // Neither a bias nor an INT64 shape constant becomes a layer.
+    let mut tensor = onnx_tensor("model.norm.bias", &[16], 1);
+    proto_bytes_field(&mut tensor, 9, &f32_bytes(&[0.5; 16]));
+    proto_bytes_field(&mut graph, 5, &tensor);
+    let mut tensor = onnx_tensor("shape", &[1, 2], 7);
+    proto_bytes_field(&mut tensor, 9, &[0u8; 16]);
+    proto_bytes_field(&mut graph, 5, &tensor);
+
+    let mut model = Vec::new();
+    proto_varint_field(&mut model, 1, 8);
+    proto_bytes_field(&mut model, 7, &graph);
+    let model_path = dir.path().join("model.onnx");
+    std::fs::write(&model_path, &model)?;
+
+    let writer_config = ArtifactWriterConfig {
+        output_dir: dir.path().to_path_buf(),
+        ..ArtifactWriterConfig::default()
+    };
+    let mut writer = ArtifactWriter::new(writer_config.clone());
+    let loaded = crate::onnx::OnnxLoader::new(QuantizationConfig::default())?
+        .load_from_path(&model_path, &mut writer)
+        .await?;
+    let mut writer = ArtifactWriter::new(writer_config);
+    let expected = SafeTensorsLoader::new(QuantizationConfig::default())?
+        .load_from_reader(Cursor::new(safetensors_file(&tensors)), &mut writer)
+        .await?;
+
+    assert_eq!(loaded.layers.len(), 4);
+    for (layer, reference) in loaded.layers.iter().zip(&expected.layers) {
+        assert_eq!(layer.name, reference.name);
+        assert_eq!(layer.dequantize(), reference.dequantize(), "{}", layer.name);
+    }
+    Ok(())
+}
+
+/// Pickle opcodes for a string, an int and a global, as `torch.save` emits them.
+fn pickle_str(out: &mut Vec<u8>, value: &str) {
+    out.push(b'X');
+    out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn pickle_int(out: &mut Vec<u8>, value: i32) {
+    out.push(b'J');
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn pickle_global(out: &mut Vec<u8>, module: &str, name: &str) {
+    out.extend_from_slice(format!("c{module}\n{name}\n").as_bytes());
+}
+
+/// Pushes `_rebuild_tensor_v2(storage, offset, size, stride, False, OrderedDict())`.
+fn pickle_tensor(out: &mut Vec<u8>, storage: (&str, &str, i32), size: &[i32], stride: &[i32]) {
+    let (kind, key, numel) = storage;
+    pickle_global(out, "torch._utils", "_rebuild_tensor_v2");
+    out.push(b'(');
+    out.push(b'(');
+    pickle_str(out, "storage");
+    pickle_global(out, "torch", kind);
+    pickle_str(out, key);
+    pickle_str(out, "cpu");
+    pickle_int(out, numel);
+    out.extend_from_slice(b"tQ");
+    pickle_int(out, 0);
+    for dims in [size, stride] {
+        out.push(b'(');
+        for &dim in dims {
+            pickle_int(out, dim);
+        }
+        out.push(b't');
+    }
+    out.push(0x89);
+    pickle_global(out, "collections", "OrderedDict");
+    out.extend_from_slice(b")RtR");
+}
+
+#[test]
+fn pytorch_checkpoints_match_safetensors() -> Result<()> {
+    let tensors: Vec<(String, Vec<usize>, Vec<f32>)> = (0..2)
+        .map(|layer| {
+            let values = (0..16 * 16)
+                .map(|i| ((i * 5 + layer * 3) % 13) as f32 * 0.25 - 1.5)
+                .collect();
+            (format!("model.layers.{layer}.weight"), vec![16, 16], values)
+        })
+        .collect();
+
+    // Layer 0 is a contiguous FloatStorage. Layer 1 is a transposed view of
+    // a HalfStorage, and layer 2 a parameter tied to layer 0's storage.
+    let mut pickle = b"\x80\x02".to_vec();
+    pickle_global(&mut pickle, "collections", "OrderedDict");
+    pickle.extend_from_slice(b")Rq\x00(");
+    pickle_str(&mut pickle, "model.layers.0.weight");
+    pickle_tensor(&mut pickle, ("FloatStorage", "0", 256), &[16, 16], &[16, 1]);
+    pickle_str(&mut pickle, "model.layers.1.weight");
+    pickle_tensor(&mut pickle, ("HalfStorage", "1", 256), &[16, 16], &[1, 16]);
+    pickle_str(&mut pickle, "model.layers.1.bias");
+    pickle_tensor(&mut pickle, ("FloatStorage", "2", 16), &[16], &[1]);
+    pickle_str(&mut pickle, "model.layers.2.weight");
+    pickle_global(&mut pickle, "torch._utils", "_rebuild_parameter");
+    pickle.push(b'(');
+    pickle_tensor(&mut pickle, ("FloatStorage", "0", 256), &[16, 16], &[16, 1]);
+    pickle.push(0x88);
+    pickle_global(&mut pickle, "collections", "OrderedDict");
+    pickle.extend_from_slice(b")RtR");
+    // SETITEMS, then BUILD with the `_metadata` state torch attaches.
+    pickle.extend_from_slice(b"u}b.");
+
+    let transposed: Vec<u8> = (0..16 * 16)
+        .flat_map(|i| {
+            let value = tensors[1].2[(i % 16) * 16 + i / 16];
+            half::f16::from_f32(value).to_le_bytes()
+        })
+        .collect();
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options =
+        zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    for (name, bytes) in [
+        ("archive/data.pkl", pickle),
+        ("archive/byteorder", b"little".to_vec()),
+        (
+            "archive/data/0",
+            tensors[0].2.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        ),
+        ("archive/data/1", transposed),
+        ("archive/data/2", vec![0u8; 64]),
+        ("archive/version", b"3\n".to_vec()),
+    ] {
+        zip.start_file(name, options)?;
+        std::io::Write::write_all(&mut zip, &bytes)?;
+    }
+    let mut checkpoint = zip.finish()?;
+
+    let dir = tempdir()?;
+    let writer_config = ArtifactWriterConfig {
+        output_dir: dir.path().to_path_buf(),
+        ..ArtifactWriterConfig::default()
+    };
+    let mut writer = ArtifactWriter::new(writer_config.clone());
+    checkpoint.set_position(0);
+    let loaded = crate::pytorch::PyTorchLoader::new(QuantizationConfig::default())?
+        .load_from_reader(checkpoint, &mut writer)?;
+
+    let mut expected_tensors = tensors.clone();
+    expected_tensors.push((
+        "model.layers.2.weight".into(),
+        vec![16, 16],
+        tensors[0].2.clone(),
+    ));
+    let mut writer = ArtifactWriter::new(writer_config);
+    let expected = tokio::runtime::Runtime::new()?.block_on(
+        SafeTensorsLoader::new(QuantizationConfig::default())?.load_from_reader(
+            Cursor::new(safetensors_file(&expected_tensors)),
+            &mut writer,
+        ),
+    )?;
+
+    assert_eq!(loaded.layers.len(), 3);
+    for (layer, reference) in loaded.layers.iter().zip(&expected.layers) {
+        assert_eq!(layer.name, reference.name);
+        assert_eq!(layer.dequantize(), reference.dequantize(), "{}", layer.name);
+    }
     Ok(())
 }
 
+#[tokio::test]
+async fn archives_match_their_members() -> Result<()> {
+    let tensors: Vec<(String, Vec<usize>, Vec<f32>)> = (0..2)
+        .map(|layer| {
+            let values = (0..8 * 16)
+                .map(|i| ((i * 3 + layer * 7) % 11) as f32 * 0.5 - 2.5)
+                .collect();
+            (format!("model.layers.{layer}.weight"), vec![8, 16], values)
+        })
+        .collect();
+    let gguf = synthetic_gguf();
+    let safetensors = safetensors_file(&tensors);
+
+    let dir = tempdir()?;
+    let writer_config = ArtifactWriterConfig {
+        output_dir: dir.path().to_path_buf(),
+        ..ArtifactWriterConfig::default()
+    };
+    let mut writer = ArtifactWriter::new(writer_config.clone());
+    let mut expected = GgufLoader::new(QuantizationConfig::default())?
+        .load_from_reader(&mut Cursor::new(gguf.clone()), &mut writer)
+        .await?;
+    expected.layers.extend(
+        SafeTensorsLoader::new(QuantizationConfig::default())?
+            .load_from_reader(Cursor::new(safetensors.clone()), &mut writer)
+            .await?
+            .layers,
+    );
+
+    // Tar members are loaded in archive order, and the config is skipped.
+    let mut tar = tokio_tar::Builder::new(Vec::new());
+    for (name, bytes) in [
+        ("bundle/model.gguf", &gguf),
+        ("bundle/config.json", &b"{}".to_vec()),
+        ("bundle/model.safetensors", &safetensors),
+    ] {
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, name, bytes.as_slice()).await?;
+    }
+    let tar = tar.into_inner().await?;
+    let mut gzip = async_compression::tokio::write::GzipEncoder::new(Vec::new());
+    gzip.write_all(&tar).await?;
+    gzip.shutdown().await?;
+
+    // Zip members are sorted by name, so the GGUF goes last here.
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let deflated = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+    zip.start_file("bundle/b.gguf", deflated)?;
+    std::io::Write::write_all(&mut zip, &gguf)?;
+    zip.start_file("bundle/a.safetensors", deflated)?;
+    std::io::Write::write_all(&mut zip, &safetensors)?;
+    let zip = zip.finish()?.into_inner();
+
+    let archives = dir.path().join("archives");
+    std::fs::create_dir(&archives)?;
+    for (name, bytes) in [
+        ("bundle.tar", tar),
+        ("bundle.tgz", gzip.into_inner()),
+        ("bundle.zip", zip),
+    ] {
+        let path = archives.join(name);
+        std::fs::write(&path, bytes)?;
+        let mut writer = ArtifactWriter::new(writer_config.clone());
+        let loaded = crate::archive::ArchiveLoader::new(QuantizationConfig::default())?
+            .load_from_path(&path, &mut writer)
+            .await?;
+
+        let mut reference: Vec<_> = expected.layers.iter().collect();
+        if name.ends_with(".zip") {
+            reference.rotate_left(1);
+        }
+        assert_eq!(loaded.layers.len(), 3, "{name}");
+        for (index, (layer, reference)) in loaded.layers.iter().zip(reference).enumerate() {
+            assert_eq!(layer.name, reference.name, "{name}");
+            assert_eq!(layer.index, index, "{name}");
+            assert_eq!(layer.dequantize(), reference.dequantize(), "{name}");
+        }
+    }
+    Ok(())
+}
+
+fn push_gguf_string(bytes: &mut Vec<u8>, value: &str) {
+    bytes.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+/// Tensor data starts at the next multiple of the default 32-byte alignment.
+fn pad_gguf_header(bytes: &mut Vec<u8>) {
+    bytes.resize(bytes.len().next_multiple_of(32), 0);
+}
+
 fn synthetic_gguf() -> Vec<u8> {
     let rows = 4usize;
     let cols = 4usize;
     let mut bytes = Vec::new();
     bytes.extend_from_slice(b"GGUF");
-    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(&3u32.to_le_bytes());
     bytes.extend_from_slice(&1u64.to_le_bytes());
     bytes.extend_from_slice(&0u64.to_le_bytes());
 
-    let name = b"linear.weight";
-    bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
-    bytes.extend_from_slice(name);
+    push_gguf_string(&mut bytes, "linear.weight");
     bytes.extend_from_slice(&2u32.to_le_bytes());
     bytes.extend_from_slice(&(rows as u64).to_le_bytes());
     bytes.extend_from_slice(&(cols as u64).to_le_bytes());
     bytes.extend_from_slice(&0u32.to_le_bytes());
     bytes.extend_from_slice(&0u64.to_le_bytes());
+    pad_gguf_header(&mut bytes);
 
     for value in 0..(rows * cols) {
         let f = value as f32;
@@ -97,6 +1203,7 @@ async fn gguf_loader_quantizes() -> Result<()> {
     let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
         chunk_bytes: 1 << 20,
         output_dir: dir.path().to_path_buf(),
+        ..ArtifactWriterConfig::default()
     });
     let loader = GgufLoader::new(QuantizationConfig::default())?;
     let data = synthetic_gguf();
@@ -107,3 +1214,310 @@ async fn gguf_loader_quantizes() -> Result<()> {
     assert!(!writer.manifest().chunks.is_empty());
     Ok(())
 }
+
+/// A GGUF with an array-valued kv entry, a non-square Q8_0 tensor and an F16
+/// tensor. Dims are written `[cols, rows]` as llama.cpp does.
+fn mixed_gguf() -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(b"GGUF");
+    bytes.extend_from_slice(&3u32.to_le_bytes());
+    bytes.extend_from_slice(&2u64.to_le_bytes());
+    bytes.extend_from_slice(&3u64.to_le_bytes());
+
+    push_gguf_string(&mut bytes, "general.architecture");
+    bytes.extend_from_slice(&8u32.to_le_bytes());
+    push_gguf_string(&mut bytes, "llama");
+
+    push_gguf_string(&mut bytes, "llama.context_length");
+    bytes.extend_from_slice(&4u32.to_le_bytes());
+    bytes.extend_from_slice(&2048u32.to_le_bytes());
+
+    push_gguf_string(&mut bytes, "tokenizer.ggml.tokens");
+    bytes.extend_from_slice(&9u32.to_le_bytes());
+    bytes.extend_from_slice(&8u32.to_le_bytes());
+    bytes.extend_from_slice(&3u64.to_le_bytes());
+    for token in ["<s>", "</s>", "hello"] {
+        push_gguf_string(&mut bytes, token);
+    }
+
+    let q8_len = 3 * 2 * 34;
+    for (name, ty, rows, cols, offset) in [
+        ("blk.0.ffn_up.weight", 8u32, 3u64, 64u64, 0u64),
+        ("blk.0.attn_q.weight", 1, 2, 8, q8_len),
+    ] {
+        push_gguf_string(&mut bytes, name);
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&cols.to_le_bytes());
+        bytes.extend_from_slice(&rows.to_le_bytes());
+        bytes.extend_from_slice(&ty.to_le_bytes());
+        bytes.extend_from_slice(&offset.to_le_bytes());
+    }
+    pad_gguf_header(&mut bytes);
+
+    for block in 0..6 {
+        bytes.extend_from_slice(&half::f16::from_f32(0.5).to_le_bytes());
+        bytes.extend((0..32).map(|i| (((i + block) % 9) as i8 - 4) as u8));
+    }
+    for value in 0..16 {
+        let value = half::f16::from_f32(value as f32 * 0.25 - 2.0);
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes
+}
+
+fn assert_mixed_layers(model: &novaq_core::QuantizedModel) {
+    let shapes: Vec<_> = model
+        .layers
+        .iter()
+        .map(|layer| (layer.name.as_str(), layer.rows, layer.cols))
+        .collect();
+    assert_eq!(
+        shapes,
+        [
+            ("blk.0.ffn_up.weight", 3, 64),
+            ("blk.0.attn_q.weight", 2, 8)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn gguf_loader_dequantizes_block_types() -> Result<()> {
+    let dir = tempdir()?;
+    let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+        output_dir: dir.path().to_path_buf(),
+        ..ArtifactWriterConfig::default()
+    });
+    let loader = GgufLoader::new(QuantizationConfig::default())?;
+    let mut reader = tokio::io::BufReader::new(Cursor::new(mixed_gguf()));
+    let model = loader.load_from_reader(&mut reader, &mut writer).await?;
+    assert_mixed_layers(&model);
+    Ok(())
+}
+
+#[tokio::test]
+async fn gguf_metadata_reaches_the_manifest() -> Result<()> {
+    let dir = tempdir()?;
+    let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+        output_dir: dir.path().to_path_buf(),
+        ..ArtifactWriterConfig::default()
+    });
+    let loader = GgufLoader::new(QuantizationConfig::default())?;
+    let mut reader = tokio::io::BufReader::new(Cursor::new(mixed_gguf()));
+    let (model, metadata) = loader.load_with_metadata(&mut reader, &mut writer).await?;
+    assert_eq!(metadata.version, 3);
+    assert_eq!(metadata.architecture(), Some("llama"));
+    assert_eq!(metadata.context_length(), Some(2048));
+
+    let manifest = assemble_manifest(
+        &crate::format::ModelLocator::new("mixed.gguf"),
+        "test",
+        &QuantizationConfig::default(),
+        &model,
+        writer.manifest(),
+    )?;
+    assert_eq!(manifest.metadata["gguf.general.architecture"], "llama");
+    assert_eq!(manifest.metadata["gguf.llama.context_length"], "2048");
+    assert!(!manifest.metadata.contains_key("gguf.tokenizer.ggml.tokens"));
+    serde_json::to_writer(
+        std::fs::File::create(dir.path().join(MANIFEST_FILE))?,
+        &manifest,
+    )?;
+
+    let reader = ArtifactReader::open(dir.path())?;
+    assert!(!reader.read_side_file("tokenizer")?.is_empty());
+    assert_eq!(GgufMetadata::from_artifact(&reader)?, Some(metadata));
+    Ok(())
+}
+
+/// A GGUF with the given `(name, type, dims)` tensors laid out back to back
+/// over `data`.
+fn gguf_with_tensors(tensors: &[(&str, u32, &[u64])], data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(b"GGUF");
+    bytes.extend_from_slice(&3u32.to_le_bytes());
+    bytes.extend_from_slice(&(tensors.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    let mut offset = 0u64;
+    for &(name, ty, dims) in tensors {
+        push_gguf_string(&mut bytes, name);
+        bytes.extend_from_slice(&(dims.len() as u32).to_le_bytes());
+        for dim in dims {
+            bytes.extend_from_slice(&dim.to_le_bytes());
+        }
+        bytes.extend_from_slice(&ty.to_le_bytes());
+        bytes.extend_from_slice(&offset.to_le_bytes());
+        // F32 elements, which is all these tests lay out.
+        offset += 4 * dims.iter().product::<u64>().min(1 << 20);
+    }
+    pad_gguf_header(&mut bytes);
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+#[tokio::test]
+async fn gguf_loaders_number_and_reject_tensors_alike() -> Result<()> {
+    let dir = tempdir()?;
+    let writer = || {
+        ArtifactWriter::new(ArtifactWriterConfig {
+            output_dir: dir.path().to_path_buf(),
+            ..ArtifactWriterConfig::default()
+        })
+    };
+    let loader = GgufLoader::new(QuantizationConfig::default())?;
+    let parser = StreamingGgufParser::new(QuantizationConfig::default(), None)?;
+    let load = |data: Vec<u8>| {
+        let mut seekable = writer();
+        let mut streamed = writer();
+        let loader = &loader;
+        let parser = &parser;
+        async move {
+            let seekable = loader
+                .load_from_reader(Cursor::new(data.clone()), &mut seekable)
+                .await;
+            let streamed = parser
+                .parse_and_quantize(
+                    Box::pin(futures::stream::iter([Ok(bytes::Bytes::from(data))])),
+                    &mut streamed,
+                )
+                .await;
+            (seekable, streamed)
+        }
+    };
+    let values: Vec<u8> = (0..20).flat_map(|v| (v as f32).to_le_bytes()).collect();
+
+    // A skipped 1-D norm ahead of the matrix must not shift its index.
+    let (seekable, streamed) = load(gguf_with_tensors(
+        &[("norm", 0, &[4]), ("linear.weight", 0, &[4, 4])],
+        &values,
+    ))
+    .await;
+    for model in [seekable?, streamed?] {
+        assert_eq!(model.layers.len(), 1);
+        assert_eq!(model.layers[0].index, 0);
+    }
+
+    // Type 255 is not a ggml type either loader can decode.
+    let (seekable, streamed) =
+        load(gguf_with_tensors(&[("odd.weight", 255, &[4, 4])], &values)).await;
+    for err in [seekable.unwrap_err(), streamed.unwrap_err()] {
+        assert!(
+            err.to_string().contains("unsupported GGUF tensor type"),
+            "{err}"
+        );
+    }
+
+    // Dims promising far more data than the file holds fail before allocating.
+    let (seekable, _) = load(gguf_with_tensors(
+        &[("huge.weight", 0, &[1 << 20, 1 << 20])],
+        &values,
+    ))
+    .await;
+    let err = format!("{:#}", seekable.unwrap_err());
+    assert!(err.contains("past the end of the file"), "{err}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn gguf_codebook_lengths_are_checked_against_the_file() -> Result<()> {
+    let dir = tempdir()?;
+    let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+        output_dir: dir.path().to_path_buf(),
+        ..ArtifactWriterConfig::default()
+    });
+    let mut data = (1u64 << 40).to_le_bytes().to_vec();
+    data.extend_from_slice(&[0; 24]);
+    let gguf = gguf_with_tensors(&[("codebook.weight", NOVAQ_GGUF_TYPE_ID, &[4, 4])], &data);
+    let err = GgufLoader::new(QuantizationConfig::default())?
+        .load_from_reader(Cursor::new(gguf), &mut writer)
+        .await
+        .unwrap_err();
+    assert!(
+        format!("{err:#}").contains("only 24 bytes follow"),
+        "{err:#}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn gguf_export_round_trips_through_the_loader() -> Result<()> {
+    let dir = tempdir()?;
+    // Each load writes the same layer names, so each gets its own artifact.
+    let writer = || {
+        ArtifactWriter::new(ArtifactWriterConfig {
+            output_dir: dir.path().to_path_buf(),
+            ..ArtifactWriterConfig::default()
+        })
+    };
+    let loader = GgufLoader::new(QuantizationConfig::default())?;
+    for source in [synthetic_gguf(), mixed_gguf()] {
+        let (model, metadata) = loader
+            .load_with_metadata(Cursor::new(source), &mut writer())
+            .await?;
+        for mode in [GgufExportMode::F16, GgufExportMode::Codebook] {
+            let mut exported = Vec::new();
+            GgufExporter::new(mode)
+                .with_metadata(metadata.clone())
+                .write(&model, &mut exported)?;
+            let (reloaded, reloaded_metadata) = loader
+                .load_with_metadata(Cursor::new(exported.clone()), &mut writer())
+                .await?;
+            let streamed = StreamingGgufParser::new(QuantizationConfig::default(), None)?
+                .parse_and_quantize(
+                    Box::pin(futures::stream::iter([Ok(bytes::Bytes::from(exported))])),
+                    &mut writer(),
+                )
+                .await?;
+
+            let shapes = |model: &novaq_core::QuantizedModel| {
+                model
+                    .layers
+                    .iter()
+                    .map(|layer| (layer.name.clone(), layer.rows, layer.cols))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(shapes(&reloaded), shapes(&model));
+            assert_eq!(shapes(&streamed), shapes(&model));
+            for (key, value) in &metadata.entries {
+                assert_eq!(reloaded_metadata.get(key), Some(value), "{key}");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn streaming_gguf_handles_small_chunks() -> Result<()> {
+    let data = mixed_gguf();
+    let chunks = |data: &[u8]| {
+        let chunks: Vec<Result<bytes::Bytes>> = data
+            .chunks(7)
+            .map(|chunk| Ok(bytes::Bytes::copy_from_slice(chunk)))
+            .collect();
+        Box::pin(futures::stream::iter(chunks))
+    };
+
+    let dir = tempdir()?;
+    let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+        output_dir: dir.path().to_path_buf(),
+        ..ArtifactWriterConfig::default()
+    });
+    let parser = StreamingGgufParser::new(QuantizationConfig::default(), None)?;
+    let (model, metadata) = parser
+        .parse_with_metadata(chunks(&data), &mut writer)
+        .await?;
+    assert_mixed_layers(&model);
+    assert_eq!(metadata.len(), 3);
+    assert!(writer.manifest().side_files.contains_key("tokenizer"));
+
+    let truncated = &data[..data.len() - 5];
+    let mut writer = ArtifactWriter::new(ArtifactWriterConfig {
+        output_dir: dir.path().to_path_buf(),
+        ..ArtifactWriterConfig::default()
+    });
+    let err = parser
+        .parse_and_quantize(chunks(truncated), &mut writer)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("ended before"), "{err}");
+    Ok(())
+}
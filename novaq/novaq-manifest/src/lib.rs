@@ -12,10 +12,46 @@ pub struct Manifest {
     pub created_at: DateTime<Utc>,
     pub generator: String,
     pub source_locator: String,
+    /// Exact origin of the source files, for sources that can be pinned such
+    /// as Hugging Face repositories.
+    #[serde(default)]
+    pub source: Option<SourceProvenance>,
     pub quantization: QuantizationSection,
+    /// How the layer chunks are serialized; manifests that predate the field
+    /// describe JSON chunks.
+    #[serde(default)]
+    pub layer_encoding: LayerEncoding,
+    /// Size layers were packed into chunks at; absent from manifests that
+    /// predate the field.
+    #[serde(default)]
+    pub chunk_bytes: Option<usize>,
     pub chunks: Vec<ChunkEntry>,
     pub layers: BTreeMap<String, LayerEntry>,
     pub metadata: BTreeMap<String, String>,
+    /// Files stored next to the chunks that belong to no layer, keyed by role
+    /// (for example `tokenizer`).
+    #[serde(default)]
+    pub side_files: BTreeMap<String, SideFileEntry>,
+}
+
+/// Where a model was loaded from, precisely enough to fetch the same bytes
+/// again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct SourceProvenance {
+    pub repo_id: String,
+    /// Revision as requested, such as a branch name.
+    pub revision: String,
+    /// Commit the revision resolved to, when the Hub reported one.
+    pub commit: Option<String>,
+    pub files: Vec<SourceFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct SourceFile {
+    pub path: String,
+    pub size: u64,
+    /// sha256 of the content, for files stored through Git LFS.
+    pub oid: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
@@ -24,6 +60,33 @@ pub struct QuantizationSection {
     pub summary: serde_json::Value,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerFormat {
+    /// One `serde_json` document per layer.
+    #[default]
+    Json,
+    /// Versioned binary container with bit-packed assignments.
+    Packed,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CentroidPrecision {
+    #[default]
+    F32,
+    /// Codebooks rounded to half precision on write.
+    F16,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct LayerEncoding {
+    pub format: LayerFormat,
+    /// Container version for packed layers; zero for JSON.
+    pub version: u16,
+    pub centroid_precision: CentroidPrecision,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct ChunkEntry {
     pub index: usize,
@@ -33,6 +96,14 @@ pub struct ChunkEntry {
     pub blake3: String,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct SideFileEntry {
+    pub path: String,
+    pub bytes: usize,
+    pub sha256: String,
+    pub blake3: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct LayerEntry {
     pub mse: f32,
@@ -41,6 +112,18 @@ pub struct LayerEntry {
     pub residual_energy: f32,
     pub bits_per_weight: f32,
     pub subspaces: Vec<serde_json::Value>,
+    /// Where the layer's serialized bytes live, in order; concatenating the
+    /// spans yields one layer in the manifest's `layer_encoding`.
+    #[serde(default)]
+    pub spans: Vec<ChunkSpan>,
+}
+
+/// Byte range `offset..offset + len` of chunk `chunk_index`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ChunkSpan {
+    pub chunk_index: usize,
+    pub offset: usize,
+    pub len: usize,
 }
 
 impl Manifest {
@@ -55,10 +138,14 @@ impl Manifest {
             created_at: Utc::now(),
             generator: generator.into(),
             source_locator: source_locator.into(),
+            source: None,
             quantization,
+            layer_encoding: LayerEncoding::default(),
+            chunk_bytes: None,
             chunks: Vec::new(),
             layers: BTreeMap::new(),
             metadata: BTreeMap::new(),
+            side_files: BTreeMap::new(),
         }
     }
 
@@ -80,4 +167,21 @@ mod tests {
         let schema = schemars::schema_for!(Manifest);
         assert!(schema.schema.object.is_some());
     }
+
+    #[test]
+    fn manifests_without_layer_encoding_describe_json_chunks() {
+        let mut value = serde_json::to_value(Manifest::new(
+            "1.0.0",
+            "test",
+            "model.safetensors",
+            QuantizationSection {
+                config: serde_json::Value::Null,
+                summary: serde_json::Value::Null,
+            },
+        ))
+        .unwrap();
+        value.as_object_mut().unwrap().remove("layer_encoding");
+        let manifest: Manifest = serde_json::from_value(value).unwrap();
+        assert_eq!(manifest.layer_encoding.format, LayerFormat::Json);
+    }
 }